@@ -0,0 +1,28 @@
+-- The message this message is replying to
+-- Replies are kept if the parent message is deleted
+ALTER TABLE messages ADD COLUMN reply_to INTEGER REFERENCES messages(id) ON DELETE SET NULL;
+-- The first message in the reply chain this message is a part of
+-- Stored on every reply so threads can be counted without walking the chain
+ALTER TABLE messages ADD COLUMN thread_root_id INTEGER REFERENCES messages(id) ON DELETE SET NULL;
+
+CREATE INDEX idx_messages_reply_to ON messages (reply_to);
+CREATE INDEX idx_messages_thread_root_id ON messages (thread_root_id);
+
+DROP VIEW chat_messages;
+
+CREATE VIEW chat_messages AS
+SELECT 
+	messages.id, 
+	messages.message, 
+	messages.user_id, 
+	messages.ai_model_id, 
+	messages.file_name, 
+	messages.created_at, 
+	messages.modified_at, 
+	messages.conversation_id, 
+	files.path as file_path,
+	messages.reply_to,
+	messages.thread_root_id,
+	(SELECT COUNT(*) FROM messages AS replies WHERE replies.thread_root_id = messages.id) as thread_reply_count
+FROM messages
+LEFT JOIN files ON messages.file_id = files.id;
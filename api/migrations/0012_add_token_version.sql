@@ -0,0 +1,3 @@
+-- Embedded in every issued JWT. Incrementing it invalidates all of the user's
+-- existing tokens, which is how logging out is implemented
+ALTER TABLE users ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0;
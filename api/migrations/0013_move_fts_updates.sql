@@ -0,0 +1,8 @@
+-- The full text search index is now kept up to date by the application instead of triggers.
+-- A trigger failing aborts the statement that fired it, so a corrupted index would prevent
+-- messages from being sent, edited, or deleted. Messages deleted through a cascade leave stale
+-- entries in the index which are filtered out by joining on `messages` and removed when the
+-- index is rebuilt.
+DROP TRIGGER messages_fts_insert;
+DROP TRIGGER messages_fts_delete;
+DROP TRIGGER messages_fts_update;
@@ -0,0 +1,23 @@
+-- A short snippet of the text in a document, such as the first page of a PDF
+-- Null if the file isn't a document or no text could be extracted from it
+ALTER TABLE files ADD COLUMN text_preview TEXT;
+
+DROP VIEW chat_messages;
+
+CREATE VIEW chat_messages AS
+SELECT 
+	messages.id, 
+	messages.message, 
+	messages.user_id, 
+	messages.ai_model_id, 
+	messages.file_name, 
+	messages.created_at, 
+	messages.modified_at, 
+	messages.conversation_id, 
+	files.path as file_path,
+	files.text_preview as file_preview,
+	messages.reply_to,
+	messages.thread_root_id,
+	(SELECT COUNT(*) FROM messages AS replies WHERE replies.thread_root_id = messages.id) as thread_reply_count
+FROM messages
+LEFT JOIN files ON messages.file_id = files.id;
@@ -0,0 +1,14 @@
+-- Users who have blocked another user
+-- Blocked users can't send friend requests to the blocker
+-- and their messages aren't delivered to the blocker
+CREATE TABLE blocked_users (
+    blocker_id INTEGER NOT NULL,
+    blocked_id INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK (blocker_id != blocked_id),
+    PRIMARY KEY (blocker_id, blocked_id),
+    FOREIGN KEY (blocker_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (blocked_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_blocked_users_blocked_id ON blocked_users(blocked_id);
@@ -0,0 +1,59 @@
+-- Users who log in with an OAuth provider don't have a password, so password_hash has to be
+-- nullable. SQLite can't drop a NOT NULL constraint with ALTER TABLE, so the table is rebuilt as
+-- described in https://www.sqlite.org/lang_altertable.html#otheralter. Migrations run with foreign
+-- keys turned off, so dropping the old table doesn't delete the rows referencing it
+CREATE TABLE users_new (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	first_name TEXT NOT NULL,
+	last_name TEXT,
+	image_id INTEGER,
+	username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+	email TEXT NOT NULL UNIQUE COLLATE NOCASE,
+	password_hash TEXT,
+	created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	modified_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	token_version INTEGER NOT NULL DEFAULT 0,
+	email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+	FOREIGN KEY (image_id) REFERENCES files(id)
+);
+
+INSERT INTO users_new (id, first_name, last_name, image_id, username, email, password_hash,
+	created_at, modified_at, token_version, email_verified)
+SELECT id, first_name, last_name, image_id, username, email, password_hash,
+	created_at, modified_at, token_version, email_verified
+FROM users;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+-- Dropping the table dropped its index and trigger
+CREATE UNIQUE INDEX idx_users_username ON users (username);
+
+CREATE TRIGGER users_update_modified_at AFTER UPDATE ON users
+BEGIN
+    UPDATE users
+    SET modified_at = CURRENT_TIMESTAMP
+    WHERE id = NEW.id;
+END;
+
+-- Accounts with an OAuth provider that can be used to log in as a user
+CREATE TABLE oauth_accounts (
+    provider TEXT NOT NULL,
+    -- The id of the account with the provider
+    provider_user_id TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (provider, provider_user_id),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_oauth_accounts_user_id ON oauth_accounts(user_id);
+
+-- Nonces sent to the provider when starting a login to check that the callback
+-- is for a login this server started
+CREATE TABLE oauth_states (
+    state_hash TEXT PRIMARY KEY NOT NULL,
+    provider TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,15 @@
+-- Long lived keys users can create to access the API from scripts.
+-- Only the hash of the key is stored, the key itself is shown once when it is created
+CREATE TABLE api_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    -- Either 'read_only' or 'full'
+    scope TEXT NOT NULL DEFAULT 'full',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_api_keys_user_id ON api_keys(user_id);
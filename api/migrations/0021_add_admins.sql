@@ -0,0 +1,3 @@
+-- Admins can use the maintenance endpoints, such as exporting and importing conversations.
+-- Granted with the `grant-admin` command
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,11 @@
+-- Only count edits to the content of a message as modifying it.
+-- Deleting a message sets `reply_to` and `thread_root_id` to null on its replies,
+-- which would otherwise make every reply look like it was edited
+DROP TRIGGER update_modified_at;
+
+CREATE TRIGGER update_modified_at AFTER UPDATE OF message, file_id, file_name ON messages
+BEGIN
+    UPDATE messages
+    SET modified_at = CURRENT_TIMESTAMP
+    WHERE id = NEW.id;
+END;
@@ -0,0 +1,3 @@
+-- Muted conversations don't notify the user of new messages or presence changes
+-- unless they are viewing the conversation
+ALTER TABLE user_conversations ADD COLUMN muted BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,25 @@
+-- The previous contents of a message, saved every time it is edited
+CREATE TABLE message_edits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    message_id INTEGER NOT NULL,
+    -- The contents of the message before the edit
+    message TEXT NOT NULL,
+    -- When the contents were replaced
+    edited_at TIMESTAMP NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+CREATE INDEX message_edits_message_id ON message_edits(message_id);
+
+-- Edits set `modified_at` themselves with sub-second precision so a message edited within
+-- a second of being sent still has a different `modified_at` than `created_at`.
+-- Only fall back to the trigger when an update doesn't set it
+DROP TRIGGER update_modified_at;
+
+CREATE TRIGGER update_modified_at AFTER UPDATE OF message, file_id, file_name ON messages
+WHEN NEW.modified_at = OLD.modified_at
+BEGIN
+    UPDATE messages
+    SET modified_at = CURRENT_TIMESTAMP
+    WHERE id = NEW.id;
+END;
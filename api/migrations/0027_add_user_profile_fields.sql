@@ -0,0 +1,4 @@
+-- Optional details shown on a user's public profile
+ALTER TABLE users ADD COLUMN bio TEXT;
+ALTER TABLE users ADD COLUMN pronouns TEXT;
+ALTER TABLE users ADD COLUMN location TEXT;
@@ -0,0 +1,16 @@
+-- Owners and admins can remove other members of a conversation.
+-- Only the owner can make members admins
+ALTER TABLE user_conversations ADD COLUMN role TEXT NOT NULL DEFAULT 'member'
+    CHECK (role IN ('owner', 'admin', 'member'));
+
+-- Only let owners and admins rename the conversation
+ALTER TABLE conversations ADD COLUMN admins_only_rename BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- The earliest member of each existing conversation becomes its owner
+UPDATE user_conversations SET role = 'owner'
+WHERE NOT EXISTS (
+    SELECT 1 FROM user_conversations AS earlier
+    WHERE earlier.conversation_id = user_conversations.conversation_id
+    AND (earlier.joined_at < user_conversations.joined_at
+        OR (earlier.joined_at = user_conversations.joined_at AND earlier.user_id < user_conversations.user_id))
+);
@@ -0,0 +1,16 @@
+-- When the user's last websocket connection closed
+ALTER TABLE users ADD COLUMN last_seen_at TIMESTAMP;
+
+-- Lets users hide when they were last seen and whether they are idle
+ALTER TABLE user_settings ADD COLUMN hide_last_seen BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Going offline isn't a change to the user's account
+DROP TRIGGER users_update_modified_at;
+
+CREATE TRIGGER users_update_modified_at AFTER UPDATE ON users
+WHEN NEW.last_seen_at IS OLD.last_seen_at
+BEGIN
+    UPDATE users
+    SET modified_at = CURRENT_TIMESTAMP
+    WHERE id = NEW.id;
+END;
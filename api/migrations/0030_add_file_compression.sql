@@ -0,0 +1,4 @@
+-- Uploads can be stored compressed on disk and are decompressed when downloaded
+ALTER TABLE files ADD COLUMN compressed BOOLEAN NOT NULL DEFAULT FALSE;
+-- The size of the file before it was compressed, in bytes
+ALTER TABLE files ADD COLUMN size INTEGER;
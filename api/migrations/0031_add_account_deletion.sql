@@ -0,0 +1,17 @@
+-- Deleted accounts are kept for a grace period so they can be reactivated before they are purged
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMP;
+
+-- The public profile of a deleted account from before it was anonymized,
+-- restored if the account is reactivated. The username stays reserved until the account is purged
+CREATE TABLE deleted_user_profiles (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    first_name TEXT NOT NULL,
+    last_name TEXT,
+    image_id INTEGER,
+    bio TEXT,
+    pronouns TEXT,
+    location TEXT,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (image_id) REFERENCES files(id) ON DELETE SET NULL
+);
@@ -0,0 +1,11 @@
+-- Email changes wait here until the new address is confirmed with the token emailed to it.
+-- Only a hash of the token is stored, the same as email verifications
+CREATE TABLE pending_email_changes (
+    -- A user can only have one email change waiting at a time
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    email TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,13 @@
+-- Every username a user has changed away from, used to limit how often usernames change
+-- and to hold on to old usernames for a while so no one can impersonate the previous owner
+CREATE TABLE username_history (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    old_username TEXT NOT NULL COLLATE NOCASE,
+    new_username TEXT NOT NULL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_username_history_user ON username_history(user_id, changed_at);
+CREATE INDEX idx_username_history_old_username ON username_history(old_username);
@@ -0,0 +1,7 @@
+-- Regional and notification preferences, existing users get the defaults
+ALTER TABLE user_settings ADD COLUMN timezone TEXT NOT NULL DEFAULT 'UTC';
+ALTER TABLE user_settings ADD COLUMN locale TEXT NOT NULL DEFAULT 'en-US';
+-- Which new messages are pushed to connections that aren't viewing the conversation
+ALTER TABLE user_settings ADD COLUMN message_notifications TEXT NOT NULL DEFAULT 'all'
+    CHECK (message_notifications IN ('all', 'mentions', 'none'));
+ALTER TABLE user_settings ADD COLUMN friend_request_notifications BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,14 @@
+-- The most tokens each model can generate in a single response
+ALTER TABLE ai_models ADD COLUMN max_tokens INTEGER NOT NULL DEFAULT 4096;
+
+-- Overrides for how the AI responds in a conversation, null to use the default
+CREATE TABLE conversation_settings (
+    conversation_id INTEGER PRIMARY KEY NOT NULL,
+    -- Replaces the persona's or the default system prompt
+    system_prompt TEXT,
+    temperature REAL,
+    max_tokens INTEGER,
+    top_p REAL,
+    modified_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+);
@@ -0,0 +1,16 @@
+-- Services that serve an OpenAI compatible chat completions api
+CREATE TABLE ai_providers (
+    name TEXT PRIMARY KEY NOT NULL,
+    -- Requests are sent to {base_url}/chat/completions, {model} is replaced with the model's name
+    base_url TEXT NOT NULL,
+    -- The environment variable holding the api key, null if the provider doesn't need one
+    api_key_env TEXT
+);
+
+INSERT INTO ai_providers (name, base_url, api_key_env) VALUES
+    ('huggingface', 'https://api-inference.huggingface.co/models/{model}/v1', 'HF_API_KEY'),
+    ('openai', 'https://api.openai.com/v1', 'OPENAI_API_KEY'),
+    ('ollama', 'http://localhost:11434/v1', NULL);
+
+-- SQLite can't add a foreign key column with a default, so this isn't enforced
+ALTER TABLE ai_models ADD COLUMN provider TEXT NOT NULL DEFAULT 'huggingface';
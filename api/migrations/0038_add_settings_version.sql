@@ -0,0 +1,2 @@
+-- Increases every time the user changes their settings so clients can tell when theirs are stale
+ALTER TABLE user_settings ADD COLUMN settings_version INTEGER NOT NULL DEFAULT 1;
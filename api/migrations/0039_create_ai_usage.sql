@@ -0,0 +1,15 @@
+-- Tokens used by the AI when generating responses, totalled per user, conversation, and month
+CREATE TABLE ai_usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- The user who asked for the responses
+    user_id INTEGER NOT NULL,
+    -- Null once the conversation is deleted, the usage still counts towards the user's quota
+    conversation_id INTEGER,
+    -- The month the tokens were used in, formatted as YYYY-MM in UTC
+    month TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL DEFAULT 0,
+    completion_tokens INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE SET NULL,
+    UNIQUE (user_id, conversation_id, month)
+);
@@ -0,0 +1,10 @@
+-- A summary of the older messages in a conversation, given to the AI in place of the messages
+-- that no longer fit in the context it is sent
+CREATE TABLE conversation_summaries (
+    conversation_id INTEGER PRIMARY KEY NOT NULL,
+    summary TEXT NOT NULL,
+    -- The newest message covered by the summary
+    last_message_id INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+);
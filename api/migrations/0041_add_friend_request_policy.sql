@@ -0,0 +1,3 @@
+-- Who is allowed to send the user friend requests
+ALTER TABLE user_settings ADD COLUMN friend_request_policy TEXT NOT NULL DEFAULT 'everyone'
+    CHECK (friend_request_policy IN ('everyone', 'conversation_members', 'nobody'));
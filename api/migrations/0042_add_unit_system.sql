@@ -0,0 +1,3 @@
+-- The units health values are shown in, they are always stored in metric
+ALTER TABLE user_settings ADD COLUMN unit_system TEXT NOT NULL DEFAULT 'metric'
+    CHECK (unit_system IN ('metric', 'imperial'));
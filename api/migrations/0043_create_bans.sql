@@ -0,0 +1,12 @@
+-- Users banned by an admin, a user has at most one ban at a time
+CREATE TABLE bans (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    -- The admin who banned the user
+    banned_by INTEGER,
+    reason TEXT,
+    -- The ban is ignored after this, NULL if it is permanent
+    expires_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (banned_by) REFERENCES users(id) ON DELETE SET NULL
+);
@@ -0,0 +1,40 @@
+-- Single use codes for logging in without the password.
+-- Only the hash of each code is stored, the codes themselves are shown once when they are generated
+CREATE TABLE recovery_codes (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    -- Set when the code is used to log in, used codes can't be used again
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id, code_hash);
+
+-- The audit log has to be recreated to allow the recovery code actions
+CREATE TABLE new_account_audit_log (
+    id INTEGER PRIMARY KEY NOT NULL,
+    -- The account that was changed
+    user_id INTEGER NOT NULL,
+    -- Who made the change, the same as user_id unless someone else changed the account
+    actor_id INTEGER,
+    action TEXT NOT NULL CHECK (action IN (
+        'profile_update', 'username_change', 'email_change_request', 'email_change',
+        'password_change', 'password_reset', 'profile_image_upload', 'profile_image_change',
+        'settings_change', 'account_deletion', 'recovery_codes_generated', 'recovery_code_login'
+    )),
+    -- Sensitive values such as passwords are never recorded
+    old_value TEXT,
+    new_value TEXT,
+    ip TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (actor_id) REFERENCES users(id) ON DELETE SET NULL
+);
+
+INSERT INTO new_account_audit_log SELECT * FROM account_audit_log;
+DROP TABLE account_audit_log;
+ALTER TABLE new_account_audit_log RENAME TO account_audit_log;
+
+CREATE INDEX account_audit_log_user_id ON account_audit_log (user_id, created_at);
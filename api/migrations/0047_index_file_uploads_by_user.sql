@@ -0,0 +1,2 @@
+-- Storage quotas add up the sizes of every file a user has uploaded
+CREATE INDEX file_uploads_user_id ON file_uploads (user_id);
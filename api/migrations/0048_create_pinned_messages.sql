@@ -0,0 +1,13 @@
+-- Messages pinned to the top of a conversation. Pins are removed along with the message
+CREATE TABLE pinned_messages (
+    message_id INTEGER PRIMARY KEY NOT NULL,
+    conversation_id INTEGER NOT NULL,
+    -- Null if the user who pinned the message has been deleted
+    pinned_by INTEGER,
+    pinned_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+    FOREIGN KEY (pinned_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX pinned_messages_conversation_id ON pinned_messages (conversation_id, pinned_at);
@@ -0,0 +1,9 @@
+-- Single use codes the client is sent back with after logging in with an OAuth provider,
+-- exchanged for a login token so the token is never put in a url
+CREATE TABLE oauth_login_codes (
+    code_hash TEXT PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- Whether a deleted account should be restored when the login finishes,
+-- deleted accounts aren't restored unless the user asked for it when starting the login
+ALTER TABLE oauth_states ADD COLUMN reactivate BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+-- API keys expire instead of staying valid until they are revoked.
+-- Keys created before keys could expire are given the default lifetime from now
+-- so the scripts using them don't break without warning
+ALTER TABLE api_keys ADD COLUMN expires_at TIMESTAMP;
+UPDATE api_keys SET expires_at = datetime('now', '+90 days');
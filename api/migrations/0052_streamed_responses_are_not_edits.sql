@@ -0,0 +1,16 @@
+-- AI responses are saved as incomplete messages while they stream in and are updated as more
+-- arrives. Those updates aren't edits, so only count updates to messages that were complete
+DROP TRIGGER update_modified_at;
+
+CREATE TRIGGER update_modified_at AFTER UPDATE OF message, file_id, file_name ON messages
+WHEN NEW.modified_at = OLD.modified_at AND NOT OLD.incomplete
+BEGIN
+    UPDATE messages
+    SET modified_at = CURRENT_TIMESTAMP
+    WHERE id = NEW.id;
+END;
+
+-- Responses that were streamed before this look edited, unless they actually were
+UPDATE messages SET modified_at = created_at
+WHERE ai_model_id IS NOT NULL
+AND id NOT IN (SELECT message_id FROM message_edits);
@@ -0,0 +1,3 @@
+-- Sessions started with a recovery code can set a new password without the current one,
+-- which the user has likely lost, until the password is changed
+ALTER TABLE sessions ADD COLUMN password_reset_required BOOLEAN NOT NULL DEFAULT FALSE;
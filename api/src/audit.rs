@@ -0,0 +1,202 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::Type, SqliteConnection, SqlitePool};
+
+use crate::{
+    auth::JwtAuth,
+    error::{AppError, AppJson},
+    users::{require_admin, UserToken},
+};
+
+/// A sensitive change to an account that is recorded in the audit log
+#[derive(Serialize, Deserialize, Type, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum AuditAction {
+    /// The name, bio, pronouns, or location changed
+    ProfileUpdate,
+    UsernameChange,
+    /// A new email was given but hasn't been confirmed yet
+    EmailChangeRequest,
+    /// The new email was confirmed
+    EmailChange,
+    PasswordChange,
+    /// The password was changed with a reset token instead of the current password
+    PasswordReset,
+    ProfileImageUpload,
+    ProfileImageChange,
+    SettingsChange,
+    AccountDeletion,
+    /// A new set of recovery codes replaced the old ones
+    RecoveryCodesGenerated,
+    /// The user logged in with a recovery code instead of their password
+    RecoveryCodeLogin,
+    /// A file was uploaded to attach to messages
+    FileUpload,
+}
+
+/// Need for sqlx to convert the action from the database to the enum
+impl From<String> for AuditAction {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "username_change" => AuditAction::UsernameChange,
+            "email_change_request" => AuditAction::EmailChangeRequest,
+            "email_change" => AuditAction::EmailChange,
+            "password_change" => AuditAction::PasswordChange,
+            "password_reset" => AuditAction::PasswordReset,
+            "profile_image_upload" => AuditAction::ProfileImageUpload,
+            "profile_image_change" => AuditAction::ProfileImageChange,
+            "settings_change" => AuditAction::SettingsChange,
+            "account_deletion" => AuditAction::AccountDeletion,
+            "recovery_codes_generated" => AuditAction::RecoveryCodesGenerated,
+            "recovery_code_login" => AuditAction::RecoveryCodeLogin,
+            "file_upload" => AuditAction::FileUpload,
+            _ => AuditAction::ProfileUpdate,
+        }
+    }
+}
+
+/// A change to record in the audit log.
+/// Only give values that are safe to show to the user and admins, never passwords or tokens
+pub(crate) struct AuditEvent {
+    user_id: i64,
+    actor_id: i64,
+    action: AuditAction,
+    old_value: Option<String>,
+    new_value: Option<String>,
+}
+
+impl AuditEvent {
+    /// A change the user made to their own account
+    pub fn new(user_id: i64, action: AuditAction) -> Self {
+        Self {
+            user_id,
+            actor_id: user_id,
+            action,
+            old_value: None,
+            new_value: None,
+        }
+    }
+
+    /// What the changed value was before and after the change
+    pub fn values(mut self, old_value: Option<String>, new_value: Option<String>) -> Self {
+        self.old_value = old_value;
+        self.new_value = new_value;
+        self
+    }
+
+    /// Write the event to the audit log, as part of the transaction that made the change
+    /// so the log can't disagree with the account
+    pub async fn record(self, conn: &mut SqliteConnection, ip: IpAddr) -> Result<(), AppError> {
+        let ip = ip.to_string();
+        sqlx::query!(
+            "INSERT INTO account_audit_log (user_id, actor_id, action, old_value, new_value, ip)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            self.user_id,
+            self.actor_id,
+            self.action,
+            self.old_value,
+            self.new_value,
+            ip
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+/// An entry in the audit log
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub user_id: i64,
+    /// None if the account that made the change has been purged
+    pub actor_id: Option<i64>,
+    pub action: AuditAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// The maximum number of audit log entries that can be requested at once
+const MAX_AUDIT_LIMIT: i64 = 200;
+
+/// Query parameters for paginating the audit log
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditQuery {
+    /// The number of entries to return, defaults to 50
+    pub limit: Option<i64>,
+    /// The number of entries to skip
+    pub offset: Option<i64>,
+    /// Only return entries for this account, only used by admins
+    pub user_id: Option<i64>,
+    /// Only return entries for this action
+    pub action: Option<AuditAction>,
+}
+
+impl AuditQuery {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(50).clamp(1, MAX_AUDIT_LIMIT)
+    }
+
+    fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+/// Get the entries of the audit log, most recent first.
+/// Entries are only filtered by account if `user_id` is given
+async fn audit_log(
+    pool: &SqlitePool,
+    user_id: Option<i64>,
+    query: &AuditQuery,
+) -> Result<Vec<AuditLogEntry>, AppError> {
+    let (limit, offset) = (query.limit(), query.offset());
+    Ok(sqlx::query_as!(
+        AuditLogEntry,
+        r#"SELECT id, user_id, actor_id, action as "action: AuditAction", old_value, new_value,
+        ip, created_at FROM account_audit_log
+        WHERE (?1 IS NULL OR user_id = ?1) AND (?2 IS NULL OR action = ?2)
+        ORDER BY created_at DESC, id DESC LIMIT ?3 OFFSET ?4"#,
+        user_id,
+        query.action,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Get the audit log of the current user's account
+pub async fn get_account_audit_log(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, AppError> {
+    let entries = audit_log(&pool, Some(user.id), &query).await?;
+    Ok((StatusCode::OK, AppJson(entries)).into_response())
+}
+
+/// Get the audit log across every account, or one account with `userId`, admins only
+pub async fn get_audit_log(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, AppError> {
+    require_admin(&pool, user.id).await?;
+    let entries = audit_log(&pool, query.user_id, &query).await?;
+    Ok((StatusCode::OK, AppJson(entries)).into_response())
+}
@@ -1,23 +1,305 @@
-use std::fmt::Display;
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::error::{AppJson, ErrorResponse};
+use crate::{
+    bans::{active_ban, Ban},
+    error::AppError,
+    state::AppState,
+    users::{hash_token, UserToken},
+};
+use ahash::RandomState;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher as _, SaltString};
 use axum::{
     async_trait,
-    extract::FromRequestParts,
-    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    extract::{FromRef, FromRequestParts, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderValue, Method, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
 };
-use dotenvy_macro::dotenv;
-use jsonwebtoken::{decode, DecodingKey, Validation};
-use serde::de::DeserializeOwned;
+use jsonwebtoken::{
+    decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use scc::{hash_map::Entry, HashMap};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::Type, SqlitePool};
+use tracing::error;
+
+/// Settings used to sign and verify JWTs
+#[derive(Clone)]
+pub struct AuthConfig {
+    /// How long a token is valid for after it is issued
+    pub ttl: chrono::Duration,
+    /// Issue a new token when one that is still in use is close to expiring
+    pub sliding: bool,
+    /// The algorithm used to sign tokens
+    pub algorithm: Algorithm,
+    /// The `iss` claim of issued tokens.
+    /// Tokens without the claim are only accepted if this isn't set
+    pub issuer: Option<String>,
+    /// The `aud` claim of issued tokens.
+    /// Tokens without the claim are only accepted if this isn't set
+    pub audience: Option<String>,
+    keys: Arc<JwtKeys>,
+}
+
+/// The secrets tokens are signed and verified with
+struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    /// The secret that was used before the current one.
+    /// Tokens signed with it are still accepted so rotating the secret doesn't log everyone out
+    previous: Option<DecodingKey>,
+}
+
+impl AuthConfig {
+    pub fn new(
+        ttl_hours: i64,
+        sliding: bool,
+        algorithm: &str,
+        key: &str,
+        previous_key: Option<&str>,
+        issuer: Option<String>,
+        audience: Option<String>,
+    ) -> Result<Self, String> {
+        if ttl_hours <= 0 {
+            return Err("JWT expiry must be a positive number of hours".to_owned());
+        }
+        if key.is_empty() {
+            return Err("JWT key must not be empty".to_owned());
+        }
+        Ok(Self {
+            ttl: chrono::Duration::try_hours(ttl_hours)
+                .ok_or_else(|| "JWT expiry is too large".to_owned())?,
+            sliding,
+            algorithm: parse_algorithm(algorithm)?,
+            issuer: issuer.filter(|issuer| !issuer.is_empty()),
+            audience: audience.filter(|audience| !audience.is_empty()),
+            keys: Arc::new(JwtKeys {
+                encoding: EncodingKey::from_secret(key.as_bytes()),
+                decoding: DecodingKey::from_secret(key.as_bytes()),
+                previous: previous_key
+                    .filter(|key| !key.is_empty())
+                    .map(|key| DecodingKey::from_secret(key.as_bytes())),
+            }),
+        })
+    }
+
+    /// The expiry timestamp for a token issued now
+    pub fn expiry(&self) -> i64 {
+        (chrono::Utc::now() + self.ttl).timestamp()
+    }
+
+    /// Whether the token should be replaced with a new one.
+    /// With sliding expiration, tokens are replaced in the last quarter of their life
+    pub fn needs_refresh(&self, token: &UserToken) -> bool {
+        let remaining = token.exp - chrono::Utc::now().timestamp();
+        self.sliding && remaining > 0 && remaining <= self.ttl.num_seconds() / 4
+    }
+
+    /// The validation rules for decoding a token
+    pub fn validation(&self) -> Validation {
+        let mut validation = Validation::new(self.algorithm);
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            // Otherwise tokens with any audience would be rejected
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+
+    /// Sign a token with the current secret, for this server's issuer and audience
+    pub fn encode(&self, claims: &UserToken) -> jsonwebtoken::errors::Result<String> {
+        let claims = UserToken {
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            ..claims.clone()
+        };
+        encode(&Header::new(self.algorithm), &claims, &self.keys.encoding)
+    }
+
+    /// Verify and decode a token signed with either the current or the previous secret
+    pub fn decode(&self, token: &str) -> jsonwebtoken::errors::Result<UserToken> {
+        let validation = self.validation();
+        match (
+            decode(token, &self.keys.decoding, &validation),
+            &self.keys.previous,
+        ) {
+            (Err(e), Some(previous)) if *e.kind() == ErrorKind::InvalidSignature => {
+                decode(token, previous, &validation)
+            }
+            (result, _) => result,
+        }
+        .map(|data| data.claims)
+    }
+
+    /// Decode a token, explaining why it was rejected if it is not valid
+    pub fn verify(&self, token: &str) -> Result<UserToken, JwtError> {
+        self.decode(token).map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => {
+                // Only read the expiry once the signature has been checked again
+                let mut validation = self.validation();
+                validation.validate_exp = false;
+                match decode::<UserToken>(token, &self.keys.decoding, &validation).or_else(|e| {
+                    match &self.keys.previous {
+                        Some(previous) => decode(token, previous, &validation),
+                        None => Err(e),
+                    }
+                }) {
+                    Ok(data) => JwtError::Expired {
+                        expired_at: data.claims.exp,
+                    },
+                    Err(_) => JwtError::InvalidToken,
+                }
+            }
+            ErrorKind::InvalidSignature => JwtError::WrongSignature,
+            ErrorKind::InvalidToken
+            | ErrorKind::Base64(_)
+            | ErrorKind::Json(_)
+            | ErrorKind::Utf8(_) => JwtError::Malformed,
+            _ => JwtError::InvalidToken,
+        })
+    }
+}
+
+impl Debug for AuthConfig {
+    // Don't print the secrets
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("ttl", &self.ttl)
+            .field("sliding", &self.sliding)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parse the name of a JWT signing algorithm.
+/// Tokens are signed with a shared secret, so only the HMAC algorithms can be used
+pub fn parse_algorithm(algorithm: &str) -> Result<Algorithm, String> {
+    match Algorithm::from_str(&algorithm.to_uppercase()) {
+        Ok(alg @ (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)) => Ok(alg),
+        Ok(_) => Err(format!(
+            "{} requires a public/private key pair, use HS256, HS384, or HS512 instead",
+            algorithm
+        )),
+        Err(_) => Err(format!(
+            "Unknown JWT algorithm {}, use HS256, HS384, or HS512",
+            algorithm
+        )),
+    }
+}
+
+/// Hashes passwords with Argon2 using the cost parameters the server was started with
+#[derive(Clone, Debug)]
+pub struct PasswordHasher {
+    algorithm: argon2::Algorithm,
+    params: argon2::Params,
+}
+
+impl Default for PasswordHasher {
+    /// The same settings `password_auth::generate_hash` uses
+    fn default() -> Self {
+        Self {
+            algorithm: argon2::Algorithm::default(),
+            params: argon2::Params::default(),
+        }
+    }
+}
+
+impl PasswordHasher {
+    pub fn new(
+        algorithm: &str,
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            algorithm: parse_password_algorithm(algorithm)?,
+            params: argon2::Params::new(memory_kib, iterations, parallelism, None)
+                .map_err(|e| format!("Invalid password hashing parameters: {}", e))?,
+        })
+    }
+
+    /// Hash a password with a random salt into a PHC string
+    pub fn hash(&self, password: impl AsRef<[u8]>) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        argon2::Argon2::new(self.algorithm, argon2::Version::V0x13, self.params.clone())
+            .hash_password(password.as_ref(), &salt)
+            // The parameters are checked when the hasher is created
+            .expect("password hashing error")
+            .to_string()
+    }
+
+    /// Check if a stored hash was made with a different algorithm or weaker parameters
+    /// than the ones currently configured, so it should be replaced the next time the
+    /// password is known. Hashes with stronger parameters are left alone
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return false;
+        };
+        let (Ok(algorithm), Ok(params)) = (
+            argon2::Algorithm::try_from(hash.algorithm),
+            argon2::Params::try_from(&hash),
+        ) else {
+            return true;
+        };
+        algorithm != self.algorithm
+            || hash.version != Some(argon2::Version::V0x13.into())
+            || params.m_cost() < self.params.m_cost()
+            || params.t_cost() < self.params.t_cost()
+            || params.p_cost() < self.params.p_cost()
+    }
+}
+
+/// Parse the name of an Argon2 variant, one of argon2id, argon2i, or argon2d
+pub fn parse_password_algorithm(algorithm: &str) -> Result<argon2::Algorithm, String> {
+    argon2::Algorithm::from_str(&algorithm.to_lowercase()).map_err(|_| {
+        format!(
+            "Unknown password hashing algorithm {}, use argon2id, argon2i, or argon2d",
+            algorithm
+        )
+    })
+}
 
 /// Custom extractor for JWT authoriation
 pub struct JwtAuth<T>(pub T);
 
 /// Error that occurs when JWT authorization fails
+#[derive(Debug)]
 pub enum JwtError {
     InvalidToken,
     MissingToken,
+    /// The token was valid but it has expired, the client should get a new one
+    Expired {
+        /// When the token expired as a unix timestamp
+        expired_at: i64,
+    },
+    /// The token isn't a JWT or is missing the `Bearer` prefix, the client should log in again
+    Malformed,
+    /// The token wasn't signed by this server, the client should log in again
+    WrongSignature,
+    /// The token was valid but has since been revoked by logging out
+    RevokedToken,
+    /// The token could not be checked against the database
+    Internal,
+    /// The API key used is not allowed to make this request
+    InsufficientScope,
+    /// The user has been banned by an admin
+    Banned(Ban),
 }
 
 /// Error message for `JwtError`
@@ -26,43 +308,492 @@ impl Display for JwtError {
         match self {
             Self::InvalidToken => write!(f, "Invalid token"),
             Self::MissingToken => write!(f, "No token provided"),
+            Self::Expired { .. } => write!(f, "Token expired"),
+            Self::Malformed => write!(f, "Token is malformed"),
+            Self::WrongSignature => write!(f, "Token signature is invalid"),
+            Self::RevokedToken => write!(f, "Token has been revoked"),
+            Self::Internal => write!(f, "Internal Server Error"),
+            Self::InsufficientScope => write!(f, "API key is not allowed to make this request"),
+            Self::Banned(ban) => write!(f, "{}", AppError::Banned(ban.clone())),
+        }
+    }
+}
+
+impl JwtError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InsufficientScope | Self::Banned(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// The error type sent to the client.
+    /// Failures the client can act on differently get their own type
+    pub fn r#type(&self) -> String {
+        match self {
+            Self::Expired { .. } => "Expired".to_owned(),
+            Self::Malformed => "Malformed".to_owned(),
+            Self::WrongSignature => "WrongSignature".to_owned(),
+            Self::Banned(_) => "Banned".to_owned(),
+            _ => "AuthError".to_owned(),
         }
     }
 }
 
 impl IntoResponse for JwtError {
     fn into_response(self) -> Response {
-        (StatusCode::UNAUTHORIZED, AppJson(ErrorResponse::from(self))).into_response()
+        AppError::Jwt(self).into_response()
     }
 }
 
 // Trait that allows us to use the struct as an extractor in the function
 // signature of a request handler
 #[async_trait]
-impl<T, S> FromRequestParts<S> for JwtAuth<T>
+impl<S> FromRequestParts<S> for JwtAuth<UserToken>
 where
-    T: DeserializeOwned,
+    SqlitePool: FromRef<S>,
+    AuthConfig: FromRef<S>,
+    SessionActivity: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = JwtError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         // Extract the token from the request headers
         let Some(token) = parts.headers.get(AUTHORIZATION) else {
             return Err(JwtError::MissingToken);
         };
+        let token = token.to_str().map_err(|_| JwtError::Malformed)?;
+        let pool = SqlitePool::from_ref(state);
+
+        // Scripts can authenticate with an API key instead of a JWT
+        if let Some(key) = token.strip_prefix("ApiKey ") {
+            let user = match authenticate_api_key(&pool, key).await {
+                Ok(Some((user, scope))) if scope.allows(&parts.method, parts.uri.path()) => user,
+                Ok(Some(_)) => return Err(JwtError::InsufficientScope),
+                Ok(None) => return Err(JwtError::InvalidToken),
+                Err(e) => {
+                    error!("Failed to check API key: {}", e);
+                    return Err(JwtError::Internal);
+                }
+            };
+            check_not_banned(&pool, user.id).await?;
+            return Ok(Self(user));
+        }
+
         // Attempt to decode the token
-        let user: T = decode(
-            token
-                .to_str()
-                .map_err(|_| JwtError::InvalidToken)?
-                .strip_prefix("Bearer ")
-                .ok_or(JwtError::InvalidToken)?,
-            &DecodingKey::from_secret(dotenv!("JWT_KEY").as_bytes()),
-            &Validation::default(),
+        let user = AuthConfig::from_ref(state)
+            .verify(token.strip_prefix("Bearer ").ok_or(JwtError::Malformed)?)?;
+
+        match token_is_current(&pool, &user).await {
+            Ok(true) => {
+                check_not_banned(&pool, user.id).await?;
+                if let Some(session_id) = user.sid {
+                    SessionActivity::from_ref(state)
+                        .touch(&pool, session_id)
+                        .await;
+                }
+                Ok(Self(user))
+            }
+            Ok(false) => Err(JwtError::RevokedToken),
+            Err(e) => {
+                error!("Failed to check token version: {}", e);
+                Err(JwtError::Internal)
+            }
+        }
+    }
+}
+
+/// Reject the request if the user has been banned
+async fn check_not_banned(pool: &SqlitePool, user_id: i64) -> Result<(), JwtError> {
+    match active_ban(pool, user_id).await {
+        Ok(Some(ban)) => Err(JwtError::Banned(ban)),
+        Ok(None) => Ok(()),
+        Err(e) => {
+            error!("Failed to check if user is banned: {}", e);
+            Err(JwtError::Internal)
+        }
+    }
+}
+
+/// Issue a new token for the user if theirs is close to expiring and sliding expiration is
+/// enabled. The token's session is extended to match.
+/// Returns the new token along with its encoded form
+pub async fn refresh_token(
+    pool: &SqlitePool,
+    auth: &AuthConfig,
+    token: &UserToken,
+) -> Result<Option<(UserToken, String)>, AppError> {
+    if !auth.needs_refresh(token) || !token_is_current(pool, token).await? {
+        return Ok(None);
+    }
+    let refreshed = UserToken {
+        exp: auth.expiry(),
+        ..token.clone()
+    };
+    if let Some(session_id) = refreshed.sid {
+        sqlx::query!(
+            "UPDATE sessions SET expires_at = datetime(?, 'unixepoch') WHERE id = ?",
+            refreshed.exp,
+            session_id
+        )
+        .execute(pool)
+        .await?;
+    }
+    let encoded = auth.encode(&refreshed)?;
+    Ok(Some((refreshed, encoded)))
+}
+
+/// Middleware that gives clients a new token in the authorization header of the response
+/// when the token they sent is close to expiring.
+/// Responses that already set a token, such as logging in, are left alone
+pub async fn sliding_expiration(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|token| token.to_str().ok())
+        .and_then(|token| token.strip_prefix("Bearer "))
+        .and_then(|token| state.auth.decode(token).ok());
+    let mut response = next.run(request).await;
+    let Some(token) = token.filter(|token| state.auth.needs_refresh(token)) else {
+        return response;
+    };
+    if !response.status().is_success() || response.headers().contains_key(AUTHORIZATION) {
+        return response;
+    }
+    match refresh_token(&state.pool, &state.auth, &token).await {
+        Ok(Some((_, encoded))) => {
+            if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", encoded)) {
+                response.headers_mut().insert(AUTHORIZATION, value);
+            }
+        }
+        Ok(None) => (),
+        Err(e) => error!("Failed to refresh token: {}", e),
+    }
+    response
+}
+
+/// What requests an API key can be used for
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum ApiKeyScope {
+    /// Only the routes in `READ_ONLY_ROUTES`
+    ReadOnly,
+    /// Every route except the ones in `LOGIN_ONLY_ROUTES`
+    #[default]
+    Full,
+}
+
+/// Routes that can't be used with an API key at all, only after logging in.
+/// A leaked key shouldn't be able to create more keys, lock the user out of their account, or delete it.
+/// Paths are relative to `/api` and a segment starting with `:` matches any segment
+const LOGIN_ONLY_ROUTES: &[(Method, &str)] = &[
+    (Method::POST, "/account"),
+    (Method::DELETE, "/account"),
+    (Method::POST, "/account/password"),
+    (Method::POST, "/account/recovery-codes"),
+    (Method::GET, "/account/apikeys"),
+    (Method::POST, "/account/apikeys"),
+    (Method::DELETE, "/account/apikeys/:id"),
+];
+
+/// Routes a read only API key can use.
+/// Routes are listed one by one instead of going by the method,
+/// since some `GET` routes change things and some `POST` routes only read.
+/// A new route can't be used by read only keys until it is added here
+const READ_ONLY_ROUTES: &[(Method, &str)] = &[
+    (Method::GET, "/login"),
+    (Method::GET, "/users/id/:id"),
+    (Method::GET, "/users/:id/status"),
+    (Method::GET, "/users/:id/friends/mutual"),
+    (Method::GET, "/users/ids"),
+    (Method::GET, "/users/username/:username"),
+    (Method::GET, "/users/search/:username"),
+    (Method::GET, "/friends"),
+    (Method::GET, "/friends/requests"),
+    (Method::GET, "/check/username/:username"),
+    (Method::GET, "/check/email/:email"),
+    (Method::POST, "/check"),
+    (Method::GET, "/account/export"),
+    (Method::GET, "/account/unread"),
+    (Method::GET, "/account/username-history"),
+    (Method::GET, "/account/settings"),
+    (Method::GET, "/account/audit"),
+    (Method::GET, "/account/ai-usage"),
+    (Method::GET, "/account/sessions"),
+    (Method::GET, "/account/recovery-codes"),
+    (Method::GET, "/chat/:id/messages"),
+    (Method::GET, "/chat/:id/pins"),
+    (Method::GET, "/chat/messages/:id/thread"),
+    (Method::GET, "/chat/models"),
+    (Method::GET, "/personas"),
+    (Method::GET, "/report"),
+    (Method::GET, "/report/pdf"),
+    (Method::GET, "/report/csv"),
+    (Method::GET, "/forms/health"),
+    (Method::GET, "/forms"),
+    (Method::GET, "/forms/trends"),
+    (Method::GET, "/files/:id"),
+];
+
+impl ApiKeyScope {
+    /// Check if a key with this scope can be used for a request to a route.
+    /// The path is the one the API router sees, without the `/api` prefix
+    pub fn allows(self, method: &Method, path: &str) -> bool {
+        let listed = |routes: &[(Method, &str)]| {
+            routes
+                .iter()
+                .any(|(m, route)| m == method && route_matches(route, path))
+        };
+        match self {
+            ApiKeyScope::ReadOnly => listed(READ_ONLY_ROUTES),
+            ApiKeyScope::Full => !listed(LOGIN_ONLY_ROUTES),
+        }
+    }
+}
+
+/// Check if a path matches a route, where a segment of the route starting with `:` matches any segment
+fn route_matches(route: &str, path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    let mut route = route.split('/');
+    let mut path = path.split('/');
+    loop {
+        match (route.next(), path.next()) {
+            (None, None) => return true,
+            (Some(r), Some(p)) if r == p || (r.starts_with(':') && !p.is_empty()) => continue,
+            _ => return false,
+        }
+    }
+}
+
+/// Need for sqlx to convert the scope from the database to the enum
+impl From<String> for ApiKeyScope {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "full" => ApiKeyScope::Full,
+            // Fall back to the least privileged scope
+            _ => ApiKeyScope::ReadOnly,
+        }
+    }
+}
+
+/// How often an API key's last used time is written to the database, in minutes
+const API_KEY_TOUCH_MINUTES: i64 = 5;
+
+/// Look up the user an API key belongs to.
+/// Returns a token for the user as if they had logged in along with the scope of the key
+pub async fn authenticate_api_key(
+    pool: &SqlitePool,
+    key: &str,
+) -> Result<Option<(UserToken, ApiKeyScope)>, sqlx::Error> {
+    let key_hash = hash_token(key);
+    let Some(row) = sqlx::query!(
+        r#"SELECT api_keys.id, scope, users.id as user_id, username, token_version,
+        unixepoch(expires_at) AS "exp!: i64" FROM api_keys
+        JOIN users ON users.id = api_keys.user_id
+        WHERE key_hash = ? AND users.deleted_at IS NULL AND expires_at > CURRENT_TIMESTAMP"#,
+        key_hash
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    // Only write the last used time every few minutes so it isn't a database write per request
+    let touch_before = format!("-{} minutes", API_KEY_TOUCH_MINUTES);
+    sqlx::query!(
+        "UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND (last_used_at IS NULL OR last_used_at < datetime('now', ?))",
+        row.id,
+        touch_before
+    )
+    .execute(pool)
+    .await?;
+
+    let user = UserToken {
+        id: row.user_id,
+        username: row.username,
+        exp: row.exp,
+        ver: row.token_version,
+        sid: None,
+        iss: None,
+        aud: None,
+    };
+    Ok(Some((user, ApiKeyScope::from(row.scope))))
+}
+
+/// Check that the token has not been revoked since it was issued.
+/// A token is revoked when the user's token version is incremented, its session is deleted,
+/// or the user no longer exists.
+pub async fn token_is_current(pool: &SqlitePool, token: &UserToken) -> Result<bool, sqlx::Error> {
+    let token_version = match token.sid {
+        Some(session_id) => {
+            sqlx::query_scalar!(
+                "SELECT token_version FROM users
+            JOIN sessions ON sessions.user_id = users.id
+            WHERE users.id = ? AND sessions.id = ?",
+                token.id,
+                session_id
+            )
+            .fetch_optional(pool)
+            .await?
+        }
+        // Tokens issued before sessions were tracked
+        None => {
+            sqlx::query_scalar!("SELECT token_version FROM users WHERE id = ?", token.id)
+                .fetch_optional(pool)
+                .await?
+        }
+    };
+    Ok(token_version == Some(token.ver))
+}
+
+/// How often a session's last used time is written to the database
+const SESSION_TOUCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Throttles recording when sessions were last used so it isn't a database write per request
+#[derive(Clone, Debug, Default)]
+pub struct SessionActivity(Arc<HashMap<i64, Instant, RandomState>>);
+
+impl SessionActivity {
+    /// Record that the session was just used
+    pub async fn touch(&self, pool: &SqlitePool, session_id: i64) {
+        let now = Instant::now();
+        let due = match self.0.entry_async(session_id).await {
+            Entry::Occupied(mut entry) => {
+                let due = now.duration_since(*entry.get()) >= SESSION_TOUCH_INTERVAL;
+                if due {
+                    *entry.get_mut() = now;
+                }
+                due
+            }
+            Entry::Vacant(entry) => {
+                entry.insert_entry(now);
+                true
+            }
+        };
+        if !due {
+            return;
+        }
+        if let Err(e) = sqlx::query!(
+            "UPDATE sessions SET last_used_at = CURRENT_TIMESTAMP WHERE id = ?",
+            session_id
         )
-        .map_err(|_| JwtError::InvalidToken)?
-        .claims;
-        Ok(Self(user))
+        .execute(pool)
+        .await
+        {
+            error!("Failed to update session {}: {}", session_id, e);
+        }
+    }
+
+    /// Forget sessions that haven't been touched recently.
+    /// They will be written on their next use anyway
+    pub async fn prune(&self) {
+        let now = Instant::now();
+        self.0
+            .retain_async(|_, touched| now.duration_since(*touched) < SESSION_TOUCH_INTERVAL)
+            .await;
+    }
+}
+
+/// What failed login attempts are counted against
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum LoginKey {
+    Username(Box<str>),
+    Ip(IpAddr),
+}
+
+impl LoginKey {
+    /// Usernames are compared with `COLLATE NOCASE`, which only folds ASCII,
+    /// so every casing of a name shares one key
+    fn username(username: &str) -> Self {
+        Self::Username(username.to_ascii_lowercase().into())
+    }
+}
+
+/// Tracks failed logins per username and per IP over a sliding window
+/// so passwords can't be guessed as fast as a client can send them
+#[derive(Debug)]
+pub struct LoginAttempts {
+    /// The timestamps of the failed attempts within the window for each key
+    failures: HashMap<LoginKey, VecDeque<Instant>, RandomState>,
+    max_user_failures: usize,
+    max_ip_failures: usize,
+    window: Duration,
+}
+
+impl LoginAttempts {
+    pub fn new(max_user_failures: usize, max_ip_failures: usize, window: Duration) -> Self {
+        Self {
+            failures: HashMap::with_hasher(RandomState::new()),
+            max_user_failures,
+            max_ip_failures,
+            window,
+        }
+    }
+
+    /// Count a login attempt against both the username and the IP.
+    /// Returns how long to wait before trying again if either is locked out.
+    ///
+    /// The attempt is counted as a failure before the password is checked,
+    /// so concurrent requests can't all slip in under the limit.
+    /// Call `succeeded` if the login works out.
+    pub async fn attempt(&self, username: &str, ip: IpAddr) -> Result<(), Duration> {
+        self.attempt_key(LoginKey::Ip(ip), self.max_ip_failures)
+            .await?;
+        self.attempt_key(LoginKey::username(username), self.max_user_failures)
+            .await
+    }
+
+    async fn attempt_key(&self, key: LoginKey, max_failures: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut entry = self.failures.entry_async(key).await.or_default();
+        let failures = entry.get_mut();
+        while failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) >= self.window)
+        {
+            failures.pop_front();
+        }
+        if failures.len() >= max_failures {
+            // Locked out until the oldest failure leaves the window
+            return Err(failures.front().map_or(self.window, |oldest| {
+                self.window - now.duration_since(*oldest)
+            }));
+        }
+        failures.push_back(now);
+        Ok(())
+    }
+
+    /// Clear the failures for a username after a successful login,
+    /// and take back the attempt it counted against the IP.
+    // The IP's other failures are kept so logging into one account can't be used
+    // to reset the limit while guessing the passwords of others
+    pub async fn succeeded(&self, username: &str, ip: IpAddr) {
+        self.failures
+            .remove_async(&LoginKey::username(username))
+            .await;
+        if let Some(mut entry) = self.failures.get_async(&LoginKey::Ip(ip)).await {
+            entry.get_mut().pop_back();
+        }
+    }
+
+    /// Remove every key without any failures left in the window
+    pub async fn prune(&self) {
+        let now = Instant::now();
+        self.failures
+            .retain_async(|_, failures| {
+                failures
+                    .back()
+                    .is_some_and(|last| now.duration_since(*last) < self.window)
+            })
+            .await;
     }
 }
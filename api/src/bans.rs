@@ -0,0 +1,143 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use validator::Validate;
+
+use crate::{
+    auth::JwtAuth,
+    error::{AppError, AppJson, AppValidate},
+    state::AppState,
+    users::{require_admin, UserToken},
+};
+
+/// Why a user was banned and until when, sent to them when they are refused
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Ban {
+    pub reason: Option<String>,
+    /// None if the ban is permanent
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// Get the user's ban if they have one that hasn't expired
+pub async fn active_ban(pool: &SqlitePool, user_id: i64) -> Result<Option<Ban>, sqlx::Error> {
+    sqlx::query_as!(
+        Ban,
+        "SELECT reason, expires_at FROM bans
+        WHERE user_id = ? AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Refuse the user with a 403 if they are banned
+pub async fn check_ban(pool: &SqlitePool, user_id: i64) -> Result<(), AppError> {
+    match active_ban(pool, user_id).await? {
+        Some(ban) => Err(AppError::Banned(ban)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Deserialize, Validate, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BanRequest {
+    /// Shown to the user when they are refused
+    #[validate(length(max = 500, code = "Reason must be at most 500 characters"))]
+    pub reason: Option<String>,
+    /// The ban is permanent if this is not given
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Ban a user until the given time, or permanently.
+/// Banning a user who is already banned replaces their ban
+pub async fn ban_user(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(user_id): Path<i64>,
+    AppJson(request): AppJson<BanRequest>,
+) -> Result<Response, AppError> {
+    require_admin(&state.pool, user.id).await?;
+    request.app_validate()?;
+
+    if user_id == user.id {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "You can't ban yourself".into(),
+        )));
+    }
+    if request
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Ban expiry must be in the future".into(),
+        )));
+    }
+    if sqlx::query!(
+        "SELECT id FROM users WHERE id = ? AND deleted_at IS NULL",
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User not found".into(),
+        )));
+    }
+
+    let expires_at = request.expires_at.map(|expires_at| expires_at.naive_utc());
+    let ban = sqlx::query_as!(
+        Ban,
+        "INSERT INTO bans (user_id, banned_by, reason, expires_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT (user_id) DO UPDATE SET banned_by = excluded.banned_by,
+        reason = excluded.reason, expires_at = excluded.expires_at, created_at = CURRENT_TIMESTAMP
+        RETURNING reason, expires_at",
+        user_id,
+        user.id,
+        request.reason,
+        expires_at
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    // Websockets are only authorized when they connect, so close any open ones
+    state
+        .disconnect_user_with(user_id, AppError::Banned(ban.clone()))
+        .await;
+
+    Ok((StatusCode::OK, AppJson(ban)).into_response())
+}
+
+/// Lift a user's ban
+pub async fn unban_user(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(user_id): Path<i64>,
+) -> Result<Response, AppError> {
+    require_admin(&pool, user.id).await?;
+
+    let deleted = sqlx::query!(
+        "DELETE FROM bans
+        WHERE user_id = ? AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+        user_id
+    )
+    .execute(&pool)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User is not banned".into(),
+        )));
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
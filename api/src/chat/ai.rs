@@ -1,24 +1,37 @@
+use anyhow::anyhow;
 use axum::{
     extract::State,
     response::{IntoResponse, Response},
 };
+use chrono::{Datelike, Months, NaiveDateTime};
 use dotenvy::var;
 use futures::{stream::FuturesUnordered, StreamExt};
-use reqwest::{header, StatusCode};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, RequestBuilder, StatusCode};
 use reqwest_streams::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 // use sonic_rs::{json, JsonValueTrait, JsonValueMutTrait};
 use serde_json::json;
 use sqlx::SqlitePool;
-use tracing::{debug, warn};
+use std::time::{Duration, Instant};
+use tokio::{sync::broadcast, time::MissedTickBehavior};
+use tracing::{debug, error, warn};
+use validator::Validate;
 
 use crate::{
+    auth::JwtAuth,
+    cli::Args,
     error::{AppError, AppJson},
-    state::{AppState, Sender},
-    users::UserToken,
+    forms::{bmi, BmiCategory},
+    state::{AppState, ConversationLeave, Sender},
+    users::{unit_system, UserToken},
 };
 
-use super::{SendMessage, SocketResponse};
+use super::{
+    broadcast_event,
+    search::{index_message, unindex_message},
+    send_to_user, ChatMessage, SocketResponse,
+};
 
 /// Stream data from the AI model
 // Might add a field for whether the message should trigger the AI
@@ -32,75 +45,562 @@ pub struct StreamMessage {
     pub message: Option<String>,
     /// The id of the user who initiated the ai the message
     pub querier_id: i64,
+    /// The id of the incomplete message being continued with `ResumeGeneration`,
+    /// None if this is a new response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i64>,
+}
+
+/// How AI output is buffered before it is streamed to clients.
+/// Models stream a chunk per token, so sending every chunk as it arrives would mean a
+/// websocket message per token for every client in the conversation
+#[derive(Clone, Copy, Debug)]
+pub struct StreamCoalescing {
+    /// Buffered output is sent at least this often
+    pub interval: Duration,
+    /// Buffered output is sent as soon as it reaches this many characters
+    pub max_chars: usize,
+}
+
+impl From<&Args> for StreamCoalescing {
+    fn from(args: &Args) -> Self {
+        Self {
+            interval: Duration::from_millis(args.stream_flush_ms),
+            max_chars: args.stream_flush_chars as usize,
+        }
+    }
+}
+
+/// The system prompt used in conversations without a persona
+const DEFAULT_SYSTEM_PROMPT: &str = "You are a medical professional who knows about medicine.  When the user tells you about a health problem that they are facing, continue probing through the problem to extract more information and attempt to gain a better understanding of a root cause and potential remedies. Do not simply give a list of potential causes without asking further questions. If you are unsure about something refer user to a doctor or medical professional.";
+
+/// Added to every system prompt so the AI knows how to tell the users in the conversation apart
+const USERNAME_INSTRUCTIONS: &str = r#"The name of the user who sent the message will be enclosed in braces like "{username}:". You should refer to the user who you are responding to by name"#;
+
+/// Generation parameters used in conversations that don't override them
+const DEFAULT_TEMPERATURE: f64 = 0.5;
+const DEFAULT_MAX_TOKENS: i64 = 1024;
+const DEFAULT_TOP_P: f64 = 0.7;
+
+/// How long a one-off completion can take before giving up on the model
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Added after an incomplete response so the model picks up where it left off
+const RESUME_INSTRUCTIONS: &str = "Your last response was cut off. Continue it from exactly where it stopped without repeating any of it or acknowledging that it was cut off.";
+
+/// How often a response is saved to the database while it is being generated
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many characters of the most recent messages are sent to the model as they are.
+/// Older messages are given to the model as a summary instead
+const RECENT_CONTEXT_CHARS: i64 = 5000;
+
+/// The most characters of older messages that are summarized at once
+const MAX_SUMMARIZED_CHARS: usize = 20_000;
+
+/// The most tokens a conversation summary can be
+const SUMMARY_MAX_TOKENS: u32 = 500;
+
+/// Tells the model how to summarize a conversation
+const SUMMARY_INSTRUCTIONS: &str = "You summarize conversations between users and a health assistant so the assistant can remember them. Update the previous summary, if there is one, with the new messages. Keep every health detail the users shared, such as symptoms, conditions, medications, and measurements, along with any advice the assistant gave. Refer to users by name. Respond with only the summary in a few short paragraphs.";
+
+/// How long to wait before the first retry of a failed request to the model
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Told to the user when the model stops responding partway through a response
+const INTERRUPTED_MESSAGE: &str =
+    "The AI's response was interrupted, what it generated so far was saved and can be resumed";
+
+/// The characters that are escaped when a model's name is put in a provider's url.
+/// Slashes are kept since providers like Hugging Face have the organization in the path
+const MODEL_NAME_ESCAPES: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+/// A service that serves an OpenAI compatible chat completions api
+struct AiProvider {
+    name: String,
+    base_url: String,
+    /// The environment variable holding the api key, if the provider needs one
+    api_key_env: Option<String>,
+}
+
+impl AiProvider {
+    /// Get the provider that serves the model.
+    /// Looked up by id since providers can serve models with the same name
+    async fn of_model(pool: &SqlitePool, model_id: i64) -> Result<Self, AppError> {
+        Ok(sqlx::query_as!(
+            AiProvider,
+            "SELECT ai_providers.name, base_url, api_key_env FROM ai_providers
+            JOIN ai_models ON ai_models.provider = ai_providers.name
+            WHERE ai_models.id = ?",
+            model_id
+        )
+        .fetch_one(pool)
+        .await?)
+    }
+
+    /// The url of the chat completions api for the model
+    fn completions_url(&self, model_name: &str) -> String {
+        format!(
+            "{}/chat/completions",
+            self.base_url.trim_end_matches('/').replace(
+                "{model}",
+                &utf8_percent_encode(model_name, MODEL_NAME_ESCAPES).to_string()
+            )
+        )
+    }
+
+    /// Start a request to the chat completions api for the model
+    fn post(&self, client: &Client, model_name: &str) -> Result<RequestBuilder, AppError> {
+        let request = client.post(self.completions_url(model_name));
+        let Some(api_key_env) = &self.api_key_env else {
+            return Ok(request);
+        };
+        let api_key = var(api_key_env).map_err(|_| {
+            anyhow!(
+                "{} must be set to use models from {}",
+                api_key_env,
+                self.name
+            )
+        })?;
+        Ok(request.bearer_auth(api_key))
+    }
 }
 
 /// An AI model that can be used to generate responses
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct AiModel {
     pub id: i64,
     pub name: String,
+    /// The most tokens the model can generate in a single response
+    pub max_tokens: i64,
+    /// The name of the provider that serves the model
+    pub provider: String,
+}
+
+impl AiModel {
+    /// Get a model by its id.
+    /// Ids come from clients so a model that doesn't exist is their mistake
+    pub async fn get(pool: &SqlitePool, model_id: i64) -> Result<Self, AppError> {
+        sqlx::query_as!(
+            AiModel,
+            "SELECT id, name, max_tokens, provider FROM ai_models WHERE id = ?",
+            model_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| {
+            AppError::UserError((StatusCode::BAD_REQUEST, "AI model does not exist".into()))
+        })
+    }
+}
+
+/// Overrides for how the AI responds in a conversation.
+/// Anything left as None uses the default
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationSettings {
+    pub conversation_id: i64,
+    /// Replaces the system prompt of the conversation's persona, or the default one
+    #[validate(length(
+        min = 1,
+        max = 4000,
+        code = "System prompt must be between 1 and 4000 characters"
+    ))]
+    pub system_prompt: Option<String>,
+    #[validate(range(min = 0.0, max = 2.0, code = "Temperature must be between 0 and 2"))]
+    pub temperature: Option<f64>,
+    /// Can't be more than the limit of the largest model,
+    /// responses from smaller models are cut off at their own limit
+    #[validate(range(min = 1, code = "Max tokens must be at least 1"))]
+    pub max_tokens: Option<i64>,
+    #[validate(range(
+        exclusive_min = 0.0,
+        max = 1.0,
+        code = "Top p must be greater than 0 and at most 1"
+    ))]
+    pub top_p: Option<f64>,
+}
+
+impl GenerationSettings {
+    /// Get the settings of a conversation, all None if it doesn't have any
+    pub async fn get(pool: &SqlitePool, conversation_id: i64) -> Result<Self, sqlx::Error> {
+        Ok(sqlx::query_as!(
+            GenerationSettings,
+            "SELECT conversation_id, system_prompt, temperature, max_tokens, top_p
+            FROM conversation_settings WHERE conversation_id = ?",
+            conversation_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .unwrap_or(GenerationSettings {
+            conversation_id,
+            system_prompt: None,
+            temperature: None,
+            max_tokens: None,
+            top_p: None,
+        }))
+    }
+}
+
+/// A persona the AI can take on in a conversation
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Persona {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    /// Replaces the default system prompt in conversations using the persona
+    pub system_prompt: String,
+    /// The model the persona works best with, the client should select it by default
+    pub suggested_model_id: Option<i64>,
+}
+
+/// An AI response that is saved to the database as an incomplete message while it is generated
+struct ResponseDraft {
+    state: AppState,
+    conversation_id: i64,
+    model_id: i64,
+    /// The user who asked for the response, whose quota the tokens count towards
+    user_id: i64,
+    /// The id of the saved message, None until there is anything to save
+    message_id: Option<i64>,
+    /// Everything the model has generated so far
+    content: String,
+    /// The message and stemmed message that were last saved, which are what the search index has
+    saved: Option<(String, Option<String>)>,
+    saved_at: Instant,
+    /// The tokens used as reported by the api, which only sends them with the last chunk
+    usage: Option<TokenUsage>,
+    /// The tokens used so far, counted while streaming in case the api never reports them
+    estimated_usage: TokenUsage,
+    usage_recorded: bool,
+}
+
+impl ResponseDraft {
+    /// Record the tokens used, as reported by the api or estimated if the stream ended first.
+    /// Canceled responses still used the tokens they generated, so they count too
+    async fn record_usage(&mut self) -> Result<(), AppError> {
+        if self.usage_recorded {
+            return Ok(());
+        }
+        self.usage_recorded = true;
+        self.usage
+            .unwrap_or(self.estimated_usage)
+            .record(&self.state.pool, self.user_id, Some(self.conversation_id))
+            .await
+    }
+
+    /// Save the content generated so far, leaving the message marked as incomplete
+    async fn save(&mut self) -> Result<(), AppError> {
+        if self.content.is_empty()
+            || self
+                .saved
+                .as_ref()
+                .is_some_and(|(saved, _)| *saved == self.content)
+        {
+            return Ok(());
+        }
+        self.write(true).await
+    }
+
+    /// Save the response and return it, marking it as incomplete if generation stopped early
+    async fn finish(&mut self, incomplete: bool) -> Result<ChatMessage, AppError> {
+        self.write(incomplete).await?;
+        let message_id = self
+            .message_id
+            .expect("the message is always saved when it is written");
+        Ok(sqlx::query_as!(
+            ChatMessage,
+            "SELECT * FROM chat_messages WHERE id = ?",
+            message_id
+        )
+        .fetch_one(&self.state.pool)
+        .await?)
+    }
+
+    /// Insert or update the message. Updating a message that is still incomplete
+    /// leaves `modified_at` alone so the finished response isn't shown as edited
+    async fn write(&mut self, incomplete: bool) -> Result<(), AppError> {
+        let pool = &self.state.pool;
+        let stemmed_message = self.state.stemmer.stem_message(&self.content);
+        let message_id = match self.message_id {
+            Some(message_id) => {
+                sqlx::query!(
+                    "UPDATE messages SET message = ?, stemmed_message = ?, incomplete = ? WHERE id = ?",
+                    self.content,
+                    stemmed_message,
+                    incomplete,
+                    message_id
+                )
+                .execute(pool)
+                .await?;
+                message_id
+            }
+            None => {
+                let message_id = sqlx::query_scalar!(
+                    "INSERT INTO messages (conversation_id, message, stemmed_message, ai_model_id, incomplete)
+                    VALUES (?, ?, ?, ?, ?) RETURNING id",
+                    self.conversation_id,
+                    self.content,
+                    stemmed_message,
+                    self.model_id,
+                    incomplete
+                )
+                .fetch_one(pool)
+                .await?;
+                self.message_id = Some(message_id);
+                message_id
+            }
+        };
+
+        // Replace what was last saved in the search index with the new contents
+        if let Some((message, stemmed_message)) = &self.saved {
+            unindex_message(
+                pool,
+                message_id,
+                self.conversation_id,
+                message,
+                stemmed_message.as_deref(),
+            )
+            .await;
+        }
+        index_message(
+            pool,
+            message_id,
+            self.conversation_id,
+            &self.content,
+            Some(&stemmed_message),
+        )
+        .await;
+        self.saved = Some((self.content.clone(), Some(stemmed_message)));
+        self.saved_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// Saves whatever was generated if the generation is canceled or fails before it completes
+struct PartialResponse(Option<ResponseDraft>);
+
+impl PartialResponse {
+    fn draft(&mut self) -> &mut ResponseDraft {
+        self.0
+            .as_mut()
+            .expect("the draft is only taken once the response is complete")
+    }
+
+    /// Save the finished response, after which nothing is saved on drop
+    async fn complete(self) -> Result<ChatMessage, AppError> {
+        self.finish(false).await
+    }
+
+    /// Save the response as incomplete because generation was interrupted,
+    /// so it can be resumed later
+    async fn interrupt(self) -> Result<ChatMessage, AppError> {
+        self.finish(true).await
+    }
+
+    async fn finish(mut self, incomplete: bool) -> Result<ChatMessage, AppError> {
+        let message = self.draft().finish(incomplete).await?;
+        self.0 = None;
+        Ok(message)
+    }
+}
+
+/// Send the request to the model, retrying transient failures with exponential backoff.
+/// `retries` counts the retries made so far and is shared with restarting a broken stream,
+/// so a generation never retries more than the configured number of times in total
+async fn send_with_retries(
+    request: &RequestBuilder,
+    max_retries: u32,
+    retries: &mut u32,
+) -> Result<reqwest::Response, AppError> {
+    loop {
+        let result = request
+            .try_clone()
+            .expect("requests with a json body can be cloned")
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        match result {
+            Ok(response) => return Ok(response),
+            Err(e) if *retries < max_retries && is_transient(&e) => {
+                let delay = retry_delay(*retries);
+                *retries += 1;
+                warn!("AI request failed, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Whether a failed request could succeed if it is tried again.
+/// Other errors sending the request aren't retried since the provider may have received it
+/// and started generating, and sending it again would use the tokens twice.
+/// That includes timeouts waiting for a response, only timeouts connecting count as connect errors
+fn is_transient(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+        None => e.is_connect() || e.is_body(),
+    }
 }
 
-/// Query the AI model with the messages in the conversation
-/// Return's the ai's response
+/// How long to wait before the given retry, doubling every time
+fn retry_delay(retries: u32) -> Duration {
+    RETRY_BASE_DELAY * 2u32.saturating_pow(retries)
+}
+
+impl Drop for PartialResponse {
+    fn drop(&mut self) {
+        let Some(mut draft) = self.0.take() else {
+            return;
+        };
+        // Canceling the generation aborts the task it runs in, so the rest is saved in a new one
+        tokio::spawn(async move {
+            if let Err(e) = draft.record_usage().await {
+                error!(
+                    "Failed to record the tokens used by an incomplete AI response: {}",
+                    e
+                );
+            }
+            if let Err(e) = draft.save().await {
+                error!("Failed to save incomplete AI response: {}", e);
+                return;
+            }
+            let Some(message_id) = draft.message_id else {
+                return;
+            };
+            match sqlx::query_as!(
+                ChatMessage,
+                "SELECT * FROM chat_messages WHERE id = ?",
+                message_id
+            )
+            .fetch_one(&draft.state.pool)
+            .await
+            {
+                Ok(message) => {
+                    let _ = broadcast_event(&draft.state, SocketResponse::Message(message)).await;
+                }
+                Err(e) => error!("Failed to load incomplete AI response: {}", e),
+            }
+        });
+    }
+}
+
+/// Query the AI model with the messages in the conversation and save its response.
+/// If `resume_id` is given, the response is added to the end of that incomplete message
+/// instead of being saved as a new one.
+/// Returns the saved message
 pub async fn query_model(
     state: &AppState,
-    message: &SendMessage,
+    conversation_id: i64,
+    model_id: i64,
     user: &UserToken,
-) -> Result<String, AppError> {
-    let model_id = message.ai_model_id.expect("Model ID should be provided");
-    let conversation_id = message
-        .conversation_id
-        .expect("Conversation ID should be provided");
-    let model = sqlx::query!("SELECT name FROM ai_models WHERE id = ?", model_id)
-        .fetch_one(&state.pool)
-        .await?;
+    resume_id: Option<i64>,
+) -> Result<ChatMessage, AppError> {
+    let model = AiModel::get(&state.pool, model_id).await?;
+    let settings = GenerationSettings::get(&state.pool, conversation_id).await?;
+    let persona_prompt = match settings.system_prompt {
+        Some(system_prompt) => Some(system_prompt),
+        None => {
+            sqlx::query_scalar!(
+                "SELECT system_prompt FROM conversations
+                JOIN personas ON personas.id = conversations.persona_id
+                WHERE conversations.id = ?",
+                conversation_id
+            )
+            .fetch_optional(&state.pool)
+            .await?
+        }
+    };
+    let system_prompt = format!(
+        "{} {}",
+        persona_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT),
+        USERNAME_INSTRUCTIONS
+    );
+    let summary = ConversationSummary::get(&state.pool, conversation_id).await?;
+    // Summarize the messages that no longer fit in the context for the next response.
+    // Done in the background so it doesn't hold up this one
+    tokio::spawn({
+        let state = state.clone();
+        let model = model.clone();
+        let user_id = user.id;
+        async move { refresh_summary(&state, conversation_id, &model, user_id).await }
+    });
     // Build the default request body for the AI model
     let mut body = json!({
         "model": model.name,
         "messages": [
-        { "role": "system", "content": r#"You are a medical professional who knows about medicine.  When the user tells you about a health problem that they are facing, continue probing through the problem to extract more information and attempt to gain a better understanding of a root cause and potential remedies. Do not simply give a list of potential causes without asking further questions. If you are unsure about something refer user to a doctor or medical professional. The name of the user who sent the message will be enclosed in braces like "{username}:". You should refer to the user who you are responding to by name"# },
+        { "role": "system", "content": system_prompt },
     ],
-        "temperature": 0.5,
-        "max_tokens": 1024,
-        "top_p": 0.7,
+        "temperature": settings.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+        "max_tokens": settings.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS).min(model.max_tokens),
+        "top_p": settings.top_p.unwrap_or(DEFAULT_TOP_P),
     // Enable streaming so we can get the response as it comes in
-        "stream": true
+        "stream": true,
+    // Ask for the tokens used in the last chunk so they can be counted
+        "stream_options": { "include_usage": true }
     });
 
     // Populate the messages array with the messages in the conversation
     if let Some(req_messages) = body["messages"].as_array_mut() {
+        // The summary stands in for the messages it covers
+        if let Some(summary) = &summary {
+            req_messages.push(json!({
+                "role": "system",
+                "content": format!("Summary of the earlier conversation: {}", summary.summary)
+            }));
+        }
+        let summarized_through = summary
+            .as_ref()
+            .map_or(0, |summary| summary.last_message_id);
         // Query the messages as a stream to save memory
         // This saves a ton on longer conversations
-        // Only select the most recent messages that add up to less than 5000 characters
+        // Only select the most recent messages that add up to less than `RECENT_CONTEXT_CHARS`
+        // and aren't in the summary
         // This is to prevent the AI from getting stuck on very long conversations
         // and token limits from the api
         let mut db_messages = sqlx::query!(
-        "WITH ranked_messages AS (
+        r#"WITH ranked_messages AS (
             SELECT
+                messages.id,
                 messages.message,
                 messages.user_id,
                 users.username,
-                SUM(LENGTH(messages.message)) OVER (PARTITION BY messages.conversation_id ORDER BY messages.created_at DESC) AS cumulative_length,
+                messages.file_name,
+                files.text_preview,
+                SUM(LENGTH(messages.message) + COALESCE(LENGTH(files.text_preview), 0)) OVER (PARTITION BY messages.conversation_id ORDER BY messages.created_at DESC, messages.id DESC) AS cumulative_length,
                 messages.created_at
             FROM
                 messages
             LEFT JOIN
                 users ON messages.user_id = users.id
+            LEFT JOIN
+                files ON messages.file_id = files.id
             WHERE
-                messages.conversation_id = ?
+                messages.conversation_id = ? AND messages.id > ?
         )
         SELECT
             message,
             user_id,
-            username
+            username as "username?",
+            file_name,
+            text_preview
         FROM
             ranked_messages
         WHERE
-            cumulative_length <= 5000
+            cumulative_length <= ?
         ORDER BY
-            created_at ASC",
-            conversation_id
+            created_at ASC, id ASC"#,
+            conversation_id,
+            summarized_through,
+            RECENT_CONTEXT_CHARS
         )
         .fetch(&state.pool);
 
@@ -138,6 +638,14 @@ pub async fn query_model(
                 (None, None) | (Some(_), None) => (),
             }
             cur_content.push_str(&message.message);
+            // Give the AI the text of any documents attached to the message
+            if let Some(text_preview) = &message.text_preview {
+                cur_content.push_str(&format!(
+                    "\n[Attached document {}: {}]",
+                    message.file_name.as_deref().unwrap_or("untitled"),
+                    text_preview
+                ));
+            }
             last_user = message.username;
             first = false;
         }
@@ -145,6 +653,13 @@ pub async fn query_model(
         "role": if last_user.is_some() { "user" } else { "assistant" },
         "content": cur_content
         }));
+        // The incomplete response is the last message in the conversation
+        if resume_id.is_some() {
+            req_messages.push(json!({
+                "role": "system",
+                "content": RESUME_INSTRUCTIONS
+            }));
+        }
 
         let form = sqlx::query!(
             "SELECT height, weight, sleep_hours, exercise_duration, food_intake, notes, modified_at FROM user_statistics WHERE user_id = ? ORDER BY created_at DESC LIMIT 1",
@@ -154,8 +669,10 @@ pub async fn query_model(
         .await?;
 
         if let Some(form) = form {
+            // Show the height and weight in the units the user is used to
+            let units = unit_system(&state.pool, user.id).await?;
             let time_diff = chrono::Utc::now().naive_utc() - form.modified_at;
-            let content = format!("{} filled out a health form {} that contains the following details: {}{}{}{}{}{}{}",
+            let content = format!("{} filled out a health form {} that contains the following details: {}{}{}{}{}{}{}{}",
                 user.username,
                 match time_diff {
                     _ if time_diff.num_weeks() > 0 => format!("{} weeks ago", time_diff.num_weeks()),
@@ -167,11 +684,15 @@ pub async fn query_model(
                 },
                 (chrono::Utc::now().naive_utc() - form.modified_at),
                 match form.height {
-                    Some(height) => format!("Height: {} cm\n", height),
+                    Some(height) => format!("Height: {:.1} {}\n", units.height(height), units.height_unit()),
                     None => "".to_string()
                 },
                 match form.weight {
-                    Some(weight) => format!("Weight: {} kg\n", weight),
+                    Some(weight) => format!("Weight: {:.1} {}\n", units.weight(weight), units.weight_unit()),
+                    None => "".to_string()
+                },
+                match bmi(form.height, form.weight) {
+                    Some(bmi) => format!("BMI: {} kg/m² ({})\n", bmi, BmiCategory::of(bmi).name()),
                     None => "".to_string()
                 },
                 match form.sleep_hours {
@@ -200,71 +721,152 @@ pub async fn query_model(
         debug!("Querying AI model with: {:?}", req_messages);
     }
 
-    let mut response = state
-        .client
-        .post(format!(
-            "https://api-inference.huggingface.co/models/{}/v1/chat/completions",
-            model.name
-        ))
-        .header(
-            header::AUTHORIZATION,
-            format!(
-                "Bearer {}",
-                var("HF_API_KEY").expect("Huggingface API key should be provided .env file as HF_API_KEY. Get one at https://huggingface.co/settings/tokens")
-            ),
-        )
-        .json(&body)
-        .send()
+    let request = AiProvider::of_model(&state.pool, model.id)
+        .await?
+        .post(&state.client, &model.name)?
+        .json(&body);
+    let mut retries = 0;
+    let mut response = send_with_retries(&request, state.ai_max_retries, &mut retries)
         .await?
         // Handle the response as a stream
         // Using serde_json::Value instead of sonic_rs::Value because it breaks for some reason
         // and gives a CodecError. I tried looking it up every where and even read through the
         // source of both reqwest_streams and sonic_rs but I couldn't figure it out.
         .json_array_stream::<serde_json::Value>(2048);
+    // Whether the current stream has sent any content, after which it can't be restarted
+    // without repeating what was already streamed to the clients
+    let mut received_content = false;
+    let mut interrupted = false;
 
-    // The accumulated response from the AI model
-    let mut res_content = String::new();
+    // The accumulated response from the AI model, saved as it comes in
+    let mut response_draft = ResponseDraft {
+        state: state.clone(),
+        conversation_id,
+        model_id,
+        user_id: user.id,
+        message_id: resume_id,
+        content: String::new(),
+        saved: None,
+        saved_at: Instant::now(),
+        usage: None,
+        estimated_usage: TokenUsage {
+            prompt_tokens: estimate_tokens(&body["messages"].to_string()),
+            completion_tokens: 0,
+        },
+        usage_recorded: false,
+    };
+    if let Some(resume_id) = resume_id {
+        let message = sqlx::query!(
+            "SELECT message, stemmed_message FROM messages WHERE id = ?",
+            resume_id
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        response_draft.content.clone_from(&message.message);
+        response_draft.saved = Some((message.message, message.stemmed_message));
+    }
+    let mut response_draft = PartialResponse(Some(response_draft));
 
     // Get a sender handle to all of the connected clients in the conversation
     // This is done, instead of calling `broadcast_event` in a loop, before streaming the response for two main reasons
     // #1 it prevents newly connected clients from receiving a half-baked response
     // #2 it avoids having to query the database for the conversation senders for each message in
     // the stream, which can be very expensive for large messages and conversations
-    let senders = get_conversation_senders(state, conversation_id).await?;
-
-    while let Some(mut bytes) = response.next().await {
-        match bytes {
-            Ok(ref mut bytes) => {
-                // Stream the individual messages to the clients
-                let mut futures: FuturesUnordered<_> = senders
-                    .iter()
-                    .map(|sender| {
-                        sender.send(SocketResponse::StreamData(StreamMessage {
+    // Subscribe before fetching the senders so no one can leave in between unnoticed
+    let mut leaves = state.conversation_leaves.subscribe();
+    let mut senders = get_conversation_senders(state, conversation_id).await?;
+
+    // Output that hasn't been sent to the clients yet
+    let mut buffer = String::new();
+    let mut buffered_chars = 0;
+    let coalescing = state.stream_coalescing;
+    let mut flush = tokio::time::interval(coalescing.interval);
+    flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            chunk = response.next() => match chunk {
+                Some(Ok(bytes)) => {
+                    let draft = response_draft.draft();
+                    if let Ok(usage) = serde_json::from_value::<TokenUsage>(bytes["usage"].clone()) {
+                        draft.usage = Some(usage);
+                    }
+                    let content = bytes["choices"][0]["delta"]["content"]
+                        .as_str()
+                        .unwrap_or("");
+                    received_content |= !content.is_empty();
+                    // Models stream a chunk per token
+                    draft.estimated_usage.completion_tokens += i64::from(!content.is_empty());
+                    // Accumulate the response content
+                    draft.content += content;
+                    if draft.saved_at.elapsed() >= SAVE_INTERVAL {
+                        draft.save().await?;
+                    }
+                    buffer += content;
+                    buffered_chars += content.chars().count();
+                    if buffered_chars >= coalescing.max_chars {
+                        stream_to_senders(
+                            state,
                             conversation_id,
-                            message: Some(
-                                bytes["choices"][0]["delta"]["content"]
-                                    .as_str()
-                                    .unwrap_or("")
-                                    .to_string(),
-                            ),
-                            querier_id: user.id,
-                        }))
-                    })
-                    .collect();
-                while let Some(result) = futures.next().await {
-                    if let Err(e) = result {
-                        warn!("Failed to send stream message: {:?}", e);
+                            user,
+                            &mut senders,
+                            &mut leaves,
+                            &mut buffer,
+                            resume_id,
+                        )
+                        .await?;
+                        buffered_chars = 0;
+                        flush.reset();
                     }
                 }
-                // Accumulate the response content
-                res_content += bytes["choices"][0]["delta"]["content"]
-                    .as_str()
-                    .unwrap_or("");
+                Some(Err(e)) if !received_content && retries < state.ai_max_retries => {
+                    let delay = retry_delay(retries);
+                    retries += 1;
+                    warn!("AI response stream failed, retrying in {:?}: {}", delay, e);
+                    tokio::time::sleep(delay).await;
+                    response = send_with_retries(&request, state.ai_max_retries, &mut retries)
+                        .await?
+                        .json_array_stream::<serde_json::Value>(2048);
+                }
+                Some(Err(e)) if !received_content => return Err(AppError::from(e)),
+                Some(Err(e)) => {
+                    warn!("AI response stream was interrupted: {}", e);
+                    interrupted = true;
+                    break;
+                }
+                None => break,
+            },
+            _ = flush.tick(), if !buffer.is_empty() => {
+                stream_to_senders(
+                    state,
+                    conversation_id,
+                    user,
+                    &mut senders,
+                    &mut leaves,
+                    &mut buffer,
+                    resume_id,
+                )
+                .await?;
+                buffered_chars = 0;
             }
-            Err(e) => return Err(AppError::from(e)),
         }
     }
 
+    // Send whatever is left before telling the clients the response is done
+    if !buffer.is_empty() {
+        stream_to_senders(
+            state,
+            conversation_id,
+            user,
+            &mut senders,
+            &mut leaves,
+            &mut buffer,
+            resume_id,
+        )
+        .await?;
+    }
+
+    response_draft.draft().record_usage().await?;
+
     // Broadcast the that the AI model has finished processing
     for sender in &senders {
         sender
@@ -272,11 +874,132 @@ pub async fn query_model(
                 conversation_id,
                 message: None,
                 querier_id: user.id,
+                message_id: resume_id,
             }))
             .await?;
     }
 
-    Ok(res_content)
+    if interrupted {
+        send_to_user(
+            state,
+            user.id,
+            SocketResponse::Error(
+                AppError::UserError((StatusCode::BAD_GATEWAY, INTERRUPTED_MESSAGE.into())).into(),
+            ),
+        )
+        .await?;
+        return response_draft.interrupt().await;
+    }
+    response_draft.complete().await
+}
+
+/// Generate a single response from the model without streaming it to any clients.
+/// Used for short generations that aren't part of a conversation.
+/// The tokens used count towards the quota of the user the response is for,
+/// in the conversation it is about if there is one
+pub async fn complete(
+    state: &AppState,
+    model: &AiModel,
+    messages: serde_json::Value,
+    max_tokens: u32,
+    user_id: i64,
+    conversation_id: Option<i64>,
+) -> Result<String, AppError> {
+    let response: serde_json::Value = AiProvider::of_model(&state.pool, model.id)
+        .await?
+        .post(&state.client, &model.name)?
+        .timeout(COMPLETION_TIMEOUT)
+        .json(&json!({
+            "model": model.name,
+            "messages": &messages,
+            "temperature": 0.3,
+            "max_tokens": max_tokens,
+            "stream": false
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let content = response["choices"][0]["message"]["content"]
+        .as_str()
+        .map(str::trim)
+        .unwrap_or_default();
+    serde_json::from_value::<TokenUsage>(response["usage"].clone())
+        .unwrap_or_else(|_| TokenUsage {
+            prompt_tokens: estimate_tokens(&messages.to_string()),
+            completion_tokens: estimate_tokens(content),
+        })
+        .record(&state.pool, user_id, conversation_id)
+        .await?;
+    if content.is_empty() {
+        return Err(anyhow!("{} responded without any content", model.name).into());
+    }
+    Ok(content.to_owned())
+}
+
+/// Send the buffered output of the AI model to every client in the conversation
+/// and empty the buffer
+async fn stream_to_senders(
+    state: &AppState,
+    conversation_id: i64,
+    user: &UserToken,
+    senders: &mut Vec<Sender<SocketResponse>>,
+    leaves: &mut broadcast::Receiver<ConversationLeave>,
+    buffer: &mut String,
+    resume_id: Option<i64>,
+) -> Result<(), AppError> {
+    // Stop streaming to users that left the conversation during generation
+    remove_left_senders(state, conversation_id, senders, leaves).await?;
+    let message = std::mem::take(buffer);
+    let mut futures: FuturesUnordered<_> = senders
+        .iter()
+        .map(|sender| {
+            sender.send(SocketResponse::StreamData(StreamMessage {
+                conversation_id,
+                message: Some(message.clone()),
+                querier_id: user.id,
+                message_id: resume_id,
+            }))
+        })
+        .collect();
+    while let Some(result) = futures.next().await {
+        if let Err(e) = result {
+            warn!("Failed to send stream message: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Remove the senders of users who have left the conversation since the senders were fetched
+async fn remove_left_senders(
+    state: &AppState,
+    conversation_id: i64,
+    senders: &mut Vec<Sender<SocketResponse>>,
+    leaves: &mut broadcast::Receiver<ConversationLeave>,
+) -> Result<(), AppError> {
+    loop {
+        match leaves.try_recv() {
+            Ok(leave) if leave.conversation_id == conversation_id => {
+                senders.retain(|sender| *sender.user_id != leave.user_id);
+            }
+            Ok(_) => (),
+            Err(broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed) => {
+                return Ok(())
+            }
+            // Some leave events were missed, so check the remaining members against the database
+            Err(broadcast::error::TryRecvError::Lagged(_)) => {
+                let members = sqlx::query_scalar!(
+                    "SELECT user_id FROM user_conversations WHERE conversation_id = ?",
+                    conversation_id
+                )
+                .fetch_all(&state.pool)
+                .await?;
+                senders.retain(|sender| members.contains(&*sender.user_id));
+            }
+        }
+    }
 }
 
 /// Get sender handles for all the connected clients in the conversation
@@ -320,3 +1043,275 @@ pub async fn get_ai_models(State(pool): State<SqlitePool>) -> Result<Response, A
     )
         .into_response())
 }
+
+/// A summary of the older messages in a conversation
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationSummary {
+    pub conversation_id: i64,
+    pub summary: String,
+    /// The newest message covered by the summary
+    pub last_message_id: i64,
+    pub created_at: NaiveDateTime,
+}
+
+impl ConversationSummary {
+    async fn get(pool: &SqlitePool, conversation_id: i64) -> Result<Option<Self>, AppError> {
+        Ok(sqlx::query_as!(
+            ConversationSummary,
+            "SELECT conversation_id, summary, last_message_id, created_at
+            FROM conversation_summaries WHERE conversation_id = ?",
+            conversation_id
+        )
+        .fetch_optional(pool)
+        .await?)
+    }
+}
+
+/// Summarize the messages in the conversation that no longer fit in the context sent to the
+/// model, adding them to the existing summary.
+/// Returns None if there aren't any messages that need summarizing.
+/// The tokens used count towards the quota of the given user, who can't ask for a summary
+/// once they have used it up
+pub async fn summarize_conversation(
+    state: &AppState,
+    conversation_id: i64,
+    model: &AiModel,
+    user_id: i64,
+) -> Result<Option<ConversationSummary>, AppError> {
+    let previous = ConversationSummary::get(&state.pool, conversation_id).await?;
+    let summarized_through = previous
+        .as_ref()
+        .map_or(0, |summary| summary.last_message_id);
+    // The messages that are too old to be sent as they are and aren't in the summary yet
+    let messages = sqlx::query!(
+        r#"WITH ranked_messages AS (
+            SELECT
+                messages.id,
+                messages.message,
+                users.username,
+                SUM(LENGTH(messages.message)) OVER (ORDER BY messages.created_at DESC, messages.id DESC) AS cumulative_length,
+                messages.created_at
+            FROM messages
+            LEFT JOIN users ON messages.user_id = users.id
+            WHERE messages.conversation_id = ? AND messages.id > ?
+        )
+        SELECT id as "id!", message, username as "username?"
+        FROM ranked_messages
+        WHERE cumulative_length > ?
+        ORDER BY created_at ASC, id ASC"#,
+        conversation_id,
+        summarized_through,
+        RECENT_CONTEXT_CHARS
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    if messages.is_empty() {
+        return Ok(None);
+    }
+    check_token_quota(state, user_id).await?;
+
+    // Summarize the oldest messages first if there are too many to do at once,
+    // the rest are added the next time
+    let mut transcript = String::new();
+    let mut last_message_id = summarized_through;
+    for message in messages {
+        if !transcript.is_empty() && transcript.len() + message.message.len() > MAX_SUMMARIZED_CHARS
+        {
+            break;
+        }
+        transcript.push_str(&format!(
+            "{}: {}\n",
+            message.username.as_deref().unwrap_or("Assistant"),
+            message.message
+        ));
+        last_message_id = message.id;
+    }
+    let prompt = match &previous {
+        Some(previous) => format!(
+            "Previous summary:\n{}\n\nNew messages:\n{}",
+            previous.summary, transcript
+        ),
+        None => format!("Messages:\n{}", transcript),
+    };
+    let summary = complete(
+        state,
+        model,
+        json!([
+            { "role": "system", "content": SUMMARY_INSTRUCTIONS },
+            { "role": "user", "content": prompt },
+        ]),
+        SUMMARY_MAX_TOKENS,
+        user_id,
+        Some(conversation_id),
+    )
+    .await?;
+
+    // Only replace the summary if another summarization didn't get further in the meantime
+    Ok(sqlx::query_as!(
+        ConversationSummary,
+        "INSERT INTO conversation_summaries (conversation_id, summary, last_message_id)
+        VALUES (?, ?, ?)
+        ON CONFLICT (conversation_id) DO UPDATE SET summary = excluded.summary,
+        last_message_id = excluded.last_message_id, created_at = CURRENT_TIMESTAMP
+        WHERE excluded.last_message_id > conversation_summaries.last_message_id
+        RETURNING conversation_id, summary, last_message_id, created_at",
+        conversation_id,
+        summary,
+        last_message_id
+    )
+    .fetch_optional(&state.pool)
+    .await?)
+}
+
+/// Bring the conversation's summary up to date and let its members know, logging any errors
+async fn refresh_summary(state: &AppState, conversation_id: i64, model: &AiModel, user_id: i64) {
+    match summarize_conversation(state, conversation_id, model, user_id).await {
+        Ok(Some(summary)) => {
+            if let Err(e) =
+                broadcast_event(state, SocketResponse::ConversationSummary(summary)).await
+            {
+                warn!("Failed to broadcast conversation summary: {}", e);
+            }
+        }
+        Ok(None) => (),
+        Err(e) => warn!(
+            "Failed to summarize conversation {}: {}",
+            conversation_id, e
+        ),
+    }
+}
+
+/// The tokens used to generate a response, as reported by the api
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct TokenUsage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+/// Roughly how many tokens a text is, for when the api doesn't say.
+/// English averages about four characters per token
+fn estimate_tokens(text: &str) -> i64 {
+    (text.chars().count() as i64 + 3) / 4
+}
+
+impl TokenUsage {
+    /// Add the tokens to the user's usage in the conversation for the current month.
+    /// Usage outside of a conversation, such as explaining a health measurement, has no conversation
+    async fn record(
+        self,
+        pool: &SqlitePool,
+        user_id: i64,
+        conversation_id: Option<i64>,
+    ) -> Result<(), AppError> {
+        sqlx::query!(
+            "INSERT INTO ai_usage (user_id, conversation_id, month, prompt_tokens, completion_tokens)
+            VALUES (?, ?, strftime('%Y-%m', 'now'), ?, ?)
+            ON CONFLICT (user_id, conversation_id, month) DO UPDATE SET
+            prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+            completion_tokens = completion_tokens + excluded.completion_tokens",
+            user_id,
+            conversation_id,
+            self.prompt_tokens,
+            self.completion_tokens
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// The total tokens the user has used this month
+async fn monthly_usage(pool: &SqlitePool, user_id: i64) -> Result<i64, AppError> {
+    Ok(sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(prompt_tokens + completion_tokens), 0) as "total!: i64"
+        FROM ai_usage WHERE user_id = ? AND month = strftime('%Y-%m', 'now')"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Check that the user hasn't used up their monthly token quota, if there is one
+pub async fn check_token_quota(state: &AppState, user_id: i64) -> Result<(), AppError> {
+    let Some(quota) = state.ai_token_quota else {
+        return Ok(());
+    };
+    if monthly_usage(&state.pool, user_id).await? < quota {
+        return Ok(());
+    }
+    let today = chrono::Utc::now().date_naive();
+    let resets_on = today
+        .with_day(1)
+        .and_then(|first| first.checked_add_months(Months::new(1)))
+        .unwrap_or(today);
+    Err(AppError::UserError((
+        StatusCode::TOO_MANY_REQUESTS,
+        format!(
+            "Monthly AI token quota of {} has been used up, it resets on {}",
+            quota, resets_on
+        )
+        .into(),
+    )))
+}
+
+/// The tokens the AI used responding to the user in a conversation
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationUsage {
+    /// None for conversations that have since been deleted
+    pub conversation_id: Option<i64>,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+/// The tokens the AI has used responding to the user
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AiUsage {
+    /// Tokens used so far this month, which count towards the quota
+    pub monthly_tokens: i64,
+    /// How many tokens can be used each month, None if unlimited
+    pub monthly_quota: Option<i64>,
+    /// All time usage in each conversation
+    pub conversations: Vec<ConversationUsage>,
+}
+
+/// Returns how many tokens the AI has used responding to the logged in user
+pub async fn get_ai_usage(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    let conversations = sqlx::query_as!(
+        ConversationUsage,
+        r#"SELECT conversation_id, SUM(prompt_tokens) as "prompt_tokens!: i64",
+        SUM(completion_tokens) as "completion_tokens!: i64"
+        FROM ai_usage WHERE user_id = ? GROUP BY conversation_id
+        ORDER BY conversation_id"#,
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    Ok((
+        StatusCode::OK,
+        AppJson(AiUsage {
+            monthly_tokens: monthly_usage(&state.pool, user.id).await?,
+            monthly_quota: state.ai_token_quota,
+            conversations,
+        }),
+    )
+        .into_response())
+}
+
+/// Returns all the personas the AI can take on
+pub async fn get_personas(State(pool): State<SqlitePool>) -> Result<Response, AppError> {
+    Ok((
+        StatusCode::OK,
+        AppJson(
+            sqlx::query_as!(Persona, "SELECT * FROM personas ORDER BY id")
+                .fetch_all(&pool)
+                .await?,
+        ),
+    )
+        .into_response())
+}
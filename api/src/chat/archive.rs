@@ -0,0 +1,653 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::JwtAuth,
+    error::{AppError, AppJson},
+    state::AppState,
+    users::{generate_token, require_admin, UserToken, IMPORTED_USERNAME_PREFIX},
+};
+
+use super::{ensure_owner, search::index_message, ConversationRole};
+
+/// The version of the bundle format written by `export_conversation`.
+/// Bump this whenever the format changes in a way older versions can't read
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Everything needed to recreate a conversation on another instance
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationBundle {
+    pub version: u32,
+    pub exported_at: NaiveDateTime,
+    pub conversation: BundleConversation,
+    /// Every user who is a member of the conversation, sent a message in it,
+    /// reacted to a message, or pinned one
+    pub users: Vec<BundleUser>,
+    pub members: Vec<BundleMember>,
+    /// Ordered by id so a reply always comes after the message it replies to
+    pub messages: Vec<BundleMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleConversation {
+    pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_message_at: Option<NaiveDateTime>,
+    /// The name of the persona the conversation uses, matched by name on import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    #[serde(default)]
+    pub admins_only_rename: bool,
+    /// Overrides for how the AI responds, None if the conversation uses the defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<BundleSettings>,
+}
+
+/// The conversation's overrides for how the AI responds, see `GenerationSettings`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleSettings {
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<i64>,
+    pub top_p: Option<f64>,
+}
+
+/// Users are matched by username on import since ids differ between instances
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleUser {
+    pub id: i64,
+    pub username: String,
+}
+
+/// A user's membership and read state in the conversation
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleMember {
+    pub user_id: i64,
+    /// Bundles exported before roles existed import everyone as a member
+    #[serde(default)]
+    pub role: ConversationRole,
+    pub joined_at: NaiveDateTime,
+    pub last_message_at: Option<NaiveDateTime>,
+    pub last_read_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleMessage {
+    pub id: i64,
+    /// None if the message was sent by an AI model
+    pub user_id: Option<i64>,
+    /// The name of the AI model that sent the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ai_model: Option<String>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<BundleAttachment>,
+    pub created_at: NaiveDateTime,
+    pub modified_at: NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<BundleReaction>,
+    /// The previous contents of the message, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<BundleEdit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<BundlePin>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleReaction {
+    pub user_id: i64,
+    pub emoji: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// What a message said before it was edited
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleEdit {
+    pub message: String,
+    pub edited_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundlePin {
+    /// None if the user who pinned the message has been deleted
+    pub pinned_by: Option<i64>,
+    pub pinned_at: NaiveDateTime,
+}
+
+/// An attached file, referenced by the hash of its contents.
+/// The file itself isn't included, it has to already be uploaded to the instance it's imported into
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleAttachment {
+    pub hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Only the version is read first so bundles from newer versions get a useful error
+/// instead of failing to deserialize
+#[derive(Deserialize)]
+struct BundleVersion {
+    version: u32,
+}
+
+/// What was left out when importing a bundle
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSummary {
+    /// The id of the newly created conversation
+    pub conversation_id: i64,
+    /// Usernames that don't exist on this instance.
+    /// Each is replaced by a placeholder account so their messages keep their author,
+    /// but they aren't added to the conversation
+    pub placeholder_users: Vec<String>,
+    /// Messages sent by an AI model that doesn't exist on this instance
+    pub skipped_messages: usize,
+    /// Messages whose attachment hasn't been uploaded to this instance are imported without it
+    pub missing_attachments: usize,
+}
+
+/// Export a conversation with all of its members, messages, reactions, edits, pins,
+/// and settings as a JSON bundle
+pub async fn export_conversation(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(conversation_id): Path<i64>,
+) -> Result<Response, AppError> {
+    require_admin(&pool, user.id).await?;
+
+    let Some(conversation) = sqlx::query!(
+        r#"SELECT conversations.id, title, created_at, last_message_at, name as "persona?",
+        admins_only_rename, conversation_settings.conversation_id as "has_settings?",
+        conversation_settings.system_prompt, conversation_settings.temperature,
+        conversation_settings.max_tokens, conversation_settings.top_p
+        FROM conversations
+        LEFT JOIN personas ON personas.id = conversations.persona_id
+        LEFT JOIN conversation_settings ON conversation_settings.conversation_id = conversations.id
+        WHERE conversations.id = ?"#,
+        conversation_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .map(|row| BundleConversation {
+        id: row.id,
+        title: row.title,
+        created_at: row.created_at,
+        last_message_at: row.last_message_at,
+        persona: row.persona,
+        admins_only_rename: row.admins_only_rename,
+        settings: row.has_settings.map(|_| BundleSettings {
+            system_prompt: row.system_prompt,
+            temperature: row.temperature,
+            max_tokens: row.max_tokens,
+            top_p: row.top_p,
+        }),
+    }) else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Conversation not found".into(),
+        )));
+    };
+
+    let users = sqlx::query_as!(
+        BundleUser,
+        "SELECT id, username FROM users
+        WHERE id IN (SELECT user_id FROM user_conversations WHERE conversation_id = ?1)
+        OR id IN (SELECT user_id FROM messages WHERE conversation_id = ?1)
+        OR id IN (SELECT message_reactions.user_id FROM message_reactions
+            JOIN messages ON messages.id = message_reactions.message_id
+            WHERE conversation_id = ?1)
+        OR id IN (SELECT pinned_by FROM pinned_messages WHERE conversation_id = ?1)
+        ORDER BY id",
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let members = sqlx::query_as!(
+        BundleMember,
+        "SELECT user_id, role, joined_at, last_message_at, last_read_at FROM user_conversations
+        WHERE conversation_id = ? ORDER BY joined_at",
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut reactions: HashMap<i64, Vec<BundleReaction>> = HashMap::new();
+    for row in sqlx::query!(
+        "SELECT message_id, message_reactions.user_id, emoji, message_reactions.created_at
+        FROM message_reactions
+        JOIN messages ON messages.id = message_reactions.message_id
+        WHERE conversation_id = ? ORDER BY message_reactions.created_at",
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?
+    {
+        reactions
+            .entry(row.message_id)
+            .or_default()
+            .push(BundleReaction {
+                user_id: row.user_id,
+                emoji: row.emoji,
+                created_at: row.created_at,
+            });
+    }
+
+    let mut edits: HashMap<i64, Vec<BundleEdit>> = HashMap::new();
+    for row in sqlx::query!(
+        "SELECT message_id, message_edits.message, edited_at FROM message_edits
+        JOIN messages ON messages.id = message_edits.message_id
+        WHERE conversation_id = ? ORDER BY message_edits.id",
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?
+    {
+        edits.entry(row.message_id).or_default().push(BundleEdit {
+            message: row.message,
+            edited_at: row.edited_at,
+        });
+    }
+
+    let mut pins: HashMap<i64, BundlePin> = sqlx::query!(
+        "SELECT message_id, pinned_by, pinned_at FROM pinned_messages WHERE conversation_id = ?",
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            row.message_id,
+            BundlePin {
+                pinned_by: row.pinned_by,
+                pinned_at: row.pinned_at,
+            },
+        )
+    })
+    .collect();
+
+    let messages = sqlx::query!(
+        r#"SELECT messages.id, user_id, ai_models.name as "ai_model?", message,
+        files.path as "file_path?", files.mime as file_mime, file_name,
+        messages.created_at, modified_at, reply_to
+        FROM messages
+        LEFT JOIN ai_models ON ai_models.id = messages.ai_model_id
+        LEFT JOIN files ON files.id = messages.file_id
+        WHERE conversation_id = ? ORDER BY messages.id"#,
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|row| BundleMessage {
+        id: row.id,
+        user_id: row.user_id,
+        ai_model: row.ai_model,
+        message: row.message,
+        attachment: row.file_path.map(|path| BundleAttachment {
+            // Uploads are named after the hash of their contents
+            hash: path.split('.').next().unwrap_or_default().to_owned(),
+            mime: row.file_mime,
+            name: row.file_name,
+        }),
+        created_at: row.created_at,
+        modified_at: row.modified_at,
+        reply_to: row.reply_to,
+        reactions: reactions.remove(&row.id).unwrap_or_default(),
+        edits: edits.remove(&row.id).unwrap_or_default(),
+        pin: pins.remove(&row.id),
+    })
+    .collect();
+
+    let bundle = ConversationBundle {
+        version: BUNDLE_VERSION,
+        exported_at: chrono::Utc::now().naive_utc(),
+        conversation,
+        users,
+        members,
+        messages,
+    };
+    Ok((
+        StatusCode::OK,
+        [(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"conversation-{}.json\"",
+                conversation_id
+            ),
+        )],
+        AppJson(bundle),
+    )
+        .into_response())
+}
+
+/// Recreate a conversation from a bundle made by `export_conversation`.
+/// The conversation and its messages are given new ids, users are matched by username
+/// and replaced by placeholder accounts if they don't exist here
+pub async fn import_conversation(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    require_admin(&state.pool, user.id).await?;
+
+    let AppJson(BundleVersion { version }) = AppJson::from_bytes(&body)?;
+    if version != BUNDLE_VERSION {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Unsupported bundle version {}, expected version {}",
+                version, BUNDLE_VERSION
+            )
+            .into(),
+        )));
+    }
+    let AppJson(mut bundle) = AppJson::<ConversationBundle>::from_bytes(&body)?;
+    bundle.messages.sort_by_key(|message| message.id);
+
+    let mut tx = state.pool.begin().await?;
+
+    // Map the user ids in the bundle to the ids of the users with the same username here
+    let mut user_ids = HashMap::new();
+    // Users without an account here are only used as authors, they don't become members
+    let mut placeholders = HashSet::new();
+    let mut placeholder_users = Vec::new();
+    for bundle_user in bundle.users {
+        let id = match sqlx::query_scalar!(
+            "SELECT id FROM users WHERE username = ?",
+            bundle_user.username
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        {
+            Some(id) => id,
+            None => {
+                let id = create_placeholder_user(&mut tx, &bundle_user.username).await?;
+                placeholders.insert(id);
+                placeholder_users.push(bundle_user.username);
+                id
+            }
+        };
+        user_ids.insert(bundle_user.id, id);
+    }
+
+    let conversation_id = sqlx::query_scalar!(
+        // The default persona is used if the persona doesn't exist on this instance
+        "INSERT INTO conversations (title, created_at, persona_id, admins_only_rename)
+        VALUES (?, ?, (SELECT id FROM personas WHERE name = ?), ?) RETURNING id",
+        bundle.conversation.title,
+        bundle.conversation.created_at,
+        bundle.conversation.persona,
+        bundle.conversation.admins_only_rename
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if let Some(settings) = bundle.conversation.settings {
+        sqlx::query!(
+            "INSERT INTO conversation_settings (conversation_id, system_prompt, temperature, max_tokens, top_p)
+            VALUES (?, ?, ?, ?, ?)",
+            conversation_id,
+            settings.system_prompt,
+            settings.temperature,
+            settings.max_tokens,
+            settings.top_p
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // The new id of each imported message along with the root of its thread
+    let mut message_ids: HashMap<i64, (i64, Option<i64>)> = HashMap::new();
+    let mut ai_model_ids = HashMap::new();
+    let mut imported = Vec::with_capacity(bundle.messages.len());
+    let mut skipped_messages = 0;
+    let mut missing_attachments = 0;
+    for message in bundle.messages {
+        // Every user in the bundle was mapped above, an id missing from the list of users is a broken bundle
+        let user_id = message
+            .user_id
+            .map(|id| mapped_user(&user_ids, id))
+            .transpose()?;
+        let ai_model_id = match message.ai_model {
+            Some(name) => {
+                let id = match ai_model_ids.get(&name) {
+                    Some(&id) => id,
+                    None => {
+                        let id =
+                            sqlx::query_scalar!("SELECT id FROM ai_models WHERE name = ?", name)
+                                .fetch_optional(&mut *tx)
+                                .await?;
+                        ai_model_ids.insert(name, id);
+                        id
+                    }
+                };
+                if id.is_none() {
+                    skipped_messages += 1;
+                    continue;
+                }
+                id
+            }
+            None => None,
+        };
+
+        let (file_id, file_name) = match message.attachment {
+            Some(attachment) => {
+                let file_id = find_file(&mut tx, &attachment.hash).await?;
+                if file_id.is_none() {
+                    missing_attachments += 1;
+                }
+                (file_id, file_id.and(attachment.name))
+            }
+            None => (None, None),
+        };
+
+        // Replies to messages that were skipped are imported as regular messages
+        let (reply_to, thread_root_id) = match message
+            .reply_to
+            .and_then(|reply_to| message_ids.get(&reply_to))
+        {
+            Some(&(parent_id, parent_root)) => {
+                (Some(parent_id), Some(parent_root.unwrap_or(parent_id)))
+            }
+            None => (None, None),
+        };
+
+        let stemmed_message = state.stemmer.stem_message(&message.message);
+        let id = sqlx::query_scalar!(
+            "INSERT INTO messages (message, stemmed_message, user_id, ai_model_id, file_id, file_name, created_at, modified_at, conversation_id, reply_to, thread_root_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING id",
+            message.message,
+            stemmed_message,
+            user_id,
+            ai_model_id,
+            file_id,
+            file_name,
+            message.created_at,
+            message.modified_at,
+            conversation_id,
+            reply_to,
+            thread_root_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        message_ids.insert(message.id, (id, thread_root_id));
+
+        for edit in message.edits {
+            sqlx::query!(
+                "INSERT INTO message_edits (message_id, message, edited_at) VALUES (?, ?, ?)",
+                id,
+                edit.message,
+                edit.edited_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        for reaction in message.reactions {
+            let reactor = mapped_user(&user_ids, reaction.user_id)?;
+            sqlx::query!(
+                "INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, created_at)
+                VALUES (?, ?, ?, ?)",
+                id,
+                reactor,
+                reaction.emoji,
+                reaction.created_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        if let Some(pin) = message.pin {
+            let pinned_by = pin
+                .pinned_by
+                .map(|id| mapped_user(&user_ids, id))
+                .transpose()?;
+            sqlx::query!(
+                "INSERT INTO pinned_messages (message_id, conversation_id, pinned_by, pinned_at)
+                VALUES (?, ?, ?, ?)",
+                id,
+                conversation_id,
+                pinned_by,
+                pin.pinned_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        imported.push((id, message.message, stemmed_message));
+    }
+
+    // Members are added after the messages so inserting them doesn't overwrite their read state
+    for member in bundle.members {
+        let user_id = mapped_user(&user_ids, member.user_id)?;
+        if placeholders.contains(&user_id) {
+            continue;
+        }
+        sqlx::query!(
+            "INSERT INTO user_conversations (conversation_id, user_id, role, joined_at, last_message_at, last_read_at)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            conversation_id,
+            user_id,
+            member.role,
+            member.joined_at,
+            member.last_message_at,
+            member.last_read_at
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    // The owner might not have an account on this instance
+    ensure_owner(&mut *tx, conversation_id).await?;
+    sqlx::query!(
+        "UPDATE conversations SET last_message_at = ? WHERE id = ?",
+        bundle.conversation.last_message_at,
+        conversation_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    for (id, message, stemmed_message) in imported {
+        index_message(
+            &state.pool,
+            id,
+            conversation_id,
+            &message,
+            Some(&stemmed_message),
+        )
+        .await;
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        AppJson(ImportSummary {
+            conversation_id,
+            placeholder_users,
+            skipped_messages,
+            missing_attachments,
+        }),
+    )
+        .into_response())
+}
+
+/// The id on this instance of a user from the bundle
+fn mapped_user(user_ids: &HashMap<i64, i64>, bundle_id: i64) -> Result<i64, AppError> {
+    user_ids.get(&bundle_id).copied().ok_or_else(|| {
+        AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!("User {} is not in the bundle's list of users", bundle_id).into(),
+        ))
+    })
+}
+
+/// Create an account to stand in for a user from another instance.
+/// It keeps the original username as its name but can't be logged into
+async fn create_placeholder_user(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    username: &str,
+) -> Result<i64, AppError> {
+    // The real username and email need the id, which isn't known until the row is inserted
+    let (temporary, _) = generate_token();
+    let id = sqlx::query_scalar!(
+        "INSERT INTO users (username, email, first_name) VALUES (?1, ?1, ?2) RETURNING id",
+        temporary,
+        username
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    let placeholder_username = format!("{}{}", IMPORTED_USERNAME_PREFIX, id);
+    // Nobody can receive mail at the reserved .invalid domain
+    let placeholder_email = format!("{}@imported.invalid", placeholder_username);
+    sqlx::query!(
+        "UPDATE users SET username = ?, email = ? WHERE id = ?",
+        placeholder_username,
+        placeholder_email,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(id)
+}
+
+/// Find an uploaded file by the hash of its contents
+async fn find_file(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    hash: &str,
+) -> Result<Option<i64>, AppError> {
+    // Hashes are hex, anything else can't match an upload and would be treated as a pattern
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let with_extension = format!("{}.%", hash);
+    Ok(sqlx::query_scalar!(
+        "SELECT id FROM files WHERE path = ? OR path LIKE ? LIMIT 1",
+        hash,
+        with_extension
+    )
+    .fetch_optional(&mut **tx)
+    .await?)
+}
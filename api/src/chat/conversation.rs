@@ -1,4 +1,5 @@
-use anyhow::anyhow;
+use std::collections::HashMap;
+
 use axum::{
     extract::{Path, State},
     http::StatusCode,
@@ -6,109 +7,251 @@ use axum::{
 };
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, SqlitePool};
+use sqlx::{prelude::FromRow, Executor, Sqlite, SqliteConnection, SqlitePool, Type};
 
-use crate::{auth::JwtAuth, error::AppError};
+use crate::{auth::JwtAuth, error::AppError, state::AppState, utils::Requested};
 use crate::{error::AppJson, users::UserToken};
 
-use super::{OnlineStatus, SendMessage};
+use super::{
+    announce_message, check_token_quota, generate_response_in_background, save_message, AiModel,
+    OnlineStatus, SendMessage,
+};
 
 /// A conversation between at least one user and an AI
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Conversation {
     /// The id of the conversation
     pub id: i64,
     /// The title of the conversation
-    /// If this is None, the frontend should fallback to listing the
-    /// usernames of the users in the conversation
+    /// If this is omitted, the conversation has no title and the frontend should
+    /// fallback to listing the usernames of the users in the conversation
     #[serde(skip_serializing_if = "Option::is_none")]
     pub title: Option<String>,
     pub created_at: NaiveDateTime,
+    /// Null if no messages have been sent in the conversation
     pub last_message_at: Option<NaiveDateTime>,
-    /// The ids of the users in the conversation
-    /// Will be None if requesting data on multiple conversations
+    /// The persona the AI uses in the conversation
+    /// Omitted if the conversation uses the default persona
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub users: Option<Box<[ConversationUser]>>,
+    pub persona_id: Option<i64>,
+    /// Whether the user muted the conversation
+    /// Omitted if it was not requested
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub muted: Requested<bool>,
+    /// Whether only owners and admins can rename the conversation
+    #[serde(default)]
+    pub admins_only_rename: bool,
+    /// The users in the conversation
+    /// Omitted if the users were not requested, an empty list means the conversation has no members
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub users: Requested<Box<[ConversationUser]>>,
+    /// The number of messages sent by other users or the AI since the user last read the conversation
+    /// Omitted if it was not requested
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub unread_count: Requested<i64>,
+}
+
+/// Totals for a conversation, so clients don't have to page through every message to count them
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationStats {
+    pub conversation_id: i64,
+    pub message_count: i64,
+    pub member_count: i64,
+    /// Null if no messages have been sent in the conversation
+    pub first_message_at: Option<NaiveDateTime>,
+    /// Null if no messages have been sent in the conversation
+    pub last_message_at: Option<NaiveDateTime>,
 }
 
-#[derive(Serialize, Debug, Default, Clone)]
+/// A user in a conversation
+/// Only the id is guaranteed, the other fields are omitted unless the
+/// conversation's details were requested with `RequestConversation`
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ConversationUser {
     /// The id of the user
     pub id: i64,
     /// The timestamp of the last message sent by the user in the conversation
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub last_message_at: Option<NaiveDateTime>,
+    /// Null if the user has not sent a message
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub last_message_at: Requested<Option<NaiveDateTime>>,
     /// The timestamp when the user last read the conversation
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub last_read_at: Option<NaiveDateTime>,
+    /// Null if the user has not read the conversation
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub last_read_at: Requested<Option<NaiveDateTime>>,
     /// The online status of the user
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub online_status: Option<OnlineStatus>,
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub online_status: Requested<OnlineStatus>,
+    /// The user's role in the conversation
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub role: Requested<ConversationRole>,
+}
+
+/// What a user is allowed to do in a conversation
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum ConversationRole {
+    /// Can remove anyone and choose who the admins are
+    /// Every conversation with members has exactly one owner
+    Owner,
+    /// Can remove members
+    Admin,
+    #[default]
+    Member,
+}
+
+/// Need for sqlx to convert the role from the database to the enum
+impl From<String> for ConversationRole {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "owner" => ConversationRole::Owner,
+            "admin" => ConversationRole::Admin,
+            // Fall back to the least privileged role
+            _ => ConversationRole::Member,
+        }
+    }
+}
+
+impl ConversationRole {
+    /// Whether the role can manage the other members of the conversation
+    pub fn is_admin(self) -> bool {
+        matches!(self, ConversationRole::Owner | ConversationRole::Admin)
+    }
+}
+
+/// Make the longest standing member of the conversation its owner if it doesn't have one,
+/// such as after the owner leaves. Admins are picked before members.
+/// Returns the id of the member who was made owner, if any
+pub(crate) async fn ensure_owner<'e, E>(
+    executor: E,
+    conversation_id: i64,
+) -> Result<Option<i64>, AppError>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    Ok(sqlx::query_scalar!(
+        "UPDATE user_conversations SET role = 'owner'
+        WHERE conversation_id = ?1 AND user_id = (
+            SELECT user_id FROM user_conversations
+            WHERE conversation_id = ?1
+            ORDER BY role = 'admin' DESC, joined_at, user_id
+            LIMIT 1
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM user_conversations WHERE conversation_id = ?1 AND role = 'owner'
+        )
+        RETURNING user_id",
+        conversation_id
+    )
+    .fetch_optional(executor)
+    .await?)
 }
 
-/// Create a conversation between the user and the AI from an initial message
-/// Initiated from a POST request
+/// Create a conversation between the user and the AI from an initial message.
+/// Initiated from a POST request, the message is broadcast and answered by the AI
+/// the same as if it was sent over a websocket
 pub async fn create_conversation_rest(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     JwtAuth(user): JwtAuth<UserToken>,
     AppJson(init_message): AppJson<SendMessage>,
 ) -> Result<Response, AppError> {
-    // Limit the title to 32 characters
-    // let title = &init_message.message[..cmp::min(init_message.message.len(), 32)];
+    if init_message.conversation_id.is_some() {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Cannot create a conversation that already exists".into(),
+        )));
+    }
+    // Reject a model that doesn't exist before the message is saved
+    // so the user isn't left with a message the AI never answers
+    if let Some(ai_model_id) = init_message.ai_model_id {
+        AiModel::get(&state.pool, ai_model_id).await?;
+        check_token_quota(&state, user.id).await?;
+    }
+    // Saving the first message creates the conversation
+    let message = save_message(&state, &init_message, &user).await?;
+    let conversation_id = message.conversation_id;
+    // The same as sending the message over a websocket, so the user's other connections see it
+    announce_message(&state, message);
+    if let Some(ai_model_id) = init_message.ai_model_id {
+        generate_response_in_background(&state, &user, conversation_id, ai_model_id);
+    }
+    let conversation = sqlx::query!(
+        "SELECT id, title, created_at, last_message_at, persona_id, admins_only_rename FROM conversations WHERE id = ?",
+        conversation_id,
+    )
+    .fetch_one(&state.pool)
+    .await?;
     Ok((
         StatusCode::OK,
-        AppJson(create_conversation(&pool, &init_message, &user).await?),
+        AppJson(Conversation {
+            id: conversation.id,
+            title: conversation.title,
+            created_at: conversation.created_at,
+            last_message_at: conversation.last_message_at,
+            persona_id: conversation.persona_id,
+            muted: Requested::Loaded(false),
+            admins_only_rename: conversation.admins_only_rename,
+            users: Requested::Loaded(
+                [ConversationUser {
+                    id: user.id,
+                    role: Requested::Loaded(ConversationRole::Owner),
+                    ..Default::default()
+                }]
+                .into(),
+            ),
+            unread_count: Requested::Loaded(0),
+        }),
     )
         .into_response())
 }
 
-// This is used in both the REST api and the websocket api so it is extracted into a function
-/// Create a conversation between the user and the AI from an initial message
+/// Create a conversation for the first message sent in it.
+///
+/// Every conversation starts with at least one human member, the user creating it, and either
+/// a first message or the users invited with `InviteUsers`. The first message isn't saved here,
+/// so this should be run in the same transaction as saving it.
 pub async fn create_conversation(
-    pool: &SqlitePool,
+    conn: &mut SqliteConnection,
     init_message: &SendMessage,
     user: &UserToken,
 ) -> Result<Conversation, AppError> {
+    // Title the conversation after the first message, or the attachment if there is no text
     let title = init_message
         .message
-        .as_ref()
-        .ok_or(anyhow!(
-            "Cannot create a conversation with an empty message"
-        ))?
+        .as_deref()
+        .map(str::trim)
+        .filter(|message| !message.is_empty())
+        .or(init_message
+            .attachment
+            .as_ref()
+            .map(|attachment| attachment.name.as_str()))
+        .ok_or_else(|| {
+            AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                "Cannot create a conversation with an empty message".into(),
+            ))
+        })?
         .chars()
         .take(32)
         .collect::<String>();
 
-    // Begin a transaction to ensure that both the conversation and the initial message are saved
-    let mut tx = pool.begin().await?;
     // Create the conversation
-    let conversation_id = sqlx::query!(
-        "INSERT INTO conversations (title) VALUES (?) RETURNING id",
+    let conversation = sqlx::query!(
+        "INSERT INTO conversations (title) VALUES (?) RETURNING id, title, created_at, last_message_at",
         title
     )
-    .fetch_one(&mut *tx)
-    .await?
-    .id;
-    // Add the user to the conversation
+    .fetch_one(&mut *conn)
+    .await?;
+    // Add the user to the conversation as its owner
     sqlx::query!(
-        "INSERT INTO user_conversations (user_id, conversation_id) VALUES (?, ?)",
+        "INSERT INTO user_conversations (user_id, conversation_id, role) VALUES (?, ?, 'owner')",
         user.id,
-        conversation_id
-    )
-    .execute(&mut *tx)
-    .await?;
-
-    // Everything went well, commit the transaction
-    tx.commit().await?;
-
-    let conversation = sqlx::query!(
-        "SELECT id, title, created_at, last_message_at FROM conversations
-        WHERE id = ? ORDER BY last_message_at DESC",
-        conversation_id,
+        conversation.id
     )
-    .fetch_one(pool)
+    .execute(&mut *conn)
     .await?;
 
     // Return the new conversation for future messages
@@ -117,13 +260,19 @@ pub async fn create_conversation(
         title: conversation.title,
         created_at: conversation.created_at,
         last_message_at: conversation.last_message_at,
-        users: Some(
+        persona_id: None,
+        muted: Requested::Loaded(false),
+        admins_only_rename: false,
+        users: Requested::Loaded(
             [ConversationUser {
                 id: user.id,
+                role: Requested::Loaded(ConversationRole::Owner),
                 ..Default::default()
             }]
             .into(),
         ),
+        // The only message is the user's own
+        unread_count: Requested::Loaded(0),
     })
 }
 
@@ -144,16 +293,33 @@ pub struct ChatMessage {
     /// This will be none if the message was sent by the AI
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user_id: Option<i64>,
+    /// The id of the attachment, downloaded from `/api/files/:id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_path: Option<String>,
+    /// A snippet of the attached document's text so it can be shown without downloading it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_preview: Option<String>,
     /// The id of the AI model that sent the message
     /// This will be none if the message was sent by a user
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ai_model_id: Option<i64>,
     pub created_at: NaiveDateTime,
     pub modified_at: NaiveDateTime,
+    /// The id of the message this message is replying to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    /// The id of the message that started the thread this message is in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_root_id: Option<i64>,
+    /// The number of replies in the thread started by this message
+    pub thread_reply_count: i64,
+    /// Whether this is an AI response that was canceled or interrupted before it finished.
+    /// The most recent message in a conversation can be continued with `ResumeGeneration`
+    pub incomplete: bool,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -162,6 +328,71 @@ pub struct DeleteMessage {
     pub conversation_id: i64,
 }
 
+/// A message along with the reactions to it
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactedMessage {
+    #[serde(flatten)]
+    pub message: ChatMessage,
+    pub reactions: Vec<ReactionCount>,
+}
+
+/// How many users reacted to a message with an emoji
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    /// Whether the user requesting the messages is one of them
+    pub reacted: bool,
+}
+
+/// A previous version of an edited message
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEdit {
+    pub message_id: i64,
+    /// The contents of the message before it was edited
+    pub message: String,
+    /// When the contents were replaced
+    pub edited_at: NaiveDateTime,
+}
+
+/// A user added or removed a reaction to a message
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionEvent {
+    pub message_id: i64,
+    pub conversation_id: i64,
+    pub user_id: i64,
+    pub emoji: String,
+    /// True if the reaction was added, false if it was removed
+    pub added: bool,
+}
+
+/// A user pinned or unpinned a message
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PinEvent {
+    pub message_id: i64,
+    pub conversation_id: i64,
+    pub user_id: i64,
+    /// True if the message was pinned, false if it was unpinned
+    pub pinned: bool,
+}
+
+/// A pinned message along with who pinned it
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PinnedMessage {
+    #[serde(flatten)]
+    pub message: ChatMessage,
+    /// None if the user who pinned the message has been deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_by: Option<i64>,
+    pub pinned_at: NaiveDateTime,
+}
+
 /// Get all the messages in a conversation
 pub async fn get_conversation(
     State(pool): State<SqlitePool>,
@@ -181,17 +412,173 @@ pub async fn get_conversation(
     {
         return Ok((StatusCode::NOT_FOUND, "Conversation not found").into_response());
     }
-    let res = &sqlx::query_as!(
-            ChatMessage,
-            r#"SELECT messages.id, message, messages.created_at, modified_at, conversation_id, user_id, ai_model_id,
-            file_name, files.path as file_path FROM messages
-            LEFT JOIN files ON files.id = messages.file_id
-            WHERE conversation_id = ? 
-            ORDER BY messages.created_at DESC"#,
-            conversation_id,
-        )
-        .fetch_all(&pool)
-        .await?;
+    let messages = sqlx::query_as!(
+        ChatMessage,
+        r#"SELECT * FROM chat_messages
+            WHERE conversation_id = ?
+            ORDER BY created_at DESC"#,
+        conversation_id,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    // Count the reactions to every message in one query instead of one per message
+    let mut reactions: HashMap<i64, Vec<ReactionCount>> = HashMap::new();
+    for row in sqlx::query!(
+        r#"SELECT message_id as "message_id!", emoji as "emoji!", COUNT(*) as "count!: i64", MAX(message_reactions.user_id = ?) as "reacted!: bool"
+            FROM message_reactions
+            JOIN messages ON messages.id = message_reactions.message_id
+            WHERE conversation_id = ?
+            GROUP BY message_id, emoji
+            ORDER BY MIN(message_reactions.created_at)"#,
+        user.id,
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?
+    {
+        reactions
+            .entry(row.message_id)
+            .or_default()
+            .push(ReactionCount {
+                emoji: row.emoji,
+                count: row.count,
+                reacted: row.reacted,
+            });
+    }
+
+    let res = messages
+        .into_iter()
+        .map(|message| ReactedMessage {
+            reactions: reactions.remove(&message.id).unwrap_or_default(),
+            message,
+        })
+        .collect::<Vec<_>>();
+    Ok((StatusCode::OK, AppJson(res)).into_response())
+}
+
+/// Get a message and every message in the reply chain that roots at it
+/// Messages are returned from oldest to newest
+pub async fn get_thread(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(message_id): Path<i64>,
+) -> Result<Response, AppError> {
+    if sqlx::query!(
+        "SELECT messages.id FROM messages
+            JOIN user_conversations ON user_conversations.conversation_id = messages.conversation_id
+            WHERE messages.id = ? AND user_conversations.user_id = ?",
+        message_id,
+        user.id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Message not found".into(),
+        )));
+    }
+    Ok((
+        StatusCode::OK,
+        AppJson(get_thread_messages(&pool, message_id).await?),
+    )
+        .into_response())
+}
+
+/// Get the first message of the thread the given message is in, if the user can see the message.
+/// A message that isn't a reply is its own thread root
+pub async fn get_thread_root(
+    pool: &SqlitePool,
+    user_id: i64,
+    message_id: i64,
+) -> Result<i64, AppError> {
+    let message = sqlx::query!(
+        "SELECT messages.id, messages.thread_root_id FROM messages
+            JOIN user_conversations ON user_conversations.conversation_id = messages.conversation_id
+            WHERE messages.id = ? AND user_conversations.user_id = ?",
+        message_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::UserError((StatusCode::NOT_FOUND, "Message not found".into())))?;
+    Ok(message.thread_root_id.unwrap_or(message.id))
+}
+
+/// Walk the reply chain starting at the given message and return every message in it
+pub async fn get_thread_messages(
+    pool: &SqlitePool,
+    message_id: i64,
+) -> Result<Vec<ChatMessage>, AppError> {
+    Ok(sqlx::query_as!(
+        ChatMessage,
+        r#"WITH RECURSIVE thread(id) AS (
+                SELECT id FROM messages WHERE id = ?
+                UNION
+                SELECT messages.id FROM messages JOIN thread ON messages.reply_to = thread.id
+            )
+            SELECT chat_messages.* FROM chat_messages
+            JOIN thread ON thread.id = chat_messages.id
+            ORDER BY chat_messages.created_at ASC"#,
+        message_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Get the pinned messages in a conversation, most recently pinned first
+pub async fn get_pinned_messages(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(conversation_id): Path<i64>,
+) -> Result<Response, AppError> {
+    if sqlx::query!(
+        "SELECT conversation_id FROM user_conversations WHERE conversation_id = ? AND user_id = ?",
+        conversation_id,
+        user.id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Conversation not found".into(),
+        )));
+    }
+
+    let mut messages: HashMap<i64, ChatMessage> = sqlx::query_as!(
+        ChatMessage,
+        r#"SELECT * FROM chat_messages WHERE conversation_id = ?1
+            AND EXISTS (SELECT 1 FROM pinned_messages WHERE message_id = chat_messages.id)"#,
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|message| (message.id, message))
+    .collect();
+    let pins = sqlx::query!(
+        "SELECT message_id, pinned_by, pinned_at FROM pinned_messages
+            WHERE conversation_id = ? ORDER BY pinned_at DESC, rowid DESC",
+        conversation_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let res = pins
+        .into_iter()
+        .filter_map(|pin| {
+            // A message could have been pinned between the two queries
+            Some(PinnedMessage {
+                message: messages.remove(&pin.message_id)?,
+                pinned_by: pin.pinned_by,
+                pinned_at: pin.pinned_at,
+            })
+        })
+        .collect::<Vec<_>>();
     Ok((StatusCode::OK, AppJson(res)).into_response())
 }
 
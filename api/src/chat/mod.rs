@@ -1,9 +1,14 @@
 // Module file that re-exports all the other chat-related modules
 mod ai;
+mod archive;
 mod conversation;
 mod search;
 mod websocket;
 
 pub use ai::*;
+pub use archive::*;
 pub use conversation::*;
+pub use search::{
+    rebuild_search_index, search_message, SearchLimits, SearchMessage, SearchResult, SearchThrottle,
+};
 pub use websocket::*;
@@ -1,11 +1,18 @@
-use chrono::NaiveDate;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose, Engine};
+use chrono::{NaiveDate, NaiveDateTime};
 use futures::StreamExt;
 use reqwest::StatusCode;
-use serde::Deserialize;
-use sqlx::{QueryBuilder, Sqlite};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, QueryBuilder, Sqlite, SqlitePool};
 use tokio::sync::mpsc::Sender;
+use tracing::error;
 
-use crate::{chat::ChatMessage, error::AppError, state::AppState};
+use crate::{chat::ChatMessage, cli::Args, error::AppError, state::AppState, users::UserToken};
 
 use super::SocketResponse;
 
@@ -17,21 +24,180 @@ pub struct SearchMessage {
     order: SearchOrder,
     #[serde(default = "Box::default")]
     filters: Box<[Filter]>,
+    /// The maximum number of messages to return, capped by `SearchLimits::max_results`
+    limit: Option<u32>,
+    /// The `nextCursor` of the previous page to continue from it.
+    /// Only valid with the same query and order it came from
+    cursor: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+/// How the time a message was sent is written in a search cursor
+const CURSOR_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// Where a page of search results ended: the value the results are ordered by and the id of
+/// the last message, which breaks ties.
+/// Sent to the client as url safe base64 so it can be passed back without being picked apart
+#[derive(Debug)]
+struct SearchCursor {
+    key: String,
+    id: i64,
+}
+
+impl SearchCursor {
+    fn of(result: &SearchResult, order: &SearchOrder) -> Self {
+        Self {
+            key: match order {
+                SearchOrder::Newest | SearchOrder::Oldest => result
+                    .message
+                    .created_at
+                    .format(CURSOR_TIME_FORMAT)
+                    .to_string(),
+                SearchOrder::Relevance => result.rank.to_string(),
+            },
+            id: result.message.id,
+        }
+    }
+
+    fn encode(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(format!("{}|{}", self.key, self.id))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, AppError> {
+        let invalid = || AppError::UserError((StatusCode::BAD_REQUEST, "Invalid cursor".into()));
+        let cursor = general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(invalid)?;
+        let (key, id) = cursor.rsplit_once('|').ok_or_else(invalid)?;
+        Ok(Self {
+            key: key.to_owned(),
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+
+    /// Limit the results to the ones after this cursor
+    fn push_condition(
+        &self,
+        builder: &mut QueryBuilder<'_, Sqlite>,
+        order: &SearchOrder,
+    ) -> Result<(), AppError> {
+        let invalid = || AppError::UserError((StatusCode::BAD_REQUEST, "Invalid cursor".into()));
+        match order {
+            SearchOrder::Newest | SearchOrder::Oldest => {
+                let created_at = NaiveDateTime::parse_from_str(&self.key, CURSOR_TIME_FORMAT)
+                    .map_err(|_| invalid())?;
+                builder.push(if matches!(order, SearchOrder::Newest) {
+                    " WHERE (created_at, id) < ("
+                } else {
+                    " WHERE (created_at, id) > ("
+                });
+                builder.push_bind(created_at);
+            }
+            SearchOrder::Relevance => {
+                let rank: f64 = self.key.parse().map_err(|_| invalid())?;
+                builder.push(" WHERE (rank, id) > (");
+                builder.push_bind(rank);
+            }
+        }
+        builder.push(", ");
+        builder.push_bind(self.id);
+        builder.push(")");
+        Ok(())
+    }
+}
+
+/// Limits on how much searching a single connection can do
+#[derive(Clone, Copy, Debug)]
+pub struct SearchLimits {
+    /// How many searches are allowed per second
+    pub rate: u32,
+    /// How many searches can be made at once before being rate limited
+    pub burst: u32,
+    pub max_results: u32,
+}
+
+impl From<&Args> for SearchLimits {
+    fn from(args: &Args) -> Self {
+        Self {
+            rate: args.search_rate,
+            burst: args.search_burst,
+            max_results: args.search_max_results,
+        }
+    }
+}
+
+/// A token bucket limiting how often a connection can search.
+/// Searches run multiple full text queries so rapid fire searches can easily pin the CPU
+#[derive(Debug)]
+pub struct SearchThrottle {
+    /// The number of searches available and when they were last refilled
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl SearchThrottle {
+    pub fn new(limits: &SearchLimits) -> Self {
+        Self {
+            bucket: Mutex::new((limits.burst as f64, Instant::now())),
+        }
+    }
+
+    /// Take a search from the bucket, or return how long until one is available
+    pub fn acquire(&self, limits: &SearchLimits) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, refilled_at) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*refilled_at).as_secs_f64() * limits.rate as f64)
+            .min(limits.burst as f64);
+        *refilled_at = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - *tokens) / limits.rate as f64,
+            ))
+        }
+    }
+}
+
+/// Marks the start of a matched term in a search snippet
+const SNIPPET_MATCH_START: &str = "<mark>";
+/// Marks the end of a matched term in a search snippet
+const SNIPPET_MATCH_END: &str = "</mark>";
+/// Stands in for `SNIPPET_MATCH_START` in the snippets SQLite makes, until the rest of the text is escaped.
+/// A private use character so it can't be mistaken for markup
+const SNIPPET_SENTINEL_START: char = '\u{E000}';
+/// Stands in for `SNIPPET_MATCH_END` until the rest of the text is escaped
+const SNIPPET_SENTINEL_END: char = '\u{E001}';
+/// The most tokens of the message to include in a search snippet
+const SNIPPET_TOKENS: u32 = 16;
+
+/// A message that matched a search
+#[derive(Serialize, Clone, Debug, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: ChatMessage,
+    /// The part of the message around the match, HTML escaped, with matched terms wrapped in
+    /// `<mark>` and `</mark>`.
+    /// Matches only found through stemming, such as "running" for "ran", aren't marked
+    pub snippet: Option<String>,
+    /// How well the message matched, lower is better.
+    /// Used to continue from the message when ordering by relevance
+    #[serde(skip)]
+    rank: f64,
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub enum SearchOrder {
+    #[default]
     Newest,
     Oldest,
     Relevance,
 }
 
-impl Default for SearchOrder {
-    fn default() -> Self {
-        Self::Newest
-    }
-}
-
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", content = "value")]
 pub enum Filter {
@@ -42,11 +208,13 @@ pub enum Filter {
     AiModel(Option<i64>),
 }
 
-// Note: This query can return duplicate rows because of the rank column being included.
-// The rank column is used to determine the relevance of the search results and will be
+// Note: The rank column is used to determine the relevance of the search results and will be
 // different depending on whether the search query matched the message or the stemmed message.
 // The rank column must be included in order to rank the results by relevance, otherwise
 // the database will return an error.
+// The snippet is also different since only matches in the message itself are marked, so a message
+// matching both ways would show up twice. The union is grouped by id to keep one row per message,
+// preferring the one that matched the message itself since its snippet has the matches marked.
 //
 // Using union to query both the `message` and `stemmed_message` columns because nothing else worked.
 // Attempting to use something simpler like a WHERE clause with a condition for `message` and
@@ -55,42 +223,69 @@ pub enum Filter {
 // ¯\_(ツ)_/¯
 //
 // The final query will look something like:
-// SELECT *, messages_fts.rank FROM chat_messages
+// SELECT * FROM (SELECT *, MAX(exact_match) FROM (
+// SELECT *, messages_fts.rank, snippet(...) as snippet, 1 as exact_match FROM chat_messages
 // JOIN messages_fts
 // ON messages.id = messages_fts.rowid
 // WHERE messages_fts.message MATCH 'NEAR(search_query, 5)'
 // UNION
-// SELECT *, messages_fts.rank FROM chat_messages
+// SELECT *, messages_fts.rank, snippet(...) as snippet, 0 as exact_match FROM chat_messages
 // JOIN messages_fts
 // ON messages.id = messages_fts.rowid
 // WHERE messages_fts.stemmed_message
-// MATCH 'NEAR(stem(search_query), 5)' ORDER BY messages_fts.rank;
+// MATCH 'NEAR(stem(search_query), 5)'
+// ) GROUP BY id) WHERE (rank, id) > (cursor) ORDER BY rank ASC, id ASC LIMIT page_size + 1;
+// Ordering by time uses `created_at` in place of `rank` instead, descending for the newest first.
 /// Search messages in the database according to given query
 pub async fn search_message(
     state: &AppState,
     search_message: &SearchMessage,
+    user: &UserToken,
     sender: &Sender<SocketResponse>,
 ) -> Result<(), AppError> {
     // Escape single quotes and convert to lowercase
     let search_query = search_message.query.replace("'", "''").to_lowercase();
     let search_query = search_query.trim();
     if search_query.is_empty() {
+        sender
+            .send(SocketResponse::SearchEnd { next_cursor: None })
+            .await?;
         return Ok(());
     }
+    let cursor = search_message
+        .cursor
+        .as_deref()
+        .map(SearchCursor::decode)
+        .transpose()?;
 
-    let mut builder: QueryBuilder<'_, Sqlite> = QueryBuilder::new("");
+    // `MAX` makes SQLite take the rest of the columns from the row with the exact match
+    let mut builder: QueryBuilder<'_, Sqlite> =
+        QueryBuilder::new("SELECT * FROM (SELECT *, MAX(exact_match) FROM (");
     // Generate two queries, one for the normal message and one for the stemmed message.
     // Union them together to get the final result.
     for i in 0..2 {
-        builder.push(
-            "SELECT *, messages_fts.rank FROM chat_messages
+        // Snippets always come from the original message since the stemmed one isn't meant to be read
+        builder.push(format!(
+            "SELECT *, messages_fts.rank,
+                snippet(messages_fts, 1, '{SNIPPET_SENTINEL_START}', '{SNIPPET_SENTINEL_END}', '…', {SNIPPET_TOKENS}) as snippet,
+                {} as exact_match
+                FROM chat_messages
                 JOIN messages_fts
                 ON chat_messages.id = messages_fts.rowid 
                 WHERE ",
-        );
+            i32::from(i == 0)
+        ));
 
+        // Only ever search the conversations the user is in.
+        // Searching without listing any conversations searches all of them
+        builder.push(
+            "chat_messages.conversation_id IN
+                (SELECT conversation_id FROM user_conversations WHERE user_id = ",
+        );
+        builder.push_bind(user.id);
+        builder.push(") AND ");
         if !search_message.conversations.is_empty() {
-            builder.push("conversation_id IN (");
+            builder.push("chat_messages.conversation_id IN (");
 
             let mut separated = builder.separated(", ");
             for conversation in search_message.conversations.iter() {
@@ -157,19 +352,44 @@ pub async fn search_message(
         }
     }
 
+    builder.push(") GROUP BY id)");
+    if let Some(cursor) = &cursor {
+        cursor.push_condition(&mut builder, &search_message.order)?;
+    }
+    // The id keeps the order stable between pages when messages share a time or rank
     builder.push(" ORDER BY ");
     builder.push(match search_message.order {
-        SearchOrder::Newest => "chat_messages.created_at DESC",
-        SearchOrder::Oldest => "chat_messages.created_at ASC",
-        SearchOrder::Relevance => "chat_messages_fts.rank DESC",
+        SearchOrder::Newest => "created_at DESC, id DESC",
+        SearchOrder::Oldest => "created_at ASC, id ASC",
+        // bm25 ranks better matches lower
+        SearchOrder::Relevance => "rank ASC, id ASC",
     });
+    let limit = search_message
+        .limit
+        .unwrap_or(state.search_limits.max_results)
+        .clamp(1, state.search_limits.max_results);
+    // Fetch one more than the page to know if there is another page after it
+    builder.push(" LIMIT ");
+    builder.push_bind(limit + 1);
 
-    let query = builder.build_query_as::<ChatMessage>();
+    let query = builder.build_query_as::<SearchResult>();
     let mut query = query.fetch(&state.pool);
 
+    let mut sent = 0;
+    let mut last = None;
+    let mut has_more = false;
     while let Some(message) = query.next().await {
         match message {
-            Ok(message) => sender.send(SocketResponse::SearchMessage(message)).await?,
+            Ok(_) if sent == limit => {
+                has_more = true;
+                break;
+            }
+            Ok(mut result) => {
+                result.snippet = result.snippet.as_deref().map(mark_snippet);
+                sent += 1;
+                last = Some(SearchCursor::of(&result, &search_message.order));
+                sender.send(SocketResponse::SearchMessage(result)).await?;
+            }
             // Check if the error is a database error with code 1 which means the search query is invalid
             Err(e)
                 if e.as_database_error()
@@ -185,5 +405,82 @@ pub async fn search_message(
             Err(e) => return Err(e.into()),
         };
     }
+    let next_cursor = last.filter(|_| has_more).map(|cursor| cursor.encode());
+    sender
+        .send(SocketResponse::SearchEnd { next_cursor })
+        .await?;
+    Ok(())
+}
+
+/// Escape the text of a snippet so it can be shown as HTML and mark the matched terms
+fn mark_snippet(snippet: &str) -> String {
+    let mut marked = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            SNIPPET_SENTINEL_START => marked.push_str(SNIPPET_MATCH_START),
+            SNIPPET_SENTINEL_END => marked.push_str(SNIPPET_MATCH_END),
+            '&' => marked.push_str("&amp;"),
+            '<' => marked.push_str("&lt;"),
+            '>' => marked.push_str("&gt;"),
+            '"' => marked.push_str("&quot;"),
+            '\'' => marked.push_str("&#39;"),
+            c => marked.push(c),
+        }
+    }
+    marked
+}
+
+/// Add a message to the full text search index.
+/// Failures are logged instead of returned so a broken index only degrades searching
+/// and never prevents a message from being saved.
+pub async fn index_message(
+    pool: &SqlitePool,
+    id: i64,
+    conversation_id: i64,
+    message: &str,
+    stemmed_message: Option<&str>,
+) {
+    if let Err(e) = sqlx::query!(
+        "INSERT INTO messages_fts(rowid, conversation_id, message, stemmed_message) VALUES (?, ?, ?, ?)",
+        id,
+        conversation_id,
+        message,
+        stemmed_message
+    )
+    .execute(pool)
+    .await
+    {
+        error!("Failed to index message {}: {}", id, e);
+    }
+}
+
+/// Remove a message from the full text search index.
+/// The values must match the ones the message was indexed with.
+pub async fn unindex_message(
+    pool: &SqlitePool,
+    id: i64,
+    conversation_id: i64,
+    message: &str,
+    stemmed_message: Option<&str>,
+) {
+    if let Err(e) = sqlx::query!(
+        "INSERT INTO messages_fts(messages_fts, rowid, conversation_id, message, stemmed_message) VALUES('delete', ?, ?, ?, ?)",
+        id,
+        conversation_id,
+        message,
+        stemmed_message
+    )
+    .execute(pool)
+    .await
+    {
+        error!("Failed to remove message {} from the search index: {}", id, e);
+    }
+}
+
+/// Rebuild the full text search index from the messages table
+pub async fn rebuild_search_index(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query!("INSERT INTO messages_fts(messages_fts) VALUES('rebuild')")
+        .execute(pool)
+        .await?;
     Ok(())
 }
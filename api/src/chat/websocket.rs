@@ -1,5 +1,5 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     net::SocketAddr,
     sync::{
         atomic::{AtomicI64, Ordering},
@@ -8,7 +8,6 @@ use std::{
 };
 
 use ahash::RandomState;
-use anyhow::anyhow;
 use atomicbox::AtomicOptionBox;
 use axum::{
     extract::{
@@ -23,23 +22,39 @@ use chrono::{DateTime, NaiveDateTime, Utc};
 use futures::{
     future,
     stream::{FuturesUnordered, SplitSink},
-    FutureExt, SinkExt, StreamExt, TryStreamExt,
+    SinkExt, StreamExt,
+};
+use serde::{ser::Error as _, Deserialize, Serialize, Serializer};
+use sqlx::{prelude::FromRow, Executor, QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+use tokio::{
+    sync::{mpsc, Notify, Semaphore},
+    time::{Instant, MissedTickBehavior},
 };
-use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, QueryBuilder, Sqlite, SqlitePool};
-use tokio::sync::mpsc;
 use tracing::{error, info, warn};
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::{
-    chat::{query_model, search::search_message, Conversation, ConversationUser},
-    error::{AppError, ErrorResponse},
-    state::{AppState, ConnectionState, InnerConnection, Sender},
-    users::{authorize_user, UserToken},
-    IDLE_TIMEOUT, MAX_MESSAGE_LEN,
+    auth::{refresh_token, JwtError},
+    chat::{
+        check_token_quota, ensure_owner, query_model,
+        search::{index_message, search_message, unindex_message, SearchThrottle},
+        summarize_conversation, AiModel, Conversation, ConversationRole, ConversationSummary,
+        ConversationUser, GenerationSettings,
+    },
+    error::{AppError, AppValidate, ErrorResponse},
+    state::{
+        AppState, ConnectionState, ConversationLeave, InnerConnection, ReplayBuffer, Sender,
+        REPLAY_WINDOW,
+    },
+    users::{authorize_user, FriendRequestPolicy, MessageNotifications, Settings, UserToken},
+    utils::Requested,
+    IDLE_TIMEOUT, MAX_MESSAGE_LEN, PING_INTERVAL, PONG_TIMEOUT,
 };
 
 use super::{
-    create_conversation, search::SearchMessage, ChatMessage, DeleteMessage, ReadEvent,
+    create_conversation, get_thread_messages, get_thread_root,
+    search::{SearchMessage, SearchResult},
+    ChatMessage, ConversationStats, DeleteMessage, MessageEdit, PinEvent, ReactionEvent, ReadEvent,
     StreamMessage,
 };
 
@@ -97,7 +112,10 @@ pub async fn init_ws(
     };
 
     headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_token)?);
-    let user = authorize_user(&headers)?;
+    let user = authorize_user(&state.pool, &state.auth, &headers).await?;
+    if let Some(session_id) = user.sid {
+        state.session_activity.touch(&state.pool, session_id).await;
+    }
 
     info!("Received websocket connection from {}", addr);
     Ok(ws
@@ -115,8 +133,14 @@ pub enum SocketResponse {
     Message(ChatMessage),
     /// Conversation to be sent to the client
     Conversation(Conversation),
+    /// Totals for a conversation, sent in response to `ConversationStats`
+    ConversationStats(ConversationStats),
     /// The i64 is the id of the message to delete
     DeleteMessage(DeleteMessage),
+    /// A reaction to a message was added or removed
+    ReactionEvent(ReactionEvent),
+    /// A message was pinned or unpinned
+    PinEvent(PinEvent),
     /// Stream data from the AI model
     StreamData(StreamMessage),
     /// Invite to a conversation
@@ -132,6 +156,38 @@ pub enum SocketResponse {
     /// Event to inform the client that a user has left a conversation
     #[serde(rename_all = "camelCase")]
     LeaveEvent { conversation_id: i64, user_id: i64 },
+    /// Event to inform the client that a user was removed from a conversation
+    #[serde(rename_all = "camelCase")]
+    RemoveEvent {
+        conversation_id: i64,
+        /// The user who was removed
+        user_id: i64,
+        /// The owner or admin who removed them
+        removed_by: i64,
+    },
+    /// Event to inform the client that a user's role in a conversation changed
+    #[serde(rename_all = "camelCase")]
+    RoleEvent {
+        conversation_id: i64,
+        user_id: i64,
+        role: ConversationRole,
+    },
+    /// Event to inform the client that the owner left a conversation
+    /// and it was handed over to another member
+    #[serde(rename_all = "camelCase")]
+    OwnershipEvent {
+        conversation_id: i64,
+        /// The owner who left
+        previous_owner_id: i64,
+        owner_id: i64,
+    },
+    /// Event to inform the client that renaming a conversation was restricted or opened up
+    #[serde(rename_all = "camelCase")]
+    RenameRestrictionEvent {
+        conversation_id: i64,
+        user_id: i64,
+        admins_only: bool,
+    },
     /// Event to inform the client that a user renamed a conversation
     #[serde(rename_all = "camelCase")]
     RenameEvent {
@@ -139,18 +195,41 @@ pub enum SocketResponse {
         user_id: i64,
         name: Option<String>,
     },
-    /// Friend request to be sent to the client
+    /// Event to inform the client that a user changed the persona of a conversation
     #[serde(rename_all = "camelCase")]
-    FriendRequest {
-        sender_id: i64,
-        receiver_id: i64,
-        created_at: chrono::NaiveDateTime,
-        status: FriendRequestStatus,
+    PersonaEvent {
+        conversation_id: i64,
+        user_id: i64,
+        persona_id: Option<i64>,
     },
+    /// The AI settings of a conversation, sent when they are requested or changed
+    GenerationSettings(GenerationSettings),
+    /// A new summary of the older messages in a conversation
+    ConversationSummary(ConversationSummary),
+    /// Friend request to be sent to the client
+    FriendRequest(FriendRequest),
     #[serde(rename_all = "camelCase")]
     FriendData { id: i64, created_at: NaiveDateTime },
+    /// The user blocked or unblocked another user
+    /// Only sent to the connections of the user who did the blocking
+    #[serde(rename_all = "camelCase")]
+    BlockEvent { user_id: i64, blocked: bool },
+    /// The user muted or unmuted a conversation
+    /// Only sent to the connections of the user who muted the conversation
+    #[serde(rename_all = "camelCase")]
+    MuteEvent { conversation_id: i64, muted: bool },
+    /// A previous version of a message, sent in response to `RequestEditHistory`
+    MessageEdit(MessageEdit),
     /// Search results from a message query
-    SearchMessage(ChatMessage),
+    SearchMessage(SearchResult),
+    /// Sent after the last result of a page of search results.
+    /// `next_cursor` continues the search from where the page ended and is null if it was the last page
+    #[serde(rename_all = "camelCase")]
+    SearchEnd { next_cursor: Option<String> },
+    /// A message in a thread
+    /// Sent when the thread is requested and to connections viewing the thread
+    /// when a new reply is sent
+    ThreadMessage(ChatMessage),
     /// Error to inform the client
     Error(ErrorResponse),
     /// Read event to inform the client that messages before a given timestamp
@@ -167,6 +246,50 @@ pub enum SocketResponse {
     /// or when explicitly requested by the client
     #[serde(rename_all = "camelCase")]
     UserStatus { user_id: i64, status: OnlineStatus },
+    /// A new token for the connection's session, sent when sliding expiration is enabled
+    /// and the client's token is close to expiring
+    TokenRefreshed { token: String },
+    /// The user changed their settings, sent to all of the user's connections
+    SettingsChanged { version: i64, settings: Settings },
+    /// The events requested with `ReplayEvents` are no longer available
+    /// and the client has to catch up by requesting the data again
+    #[serde(rename_all = "camelCase")]
+    ReplayUnavailable { latest_seq: u64 },
+    /// An event broadcast to the user along with its sequence number
+    /// Serialized as the event with an extra `seq` field
+    #[serde(untagged)]
+    Sequenced(SequencedEvent),
+}
+
+/// A broadcast event with a sequence number.
+/// Sequence numbers increase by one for every event broadcast to a user
+/// so clients can detect when they missed events and replay them
+#[derive(Clone, Debug)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    pub event: Box<SocketResponse>,
+}
+
+impl Serialize for SequencedEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Serialize the event on its own and add the sequence number to it.
+        // `#[serde(flatten)]` can't be used since the serializer types would recurse forever
+        let mut value = serde_json::to_value(&*self.event).map_err(S::Error::custom)?;
+        if let Some(object) = value.as_object_mut() {
+            object.insert("seq".to_owned(), self.seq.into());
+        }
+        value.serialize(serializer)
+    }
+}
+
+/// A friend request between two users and what happened to it
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FriendRequest {
+    pub sender_id: i64,
+    pub receiver_id: i64,
+    pub created_at: NaiveDateTime,
+    pub status: FriendRequestStatus,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -176,7 +299,7 @@ pub enum FriendRequestStatus {
     Rejected,
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum OnlineStatus {
     Online,
     Idle,
@@ -204,6 +327,27 @@ enum SocketRequest {
     /// Deleted a message in the conversation
     #[serde(rename_all = "camelCase")]
     DeleteMessage { message_id: i64 },
+    /// Request every previous version of an edited message
+    /// Returns versions in order of oldest to newest
+    #[serde(rename_all = "camelCase")]
+    RequestEditHistory { message_id: i64 },
+    /// Add or remove a reaction to a message
+    #[serde(rename_all = "camelCase")]
+    React {
+        message_id: i64,
+        /// A single emoji
+        emoji: String,
+        /// Add the reaction if true, remove it if false
+        add: bool,
+    },
+    /// Pin or unpin a message in the conversation
+    /// Pinned messages can be fetched from `/api/chat/:id/pins`
+    #[serde(rename_all = "camelCase")]
+    PinMessage {
+        message_id: i64,
+        /// Pin the message if true, unpin it if false
+        pinned: bool,
+    },
     /// Send, accept, reject, or revoke a friend request
     // Put all the friend request stuff in one enum variant
     // so its easier to handle on the frontend
@@ -217,6 +361,13 @@ enum SocketRequest {
         /// Reject or revoke a friend request if false
         accept: bool,
     },
+    /// Block a user, stopping their messages from being delivered and
+    /// rejecting friend requests between the users
+    #[serde(rename_all = "camelCase")]
+    BlockUser { user_id: i64 },
+    /// Unblock a previously blocked user
+    #[serde(rename_all = "camelCase")]
+    UnblockUser { user_id: i64 },
     /// Invite users to a conversation
     #[serde(rename_all = "camelCase")]
     InviteUsers {
@@ -229,6 +380,25 @@ enum SocketRequest {
     /// Leave a conversation
     #[serde(rename_all = "camelCase")]
     LeaveConversation { conversation_id: i64 },
+    /// Remove another user from a conversation
+    /// Owners can remove anyone, admins can only remove members
+    #[serde(rename_all = "camelCase")]
+    RemoveUser { conversation_id: i64, user_id: i64 },
+    /// Make a member of a conversation an admin or a regular member again
+    /// Only the owner can change roles
+    #[serde(rename_all = "camelCase")]
+    SetRole {
+        conversation_id: i64,
+        user_id: i64,
+        /// Either `admin` or `member`, ownership can't be given away
+        role: ConversationRole,
+    },
+    /// Choose whether only owners and admins can rename a conversation
+    #[serde(rename_all = "camelCase")]
+    RestrictRenaming {
+        conversation_id: i64,
+        admins_only: bool,
+    },
     /// Rename a conversation
     #[serde(rename_all = "camelCase")]
     RenameConversation {
@@ -238,6 +408,18 @@ enum SocketRequest {
         /// usernames of the users in the conversation
         name: Option<String>,
     },
+    /// Mute or unmute a conversation for the user.
+    /// Muted conversations only send new messages, reactions, and user statuses
+    /// to connections that are viewing the conversation
+    #[serde(rename_all = "camelCase")]
+    MuteConversation { conversation_id: i64, muted: bool },
+    /// Change the persona the AI uses in a conversation
+    #[serde(rename_all = "camelCase")]
+    SetPersona {
+        conversation_id: i64,
+        /// The persona to use, None to go back to the default persona
+        persona_id: Option<i64>,
+    },
     /// Request to search messages in given conversations
     /// that match the query string
     SearchMessages(SearchMessage),
@@ -252,15 +434,53 @@ enum SocketRequest {
     /// Request data on a conversation with the given id
     #[serde(rename_all = "camelCase")]
     RequestConversation { conversation_id: i64 },
+    /// Change the system prompt and generation parameters the AI uses in a conversation
+    /// Replaces all of the conversation's current settings
+    SetGenerationSettings(GenerationSettings),
+    /// Request the system prompt and generation parameters the AI uses in a conversation
+    #[serde(rename_all = "camelCase")]
+    RequestGenerationSettings { conversation_id: i64 },
+    /// Summarize the older messages in a conversation so the AI remembers them
+    /// Older messages are also summarized automatically when the AI responds
+    #[serde(rename_all = "camelCase")]
+    SummarizeConversation {
+        conversation_id: i64,
+        /// The model that writes the summary, the user's default model if None
+        ai_model_id: Option<i64>,
+    },
+    /// Request the number of messages and members in a conversation
+    /// along with when its first and last messages were sent
+    #[serde(rename_all = "camelCase")]
+    ConversationStats { conversation_id: i64 },
     /// Request a stream of conversations the user is in
     /// Returns conversations in order of last message sent
     RequestConversations(RequestConversation),
+    /// Request every message in the thread started by the given message
+    /// The connection will receive new replies to the thread until `CloseThread` is sent
+    /// or another thread is requested
+    #[serde(rename_all = "camelCase")]
+    RequestThread { message_id: i64 },
+    /// Stop receiving replies to the thread currently being viewed
+    CloseThread,
     /// Request a stream of the user's friends
     RequestFriends,
     /// Request a stream of the user's friend requests
     RequestFriendRequests,
+    /// Request the online status of each of the given users
+    /// Replies with a `UserStatus` for each id, at most `MAX_STATUS_REQUEST_IDS` at a time
+    #[serde(rename_all = "camelCase")]
+    RequestUserStatus { user_ids: Box<[i64]> },
     /// Can be used to cancel an ongoing AI generation
+    /// Whatever was generated before canceling is kept as an incomplete message
     CancelGeneration,
+    /// Continue generating an incomplete AI response where it left off
+    /// Only the most recent message in a conversation can be resumed
+    #[serde(rename_all = "camelCase")]
+    ResumeGeneration { message_id: i64 },
+    /// Request every event broadcast to the user after the given sequence number
+    /// Used to catch up on missed events after reconnecting
+    #[serde(rename_all = "camelCase")]
+    ReplayEvents { since: u64 },
 }
 
 /// A chat message sent by the client to the server
@@ -276,6 +496,9 @@ pub struct SendMessage {
     pub ai_model_id: Option<i64>,
     /// Any attachments to the message
     pub attachment: Option<SendAttachment>,
+    /// The id of the message being replied to
+    /// Must be a message in the same conversation
+    pub reply_to: Option<i64>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -320,15 +543,16 @@ enum Pagination {
 /// A request for conversations the user is in
 /// This api returns a stream of conversation the user is a part of
 /// only the most recent conversations with an id are returned
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "camelCase")]
-struct RequestConversation {
-    /// The id of the last conversation the client received from the server
-    /// If this is None, the client has not received any messages yet
-    last_message_at: Option<NaiveDateTime>,
+pub struct RequestConversation {
+    /// The last message time of the last conversation the client received from the server,
+    /// conversations without messages use their creation time instead
+    /// If this is None, the client has not received any conversations yet
+    pub last_message_at: Option<NaiveDateTime>,
     /// The maximum number of messages to request
     /// If this is None, the client is requesting 50 conversations
-    message_num: Option<i64>,
+    pub message_num: Option<i64>,
 }
 
 /// Checks if the user is idle and updates their status accordingly
@@ -371,6 +595,9 @@ async fn idle_check(state: &AppState, user_id: i64) {
     }
 }
 
+/// The most users whose status can be requested with a single `RequestUserStatus`
+const MAX_STATUS_REQUEST_IDS: usize = 100;
+
 /// Get  the online status of a user
 pub async fn get_user_status(state: &AppState, user_id: i64) -> OnlineStatus {
     let Some(conn_state) = state
@@ -395,6 +622,22 @@ pub async fn get_user_status(state: &AppState, user_id: i64) -> OnlineStatus {
     OnlineStatus::Online
 }
 
+/// The maximum number of messages from a single connection that can be handled at once.
+/// Once reached, the connection isn't read from until one of them finishes
+const MAX_CONCURRENT_MESSAGES: usize = 8;
+
+/// Resolves once the client has gone longer than `PONG_TIMEOUT` without answering a ping
+async fn pong_timeout(last_pong_at: &AtomicI64) {
+    loop {
+        let since_pong = Utc::now().timestamp_millis() - last_pong_at.load(Ordering::SeqCst);
+        let remaining = PONG_TIMEOUT.as_millis() as i64 - since_pong;
+        if remaining <= 0 {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(remaining as u64)).await;
+    }
+}
+
 /// Handles incoming websocket connections
 pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
     let (mut sender, mut receiver) = stream.split();
@@ -406,6 +649,10 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
         channel: Sender::new(tx, user.id, 0),
         focused_conversation: Arc::new(AtomicI64::new(0)),
         focused_handle: Arc::new(AtomicOptionBox::none()),
+        focused_thread: Arc::new(AtomicI64::new(0)),
+        close: Arc::new(Notify::new()),
+        session_id: user.sid,
+        search_throttle: Arc::new(SearchThrottle::new(&state.search_limits)),
     };
 
     let connection_id = match state.user_sockets.get_async(&user.id).await {
@@ -414,6 +661,21 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
             let conn_id = match conn.connections.iter().position(|x| x.is_none()) {
                 Some(k) => k,
                 None => {
+                    // Tell the client why before closing so it doesn't keep reconnecting
+                    let error = SocketResponse::Error(
+                        AppError::UserError((
+                            StatusCode::TOO_MANY_REQUESTS,
+                            format!(
+                                "Too many connections, at most {} can be open at once",
+                                state.max_connections_per_user
+                            )
+                            .into(),
+                        ))
+                        .into(),
+                    );
+                    let _ = sender
+                        .send(Message::Text(sonic_rs::to_string(&error).unwrap()))
+                        .await;
                     let _ = sender.close().await;
                     return;
                 }
@@ -424,16 +686,23 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
         }
         // First time the user has connected to the server
         None => {
-            let mut connections = [const { None }; 10];
+            let mut connections = vec![None; state.max_connections_per_user];
             connections[0] = Some(connection.clone());
             let _ = state
                 .user_sockets
                 .insert_async(
                     user.id,
                     ConnectionState {
-                        connections: connections.clone(),
+                        connections,
                         ai_responding: Arc::new(AtomicI64::new(0)),
                         ai_handle: Arc::new(AtomicOptionBox::none()),
+                        // Pick up the events broadcast while the user was briefly disconnected
+                        replay: state
+                            .parked_replays
+                            .remove_async(&user.id)
+                            .await
+                            .map(|(_, replay)| replay)
+                            .unwrap_or_default(),
                         last_sent_at: Arc::new(AtomicI64::new(Utc::now().timestamp_millis())),
                         idle_handle: Arc::new(
                             tokio::spawn({
@@ -464,23 +733,66 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
         .await
         .unwrap();
 
+    // The timestamp of the last pong received from the client, used to detect dead connections
+    let last_pong_at = Arc::new(AtomicI64::new(Utc::now().timestamp_millis()));
+
     // Send messages to the client over the websocket
     // Messages are received from the broadcast channel
     let mut send_task = tokio::spawn({
-        let user = user.clone();
+        let state = state.clone();
+        // The send task keeps its own copy of the token since it is replaced when refreshed
+        let mut user = (*user).clone();
+        let close = connection.close.clone();
         async move {
+            let mut ping_interval =
+                tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+            ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
             // Keep checking for incoming messages and sending messages to the client accordingly
             // until the connection is closed
-            while let Some(msg) = rx.recv().await {
-                match send_message(&mut sender, msg, &user).await {
-                    Ok(true) => (),
-                    Ok(false) => {
+            loop {
+                tokio::select! {
+                    msg = rx.recv() => {
+                        let Some(msg) = msg else {
+                            break;
+                        };
+                        match send_message(&state, &mut sender, msg, &mut user).await {
+                            Ok(true) => (),
+                            Ok(false) => {
+                                let _ = sender.close().await;
+                                break;
+                            }
+                            Err(e) => {
+                                error!("Error sending message: {}", e);
+                                sender.send(Message::Text(e.to_string())).await.unwrap();
+                            }
+                        }
+                    }
+                    // The server requested the connection be closed
+                    _ = close.notified() => {
+                        // Flush what was queued before the request, like the reason for it
+                        while let Ok(msg) = rx.try_recv() {
+                            let msg = Message::Text(sonic_rs::to_string(&msg).unwrap());
+                            if sender.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
                         let _ = sender.close().await;
                         break;
                     }
-                    Err(e) => {
-                        error!("Error sending message: {}", e);
-                        sender.send(Message::Text(e.to_string())).await.unwrap();
+                    // Ping the client so half open connections are noticed
+                    _ = ping_interval.tick() => {
+                        if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                            break;
+                        }
+                        // Idle connections still need their token refreshed before it expires
+                        match check_token(&state, &mut sender, &mut user).await {
+                            Ok(true) => (),
+                            Ok(false) => {
+                                let _ = sender.close().await;
+                                break;
+                            }
+                            Err(e) => error!("Error checking token: {}", e),
+                        }
                     }
                 }
             }
@@ -492,9 +804,34 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
         let state = state.clone();
         let user = user.clone();
         let connection = connection.clone();
+        let last_pong_at = last_pong_at.clone();
         async move {
+            // Bounds the number of handler tasks a client can have running so flooding the
+            // connection applies backpressure instead of spawning tasks without limit
+            let handler_permits = Arc::new(Semaphore::new(MAX_CONCURRENT_MESSAGES));
             // Keep receiving messages until the connection is closed
             while let Some(msg) = receiver.next().await {
+                let msg = match msg {
+                    // Pongs are answers to the server's pings, not user activity,
+                    // so they shouldn't stop the user from going idle
+                    Ok(Message::Pong(_)) => {
+                        last_pong_at.store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+                        continue;
+                    }
+                    // Only text frames are requests, pings and closes are answered by the socket
+                    // itself so they don't need a handler or a slot
+                    Ok(msg @ Message::Text(_)) => msg,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        error!("Error receiving message: {}", e);
+                        continue;
+                    }
+                };
+                // Wait for a free slot before spawning so messages still start being handled
+                // in the order they were received. The semaphore is never closed
+                let Ok(permit) = handler_permits.clone().acquire_owned().await else {
+                    break;
+                };
                 // Spawn a new task for each message received
                 tokio::spawn({
                     let connection = connection.clone();
@@ -502,31 +839,25 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
                     let socket = socket.clone();
                     let state = state.clone();
                     async move {
-                        match msg {
-                            Ok(msg) => {
-                                // Check if the user was idle and update their status so they are
-                                // no longer idle
-                                if socket.is_idle() {
-                                    let _ = emit_user_status(&state, user.id, OnlineStatus::Online)
-                                        .await;
-                                }
+                        // Free the slot once the message has been handled
+                        let _permit = permit;
+                        // Check if the user was idle and update their status so they are
+                        // no longer idle
+                        if socket.is_idle() {
+                            let _ = emit_user_status(&state, user.id, OnlineStatus::Online).await;
+                        }
 
-                                // Update the timestamp of the last sent message for idle checking
-                                socket.update_last_sent();
-                                // Handle the received message
-                                if let Err(e) =
-                                    handle_message(msg, &state, &user, &socket, &connection).await
-                                {
-                                    error!("Error handling message: {}", e);
-                                    let _ = connection
-                                        .channel
-                                        .send(SocketResponse::Error(e.into()))
-                                        .await;
-                                }
-                            }
-                            Err(e) => {
-                                error!("Error receiving message: {}", e);
-                            }
+                        // Update the timestamp of the last sent message for idle checking
+                        socket.update_last_sent();
+                        // Handle the received message
+                        if let Err(e) =
+                            handle_message(msg, &state, &user, &socket, &connection).await
+                        {
+                            error!("Error handling message: {}", e);
+                            let _ = connection
+                                .channel
+                                .send(SocketResponse::Error(e.into()))
+                                .await;
                         }
                     }
                 });
@@ -538,7 +869,12 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
     // If either of the tasks completes, we want to abort the other one
     tokio::select! {
         _ = &mut receive_task => send_task.abort(),
-        _ = &mut send_task => receive_task.abort()
+        _ = &mut send_task => receive_task.abort(),
+        // The client stopped answering pings so the connection is most likely dead
+        _ = pong_timeout(&last_pong_at) => {
+            send_task.abort();
+            receive_task.abort();
+        }
     };
 
     // Decrease the number of connections the user has
@@ -557,6 +893,22 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
         set.get_mut().remove(&connection.channel);
     }
 
+    // Park the replay buffer before the connection state is removed
+    // so events broadcast in between are still kept for replay
+    let replay = state
+        .user_sockets
+        .read_async(&user.id, |_, v| {
+            v.connections
+                .iter()
+                .all(|x| x.is_none())
+                .then(|| v.replay.clone())
+        })
+        .await
+        .flatten();
+    if let Some(replay) = &replay {
+        park_replay(&state, user.id, replay.clone()).await;
+    }
+
     // Remove the user from the connection once all the tasks are
     // complete and all user devices have disconnected
     if let Some((_, conn)) = state
@@ -566,9 +918,119 @@ pub async fn handle_ws(stream: WebSocket, state: AppState, user: UserToken) {
     {
         // Abort the idle checker since the user has no active connections to check for messages on
         conn.idle_handle.abort();
+        if let Err(e) = sqlx::query!(
+            "UPDATE users SET last_seen_at = CURRENT_TIMESTAMP WHERE id = ?",
+            user.id
+        )
+        .execute(&state.pool)
+        .await
+        {
+            error!(
+                "Failed to update when user {} was last seen: {}",
+                user.id, e
+            );
+        }
         // Attempt to let other users know that the user is offline
         let _ = emit_user_status(&state, user.id, OnlineStatus::Offline).await;
+    } else if let Some(replay) = replay {
+        // The user connected again in the meantime and is still using the buffer
+        state
+            .parked_replays
+            .remove_if_async(&user.id, |parked| Arc::ptr_eq(parked, &replay))
+            .await;
+    }
+}
+
+/// Streams the conversations the user is in, most recently active first,
+/// starting after the last conversation the client received
+pub async fn request_conversations(
+    pool: &SqlitePool,
+    request: &RequestConversation,
+    tx: &mpsc::Sender<SocketResponse>,
+    user: &UserToken,
+) -> Result<(), AppError> {
+    let limit = request.message_num.unwrap_or(50);
+    // Create a helper to map rows to conversation struct easier
+    // Have to use an unchecked query as a workaround because sqlx has a bug where
+    // aggregate functions return the wrong type.
+    // Reference Issue: https://github.com/launchbadge/sqlx/issues/3238
+    // For example in this scenario, GROUP_CONCAT(user_id) should return a string
+    // but sqlx parses it as a i64, preventing us from using it in the struct
+    #[derive(FromRow)]
+    struct ConversationHelper {
+        id: i64,
+        title: Option<String>,
+        created_at: NaiveDateTime,
+        last_message_at: Option<NaiveDateTime>,
+        persona_id: Option<i64>,
+        users: String,
+        unread_count: i64,
+        muted: bool,
+        admins_only_rename: bool,
+    }
+
+    // Query the database for the conversations the user is in
+    // Use fetch instead of fetch all to stream results to the client
+    // Conversations nobody has sent a message in yet, such as groups users were just
+    // invited to, are ordered by when they were created
+    let mut query = sqlx::query_as::<Sqlite, ConversationHelper>(
+        r#"SELECT conversations.*, GROUP_CONCAT(user_id) as users,
+           -- Count the messages sent by anyone but the user since they last read the conversation.
+           -- Timestamps are compared with julianday since read times are stored with a
+           -- different format from the message timestamps
+           (SELECT COUNT(*) FROM messages
+           JOIN user_conversations AS reader
+           ON reader.conversation_id = messages.conversation_id AND reader.user_id = ?1
+           WHERE messages.conversation_id = conversations.id
+           AND messages.user_id IS NOT reader.user_id
+           AND (reader.last_read_at IS NULL
+           OR julianday(messages.created_at) > julianday(reader.last_read_at))) as unread_count,
+           (SELECT muted FROM user_conversations
+           WHERE conversation_id = conversations.id AND user_id = ?1) as muted
+           FROM conversations
+           JOIN user_conversations
+           ON conversations.id = user_conversations.conversation_id
+           WHERE id IN
+           (SELECT id FROM conversations
+           JOIN user_conversations
+           ON conversations.id = user_conversations.conversation_id
+           WHERE user_id = ?1 AND (?2 IS NULL
+           OR julianday(COALESCE(conversations.last_message_at, conversations.created_at)) < julianday(?2))
+           ORDER BY COALESCE(conversations.last_message_at, conversations.created_at) DESC, id DESC
+           LIMIT ?3)
+           GROUP BY id
+           ORDER BY COALESCE(conversations.last_message_at, conversations.created_at) DESC, id DESC"#,
+    )
+    .bind(user.id)
+    .bind(request.last_message_at)
+    .bind(limit)
+    .fetch(pool);
+
+    while let Some(conversation) = query.next().await {
+        let conversation = conversation?;
+        tx.send(SocketResponse::Conversation(Conversation {
+            id: conversation.id,
+            title: conversation.title,
+            created_at: conversation.created_at,
+            last_message_at: conversation.last_message_at,
+            persona_id: conversation.persona_id,
+            muted: Requested::Loaded(conversation.muted),
+            admins_only_rename: conversation.admins_only_rename,
+            users: Requested::Loaded(
+                conversation
+                    .users
+                    .split(',')
+                    .map(|u| ConversationUser {
+                        id: u.parse::<i64>().unwrap(),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            unread_count: Requested::Loaded(conversation.unread_count),
+        }))
+        .await?;
     }
+    Ok(())
 }
 
 /// Requests the most recent messages sent in a conversation before the given message id
@@ -668,77 +1130,195 @@ async fn request_messages(
 }
 
 /// Emit a change in the user x's online status to users who are focused
-/// on a conversation that user x is in
+/// on a conversation that user x is in and to all of user x's online friends.
+/// Each connection receives the event at most once
 async fn emit_user_status(
     state: &AppState,
     user_id: i64,
     status: OnlineStatus,
 ) -> Result<(), AppError> {
     // Collect all of the conversations the user is in
-    let conversations: HashSet<i64> = sqlx::query!(
-        "SELECT DISTINCT conversation_id FROM user_conversations WHERE user_id = ?",
+    // along with the users who muted each of them
+    let mut conversations: HashMap<i64, HashSet<i64>> = HashMap::new();
+    let mut rows = sqlx::query!(
+        r#"SELECT member.conversation_id, muter.user_id as "muted_by?" FROM user_conversations member
+        LEFT JOIN user_conversations muter
+        ON muter.conversation_id = member.conversation_id AND muter.muted
+        WHERE member.user_id = ?"#,
+        user_id
+    )
+    .fetch(&state.pool);
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let muted_by = conversations.entry(row.conversation_id).or_default();
+        muted_by.extend(row.muted_by);
+    }
+    drop(rows);
+
+    let friends = sqlx::query_scalar!(
+        r#"SELECT IIF(user1_id = ?1, user2_id, user1_id) as "id!: i64" FROM friendships
+        WHERE user1_id = ?1 OR user2_id = ?1"#,
         user_id
     )
-    .fetch(&state.pool)
-    .map(|row| row.map(|x| x.conversation_id))
-    .try_collect()
-    .boxed()
+    .fetch_all(&state.pool)
     .await?;
 
-    // Use a FuturesUnordered to collect all the futures
-    // and concurrently poll all of them at once to prevent a lagging receiver
-    // (slow user connection) from bottlenecking the entire process
-    // For each conversation the user is in
-    let outer_futures: FuturesUnordered<_> = conversations
-        .iter()
-        .map(|conversation| {
-            let status = status.clone();
-            async move {
-                // Find the user_id and connection_id of connections
-                // that are focused on the conversation
-                let Some(connections) = state
-                    .conversation_connections
-                    .read_async(conversation, |_, v| v.clone())
-                    .await
-                else {
-                    return;
-                };
-                // Use a second, nested FuturesUnordered to manage sending the messages to all
-                // users focused on a given conversation concurrently
-                let mut inner_futures: FuturesUnordered<_> = connections
+    // Friends get the event on every connection, so it's sequenced for them like any other
+    // event sent to the whole user. Friends who aren't online have no connections to notify
+    let event = SocketResponse::UserStatus { user_id, status };
+    let mut sends = Vec::new();
+    for &friend_id in &friends {
+        if let Some((connections, event)) = sequence_for_user(state, friend_id, event.clone()).await
+        {
+            sends.extend(
+                connections
                     .iter()
-                    .map(|sender| {
-                        sender.send(SocketResponse::UserStatus {
-                            user_id,
-                            status: status.clone(),
+                    .flatten()
+                    .map(|connection| (connection.channel.clone(), event.clone())),
+            );
+        }
+    }
+
+    // Other members only get the event on connections focused on a shared conversation,
+    // so like quiet broadcast events it isn't sequenced for them.
+    // Collect the connections into a set so a member focused on several
+    // shared conversations doesn't receive the same event twice
+    #[allow(clippy::mutable_key_type)]
+    let mut recipients: HashSet<Sender<SocketResponse>, RandomState> = HashSet::default();
+    for (conversation, muted_by) in conversations.iter() {
+        // Find the connections that are focused on the conversation
+        state
+            .conversation_connections
+            .read_async(conversation, |_, connections| {
+                recipients.extend(
+                    connections
+                        .iter()
+                        .filter(|sender| {
+                            !muted_by.contains(&sender.user_id)
+                                && !friends.contains(&sender.user_id)
                         })
-                    })
-                    .collect();
-                while let Some(result) = inner_futures.next().await {
-                    if let Err(e) = result {
-                        warn!("Error sending user status: {}", e);
-                    }
-                }
-            }
-        })
-        .collect();
+                        .cloned(),
+                );
+            })
+            .await;
+    }
+    sends.extend(recipients.into_iter().map(|sender| (sender, event.clone())));
 
-    // Wait for all the contained futures to complete
-    // Collecting the futures into a Vec of unit structs will not allocate memory
-    // so this is just more idiomatic than using `while let Some(...) = ...`
-    // See https://doc.rust-lang.org/std/vec/struct.Vec.html#guarantees
-    outer_futures.collect::<Vec<()>>().await;
+    // Use a FuturesUnordered to concurrently poll all of the sends at once to prevent a
+    // lagging receiver (slow user connection) from bottlenecking the entire process
+    let mut futures: FuturesUnordered<_> = sends
+        .iter()
+        .map(|(sender, event)| sender.send(event.clone()))
+        .collect();
+    while let Some(result) = futures.next().await {
+        if let Err(e) = result {
+            warn!("Error sending user status: {}", e);
+        }
+    }
     Ok(())
 }
 
-/// Save a message to the database
-async fn save_message(
+/// Generate a response from the AI model in the conversation and broadcast it once it finishes.
+/// If `resume_id` is given, the incomplete message is continued instead of starting a new one
+async fn generate_response(
+    state: &AppState,
+    user: &UserToken,
+    socket: &ConnectionState,
+    conversation_id: i64,
+    ai_model_id: i64,
+    resume_id: Option<i64>,
+) -> Result<(), AppError> {
+    // The user is explicitly trying to query the model, so check if there is
+    // already an AI generation in progress in any conversation they are a part of
+    // and prevent them from starting a new one
+    if socket.ai_responding.load(Ordering::SeqCst) != 0 {
+        return Err(AppError::UserError((StatusCode::TOO_MANY_REQUESTS, "AI generation is already in progress. Please cancel generation or wait before making another query".into())));
+    }
+    check_token_quota(state, user.id).await?;
+    socket
+        .ai_responding
+        .store(conversation_id, Ordering::SeqCst);
+
+    // Spawn the AI response generation in a separate task to allow cancellation
+    // by another message from the user
+    let handle = tokio::spawn({
+        let state = state.clone();
+        let user = user.clone();
+        async move { query_model(&state, conversation_id, ai_model_id, &user, resume_id).await }
+    });
+
+    // Save an abort handle to the thread in the connection state of the user
+    // to allow another thread to abort the AI generation if requested by the user
+    socket
+        .ai_handle
+        .store(Some(Box::new(handle.abort_handle())), Ordering::SeqCst);
+
+    // This will be Ok() if the AI response generation was not canceled
+    // If it was canceled then we can just reset the value of the responding
+    // conversation and return early. The response is saved as an incomplete
+    // message by `query_model` when it is aborted
+    let Ok(ai_message) = handle.await else {
+        socket.ai_responding.store(0, Ordering::SeqCst);
+        return Ok(());
+    };
+
+    // Reset the AI generation flag to 0 to allow the user to query the model again
+    // Must be done inside this block to prevent the flage from being reset if the user sends another message
+    // before the AI model is finished responding or canceled
+    socket.ai_responding.store(0, Ordering::SeqCst);
+
+    // Broadcast the AI model's response to the conversation
+    broadcast_event(state, SocketResponse::Message(ai_message?)).await
+}
+
+/// Broadcast a new message to the conversation, and to the users viewing its thread if it is a reply.
+/// The broadcast runs in a separate task so the sender isn't blocked by it
+pub(crate) fn announce_message(state: &AppState, chat_message: ChatMessage) {
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            let _ = broadcast_event(&state, SocketResponse::Message(chat_message.clone())).await;
+            if chat_message.thread_root_id.is_some() {
+                let _ = broadcast_thread_message(&state, chat_message).await;
+            }
+        }
+    });
+}
+
+/// Have the AI respond in a conversation without a websocket to stream the response to.
+/// The response is broadcast to the conversation like any other, but it can't be canceled.
+/// The user's token quota should be checked before their message is saved
+pub(crate) fn generate_response_in_background(
+    state: &AppState,
+    user: &UserToken,
+    conversation_id: i64,
+    ai_model_id: i64,
+) {
+    tokio::spawn({
+        let state = state.clone();
+        let user = user.clone();
+        async move {
+            match query_model(&state, conversation_id, ai_model_id, &user, None).await {
+                Ok(ai_message) => {
+                    let _ = broadcast_event(&state, SocketResponse::Message(ai_message)).await;
+                }
+                Err(e) => error!(
+                    "Failed to generate a response in conversation {}: {}",
+                    conversation_id, e
+                ),
+            }
+        }
+    });
+}
+
+/// Save a message to the database.
+/// If the message doesn't have a conversation id, a new conversation is created with it as the
+/// first message
+pub async fn save_message(
     state: &AppState,
     message: &SendMessage,
     user: &UserToken,
 ) -> Result<ChatMessage, AppError> {
-    // If the conversation_id is None, this is the first message in a conversation
-    // so create a new conversation and get the id
     let stemmed_message = match (&message.message, &message.attachment) {
         // The message does not contain any content
         (None, None) => {
@@ -747,6 +1327,12 @@ async fn save_message(
                 "Message cannot be empty".into(),
             )))
         }
+        (Some(message_content), None) if message_content.trim().is_empty() => {
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                "Message cannot be empty".into(),
+            )))
+        }
         // Check if the message is too long and stem it if it is not
         (Some(message_content), _) => {
             if message_content.chars().count() > MAX_MESSAGE_LEN {
@@ -760,71 +1346,163 @@ async fn save_message(
         _ => None,
     };
 
-    let conversation_id = match message.conversation_id {
-        Some(k) => k,
-        None => create_conversation(&state.pool, message, user).await?.id,
-    };
-
-    if sqlx::query!(
-        "SELECT conversation_id FROM user_conversations WHERE conversation_id = ? and user_id = ?",
-        conversation_id,
-        user.id
-    )
-    .fetch_optional(&state.pool)
-    .await?
-    .is_none()
-    {
-        return Err(AppError::UserError((
-            StatusCode::FORBIDDEN,
-            "User is not in the conversation".into(),
-        )));
-    }
-
     if let Some(attachment) = &message.attachment {
-        sqlx::query!(
+        if sqlx::query!(
             "SELECT file_id FROM file_uploads WHERE file_id = ? and user_id = ?",
             attachment.id,
             user.id
         )
         .fetch_optional(&state.pool)
         .await?
-        .ok_or_else(|| anyhow!("Image not found"))?;
+        .is_none()
+        {
+            return Err(AppError::UserError((
+                StatusCode::NOT_FOUND,
+                "Attachment not found".into(),
+            )));
+        }
     }
 
-    let message_id = match &message.attachment {
-        Some(attachment) => {
-            sqlx::query!(
-                "INSERT INTO messages (user_id, conversation_id, message, stemmed_message, file_id, file_name) VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
-                user.id,
+    let message_id = match message.conversation_id {
+        Some(conversation_id) => {
+            if sqlx::query!(
+                "SELECT conversation_id FROM user_conversations WHERE conversation_id = ? and user_id = ?",
                 conversation_id,
-                message.message,
-                stemmed_message,
-                attachment.id,
-                attachment.name,
+                user.id
             )
-            .fetch_one(&state.pool)
-            .await?.id
-        },
-        None => {
-            sqlx::query!(
-                "INSERT INTO messages (user_id, conversation_id, message, stemmed_message) VALUES (?, ?, ?, ?) RETURNING id",
-                user.id,
+            .fetch_optional(&state.pool)
+            .await?
+            .is_none()
+            {
+                return Err(AppError::UserError((
+                    StatusCode::FORBIDDEN,
+                    "User is not in the conversation".into(),
+                )));
+            }
+
+            // Replies start or continue the thread of the message they reply to
+            let thread_root_id = match message.reply_to {
+                Some(reply_to) => {
+                    let Some(parent) = sqlx::query!(
+                        "SELECT id, thread_root_id FROM messages WHERE id = ? AND conversation_id = ?",
+                        reply_to,
+                        conversation_id
+                    )
+                    .fetch_optional(&state.pool)
+                    .await?
+                    else {
+                        return Err(AppError::UserError((
+                            StatusCode::BAD_REQUEST,
+                            "Replied message not found".into(),
+                        )));
+                    };
+                    Some(parent.thread_root_id.unwrap_or(parent.id))
+                }
+                None => None,
+            };
+
+            insert_message(
+                &state.pool,
                 conversation_id,
-                message.message,
-                stemmed_message
+                message,
+                stemmed_message.as_deref(),
+                thread_root_id,
+                user,
             )
-            .fetch_one(&state.pool)
-            .await?.id
+            .await?
         }
-    };
-
-    Ok(sqlx::query_as!(
-        ChatMessage,
-        "SELECT * FROM chat_messages WHERE id = ?",
-        message_id
-    )
-    .fetch_one(&state.pool)
-    .await?)
+        // This is the first message in a conversation so create a new conversation for it
+        None => {
+            if message.reply_to.is_some() {
+                return Err(AppError::UserError((
+                    StatusCode::BAD_REQUEST,
+                    "Cannot reply to a message in a new conversation".into(),
+                )));
+            }
+            // Create the conversation and its first message together
+            // so a failed insert doesn't leave an empty conversation behind
+            let mut tx = state.pool.begin().await?;
+            let conversation = create_conversation(&mut tx, message, user).await?;
+            let message_id = insert_message(
+                &mut *tx,
+                conversation.id,
+                message,
+                stemmed_message.as_deref(),
+                None,
+                user,
+            )
+            .await?;
+            tx.commit().await?;
+            message_id
+        }
+    };
+
+    let chat_message = sqlx::query_as!(
+        ChatMessage,
+        "SELECT * FROM chat_messages WHERE id = ?",
+        message_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    index_message(
+        &state.pool,
+        chat_message.id,
+        chat_message.conversation_id,
+        &chat_message.message,
+        stemmed_message.as_deref(),
+    )
+    .await;
+
+    Ok(chat_message)
+}
+
+/// Insert a validated message and return its id.
+/// Membership is re-checked as part of the insert so a user removed from the conversation
+/// after it was checked can't get a message in
+async fn insert_message<'e, E>(
+    executor: E,
+    conversation_id: i64,
+    message: &SendMessage,
+    stemmed_message: Option<&str>,
+    thread_root_id: Option<i64>,
+    user: &UserToken,
+) -> Result<i64, AppError>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    // Messages with only an attachment don't have any text
+    let content = message.message.as_deref().unwrap_or_default();
+    let (file_id, file_name) = message
+        .attachment
+        .as_ref()
+        .map(|attachment| (attachment.id, attachment.name.as_str()))
+        .unzip();
+    let Some(inserted) = sqlx::query!(
+        "INSERT INTO messages (user_id, conversation_id, message, stemmed_message, file_id, file_name, reply_to, thread_root_id)
+        SELECT ?, ?, ?, ?, ?, ?, ?, ?
+        WHERE EXISTS (SELECT 1 FROM user_conversations WHERE conversation_id = ? AND user_id = ?)
+        RETURNING id",
+        user.id,
+        conversation_id,
+        content,
+        stemmed_message,
+        file_id,
+        file_name,
+        message.reply_to,
+        thread_root_id,
+        conversation_id,
+        user.id,
+    )
+    .fetch_optional(executor)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "User is not in the conversation".into(),
+        )));
+    };
+    Ok(inserted.id)
 }
 
 /// Edit message in the database
@@ -834,9 +1512,12 @@ async fn edit_message(
     user: &UserToken,
 ) -> Result<ChatMessage, AppError> {
     // Check if the message exists in the database
-    let Some(message_user) = sqlx::query!("SELECT user_id FROM messages WHERE id = ?", message.id)
-        .fetch_optional(&state.pool)
-        .await?
+    let Some(message_user) = sqlx::query!(
+        "SELECT user_id, conversation_id, message, stemmed_message FROM messages WHERE id = ?",
+        message.id
+    )
+    .fetch_optional(&state.pool)
+    .await?
     else {
         return Err(AppError::UserError((
             StatusCode::FORBIDDEN,
@@ -854,16 +1535,45 @@ async fn edit_message(
 
     let stemmed_message = state.stemmer.stem_message(&message.message);
 
+    let mut tx = state.pool.begin().await?;
+    // Keep the old contents so the edit history can be viewed
+    sqlx::query!(
+        "INSERT INTO message_edits (message_id, message) VALUES (?, ?)",
+        message.id,
+        message_user.message
+    )
+    .execute(&mut *tx)
+    .await?;
     // Update the message in the database
-    // We know the message exists so we can just use `fetch_one`
+    // Set `modified_at` with milliseconds so it differs from `created_at`
+    // even if the message is edited right after being sent
     sqlx::query!(
-        "UPDATE messages SET message = ?, stemmed_message = ? WHERE id = ?",
+        "UPDATE messages SET message = ?, stemmed_message = ?, modified_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?",
         message.message,
         stemmed_message,
         message.id
     )
-    .execute(&state.pool)
+    .execute(&mut *tx)
     .await?;
+    tx.commit().await?;
+
+    // Replace the old contents of the message in the search index with the new ones
+    unindex_message(
+        &state.pool,
+        message.id,
+        message_user.conversation_id,
+        &message_user.message,
+        message_user.stemmed_message.as_deref(),
+    )
+    .await;
+    index_message(
+        &state.pool,
+        message.id,
+        message_user.conversation_id,
+        &message.message,
+        Some(&stemmed_message),
+    )
+    .await;
 
     Ok(sqlx::query_as!(
         ChatMessage,
@@ -874,6 +1584,41 @@ async fn edit_message(
     .await?)
 }
 
+/// Get the previous versions of a message, oldest first.
+/// Only the author and members of the conversation can see them
+async fn get_edit_history(
+    pool: &SqlitePool,
+    message_id: i64,
+    user: &UserToken,
+) -> Result<Vec<MessageEdit>, AppError> {
+    if sqlx::query!(
+        "SELECT id FROM messages WHERE id = ? AND (user_id = ? OR EXISTS (
+            SELECT 1 FROM user_conversations
+            WHERE user_conversations.conversation_id = messages.conversation_id AND user_conversations.user_id = ?
+        ))",
+        message_id,
+        user.id,
+        user.id
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Message not found".into(),
+        )));
+    }
+
+    Ok(sqlx::query_as!(
+        MessageEdit,
+        "SELECT message_id, message, edited_at FROM message_edits WHERE message_id = ? ORDER BY id ASC",
+        message_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
 /// Delete a message in the database
 async fn delete_message(
     pool: &SqlitePool,
@@ -898,13 +1643,253 @@ async fn delete_message(
         )));
     }
     // Delete the message from the database
-    Ok(sqlx::query_as!(
-        DeleteMessage,
-        "DELETE FROM messages WHERE id = ? RETURNING id as message_id, conversation_id",
+    let deleted = sqlx::query!(
+        "DELETE FROM messages WHERE id = ? RETURNING id, conversation_id, message, stemmed_message",
         message.id
     )
     .fetch_one(pool)
-    .await?)
+    .await?;
+
+    unindex_message(
+        pool,
+        deleted.id,
+        deleted.conversation_id,
+        &deleted.message,
+        deleted.stemmed_message.as_deref(),
+    )
+    .await;
+
+    Ok(DeleteMessage {
+        message_id: deleted.id,
+        conversation_id: deleted.conversation_id,
+    })
+}
+
+/// The longest an emoji can be in bytes.
+/// Some emoji are made of several characters joined together, but a single grapheme
+/// could otherwise be stacked with any number of combining characters
+const MAX_EMOJI_LEN: usize = 64;
+
+/// Add or remove a reaction to a message.
+/// Returns None if the reaction had already been added or removed
+async fn react_to_message(
+    pool: &SqlitePool,
+    message_id: i64,
+    emoji: String,
+    add: bool,
+    user: &UserToken,
+) -> Result<Option<ReactionEvent>, AppError> {
+    if emoji.len() > MAX_EMOJI_LEN || emoji.trim().is_empty() || emoji.graphemes(true).count() != 1
+    {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Reaction must be a single emoji".into(),
+        )));
+    }
+
+    let Some(conversation_id) = sqlx::query_scalar!(
+        "SELECT conversation_id FROM messages WHERE id = ?",
+        message_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Message not found".into(),
+        )));
+    };
+
+    let changed = if add {
+        // Check membership as part of the insert, the same as sending a message
+        sqlx::query!(
+            "INSERT INTO message_reactions (message_id, user_id, emoji)
+            SELECT ?, ?, ?
+            WHERE EXISTS (SELECT 1 FROM user_conversations WHERE conversation_id = ? AND user_id = ?)
+            ON CONFLICT DO NOTHING",
+            message_id,
+            user.id,
+            emoji,
+            conversation_id,
+            user.id
+        )
+        .execute(pool)
+        .await?
+        .rows_affected()
+            > 0
+    } else {
+        sqlx::query!(
+            "DELETE FROM message_reactions WHERE message_id = ? AND user_id = ? AND emoji = ?
+            AND EXISTS (SELECT 1 FROM user_conversations WHERE conversation_id = ? AND user_id = ?)",
+            message_id,
+            user.id,
+            emoji,
+            conversation_id,
+            user.id
+        )
+        .execute(pool)
+        .await?
+        .rows_affected()
+            > 0
+    };
+
+    if !changed
+        && sqlx::query!(
+            "SELECT conversation_id FROM user_conversations WHERE conversation_id = ? and user_id = ?",
+            conversation_id,
+            user.id
+        )
+        .fetch_optional(pool)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "User is not in the conversation".into(),
+        )));
+    }
+
+    Ok(changed.then_some(ReactionEvent {
+        message_id,
+        conversation_id,
+        user_id: user.id,
+        emoji,
+        added: add,
+    }))
+}
+
+/// The most messages that can be pinned in a conversation at once
+const MAX_PINNED_MESSAGES: i64 = 50;
+
+/// Pin or unpin a message.
+/// Returns None if the message was already pinned or unpinned
+async fn pin_message(
+    pool: &SqlitePool,
+    message_id: i64,
+    pinned: bool,
+    user: &UserToken,
+) -> Result<Option<PinEvent>, AppError> {
+    let Some(conversation_id) = sqlx::query_scalar!(
+        "SELECT conversation_id FROM messages WHERE id = ?",
+        message_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        // Pins are removed along with their message, so a deleted message is already unpinned
+        if !pinned {
+            return Ok(None);
+        }
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Message not found".into(),
+        )));
+    };
+    get_role(pool, conversation_id, user.id).await?;
+
+    let changed = if pinned {
+        // Count the pins as part of the insert so two pins at once can't go over the limit
+        let inserted = sqlx::query!(
+            "INSERT INTO pinned_messages (message_id, conversation_id, pinned_by)
+            SELECT ?1, ?2, ?3
+            WHERE (SELECT COUNT(*) FROM pinned_messages WHERE conversation_id = ?2) < ?4
+            ON CONFLICT DO NOTHING",
+            message_id,
+            conversation_id,
+            user.id,
+            MAX_PINNED_MESSAGES
+        )
+        .execute(pool)
+        .await?
+        .rows_affected()
+            > 0;
+        if !inserted {
+            // Nothing is inserted either when the message is already pinned or when the limit
+            // is reached, so check which one it was after the fact
+            if sqlx::query!(
+                "SELECT message_id FROM pinned_messages WHERE message_id = ?",
+                message_id
+            )
+            .fetch_optional(pool)
+            .await?
+            .is_some()
+            {
+                return Ok(None);
+            }
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Cannot pin more than {} messages in a conversation",
+                    MAX_PINNED_MESSAGES
+                )
+                .into(),
+            )));
+        }
+        true
+    } else {
+        sqlx::query!(
+            "DELETE FROM pinned_messages WHERE message_id = ?",
+            message_id
+        )
+        .execute(pool)
+        .await?
+        .rows_affected()
+            > 0
+    };
+
+    Ok(changed.then_some(PinEvent {
+        message_id,
+        conversation_id,
+        user_id: user.id,
+        pinned,
+    }))
+}
+
+/// Check that the receiver's privacy settings allow the sender to send them a friend request
+async fn check_friend_request_policy(
+    state: &AppState,
+    sender_id: i64,
+    receiver_id: i64,
+) -> Result<(), AppError> {
+    let policy = sqlx::query_scalar!(
+        r#"SELECT COALESCE(
+            (SELECT friend_request_policy FROM user_settings WHERE user_id = ?), 'everyone'
+        ) as "policy!: FriendRequestPolicy""#,
+        receiver_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    match policy {
+        FriendRequestPolicy::Everyone => Ok(()),
+        FriendRequestPolicy::Nobody => Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "This user is not accepting friend requests".into(),
+        ))),
+        FriendRequestPolicy::ConversationMembers => {
+            // Check if the users share at least one conversation
+            let shared = sqlx::query_scalar!(
+                r#"SELECT EXISTS (
+                    SELECT 1 FROM user_conversations a
+                    JOIN user_conversations b ON a.conversation_id = b.conversation_id
+                    WHERE a.user_id = ? AND b.user_id = ?
+                ) as "shared!: bool""#,
+                sender_id,
+                receiver_id
+            )
+            .fetch_one(&state.pool)
+            .await?;
+            if shared {
+                Ok(())
+            } else {
+                Err(AppError::UserError((
+                    StatusCode::FORBIDDEN,
+                    "This user only accepts friend requests from people they share a conversation with"
+                        .into(),
+                )))
+            }
+        }
+    }
 }
 
 /// Handle friend requests
@@ -912,12 +1897,14 @@ async fn delete_message(
 /// or sent if it does not
 ///
 /// If accept is false, the friend request will be rejected or revoked
-async fn handle_friend_request(
+///
+/// Both users are notified over the websocket whichever way the request came in
+pub(crate) async fn handle_friend_request(
     state: &AppState,
     other_user_id: i64,
     accept: bool,
     user: &UserToken,
-) -> Result<(), AppError> {
+) -> Result<FriendRequest, AppError> {
     // Check that the users are not already friends
     let (user1_id, user2_id) = match user.id.cmp(&other_user_id) {
         std::cmp::Ordering::Less => (user.id, other_user_id),
@@ -930,6 +1917,15 @@ async fn handle_friend_request(
         }
     };
 
+    // Rejecting is still allowed so either user can clean up an old request
+    if accept && is_blocked_between(&state.pool, user.id, other_user_id).await? {
+        // Don't reveal which user did the blocking
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Cannot send a friend request to this user".into(),
+        )));
+    }
+
     if sqlx::query!(
         "SELECT user1_id FROM friendships WHERE user1_id = ? and user2_id = ?",
         user1_id,
@@ -997,14 +1993,16 @@ async fn handle_friend_request(
             // Have to make the friend request enum manually
             // because the table doesn't have a status column
             // and it doesn't let me add one with select queries
-            SocketResponse::FriendRequest {
+            FriendRequest {
                 sender_id: user.id,
                 receiver_id: other_user_id,
                 created_at: friendship.created_at,
                 status: FriendRequestStatus::Accepted,
             }
         } else {
-            // A friend request does not exist so send it
+            // A friend request does not exist so make sure the other user
+            // accepts requests from this user before sending it
+            check_friend_request_policy(state, user.id, other_user_id).await?;
             let friendship = sqlx::query!(
                 "INSERT INTO friend_requests (sender_id, receiver_id) VALUES (?, ?) RETURNING created_at",
                 user.id,
@@ -1012,7 +2010,7 @@ async fn handle_friend_request(
             )
             .fetch_one(&state.pool)
             .await?;
-            SocketResponse::FriendRequest {
+            FriendRequest {
                 sender_id: user.id,
                 receiver_id: other_user_id,
                 created_at: friendship.created_at,
@@ -1033,7 +2031,7 @@ async fn handle_friend_request(
             .await? else {
             return Err(AppError::UserError((StatusCode::NOT_FOUND, "Friend request does not exist".into())));
         };
-        SocketResponse::FriendRequest {
+        FriendRequest {
             sender_id: friend_request.sender_id,
             receiver_id: friend_request.receiver_id,
             created_at: friend_request.created_at,
@@ -1041,74 +2039,176 @@ async fn handle_friend_request(
         }
     };
 
+    let event = SocketResponse::FriendRequest(friend_request.clone());
+
     // Only send the friend request over the websocket to the receiver
-    // if the receiver is online
-    if let Some(receiver_connections) = state
-        .user_sockets
-        .read_async(&other_user_id, |_, v| v.connections.clone())
-        .await
-    {
-        for conn in receiver_connections.iter().flatten() {
-            conn.channel.send(friend_request.clone()).await?;
-        }
+    // if the receiver is online and wants to be notified of friend requests
+    let notify_receiver = sqlx::query_scalar!(
+        "SELECT friend_request_notifications FROM user_settings WHERE user_id = ?",
+        other_user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .unwrap_or(true);
+    if notify_receiver {
+        send_to_user(state, other_user_id, event.clone()).await?;
     }
 
     // Send the friend request over the websocket to the sender
     // to let them know that the friend request was sent successfully
-    if let Some(sender_connections) = state
-        .user_sockets
-        .read_async(&user.id, |_, v| v.connections.clone())
-        .await
-    {
-        for conn in sender_connections.iter().flatten() {
-            conn.channel.send(friend_request.clone()).await?;
+    send_to_user(state, user.id, event).await?;
+    Ok(friend_request)
+}
+
+/// Send an event to all of the user's connections, if they have any open.
+/// The event is sequenced the same way as broadcast events so it can be replayed
+pub async fn send_to_user(
+    state: &AppState,
+    user_id: i64,
+    msg: SocketResponse,
+) -> Result<(), AppError> {
+    if let Some((connections, msg)) = sequence_for_user(state, user_id, msg).await {
+        for conn in connections.iter().flatten() {
+            conn.channel.send(msg.clone()).await?;
         }
     }
     Ok(())
 }
 
-/// Invite multiple users to a conversation
-/// Returns the conversation id that the users were invited to
-async fn invite_user(
+/// Check if either user has blocked the other
+async fn is_blocked_between(
     pool: &SqlitePool,
-    conversation_id: Option<i64>,
-    invitees: &[i64],
+    user_id: i64,
+    other_user_id: i64,
+) -> Result<bool, sqlx::Error> {
+    Ok(sqlx::query!(
+        "SELECT blocker_id FROM blocked_users
+        WHERE (blocker_id = ? AND blocked_id = ?) OR (blocker_id = ? AND blocked_id = ?)",
+        user_id,
+        other_user_id,
+        other_user_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some())
+}
+
+/// Block or unblock another user.
+/// Blocking also rejects any pending friend request between the users
+async fn block_user(
+    state: &AppState,
+    other_user_id: i64,
+    block: bool,
     user: &UserToken,
-) -> Result<i64, AppError> {
-    let conversation_id = match conversation_id {
-        // Conversation already exists so check if inviter is in it
-        Some(conversation_id) => {
-            if sqlx::query!(
-                "SELECT conversation_id FROM user_conversations WHERE conversation_id = ? and user_id = ?",
-                conversation_id,
-                user.id
-            )
-                .fetch_optional(pool)
-                .await?
-                .is_none()
-            {
-                return Err(AppError::UserError((StatusCode::FORBIDDEN, "Inviter is not in the conversation".into())));
-            }
-            conversation_id
+) -> Result<(), AppError> {
+    if other_user_id == user.id {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "User cannot block themselves".into(),
+        )));
+    }
+
+    let mut rejected = None;
+    if block {
+        if sqlx::query!("SELECT id FROM users WHERE id = ?", other_user_id)
+            .fetch_optional(&state.pool)
+            .await?
+            .is_none()
+        {
+            return Err(AppError::UserError((
+                StatusCode::NOT_FOUND,
+                "User not found".into(),
+            )));
         }
-        // Conversation does not exist so create a new one and invite the inviter
-        None => {
-            let mut tx = pool.begin().await?;
-            let conversation_id = sqlx::query!("INSERT INTO conversations DEFAULT VALUES")
-                .execute(&mut *tx)
-                .await?
-                .last_insert_rowid();
-            sqlx::query!(
-                "INSERT INTO user_conversations (user_id, conversation_id) VALUES (?, ?)",
-                user.id,
-                conversation_id
-            )
-            .execute(&mut *tx)
-            .await?;
-            tx.commit().await?;
-            conversation_id
+        let mut tx = state.pool.begin().await?;
+        sqlx::query!(
+            "INSERT INTO blocked_users (blocker_id, blocked_id) VALUES (?, ?) ON CONFLICT DO NOTHING",
+            user.id,
+            other_user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        rejected = sqlx::query!(
+            "DELETE FROM friend_requests WHERE (sender_id = ? AND receiver_id = ?) OR (sender_id = ? AND receiver_id = ?) RETURNING *",
+            user.id,
+            other_user_id,
+            other_user_id,
+            user.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+    } else {
+        sqlx::query!(
+            "DELETE FROM blocked_users WHERE blocker_id = ? AND blocked_id = ?",
+            user.id,
+            other_user_id
+        )
+        .execute(&state.pool)
+        .await?;
+    }
+
+    // Let both users know the pending friend request is gone
+    if let Some(friend_request) = rejected {
+        let friend_request = SocketResponse::FriendRequest(FriendRequest {
+            sender_id: friend_request.sender_id,
+            receiver_id: friend_request.receiver_id,
+            created_at: friend_request.created_at,
+            status: FriendRequestStatus::Rejected,
+        });
+        for user_id in [user.id, other_user_id] {
+            send_to_user(state, user_id, friend_request.clone()).await?;
         }
-    };
+    }
+
+    send_to_user(
+        state,
+        user.id,
+        SocketResponse::BlockEvent {
+            user_id: other_user_id,
+            blocked: block,
+        },
+    )
+    .await
+}
+
+/// Invite multiple users to a conversation
+/// Returns the conversation id that the users were invited to
+///
+/// Only owners and admins can invite users to an existing conversation.
+/// If no conversation id is given, a new group conversation is created with the inviter and
+/// the invitees. At least one other user has to be invited to create one
+pub async fn invite_user(
+    pool: &SqlitePool,
+    conversation_id: Option<i64>,
+    invitees: &[i64],
+    user: &UserToken,
+) -> Result<i64, AppError> {
+    // Ignore duplicates and the inviter so they can't stand in for other users
+    let mut invitees = invitees
+        .iter()
+        .copied()
+        .filter(|&invitee| invitee != user.id)
+        .collect::<Vec<_>>();
+    invitees.sort_unstable();
+    invitees.dedup();
+    if invitees.is_empty() {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "No other users to invite".into(),
+        )));
+    }
+
+    if let Some(conversation_id) = conversation_id {
+        // Conversation already exists so check if inviter is allowed to add members to it
+        if !get_role(pool, conversation_id, user.id).await?.is_admin() {
+            return Err(AppError::UserError((
+                StatusCode::FORBIDDEN,
+                "Only owners and admins can invite users".into(),
+            )));
+        }
+    }
 
     // Build a query to check if all the users being invited exist
     // Final query will look like this: SELECT COUNT(id) FROM users WHERE id IN (?, ?, ?)
@@ -1116,7 +2216,7 @@ async fn invite_user(
         QueryBuilder::new("SELECT COUNT(id) FROM users WHERE id IN (");
 
     let mut separated = query_builder.separated(", ");
-    for invitee in invitees {
+    for invitee in &invitees {
         separated.push_bind(invitee);
     }
     // Use query_scalar to extract the value of the first column, COUNT(id) in this case,
@@ -1133,6 +2233,28 @@ async fn invite_user(
         )));
     }
 
+    // Create the conversation and invite the users together so the conversation
+    // is never left with only the inviter in it
+    let mut tx = pool.begin().await?;
+    let conversation_id = match conversation_id {
+        Some(conversation_id) => conversation_id,
+        // Conversation does not exist so create a new one and invite the inviter
+        None => {
+            let conversation_id = sqlx::query!("INSERT INTO conversations DEFAULT VALUES")
+                .execute(&mut *tx)
+                .await?
+                .last_insert_rowid();
+            sqlx::query!(
+                "INSERT INTO user_conversations (user_id, conversation_id, role) VALUES (?, ?, 'owner')",
+                user.id,
+                conversation_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            conversation_id
+        }
+    };
+
     // Use a query builder to invite all the users at once instead of multiple
     // queries in a loop for significantly better performance
     // Can't use the query! macro because it doesn't support bulk inserts
@@ -1143,14 +2265,15 @@ async fn invite_user(
         QueryBuilder::new("INSERT INTO user_conversations (user_id, conversation_id) ");
 
     // Pushes a VALUES clause with the user_id and conversation_id for each user
-    query_builder.push_values(invitees, |mut builder, invitee| {
+    query_builder.push_values(&invitees, |mut builder, invitee| {
         builder.push_bind(invitee).push_bind(conversation_id);
     });
 
     query_builder.push(" ON CONFLICT DO NOTHING");
 
     let query = query_builder.build();
-    query.execute(pool).await?;
+    query.execute(&mut *tx).await?;
+    tx.commit().await?;
     Ok(conversation_id)
 }
 
@@ -1197,6 +2320,11 @@ async fn handle_message(
                     {
                         return Err(AppError::UserError((StatusCode::TOO_MANY_REQUESTS, "AI generation is already in progress. Please cancel generation or wait before making another query".into())));
                     }
+                    // Reject a model that doesn't exist before the message is saved
+                    // so the user isn't left with a message the AI never answers
+                    if let Some(ai_model_id) = send_message.ai_model_id {
+                        AiModel::get(&state.pool, ai_model_id).await?;
+                    }
 
                     let chat_message = match (&send_message.message, &send_message.attachment) {
                         (None, None) => None,
@@ -1208,110 +2336,103 @@ async fn handle_message(
                         }
                     };
 
-                    // Broadcast the message in a separate task to prevent the
-                    // current thread from being blocked by the broadcast
-                    tokio::spawn({
-                        let state = state.clone();
-                        let chat_message = chat_message.clone();
-                        async move {
-                            // Only broadcast the message if it is not empty
-                            if let Some(chat_message) = chat_message {
-                                let _ = broadcast_event(
-                                    &state,
-                                    SocketResponse::Message(chat_message.clone()),
-                                )
-                                .await;
-                            }
-                        }
-                    });
+                    // Only broadcast the message if it is not empty
+                    if let Some(chat_message) = chat_message {
+                        announce_message(state, chat_message);
+                    }
 
                     // Check if the user is attempting to query the model,
                     // if they aren't then we can return early
                     let Some(ai_model_id) = send_message.ai_model_id else {
                         return Ok(());
                     };
-
-                    // The user is explicitly trying to query the model, so check if there is
-                    // already an AI generation in progress in any conversation they are a part of
-                    // and prevent them from starting a new one
-                    if socket.ai_responding.load(Ordering::SeqCst) != 0 {
-                        return Err(AppError::UserError((StatusCode::TOO_MANY_REQUESTS, "AI generation is already in progress. Please cancel generation or wait before making another query".into())));
-                    }
-
-                    socket.ai_responding.store(
+                    let conversation_id =
                         send_message.conversation_id.ok_or(AppError::UserError((
                             StatusCode::BAD_REQUEST,
                             "Cannot send ai message in non-existant conversation!".into(),
-                        )))?,
-                        Ordering::SeqCst,
-                    );
-
-                    // Spawn the AI response generation in a separate task to allow cancellation
-                    // by another message from the user
-                    let handle = tokio::spawn({
-                        let state = state.clone();
-                        let send_message = send_message.clone();
-                        let user = user.clone();
-                        async move { query_model(&state, &send_message, &user).await }
-                    });
-
-                    // Save an abort handle to the thread in the connection state of the user
-                    // to allow another thread to abort the AI generation if requested by the user
-                    socket
-                        .ai_handle
-                        .store(Some(Box::new(handle.abort_handle())), Ordering::SeqCst);
-
-                    // This will be Ok() if the AI response generation was not canceled
-                    // If it was canceled then we can just reset the value of the responding
-                    // conversation and return early
-                    let Ok(ai_message) = handle.await else {
-                        socket.ai_responding.store(0, Ordering::SeqCst);
-                        return Ok(());
+                        )))?;
+                    generate_response(state, user, socket, conversation_id, ai_model_id, None)
+                        .await?;
+                }
+                SocketRequest::ResumeGeneration { message_id } => {
+                    let Some(message) = sqlx::query!(
+                        r#"SELECT messages.conversation_id, ai_model_id, incomplete,
+                        NOT EXISTS(SELECT 1 FROM messages AS later
+                            WHERE later.conversation_id = messages.conversation_id
+                            AND later.id > messages.id) as "latest!: bool"
+                        FROM messages
+                        JOIN user_conversations ON user_conversations.conversation_id = messages.conversation_id
+                        WHERE messages.id = ? AND user_conversations.user_id = ?"#,
+                        message_id,
+                        user.id
+                    )
+                    .fetch_optional(&state.pool)
+                    .await?
+                    else {
+                        return Err(AppError::UserError((
+                            StatusCode::NOT_FOUND,
+                            "Message not found".into(),
+                        )));
                     };
-
-                    // Reset the AI generation flag to 0 to allow the user to query the model again
-                    // Must be done inside this block to prevent the flage from being reset if the user sends another message
-                    // before the AI model is finished responding or canceled
-                    socket.ai_responding.store(0, Ordering::SeqCst);
-
-                    let ai_message = ai_message?;
-                    let stemmed_message = state.stemmer.stem_message(&ai_message);
-
-                    // Save the AI model's response to the database
-                    // This is done outside of the `query_model` function to
-                    // prevent the message from being lost if the user cancels
-                    // the AI generation while writing to the database
-                    let message = sqlx::query!(
-                            "INSERT INTO messages (conversation_id, message, stemmed_message, ai_model_id) VALUES (?, ?, ?, ?) RETURNING id",
-                            send_message.conversation_id,
-                            ai_message,
-                            stemmed_message,
-                            ai_model_id
-                        )
-                        .fetch_one(&state.pool)
-                        .await?.id;
-
-                    let ai_message = sqlx::query_as!(
-                        ChatMessage,
-                        "SELECT * FROM chat_messages WHERE id = ?",
-                        message
+                    let Some(ai_model_id) = message.ai_model_id.filter(|_| message.incomplete)
+                    else {
+                        return Err(AppError::UserError((
+                            StatusCode::BAD_REQUEST,
+                            "Only incomplete AI responses can be resumed".into(),
+                        )));
+                    };
+                    if !message.latest {
+                        return Err(AppError::UserError((
+                            StatusCode::CONFLICT,
+                            "Only the most recent message in a conversation can be resumed".into(),
+                        )));
+                    }
+                    generate_response(
+                        state,
+                        user,
+                        socket,
+                        message.conversation_id,
+                        ai_model_id,
+                        Some(message_id),
                     )
-                    .fetch_one(&state.pool)
                     .await?;
-
-                    // Broadcast the AI model's response to the conversation
-                    broadcast_event(state, SocketResponse::Message(ai_message)).await?;
                 }
                 SocketRequest::EditMessage(chat_message) => {
                     let chat_message = edit_message(state, &chat_message, user).await?;
                     // Broadcast the edited message to all the users in the conversation
                     broadcast_event(state, SocketResponse::Message(chat_message.clone())).await?;
                 }
+                SocketRequest::RequestEditHistory { message_id } => {
+                    for edit in get_edit_history(&state.pool, message_id, user).await? {
+                        inner
+                            .channel
+                            .send(SocketResponse::MessageEdit(edit))
+                            .await?;
+                    }
+                }
                 SocketRequest::DeleteMessage { message_id } => {
                     let deleted_message = delete_message(&state.pool, message_id, user).await?;
                     // Broadcast the deleted message to all the users in the conversation
                     broadcast_event(state, SocketResponse::DeleteMessage(deleted_message)).await?;
                 }
+                SocketRequest::React {
+                    message_id,
+                    emoji,
+                    add,
+                } => {
+                    // Nothing is broadcast if the reaction was already added or removed
+                    if let Some(event) =
+                        react_to_message(&state.pool, message_id, emoji, add, user).await?
+                    {
+                        broadcast_event(state, SocketResponse::ReactionEvent(event)).await?;
+                    }
+                }
+                SocketRequest::PinMessage { message_id, pinned } => {
+                    // Nothing is broadcast if the message was already pinned or unpinned
+                    if let Some(event) = pin_message(&state.pool, message_id, pinned, user).await? {
+                        broadcast_event(state, SocketResponse::PinEvent(event)).await?;
+                    }
+                }
                 SocketRequest::InviteUsers {
                     invitees,
                     mut conversation_id,
@@ -1341,6 +2462,12 @@ async fn handle_message(
                 } => {
                     handle_friend_request(state, other_user_id, accept, user).await?;
                 }
+                SocketRequest::BlockUser { user_id } => {
+                    block_user(state, user_id, true, user).await?;
+                }
+                SocketRequest::UnblockUser { user_id } => {
+                    block_user(state, user_id, false, user).await?;
+                }
                 SocketRequest::ReadMessage { conversation_id } => {
                     read_event(&state.pool, conversation_id, user).await?;
                     broadcast_event(
@@ -1356,11 +2483,18 @@ async fn handle_message(
                 SocketRequest::RequestMessages(request_message) => {
                     request_messages(&state.pool, &request_message, &inner.channel, user).await?;
                 }
+                SocketRequest::ConversationStats { conversation_id } => {
+                    let stats = get_conversation_stats(&state.pool, conversation_id, user).await?;
+                    inner
+                        .channel
+                        .send(SocketResponse::ConversationStats(stats))
+                        .await?;
+                }
                 SocketRequest::RequestConversation { conversation_id } => {
                     // Get the converation and all of the users inside the conversation in the same
                     // query to minimize the number of database queries
                     let mut query =  sqlx::query!(
-                        "SELECT id, title, conversations.created_at, conversations.last_message_at, user_id, user_conversations.last_message_at as user_last_message_at, last_read_at FROM conversations
+                        "SELECT id, title, conversations.created_at, conversations.last_message_at, persona_id, admins_only_rename, user_id, user_conversations.last_message_at as user_last_message_at, last_read_at, muted, role FROM conversations
                         JOIN user_conversations
                         ON conversations.id = user_conversations.conversation_id
                         WHERE conversation_id = ?",
@@ -1378,23 +2512,30 @@ async fn handle_message(
                                     id: conversation.id,
                                     created_at: conversation.created_at,
                                     last_message_at: conversation.last_message_at,
+                                    persona_id: conversation.persona_id,
+                                    muted: Requested::Loaded(conversation.muted),
+                                    admins_only_rename: conversation.admins_only_rename,
                                     // Have to take the title because we can't move it from the row
                                     // and cloning is more expensive than taking
                                     title: conversation.title.take(),
-                                    users: Some(
+                                    users: Requested::Loaded(
                                         future::join_all(query.iter().map(|u| async {
                                             ConversationUser {
                                                 id: u.user_id,
-                                                last_message_at: u.user_last_message_at,
-                                                last_read_at: u.last_read_at,
-                                                online_status: Some(
+                                                last_message_at: Requested::Loaded(
+                                                    u.user_last_message_at,
+                                                ),
+                                                last_read_at: Requested::Loaded(u.last_read_at),
+                                                online_status: Requested::Loaded(
                                                     get_user_status(state, u.user_id).await,
                                                 ),
+                                                role: Requested::Loaded(u.role.clone().into()),
                                             }
                                         }))
                                         .await
                                         .into(),
                                     ),
+                                    unread_count: Requested::NotRequested,
                                 }))
                                 .await?;
                         }
@@ -1471,6 +2612,9 @@ async fn handle_message(
                                     entry.get_mut().insert(inner.channel.clone());
                                 }
                                 None => {
+                                    // `Sender` is hashed by the user and connection id, neither
+                                    // of which are mutable, so this is safe to use as a key
+                                    #[allow(clippy::mutable_key_type)]
                                     let _ = state
                                         .conversation_connections
                                         .insert_async(conversation_id, {
@@ -1492,68 +2636,39 @@ async fn handle_message(
                         .store(Some(Box::new(handle.abort_handle())), Ordering::SeqCst);
                 }
                 SocketRequest::RequestConversations(request_message) => {
-                    let limit = request_message.message_num.unwrap_or(50);
-                    let last_message_at = request_message
-                        .last_message_at
-                        .unwrap_or(NaiveDateTime::MAX);
-                    // Create a helper to map rows to conversation struct easier
-                    // Have to use an unchecked query as a workaround because sqlx has a bug where
-                    // aggregate functions return the wrong type.
-                    // Reference Issue: https://github.com/launchbadge/sqlx/issues/3238
-                    // For example in this scenario, GROUP_CONCAT(user_id) should return a string
-                    // but sqlx parses it as a i64, preventing us from using it in the struct
-                    #[derive(FromRow)]
-                    struct ConversationHelper {
-                        id: i64,
-                        title: Option<String>,
-                        created_at: NaiveDateTime,
-                        last_message_at: Option<NaiveDateTime>,
-                        users: String,
-                    }
-
-                    // Query the database for the conversations the user is in
-                    // Use fetch instead of fetch all to stream results to the client
-                    let mut query = sqlx::query_as::<Sqlite, ConversationHelper>(
-                        r#"SELECT conversations.*, GROUP_CONCAT(user_id) as users FROM conversations
-                           JOIN user_conversations 
-                           ON conversations.id = user_conversations.conversation_id 
-                           WHERE id IN 
-                           (SELECT id FROM conversations
-                           JOIN user_conversations
-                           ON conversations.id = user_conversations.conversation_id
-                           WHERE user_id = ? AND conversations.last_message_at > ?
-                           ORDER BY conversations.last_message_at DESC
-                           LIMIT ?) 
-                           GROUP BY id"#,
-                    )
-                    .bind(user.id)
-                    .bind(last_message_at)
-                    .bind(limit)
-                    .fetch(&state.pool);
-
-                    while let Some(conversation) = query.next().await {
-                        let conversation = conversation?;
+                    request_conversations(&state.pool, &request_message, &inner.channel, user)
+                        .await?;
+                }
+                SocketRequest::RequestThread { message_id } => {
+                    // Replies are matched against the root of the thread they were sent in,
+                    // so focus the root even when a reply further down the thread was requested
+                    let thread_root_id = get_thread_root(&state.pool, user.id, message_id).await?;
+                    inner.focused_thread.store(thread_root_id, Ordering::SeqCst);
+                    for message in get_thread_messages(&state.pool, thread_root_id).await? {
                         inner
                             .channel
-                            .send(SocketResponse::Conversation(Conversation {
-                                id: conversation.id,
-                                title: conversation.title,
-                                created_at: conversation.created_at,
-                                last_message_at: conversation.last_message_at,
-                                users: Some(
-                                    conversation
-                                        .users
-                                        .split(',')
-                                        .map(|u| ConversationUser {
-                                            id: u.parse::<i64>().unwrap(),
-                                            ..Default::default()
-                                        })
-                                        .collect(),
-                                ),
-                            }))
+                            .send(SocketResponse::ThreadMessage(message))
                             .await?;
                     }
                 }
+                SocketRequest::CloseThread => {
+                    inner.focused_thread.store(0, Ordering::SeqCst);
+                }
+                SocketRequest::ReplayEvents { since } => match socket.replay.since(since) {
+                    Some(events) => {
+                        for event in events {
+                            inner.channel.send(SocketResponse::Sequenced(event)).await?;
+                        }
+                    }
+                    None => {
+                        inner
+                            .channel
+                            .send(SocketResponse::ReplayUnavailable {
+                                latest_seq: socket.replay.latest_seq(),
+                            })
+                            .await?;
+                    }
+                },
                 SocketRequest::RequestFriends => {
                     let mut query = sqlx::query!(
                         "SELECT * FROM friendships WHERE user1_id = ? OR user2_id = ?",
@@ -1589,11 +2704,32 @@ async fn handle_message(
                         let friend_request = friend_request?;
                         inner
                             .channel
-                            .send(SocketResponse::FriendRequest {
+                            .send(SocketResponse::FriendRequest(FriendRequest {
                                 sender_id: friend_request.sender_id,
                                 receiver_id: friend_request.receiver_id,
                                 created_at: friend_request.created_at,
                                 status: FriendRequestStatus::Pending,
+                            }))
+                            .await?;
+                    }
+                }
+                SocketRequest::RequestUserStatus { user_ids } => {
+                    if user_ids.len() > MAX_STATUS_REQUEST_IDS {
+                        return Err(AppError::UserError((
+                            StatusCode::BAD_REQUEST,
+                            format!(
+                                "Cannot request the status of more than {} users at once",
+                                MAX_STATUS_REQUEST_IDS
+                            )
+                            .into(),
+                        )));
+                    }
+                    for &user_id in user_ids.iter() {
+                        inner
+                            .channel
+                            .send(SocketResponse::UserStatus {
+                                user_id,
+                                status: get_user_status(state, user_id).await,
                             })
                             .await?;
                     }
@@ -1645,26 +2781,80 @@ async fn handle_message(
                     }
                 }
                 SocketRequest::SearchMessages(message) => {
-                    search_message(state, &message, &inner.channel).await?;
+                    inner
+                        .search_throttle
+                        .acquire(&state.search_limits)
+                        .map_err(AppError::RateLimited)?;
+                    search_message(state, &message, user, &inner.channel).await?;
                 }
                 SocketRequest::LeaveConversation { conversation_id } => {
                     // Remove the user from the conversation
-                    leave_conversation(&state.pool, conversation_id, user.id).await?;
+                    let new_owner =
+                        leave_conversation(&state.pool, conversation_id, user.id).await?;
 
+                    // Send the leave event back to the user explicitly
+                    // to let them know that they have left the conversation since
+                    // `broadcast_event` will not send events to the user that left
                     let leave_event = SocketResponse::LeaveEvent {
                         conversation_id,
                         user_id: user.id,
                     };
+                    send_to_user(state, user.id, leave_event).await?;
 
-                    // Send the leave event back to the user explicitly
-                    // to let them know that they have left the conversation since
-                    // `broadcast_event` will not send events to the user that left
-                    for connection in socket.connections.iter().flatten() {
-                        connection.channel.send(leave_event.clone()).await?;
-                    }
+                    announce_leave(state, conversation_id, user.id, new_owner).await?;
+                }
+                SocketRequest::RemoveUser {
+                    conversation_id,
+                    user_id,
+                } => {
+                    remove_user(&state.pool, conversation_id, user_id, user).await?;
+                    // Sending only fails if nothing is listening, which is fine
+                    let _ = state.conversation_leaves.send(ConversationLeave {
+                        conversation_id,
+                        user_id,
+                    });
+
+                    let remove_event = SocketResponse::RemoveEvent {
+                        conversation_id,
+                        user_id,
+                        removed_by: user.id,
+                    };
 
-                    // Broadcast the user leaving the conversation to all the remaining users in the conversation
-                    broadcast_event(state, leave_event).await?;
+                    // `broadcast_event` won't reach the removed user anymore so let them know directly
+                    send_to_user(state, user_id, remove_event.clone()).await?;
+
+                    broadcast_event(state, remove_event).await?;
+                }
+                SocketRequest::SetRole {
+                    conversation_id,
+                    user_id,
+                    role,
+                } => {
+                    set_role(&state.pool, conversation_id, user_id, role, user).await?;
+                    broadcast_event(
+                        state,
+                        SocketResponse::RoleEvent {
+                            conversation_id,
+                            user_id,
+                            role,
+                        },
+                    )
+                    .await?;
+                }
+                SocketRequest::RestrictRenaming {
+                    conversation_id,
+                    admins_only,
+                } => {
+                    restrict_renaming(&state.pool, conversation_id, admins_only, user).await?;
+                    broadcast_event(
+                        state,
+                        SocketResponse::RenameRestrictionEvent {
+                            conversation_id,
+                            user_id: user.id,
+                            admins_only,
+                        },
+                    )
+                    .await?;
                 }
                 SocketRequest::RenameConversation {
                     conversation_id,
@@ -1681,6 +2871,71 @@ async fn handle_message(
                     )
                     .await?;
                 }
+                SocketRequest::MuteConversation {
+                    conversation_id,
+                    muted,
+                } => {
+                    mute_conversation(state, conversation_id, muted, user).await?;
+                }
+                SocketRequest::SetPersona {
+                    conversation_id,
+                    persona_id,
+                } => {
+                    set_persona(&state.pool, conversation_id, persona_id, user).await?;
+                    broadcast_event(
+                        state,
+                        SocketResponse::PersonaEvent {
+                            conversation_id,
+                            persona_id,
+                            user_id: user.id,
+                        },
+                    )
+                    .await?;
+                }
+                SocketRequest::SetGenerationSettings(settings) => {
+                    set_generation_settings(&state.pool, &settings, user).await?;
+                    broadcast_event(state, SocketResponse::GenerationSettings(settings)).await?;
+                }
+                SocketRequest::SummarizeConversation {
+                    conversation_id,
+                    ai_model_id,
+                } => {
+                    ensure_member(&state.pool, conversation_id, user).await?;
+                    let Some(model) = sqlx::query_as!(
+                        AiModel,
+                        "SELECT id, name, max_tokens, provider FROM ai_models WHERE id = COALESCE(?,
+                        (SELECT ai_model_id FROM user_settings WHERE user_id = ?),
+                        (SELECT id FROM ai_models ORDER BY id LIMIT 1))",
+                        ai_model_id,
+                        user.id
+                    )
+                    .fetch_optional(&state.pool)
+                    .await?
+                    else {
+                        return Err(AppError::UserError((
+                            StatusCode::NOT_FOUND,
+                            "AI model not found".into(),
+                        )));
+                    };
+                    let Some(summary) =
+                        summarize_conversation(state, conversation_id, &model, user.id).await?
+                    else {
+                        return Err(AppError::UserError((
+                            StatusCode::BAD_REQUEST,
+                            "Conversation is short enough that it doesn't need a summary".into(),
+                        )));
+                    };
+                    broadcast_event(state, SocketResponse::ConversationSummary(summary)).await?;
+                }
+                SocketRequest::RequestGenerationSettings { conversation_id } => {
+                    ensure_member(&state.pool, conversation_id, user).await?;
+                    inner
+                        .channel
+                        .send(SocketResponse::GenerationSettings(
+                            GenerationSettings::get(&state.pool, conversation_id).await?,
+                        ))
+                        .await?;
+                }
             }
         }
         Message::Binary(_) => {
@@ -1688,8 +2943,7 @@ async fn handle_message(
         }
         // We do not need to handle ping or close messages
         // because tokio_tungstenite will handle them for us
-        #[allow(clippy::wildcard_in_or_patterns)]
-        Message::Ping(_) | Message::Close(_) | _ => (),
+        _ => (),
     }
     Ok(())
 }
@@ -1700,11 +2954,25 @@ pub async fn broadcast_event(state: &AppState, msg: SocketResponse) -> Result<()
     let id = match &msg {
         SocketResponse::Message(chat_msg) => chat_msg.conversation_id,
         SocketResponse::DeleteMessage(delete_msg) => delete_msg.conversation_id,
+        SocketResponse::ReactionEvent(event) => event.conversation_id,
+        SocketResponse::PinEvent(event) => event.conversation_id,
         SocketResponse::ReadEvent(event) => event.conversation_id,
         SocketResponse::StreamData(data) => data.conversation_id,
         SocketResponse::LeaveEvent {
             conversation_id, ..
         } => *conversation_id,
+        SocketResponse::RemoveEvent {
+            conversation_id, ..
+        } => *conversation_id,
+        SocketResponse::RoleEvent {
+            conversation_id, ..
+        } => *conversation_id,
+        SocketResponse::OwnershipEvent {
+            conversation_id, ..
+        } => *conversation_id,
+        SocketResponse::RenameRestrictionEvent {
+            conversation_id, ..
+        } => *conversation_id,
         SocketResponse::Invite {
             conversation_id, ..
         } => *conversation_id,
@@ -1714,31 +2982,84 @@ pub async fn broadcast_event(state: &AppState, msg: SocketResponse) -> Result<()
         SocketResponse::RenameEvent {
             conversation_id, ..
         } => *conversation_id,
+        SocketResponse::PersonaEvent {
+            conversation_id, ..
+        } => *conversation_id,
+        SocketResponse::GenerationSettings(settings) => settings.conversation_id,
+        SocketResponse::ConversationSummary(summary) => summary.conversation_id,
         _ => unreachable!("uuhhh how"),
     };
+    // Messages and reactions aren't delivered to users who blocked the sender
+    let sender_id = match &msg {
+        SocketResponse::Message(chat_msg) => chat_msg.user_id,
+        SocketResponse::ReactionEvent(event) => Some(event.user_id),
+        _ => None,
+    };
     let users = sqlx::query!(
-        "SELECT user_id FROM user_conversations WHERE conversation_id = ?",
-        id
+        r#"SELECT user_conversations.user_id, muted, username,
+        COALESCE(message_notifications, 'all') as "message_notifications!: MessageNotifications"
+        FROM user_conversations
+        JOIN users ON users.id = user_conversations.user_id
+        LEFT JOIN user_settings ON user_settings.user_id = user_conversations.user_id
+        WHERE conversation_id = ?
+        AND user_conversations.user_id NOT IN (SELECT blocker_id FROM blocked_users WHERE blocked_id = ?)"#,
+        id,
+        sender_id
     )
     .fetch_all(&state.pool)
     .await?;
 
     // Use `join_all` to broadcast the message to all the users in the conversation
     // concurrently to minimize the time it takes to broadcast the message
-    let inner = future::join_all(users.into_iter().map(|user| async move {
-        state
-            .user_sockets
-            .read_async(&user.user_id, |_, v| v.connections.clone())
-            .await
+    let inner = future::join_all(users.into_iter().map(|user| {
+        let msg = msg.clone();
+        // Users who muted the conversation or turned off notifications for the message are
+        // only notified of new activity on connections that are viewing it
+        let quiet = match &msg {
+            SocketResponse::Message(chat_msg) => {
+                user.muted
+                    || match user.message_notifications {
+                        MessageNotifications::All => false,
+                        MessageNotifications::Mentions => {
+                            !mentions(&chat_msg.message, &user.username)
+                        }
+                        MessageNotifications::None => true,
+                    }
+            }
+            SocketResponse::ReactionEvent(_) => user.muted,
+            _ => false,
+        };
+        async move {
+            if quiet {
+                // Quiet events are sent without a sequence number. Only some of the user's
+                // connections get them, so numbering them would leave the others with gaps
+                // they would try to replay
+                state
+                    .user_sockets
+                    .read_async(&user.user_id, |_, v| v.connections.clone())
+                    .await
+                    .map(|connections| (connections, msg, quiet))
+            } else {
+                sequence_for_user(state, user.user_id, msg)
+                    .await
+                    .map(|(connections, msg)| (connections, msg, quiet))
+            }
+        }
     }))
     .await;
 
     let mut unordered: FuturesUnordered<_> = inner
         .iter()
         .flatten()
-        .flatten()
-        .flatten()
-        .map(|connection| connection.channel.send(msg.clone()))
+        .flat_map(|(connections, msg, quiet)| {
+            connections
+                .iter()
+                .flatten()
+                .filter(move |connection| {
+                    !quiet || connection.focused_conversation.load(Ordering::SeqCst) == id
+                })
+                .map(move |connection| connection.channel.send(msg.clone()))
+        })
         .collect();
 
     while let Some(fut) = unordered.next().await {
@@ -1749,18 +3070,129 @@ pub async fn broadcast_event(state: &AppState, msg: SocketResponse) -> Result<()
     Ok(())
 }
 
-/// Send a message to the client over the websocket
-/// bool is returned because the connection may have been closed
-/// true is returned if the message was sent successfully
-/// false is returned if the connection was closed
-async fn send_message(
-    sender: &mut SplitSink<WebSocket, Message>,
-    msg: SocketResponse,
-    user: &UserToken,
+/// Check if a message mentions the user with `@username`
+fn mentions(message: &str, username: &str) -> bool {
+    let message = message.to_lowercase();
+    let username = username.to_lowercase();
+    message.match_indices('@').any(|(i, _)| {
+        message[i + 1..]
+            .strip_prefix(&username)
+            // Don't match a longer username that starts with this one
+            .is_some_and(|rest| {
+                !rest
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_alphanumeric() || c == '_')
+            })
+    })
+}
+
+/// Record the event in the user's replay buffer and attach its sequence number.
+/// AI stream data is sent as is since partial responses are not worth replaying,
+/// the finished message is broadcast once generation completes.
+/// Errors are only meaningful when they happen, so they aren't replayed either
+fn sequence(replay: &ReplayBuffer, msg: SocketResponse) -> SocketResponse {
+    match msg {
+        SocketResponse::StreamData(_) | SocketResponse::Error(_) => msg,
+        msg => SocketResponse::Sequenced(replay.push(msg)),
+    }
+}
+
+/// Sequence an event for every connection of the user and get the connections to send it to.
+/// Users who just disconnected have no connections, but the event is kept so they can replay it
+async fn sequence_for_user(
+    state: &AppState,
+    user_id: i64,
+    msg: SocketResponse,
+) -> Option<(Vec<Option<InnerConnection>>, SocketResponse)> {
+    match state
+        .user_sockets
+        .read_async(&user_id, |_, v| (v.connections.clone(), v.replay.clone()))
+        .await
+    {
+        Some((connections, replay)) => Some((connections, sequence(&replay, msg))),
+        None => {
+            if let Some(replay) = state
+                .parked_replays
+                .read_async(&user_id, |_, v| v.clone())
+                .await
+            {
+                sequence(&replay, msg);
+            }
+            None
+        }
+    }
+}
+
+/// Keep the replay buffer of a user who disconnected from every connection
+/// for long enough that they can replay the events they missed if they reconnect
+async fn park_replay(state: &AppState, user_id: i64, replay: Arc<ReplayBuffer>) {
+    let parked_replays = state.parked_replays.clone();
+    parked_replays.upsert_async(user_id, replay.clone()).await;
+    tokio::spawn(async move {
+        tokio::time::sleep(REPLAY_WINDOW).await;
+        // Only remove the buffer if the user hasn't reconnected and disconnected again since
+        parked_replays
+            .remove_if_async(&user_id, |parked| Arc::ptr_eq(parked, &replay))
+            .await;
+    });
+}
+
+/// Send a reply to the connections of users in the conversation
+/// that are currently viewing the thread the reply belongs to
+async fn broadcast_thread_message(state: &AppState, message: ChatMessage) -> Result<(), AppError> {
+    let Some(thread_root_id) = message.thread_root_id else {
+        return Ok(());
+    };
+    let users = sqlx::query!(
+        "SELECT user_id FROM user_conversations WHERE conversation_id = ?
+        AND user_id NOT IN (SELECT blocker_id FROM blocked_users WHERE blocked_id = ?)",
+        message.conversation_id,
+        message.user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let inner = future::join_all(users.into_iter().map(|user| async move {
+        state
+            .user_sockets
+            .read_async(&user.user_id, |_, v| v.connections.clone())
+            .await
+    }))
+    .await;
+
+    let mut unordered: FuturesUnordered<_> = inner
+        .iter()
+        .flatten()
+        .flatten()
+        .flatten()
+        .filter(|connection| connection.focused_thread.load(Ordering::SeqCst) == thread_root_id)
+        .map(|connection| {
+            connection
+                .channel
+                .send(SocketResponse::ThreadMessage(message.clone()))
+        })
+        .collect();
+
+    while let Some(fut) = unordered.next().await {
+        if let Err(e) = fut {
+            warn!("Error broadcasting thread message: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Send a message to the client over the websocket
+/// bool is returned because the connection may have been closed
+/// true is returned if the message was sent successfully
+/// false is returned if the connection was closed
+async fn send_message(
+    state: &AppState,
+    sender: &mut SplitSink<WebSocket, Message>,
+    msg: SocketResponse,
+    user: &mut UserToken,
 ) -> Result<bool, AppError> {
-    // Check if the user is still authorized
-    // and close the connection if they are not
-    if user.exp < chrono::Utc::now().timestamp() {
+    if !check_token(state, sender, user).await? {
         return Ok(false);
     }
     // All responses should be serialized to JSON
@@ -1771,20 +3203,69 @@ async fn send_message(
     Ok(true)
 }
 
+/// Check if the user is still authorized, the same way as for http requests.
+/// With sliding expiration the client is sent a new token when theirs is close to expiring,
+/// otherwise the client is told their token expired.
+/// false is returned if the connection should be closed
+async fn check_token(
+    state: &AppState,
+    sender: &mut SplitSink<WebSocket, Message>,
+    user: &mut UserToken,
+) -> Result<bool, AppError> {
+    if let Some((refreshed, token)) = refresh_token(&state.pool, &state.auth, user).await? {
+        *user = refreshed;
+        sender
+            .send(Message::Text(
+                sonic_rs::to_string(&SocketResponse::TokenRefreshed { token }).unwrap(),
+            ))
+            .await?;
+    }
+    if user.exp < chrono::Utc::now().timestamp() {
+        let error = SocketResponse::Error(
+            AppError::Jwt(JwtError::Expired {
+                expired_at: user.exp,
+            })
+            .into(),
+        );
+        let _ = sender
+            .send(Message::Text(sonic_rs::to_string(&error).unwrap()))
+            .await;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
 /// Removes a user from a conversation
-/// If the conversation has no users left, it is also deleted
-async fn leave_conversation(
+/// If the conversation has no users left, it is also deleted.
+/// If the owner left, ownership goes to the longest standing admin, or the longest
+/// standing member if there are no admins, and the id of the new owner is returned
+pub async fn leave_conversation(
     pool: &SqlitePool,
     conversation_id: i64,
     user_id: i64,
-) -> Result<(), AppError> {
+) -> Result<Option<i64>, AppError> {
+    let mut tx = pool.begin().await?;
+    let new_owner = leave_conversation_in(&mut tx, conversation_id, user_id).await?;
+    tx.commit().await?;
+
+    Ok(new_owner)
+}
+
+/// Remove a user from a conversation as part of a larger transaction.
+/// Deletes the conversation if nobody is left in it, otherwise returns the new owner
+/// if the user that left owned it
+pub(crate) async fn leave_conversation_in(
+    tx: &mut SqliteConnection,
+    conversation_id: i64,
+    user_id: i64,
+) -> Result<Option<i64>, AppError> {
     // Remove the user from the conversation
     let query = sqlx::query!(
         "DELETE FROM user_conversations WHERE user_id = ? and conversation_id = ?",
         user_id,
         conversation_id
     )
-    .execute(pool)
+    .execute(&mut *tx)
     .await?;
 
     if query.rows_affected() == 0 {
@@ -1799,25 +3280,336 @@ async fn leave_conversation(
         "SELECT COUNT(*) FROM user_conversations WHERE conversation_id = ?",
         conversation_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
     // Check if the conversation has no users left and delete it if it does
-    if remaining_users == 0 {
+    let new_owner = if remaining_users == 0 {
         sqlx::query!("DELETE FROM conversations WHERE id = ?", conversation_id)
-            .execute(pool)
+            .execute(&mut *tx)
             .await?;
+        None
+    } else {
+        // Hand the conversation over to someone else if the owner left
+        ensure_owner(&mut *tx, conversation_id).await?
+    };
+
+    Ok(new_owner)
+}
+
+/// Let the rest of a conversation know that a user left it, and who owns it now if that changed
+pub(crate) async fn announce_leave(
+    state: &AppState,
+    conversation_id: i64,
+    user_id: i64,
+    new_owner: Option<i64>,
+) -> Result<(), AppError> {
+    // Sending only fails if nothing is listening, which is fine
+    let _ = state.conversation_leaves.send(ConversationLeave {
+        conversation_id,
+        user_id,
+    });
+
+    // Broadcast the user leaving the conversation to all the remaining users in the conversation
+    broadcast_event(
+        state,
+        SocketResponse::LeaveEvent {
+            conversation_id,
+            user_id,
+        },
+    )
+    .await?;
+
+    if let Some(owner_id) = new_owner {
+        broadcast_event(
+            state,
+            SocketResponse::OwnershipEvent {
+                conversation_id,
+                previous_owner_id: user_id,
+                owner_id,
+            },
+        )
+        .await?;
     }
+    Ok(())
+}
 
+/// Count the messages and members of a conversation the user is in
+async fn get_conversation_stats(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    user: &UserToken,
+) -> Result<ConversationStats, AppError> {
+    if sqlx::query!(
+        "SELECT user_id FROM user_conversations WHERE conversation_id = ? and user_id = ?",
+        conversation_id,
+        user.id
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "User is not in the conversation".into(),
+        )));
+    }
+    Ok(sqlx::query_as!(
+        ConversationStats,
+        r#"SELECT
+            ? as "conversation_id!: i64",
+            (SELECT COUNT(*) FROM messages WHERE conversation_id = ?1) as "message_count!: i64",
+            (SELECT COUNT(*) FROM user_conversations WHERE conversation_id = ?1) as "member_count!: i64",
+            (SELECT MIN(created_at) FROM messages WHERE conversation_id = ?1) as "first_message_at: NaiveDateTime",
+            (SELECT MAX(created_at) FROM messages WHERE conversation_id = ?1) as "last_message_at: NaiveDateTime""#,
+        conversation_id
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Get the role of a user in a conversation
+/// Errors if the user is not in the conversation
+async fn get_role(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    user_id: i64,
+) -> Result<ConversationRole, AppError> {
+    match sqlx::query_scalar!(
+        "SELECT role FROM user_conversations WHERE conversation_id = ? and user_id = ?",
+        conversation_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        Some(role) => Ok(role.into()),
+        None => Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "User is not in the conversation".into(),
+        ))),
+    }
+}
+
+/// Remove another user from a conversation
+/// Owners can remove anyone, admins can only remove members
+async fn remove_user(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    user_id: i64,
+    user: &UserToken,
+) -> Result<(), AppError> {
+    if user_id == user.id {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Leave the conversation instead of removing yourself".into(),
+        )));
+    }
+    let role = get_role(pool, conversation_id, user.id).await?;
+    if !role.is_admin() {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Only owners and admins can remove users".into(),
+        )));
+    }
+    // Checking the removed user's role in the same query means
+    // they can't be promoted to admin between checking and removing them
+    let removed = sqlx::query!(
+        "DELETE FROM user_conversations
+        WHERE conversation_id = ? AND user_id = ? AND (role = 'member' OR ? = 'owner')",
+        conversation_id,
+        user_id,
+        role
+    )
+    .execute(pool)
+    .await?;
+    if removed.rows_affected() == 0 {
+        return Err(match get_role(pool, conversation_id, user_id).await {
+            Ok(_) => AppError::UserError((
+                StatusCode::FORBIDDEN,
+                "Admins can only remove members".into(),
+            )),
+            Err(_) => AppError::UserError((
+                StatusCode::NOT_FOUND,
+                "User is not in the conversation".into(),
+            )),
+        });
+    }
+    Ok(())
+}
+
+/// Make a member of the conversation an admin or a regular member
+/// Only the owner can change roles
+async fn set_role(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    user_id: i64,
+    role: ConversationRole,
+    user: &UserToken,
+) -> Result<(), AppError> {
+    if role == ConversationRole::Owner {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Ownership of a conversation can't be given away".into(),
+        )));
+    }
+    if get_role(pool, conversation_id, user.id).await? != ConversationRole::Owner {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Only the owner can change roles".into(),
+        )));
+    }
+    if user_id == user.id {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "The owner's role can't be changed".into(),
+        )));
+    }
+    if sqlx::query!(
+        "UPDATE user_conversations SET role = ? WHERE conversation_id = ? AND user_id = ?",
+        role,
+        conversation_id,
+        user_id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User is not in the conversation".into(),
+        )));
+    }
+    Ok(())
+}
+
+/// Choose whether only owners and admins can rename a conversation
+async fn restrict_renaming(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    admins_only: bool,
+    user: &UserToken,
+) -> Result<(), AppError> {
+    if !get_role(pool, conversation_id, user.id).await?.is_admin() {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Only owners and admins can change who can rename the conversation".into(),
+        )));
+    }
+    sqlx::query!(
+        "UPDATE conversations SET admins_only_rename = ? WHERE id = ?",
+        admins_only,
+        conversation_id
+    )
+    .execute(pool)
+    .await?;
     Ok(())
 }
 
 /// Renames a conversation
+/// Only owners and admins can rename it if the conversation restricts renaming
 async fn rename_conversation(
     pool: &SqlitePool,
     conversation_id: i64,
     name: &Option<String>,
     user: &UserToken,
+) -> Result<(), AppError> {
+    let role = get_role(pool, conversation_id, user.id).await?;
+    if !role.is_admin()
+        && sqlx::query_scalar!(
+            "SELECT admins_only_rename FROM conversations WHERE id = ?",
+            conversation_id
+        )
+        .fetch_one(pool)
+        .await?
+    {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Only owners and admins can rename the conversation".into(),
+        )));
+    }
+    sqlx::query!(
+        "UPDATE conversations SET title = ? WHERE id = ?",
+        name,
+        conversation_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Mute or unmute a conversation for the logged in user
+async fn mute_conversation(
+    state: &AppState,
+    conversation_id: i64,
+    muted: bool,
+    user: &UserToken,
+) -> Result<(), AppError> {
+    if sqlx::query!(
+        "UPDATE user_conversations SET muted = ? WHERE conversation_id = ? AND user_id = ?",
+        muted,
+        conversation_id,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "User is not in the conversation".into(),
+        )));
+    }
+
+    // Let the user's other devices know
+    send_to_user(
+        state,
+        user.id,
+        SocketResponse::MuteEvent {
+            conversation_id,
+            muted,
+        },
+    )
+    .await
+}
+
+/// Change the persona the AI uses in a conversation
+async fn set_persona(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    persona_id: Option<i64>,
+    user: &UserToken,
+) -> Result<(), AppError> {
+    ensure_member(pool, conversation_id, user).await?;
+    if let Some(persona_id) = persona_id {
+        if sqlx::query!("SELECT id FROM personas WHERE id = ?", persona_id)
+            .fetch_optional(pool)
+            .await?
+            .is_none()
+        {
+            return Err(AppError::UserError((
+                StatusCode::NOT_FOUND,
+                "Persona not found".into(),
+            )));
+        }
+    }
+    sqlx::query!(
+        "UPDATE conversations SET persona_id = ? WHERE id = ?",
+        persona_id,
+        conversation_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Check that the user is in the conversation
+async fn ensure_member(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    user: &UserToken,
 ) -> Result<(), AppError> {
     if sqlx::query!(
         "SELECT user_id FROM user_conversations WHERE conversation_id = ? and user_id = ?",
@@ -1833,10 +3625,49 @@ async fn rename_conversation(
             "User is not in the conversation".into(),
         )));
     }
+    Ok(())
+}
+
+/// Replace the system prompt and generation parameters the AI uses in a conversation.
+/// Only owners and admins can change them since they apply to every member
+pub async fn set_generation_settings(
+    pool: &SqlitePool,
+    settings: &GenerationSettings,
+    user: &UserToken,
+) -> Result<(), AppError> {
+    settings.app_validate()?;
+    if !get_role(pool, settings.conversation_id, user.id)
+        .await?
+        .is_admin()
+    {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Only owners and admins can change the generation settings".into(),
+        )));
+    }
+    if let Some(max_tokens) = settings.max_tokens {
+        // None if there are no models to compare against
+        let limit = sqlx::query_scalar!(r#"SELECT MAX(max_tokens) as "limit: i64" FROM ai_models"#)
+            .fetch_one(pool)
+            .await?;
+        if let Some(limit) = limit.filter(|limit| max_tokens > *limit) {
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                format!("Max tokens can't be more than {}", limit).into(),
+            )));
+        }
+    }
     sqlx::query!(
-        "UPDATE conversations SET title = ? WHERE id = ?",
-        name,
-        conversation_id
+        "INSERT INTO conversation_settings (conversation_id, system_prompt, temperature, max_tokens, top_p)
+        VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT (conversation_id) DO UPDATE SET system_prompt = excluded.system_prompt,
+        temperature = excluded.temperature, max_tokens = excluded.max_tokens,
+        top_p = excluded.top_p, modified_at = CURRENT_TIMESTAMP",
+        settings.conversation_id,
+        settings.system_prompt,
+        settings.temperature,
+        settings.max_tokens,
+        settings.top_p
     )
     .execute(pool)
     .await?;
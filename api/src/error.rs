@@ -1,5 +1,8 @@
 use core::fmt;
-use std::fmt::{Display, Formatter};
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
 
 use axum::{
     async_trait,
@@ -16,7 +19,7 @@ use serde::{de::DeserializeOwned, Serialize};
 use tracing::{error, warn};
 use validator::Validate;
 
-use crate::auth::JwtError;
+use crate::{auth::JwtError, bans::Ban};
 
 /// Error that wraps `anyhow::Error`.
 /// Useful to provide more fine grained error handling in our application.
@@ -27,7 +30,13 @@ pub enum AppError {
     SerdeError(sonic_rs::Error),
     ValidationError(Vec<AppValidationError>),
     AuthError(anyhow::Error),
+    /// The request's token was missing or rejected
+    Jwt(JwtError),
     UserError((StatusCode, Box<str>)),
+    /// Too many requests were made, contains how long until the client can try again
+    RateLimited(Duration),
+    /// The user has been banned by an admin
+    Banned(Ban),
     Generic(anyhow::Error),
 }
 
@@ -38,6 +47,12 @@ pub enum AppError {
 pub struct ErrorResponse {
     error_type: String,
     message: String,
+    /// The reason for and expiry of the ban if the user is banned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ban: Option<Ban>,
+    /// When the token expired as a unix timestamp if it was rejected for being expired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expired_at: Option<i64>,
 }
 
 impl From<AppError> for ErrorResponse {
@@ -45,16 +60,15 @@ impl From<AppError> for ErrorResponse {
         ErrorResponse {
             error_type: value.r#type(),
             message: value.to_string(),
+            ban: value.ban().cloned(),
+            expired_at: value.expired_at(),
         }
     }
 }
 
 impl From<JwtError> for ErrorResponse {
     fn from(value: JwtError) -> Self {
-        ErrorResponse {
-            error_type: "AuthError".to_owned(),
-            message: value.to_string(),
-        }
+        AppError::Jwt(value).into()
     }
 }
 
@@ -109,9 +123,12 @@ impl IntoResponse for AppError {
         match self {
             AppError::JsonRejection(_)
             | AppError::AuthError(_)
+            | AppError::Jwt(_)
             | AppError::SerdeError(_)
             | AppError::ValidationError(_)
-            | AppError::UserError(_) => warn!("{}", self),
+            | AppError::UserError(_)
+            | AppError::RateLimited(_)
+            | AppError::Banned(_) => warn!("{}", self),
             AppError::SqlxError(_) | AppError::Generic(_) => error!("{}", self),
         }
         let (status, message) = match &self {
@@ -121,7 +138,10 @@ impl IntoResponse for AppError {
             }
             AppError::SerdeError(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             AppError::AuthError(e) => (StatusCode::UNAUTHORIZED, e.to_string()),
+            AppError::Jwt(e) => (e.status(), e.to_string()),
             AppError::UserError((code, e)) => (*code, e.to_string()),
+            AppError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
+            AppError::Banned(_) => (StatusCode::FORBIDDEN, self.to_string()),
             AppError::SqlxError(_) | AppError::Generic(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Internal Server Error".to_owned(),
@@ -130,9 +150,12 @@ impl IntoResponse for AppError {
         // Return a JSON response with the error type and message.
         (
             status,
+            self.headers(),
             AppJson(ErrorResponse {
                 error_type: self.r#type(),
                 message,
+                ban: self.ban().cloned(),
+                expired_at: self.expired_at(),
             }),
         )
             .into_response()
@@ -140,6 +163,31 @@ impl IntoResponse for AppError {
 }
 
 impl AppError {
+    /// Extra headers to send with the error response, such as when the client can retry
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let AppError::RateLimited(retry_after) = self {
+            headers.insert(header::RETRY_AFTER, retry_after_secs(*retry_after).into());
+        }
+        headers
+    }
+
+    /// The ban that caused the error, if it was caused by one
+    pub fn ban(&self) -> Option<&Ban> {
+        match self {
+            AppError::Banned(ban) | AppError::Jwt(JwtError::Banned(ban)) => Some(ban),
+            _ => None,
+        }
+    }
+
+    /// When the token expired, if the error was caused by an expired token
+    pub fn expired_at(&self) -> Option<i64> {
+        match self {
+            AppError::Jwt(JwtError::Expired { expired_at }) => Some(*expired_at),
+            _ => None,
+        }
+    }
+
     /// Get the error type as a string to notify the client of what went wrong
     pub fn r#type(&self) -> String {
         match self {
@@ -147,9 +195,12 @@ impl AppError {
             AppError::ValidationError(_) => "ValidationError".to_owned(),
             AppError::SerdeError(_) => "SerdeError".to_owned(),
             AppError::AuthError(_) => "AuthError".to_owned(),
+            AppError::Jwt(e) => e.r#type(),
             AppError::SqlxError(_) => "SqlxError".to_owned(),
             AppError::Generic(_) => "Generic".to_owned(),
             AppError::UserError(_) => "User".to_owned(),
+            AppError::RateLimited(_) => "RateLimited".to_owned(),
+            AppError::Banned(_) => "Banned".to_owned(),
         }
     }
 }
@@ -162,13 +213,29 @@ impl Display for AppError {
             AppError::SerdeError(e) => write!(f, "{}", e),
             AppError::ValidationError(e) => write!(f, "{}", sonic_rs::to_string(&e).unwrap()),
             AppError::AuthError(e) => write!(f, "{}", e),
+            AppError::Jwt(e) => write!(f, "{}", e),
             AppError::SqlxError(e) => write!(f, "{}", e),
             AppError::Generic(err) => write!(f, "{}", err),
             AppError::UserError((_, err)) => write!(f, "{}", err),
+            AppError::RateLimited(retry_after) => write!(
+                f,
+                "Too many requests, try again in {} seconds",
+                retry_after_secs(*retry_after)
+            ),
+            AppError::Banned(Ban {
+                expires_at: Some(expires_at),
+                ..
+            }) => write!(f, "You are banned until {} UTC", expires_at),
+            AppError::Banned(_) => write!(f, "You are banned"),
         }
     }
 }
 
+/// Round up so clients never retry too early
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
 // Implement `From` for `AppError` to implicitly convert from `anyhow::Error`
 // This lets us use `?` without having to wrap every error in `AppError` because the compiler will
 // authomatically convert it for us.
@@ -183,13 +250,13 @@ where
         // We don't need to add `AuthError` or `ValidationError` because we will handle those
         // explicitly in our application.
         if err.downcast_ref::<JsonRejection>().is_some() {
-            return Self::JsonRejection(err.downcast().unwrap());
+            Self::JsonRejection(err.downcast().unwrap())
         } else if err.downcast_ref::<sqlx::Error>().is_some() {
-            return Self::SqlxError(err.downcast().unwrap());
+            Self::SqlxError(err.downcast().unwrap())
         } else if err.downcast_ref::<sonic_rs::Error>().is_some() {
-            return Self::SerdeError(err.downcast().unwrap());
+            Self::SerdeError(err.downcast().unwrap())
         } else {
-            return Self::Generic(err);
+            Self::Generic(err)
         }
     }
 }
@@ -235,7 +302,7 @@ fn json_content_type(headers: &HeaderMap) -> bool {
     };
 
     let is_json_content_type = mime.type_() == "application"
-        && (mime.subtype() == "json" || mime.suffix().map_or(false, |name| name == "json"));
+        && (mime.subtype() == "json" || mime.suffix().is_some_and(|name| name == "json"));
 
     is_json_content_type
 }
@@ -0,0 +1,411 @@
+use std::io;
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::NaiveDateTime;
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tracing::error;
+
+use crate::{
+    audit::{AuditAction, AuditLogEntry},
+    auth::JwtAuth,
+    chat::{ChatMessage, ConversationRole},
+    error::AppError,
+    forms::HealthForm,
+    recovery::RecoveryCodeStatus,
+    users::{Settings, UserToken},
+};
+
+/// Rows are collected into chunks of about this many bytes before being sent
+/// so the export isn't sent to the client one row at a time
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportProfile {
+    id: i64,
+    username: String,
+    email: String,
+    first_name: String,
+    last_name: Option<String>,
+    bio: Option<String>,
+    pronouns: Option<String>,
+    location: Option<String>,
+    image_path: Option<String>,
+    created_at: NaiveDateTime,
+    last_seen_at: Option<NaiveDateTime>,
+}
+
+/// A conversation the user is in, along with their membership in it
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportConversation {
+    id: i64,
+    title: Option<String>,
+    created_at: NaiveDateTime,
+    joined_at: NaiveDateTime,
+    role: ConversationRole,
+    last_read_at: Option<NaiveDateTime>,
+    muted: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportFriend {
+    user_id: i64,
+    username: String,
+    created_at: NaiveDateTime,
+}
+
+/// A file the user uploaded, the file itself can be downloaded from its path
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportFile {
+    id: i64,
+    path: String,
+    mime: Option<String>,
+    uploaded_at: NaiveDateTime,
+}
+
+/// The user's ban, kept even after it expires.
+/// The admin who banned the user is left out
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportBan {
+    reason: Option<String>,
+    expires_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+/// A message the user pinned, which may have been sent by someone else
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportPin {
+    message_id: i64,
+    conversation_id: i64,
+    pinned_at: NaiveDateTime,
+}
+
+/// The tokens the AI used answering the user in a conversation during a month
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportAiUsage {
+    /// None once the conversation is deleted
+    conversation_id: Option<i64>,
+    month: String,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+}
+
+/// The AI's summary of a conversation the user is in
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportSummary {
+    conversation_id: i64,
+    summary: String,
+    last_message_id: i64,
+    created_at: NaiveDateTime,
+}
+
+/// What is deliberately left out of the export, listed in the export itself
+/// so the user can tell it apart from data that was lost
+const EXCLUDED: &[&str] = &[
+    "passwordHash",
+    "recoveryCodeHashes",
+    "apiKeys",
+    "sessions",
+    "linkedOAuthAccounts",
+    "otherUsersMessages",
+];
+
+/// Writes the export as JSON to the response body in chunks
+struct ExportWriter {
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    buf: BytesMut,
+}
+
+impl ExportWriter {
+    fn raw(&mut self, json: &str) {
+        self.buf.put_slice(json.as_bytes());
+    }
+
+    fn value<T: Serialize>(&mut self, value: &T) -> Result<(), AppError> {
+        self.buf.put_slice(&sonic_rs::to_vec(value)?);
+        Ok(())
+    }
+
+    /// Write a field of the export object whose value is a single row
+    fn field<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), AppError> {
+        self.raw(&format!(",\"{key}\":"));
+        self.value(value)
+    }
+
+    /// Write a field of the export object whose value is an array of every row in the stream
+    async fn array<T: Serialize>(
+        &mut self,
+        key: &str,
+        rows: impl Stream<Item = Result<T, sqlx::Error>>,
+    ) -> Result<(), AppError> {
+        self.raw(&format!(",\"{key}\":["));
+        let mut rows = std::pin::pin!(rows);
+        let mut first = true;
+        while let Some(row) = rows.next().await {
+            if !first {
+                self.raw(",");
+            }
+            first = false;
+            self.value(&row?)?;
+            if self.buf.len() >= CHUNK_SIZE {
+                self.flush().await?;
+            }
+        }
+        self.raw("]");
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), AppError> {
+        self.tx
+            .send(Ok(self.buf.split().freeze()))
+            .await
+            .map_err(|_| anyhow!("The client stopped receiving the export"))?;
+        Ok(())
+    }
+}
+
+/// Download everything stored about the logged in user as a single JSON document.
+/// The document is streamed as it is read from the database so large accounts
+/// don't have to fit in memory, and is compressed along with every other response.
+/// Only the user's own messages are included, not the rest of their group conversations.
+/// Secrets such as the password hash, recovery codes, and API keys are never exported,
+/// the `excluded` field of the export lists everything that is left out
+pub async fn export_account(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let mut writer = ExportWriter {
+            tx,
+            buf: BytesMut::with_capacity(CHUNK_SIZE),
+        };
+        if let Err(e) = write_export(&pool, user.id, &mut writer).await {
+            if writer.tx.is_closed() {
+                return;
+            }
+            error!("Failed to export the account of user {}: {}", user.id, e);
+            // Break the response so the client can't mistake a partial export for a complete one
+            let _ = writer
+                .tx
+                .send(Err(io::Error::other("Failed to export account")))
+                .await;
+        }
+    });
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"account_export.json\"",
+            ),
+        ],
+        Body::from_stream(rx),
+    )
+        .into_response())
+}
+
+async fn write_export(
+    pool: &SqlitePool,
+    user_id: i64,
+    writer: &mut ExportWriter,
+) -> Result<(), AppError> {
+    writer.raw("{\"exportedAt\":");
+    writer.value(&chrono::Utc::now().naive_utc())?;
+
+    let profile = sqlx::query_as!(
+        ExportProfile,
+        "SELECT users.id, username, email, first_name, last_name, bio, pronouns, location,
+        path as image_path, users.created_at, last_seen_at FROM users
+        LEFT JOIN files ON files.id = users.image_id
+        WHERE users.id = ?",
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    writer.field("profile", &profile)?;
+
+    let settings = sqlx::query_as!(
+        Settings,
+        "SELECT ai_enabled, ai_model_id, theme, hide_last_seen, timezone, locale,
+        message_notifications, friend_request_notifications, friend_request_policy, unit_system, settings_version
+        FROM user_settings WHERE user_id = ?",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    writer.field("settings", &settings)?;
+
+    writer
+        .array(
+            "healthForms",
+            sqlx::query_as!(
+                HealthForm,
+                "SELECT * FROM user_statistics WHERE user_id = ? ORDER BY created_at ASC",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer
+        .array(
+            "conversations",
+            sqlx::query_as!(
+                ExportConversation,
+                "SELECT id, title, created_at, joined_at, role, last_read_at, muted
+                FROM conversations
+                JOIN user_conversations ON user_conversations.conversation_id = conversations.id
+                WHERE user_id = ?
+                ORDER BY id",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer
+        .array(
+            "messages",
+            sqlx::query_as!(
+                ChatMessage,
+                "SELECT * FROM chat_messages WHERE user_id = ? ORDER BY id",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer
+        .array(
+            "friends",
+            sqlx::query_as!(
+                ExportFriend,
+                "SELECT users.id as user_id, username, friendships.created_at FROM friendships
+                JOIN users ON users.id = IIF(user1_id = ?1, user2_id, user1_id)
+                WHERE user1_id = ?1 OR user2_id = ?1
+                ORDER BY friendships.created_at",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer
+        .array(
+            "files",
+            sqlx::query_as!(
+                ExportFile,
+                "SELECT files.id, path, mime, file_uploads.created_at as uploaded_at FROM file_uploads
+                JOIN files ON files.id = file_uploads.file_id
+                WHERE user_id = ?
+                ORDER BY file_uploads.created_at",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer
+        .array(
+            "auditLog",
+            sqlx::query_as!(
+                AuditLogEntry,
+                r#"SELECT id, user_id, actor_id, action as "action: AuditAction", old_value, new_value,
+                ip, created_at FROM account_audit_log
+                WHERE user_id = ?
+                ORDER BY created_at, id"#,
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    let ban = sqlx::query_as!(
+        ExportBan,
+        "SELECT reason, expires_at, created_at FROM bans WHERE user_id = ?",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    writer.field("ban", &ban)?;
+
+    // Only how many codes are left, the codes themselves can't be recovered from their hashes
+    let recovery_codes = sqlx::query_as!(
+        RecoveryCodeStatus,
+        r#"SELECT COUNT(*) - COUNT(used_at) as "remaining!: i64",
+        MIN(created_at) as "generated_at: NaiveDateTime"
+        FROM recovery_codes WHERE user_id = ?"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    writer.field("recoveryCodes", &recovery_codes)?;
+
+    writer
+        .array(
+            "pins",
+            sqlx::query_as!(
+                ExportPin,
+                "SELECT message_id, conversation_id, pinned_at FROM pinned_messages
+                WHERE pinned_by = ?
+                ORDER BY pinned_at",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer
+        .array(
+            "aiUsage",
+            sqlx::query_as!(
+                ExportAiUsage,
+                "SELECT conversation_id, month, prompt_tokens, completion_tokens FROM ai_usage
+                WHERE user_id = ?
+                ORDER BY month, conversation_id",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer
+        .array(
+            "conversationSummaries",
+            sqlx::query_as!(
+                ExportSummary,
+                "SELECT conversation_summaries.conversation_id, summary, last_message_id,
+                conversation_summaries.created_at FROM conversation_summaries
+                JOIN user_conversations
+                ON user_conversations.conversation_id = conversation_summaries.conversation_id
+                WHERE user_id = ?
+                ORDER BY conversation_summaries.conversation_id",
+                user_id
+            )
+            .fetch(pool),
+        )
+        .await?;
+
+    writer.field("excluded", &EXCLUDED)?;
+    writer.raw("}");
+    writer.flush().await
+}
@@ -1,25 +1,54 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     response::{IntoResponse, Response},
 };
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
 use macros::response;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::prelude::FromRow;
+use tracing::warn;
+use validator::Validate;
 
 use crate::{
     auth::JwtAuth,
-    error::{AppError, AppJson},
+    chat::{complete, AiModel},
+    error::{AppError, AppJson, AppValidate},
     state::AppState,
-    users::UserToken,
+    users::{unit_system, UnitSystem, UserToken},
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Validate)]
 #[serde(rename_all = "camelCase")]
 pub struct HealthForm {
+    /// In centimeters
+    #[validate(range(
+        exclusive_min = 0.0,
+        max = 300.0,
+        code = "Height must be more than 0 and at most 300 cm"
+    ))]
     pub height: Option<f64>,
+    /// In kilograms
+    #[validate(range(
+        exclusive_min = 0.0,
+        max = 700.0,
+        code = "Weight must be more than 0 and at most 700 kg"
+    ))]
     pub weight: Option<f64>,
+    /// In minutes per day
+    #[validate(range(
+        min = 0.0,
+        max = 1440.0,
+        code = "Exercise duration must be between 0 and 1440 minutes"
+    ))]
     pub exercise_duration: Option<f64>,
+    /// In hours per night
+    #[validate(range(
+        min = 0.0,
+        max = 24.0,
+        code = "Sleep hours must be between 0 and 24 hours"
+    ))]
     pub sleep_hours: Option<f64>,
     pub notes: Option<String>,
     pub food_intake: Option<String>,
@@ -31,11 +60,88 @@ pub struct HealthForm {
     pub modified_at: Option<NaiveDateTime>,
 }
 
+/// Body mass index in kg/m² from a height in centimeters and a weight in kilograms,
+/// rounded to one decimal place. None unless both are known
+pub fn bmi(height: Option<f64>, weight: Option<f64>) -> Option<f64> {
+    let height = height.filter(|height| *height > 0.0)? / 100.0;
+    let bmi = weight? / (height * height);
+    Some((bmi * 10.0).round() / 10.0)
+}
+
+/// The weight category a BMI falls into for adults
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum BmiCategory {
+    Underweight,
+    Healthy,
+    Overweight,
+    Obese,
+}
+
+impl BmiCategory {
+    pub fn of(bmi: f64) -> Self {
+        match bmi {
+            _ if bmi < 18.5 => Self::Underweight,
+            _ if bmi < 25.0 => Self::Healthy,
+            _ if bmi < 30.0 => Self::Overweight,
+            _ => Self::Obese,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Underweight => "underweight",
+            Self::Healthy => "healthy",
+            Self::Overweight => "overweight",
+            Self::Obese => "obese",
+        }
+    }
+}
+
+/// A health form along with the metrics derived from it
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthFormResponse {
+    /// The height and weight are in the units of `unit_system`
+    #[serde(flatten)]
+    pub form: HealthForm,
+    /// Omitted unless the form has both a height and a weight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bmi: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bmi_category: Option<BmiCategory>,
+    pub unit_system: UnitSystem,
+}
+
+impl HealthFormResponse {
+    /// Convert a form stored in metric to the given unit system
+    pub fn new(mut form: HealthForm, unit_system: UnitSystem) -> Self {
+        // BMI is always in kg/m² so it is calculated before converting
+        let bmi = bmi(form.height, form.weight);
+        form.height = form.height.map(|height| unit_system.height(height));
+        form.weight = form.weight.map(|weight| unit_system.weight(weight));
+        Self {
+            form,
+            bmi,
+            bmi_category: bmi.map(BmiCategory::of),
+            unit_system,
+        }
+    }
+}
+
+/// Forms are submitted in metric so they are returned in metric too
+impl From<HealthForm> for HealthFormResponse {
+    fn from(form: HealthForm) -> Self {
+        Self::new(form, UnitSystem::Metric)
+    }
+}
+
 pub async fn save_health_form(
     State(state): State<AppState>,
     JwtAuth(user): JwtAuth<UserToken>,
     AppJson(form): AppJson<HealthForm>,
 ) -> Result<Response, AppError> {
+    form.app_validate()?;
     let data = sqlx::query_as!(
         HealthForm,
         "INSERT INTO user_statistics (user_id, height, weight, exercise_duration, sleep_hours, notes, food_intake)
@@ -48,6 +154,7 @@ pub async fn save_health_form(
             form.notes,
             form.food_intake
     ).fetch_one(&state.pool).await?;
+    let data = HealthFormResponse::from(data);
     Ok((
         StatusCode::CREATED,
         AppJson(response!("Form successfully created", data)),
@@ -60,31 +167,240 @@ pub async fn get_health_form(
     State(state): State<AppState>,
     JwtAuth(user): JwtAuth<UserToken>,
 ) -> Result<Response, AppError> {
-    let data = sqlx::query_as!(
+    // Order by id too so the latest form is still picked when several were saved in the same second
+    let Some(data) = sqlx::query_as!(
         HealthForm,
-        "SELECT * FROM user_statistics WHERE user_id = ? ORDER BY created_at DESC LIMIT 1",
+        "SELECT * FROM user_statistics WHERE user_id = ? ORDER BY created_at DESC, id DESC LIMIT 1",
         user.id
     )
-    .fetch_one(&state.pool)
-    .await?;
-    Ok((StatusCode::OK, AppJson(data)).into_response())
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "No health forms found".into(),
+        )));
+    };
+    let unit_system = unit_system(&state.pool, user.id).await?;
+    Ok((
+        StatusCode::OK,
+        AppJson(HealthFormResponse::new(data, unit_system)),
+    )
+        .into_response())
 }
 
-/// Get all the saved forms for the current user
+/// The maximum number of forms that can be requested at once
+const MAX_FORMS_LIMIT: i64 = 200;
+/// How many days of forms are returned if no date range is given
+const DEFAULT_FORMS_WINDOW_DAYS: i64 = 90;
+
+/// Query parameters for paginating and filtering the saved forms
+#[derive(Deserialize, Debug)]
+pub struct FormsQuery {
+    /// The number of forms to return, defaults to 50
+    pub limit: Option<i64>,
+    /// The number of forms to skip
+    pub offset: Option<i64>,
+    /// Only return forms created on or after this date
+    pub from: Option<NaiveDate>,
+    /// Only return forms created on or before this date
+    pub to: Option<NaiveDate>,
+}
+
+/// Get the saved forms for the current user, most recent first.
+/// Defaults to the forms from the last 90 days if no date range is given
 pub async fn get_forms(
     State(state): State<AppState>,
     JwtAuth(user): JwtAuth<UserToken>,
+    Query(query): Query<FormsQuery>,
 ) -> Result<Response, AppError> {
+    let limit = query.limit.unwrap_or(50).clamp(1, MAX_FORMS_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().date_naive());
+    let from = query
+        .from
+        .unwrap_or_else(|| to - chrono::Duration::days(DEFAULT_FORMS_WINDOW_DAYS));
+    if from > to {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "`from` must not be after `to`".into(),
+        )));
+    }
+    // The range includes the whole of the `to` day
+    let from = from.and_time(NaiveTime::MIN);
+    let to = (to + chrono::Duration::days(1)).and_time(NaiveTime::MIN);
+
     let data = sqlx::query_as!(
         HealthForm,
-        "SELECT * FROM user_statistics WHERE user_id = ? ORDER BY created_at DESC",
-        user.id
+        "SELECT * FROM user_statistics WHERE user_id = ? AND created_at >= ? AND created_at < ?
+        ORDER BY created_at DESC LIMIT ? OFFSET ?",
+        user.id,
+        from,
+        to,
+        limit,
+        offset
     )
     .fetch_all(&state.pool)
     .await?;
+    let unit_system = unit_system(&state.pool, user.id).await?;
+    let data: Vec<HealthFormResponse> = data
+        .into_iter()
+        .map(|form| HealthFormResponse::new(form, unit_system))
+        .collect();
     Ok((StatusCode::OK, AppJson(data)).into_response())
 }
 
+/// A health form measurement that can be charted over time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrendMetric {
+    Height,
+    Weight,
+    #[serde(alias = "sleepHours")]
+    SleepHours,
+    #[serde(alias = "exerciseDuration")]
+    ExerciseDuration,
+}
+
+impl TrendMetric {
+    /// The `user_statistics` column the metric is stored in.
+    /// Only these are ever put into the query so the column can't be injected
+    fn column(&self) -> &'static str {
+        match self {
+            Self::Height => "height",
+            Self::Weight => "weight",
+            Self::SleepHours => "sleep_hours",
+            Self::ExerciseDuration => "exercise_duration",
+        }
+    }
+
+    /// Convert a value of the metric, stored in metric, to the given unit system
+    fn convert(&self, value: f64, unit_system: UnitSystem) -> f64 {
+        match self {
+            Self::Height => unit_system.height(value),
+            Self::Weight => unit_system.weight(value),
+            Self::SleepHours | Self::ExerciseDuration => value,
+        }
+    }
+}
+
+/// How long each point in a trend covers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TrendBucket {
+    #[default]
+    Day,
+    /// Weeks start on Monday
+    Week,
+    Month,
+}
+
+impl TrendBucket {
+    /// SQL for the first day of the bucket a form falls in
+    fn start_date(&self) -> &'static str {
+        match self {
+            Self::Day => "date(created_at)",
+            // Go to the next Sunday, unless it already is one, then back to the Monday before it
+            Self::Week => "date(created_at, 'weekday 0', '-6 days')",
+            Self::Month => "date(created_at, 'start of month')",
+        }
+    }
+}
+
+/// Query parameters for a health trend
+#[derive(Deserialize, Debug)]
+pub struct TrendsQuery {
+    pub metric: TrendMetric,
+    /// Defaults to 90 days before `to`
+    pub from: Option<NaiveDate>,
+    /// Defaults to today
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub bucket: TrendBucket,
+}
+
+/// The values of a metric in one bucket of time
+#[derive(Serialize, Debug, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct TrendPoint {
+    /// The first day of the bucket
+    pub date: NaiveDate,
+    pub average: f64,
+    pub min: f64,
+    pub max: f64,
+    /// The number of forms with the metric filled in
+    pub count: i64,
+}
+
+/// A metric over time, for charting
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthTrend {
+    pub metric: TrendMetric,
+    pub bucket: TrendBucket,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// Buckets without any forms are left out.
+    /// Heights and weights are in the units of `unit_system`
+    pub points: Vec<TrendPoint>,
+    pub unit_system: UnitSystem,
+}
+
+/// Get the average, lowest, and highest values of a metric from the current user's forms,
+/// grouped into days, weeks, or months.
+/// Defaults to the last 90 days if no date range is given, the same as `get_forms`
+pub async fn get_health_trends(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Query(query): Query<TrendsQuery>,
+) -> Result<Response, AppError> {
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().date_naive());
+    let from = query
+        .from
+        .unwrap_or_else(|| to - chrono::Duration::days(DEFAULT_FORMS_WINDOW_DAYS));
+    if from > to {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "`from` must not be after `to`".into(),
+        )));
+    }
+
+    let column = query.metric.column();
+    let mut points = sqlx::query_as::<_, TrendPoint>(&format!(
+        "SELECT {start} as date, AVG({column}) as average, MIN({column}) as min,
+        MAX({column}) as max, COUNT({column}) as count
+        FROM user_statistics
+        WHERE user_id = ? AND created_at >= ? AND created_at < ? AND {column} IS NOT NULL
+        GROUP BY date ORDER BY date",
+        start = query.bucket.start_date(),
+    ))
+    .bind(user.id)
+    // The range includes the whole of the `to` day
+    .bind(from.and_time(NaiveTime::MIN))
+    .bind((to + chrono::Duration::days(1)).and_time(NaiveTime::MIN))
+    .fetch_all(&state.pool)
+    .await?;
+    let unit_system = unit_system(&state.pool, user.id).await?;
+    for point in &mut points {
+        point.average = query.metric.convert(point.average, unit_system);
+        point.min = query.metric.convert(point.min, unit_system);
+        point.max = query.metric.convert(point.max, unit_system);
+    }
+
+    Ok((
+        StatusCode::OK,
+        AppJson(HealthTrend {
+            metric: query.metric,
+            bucket: query.bucket,
+            from,
+            to,
+            points,
+            unit_system,
+        }),
+    )
+        .into_response())
+}
+
 /// Get the most recent health form for the current user
 pub async fn update_health_form(
     State(state): State<AppState>,
@@ -92,6 +408,7 @@ pub async fn update_health_form(
     Path(id): Path<i64>,
     AppJson(form): AppJson<HealthForm>,
 ) -> Result<Response, AppError> {
+    form.app_validate()?;
     let Some(row) = sqlx::query!("SELECT user_id FROM user_statistics WHERE id = ?", id)
         .fetch_optional(&state.pool)
         .await?
@@ -121,6 +438,7 @@ pub async fn update_health_form(
             user.id,
             id
     ).fetch_one(&state.pool).await?;
+    let data = HealthFormResponse::from(data);
 
     Ok((
         StatusCode::CREATED,
@@ -128,3 +446,260 @@ pub async fn update_health_form(
     )
         .into_response())
 }
+
+/// Delete one of the current user's health forms
+pub async fn delete_health_form(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(id): Path<i64>,
+) -> Result<Response, AppError> {
+    let Some(row) = sqlx::query!("SELECT user_id FROM user_statistics WHERE id = ?", id)
+        .fetch_optional(&state.pool)
+        .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Form not found".into(),
+        )));
+    };
+
+    if row.user_id != user.id {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "You do not have permission to delete this form".into(),
+        )));
+    }
+
+    sqlx::query!(
+        "DELETE FROM user_statistics WHERE user_id = ? AND id = ?",
+        user.id,
+        id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Appended to every explanation since they are not medical advice
+const EXPLANATION_DISCLAIMER: &str = "This explanation is for general information only and is not medical advice. Please consult a doctor or other qualified health professional about your results.";
+
+/// A health metric that can be explained
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthMetric {
+    /// Body mass index in kg/m²
+    Bmi,
+    /// Hours of sleep per night
+    SleepHours,
+    /// Minutes of exercise per day
+    ExerciseDuration,
+}
+
+/// The range of values that is typical for healthy adults
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferenceRange {
+    pub low: f64,
+    pub high: f64,
+}
+
+impl HealthMetric {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Bmi => "body mass index (BMI)",
+            Self::SleepHours => "sleep per night",
+            Self::ExerciseDuration => "exercise per day",
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self {
+            Self::Bmi => "kg/m²",
+            Self::SleepHours => "hours",
+            Self::ExerciseDuration => "minutes",
+        }
+    }
+
+    fn reference_range(&self) -> ReferenceRange {
+        match self {
+            Self::Bmi => ReferenceRange {
+                low: 18.5,
+                high: 24.9,
+            },
+            Self::SleepHours => ReferenceRange {
+                low: 7.0,
+                high: 9.0,
+            },
+            // 150 to 300 minutes of moderate activity a week
+            Self::ExerciseDuration => ReferenceRange {
+                low: 20.0,
+                high: 45.0,
+            },
+        }
+    }
+
+    /// The largest value that could plausibly be real, anything above is rejected
+    fn max_value(&self) -> f64 {
+        match self {
+            Self::Bmi => 200.0,
+            Self::SleepHours => 24.0,
+            Self::ExerciseDuration => 24.0 * 60.0,
+        }
+    }
+
+    /// Describe the value using the reference range, used when the AI is unavailable
+    fn reference_explanation(&self, value: f64) -> String {
+        let range = self.reference_range();
+        let assessment = match self {
+            Self::Bmi if value < range.low => "is below the healthy range and is considered underweight",
+            Self::Bmi if value < 25.0 => "is within the healthy range",
+            Self::Bmi if value < 30.0 => "is above the healthy range and is considered overweight",
+            Self::Bmi => "is in the range considered obese",
+            Self::SleepHours if value < range.low => "is less than the amount recommended for most adults",
+            Self::SleepHours if value <= range.high => "is within the amount recommended for most adults",
+            Self::SleepHours => "is more than most adults need, which can sometimes be a sign of poor sleep quality",
+            Self::ExerciseDuration if value < range.low => "is below the amount of activity recommended for most adults",
+            Self::ExerciseDuration if value <= range.high => "meets the amount of activity recommended for most adults",
+            Self::ExerciseDuration => "is more than the minimum recommended, which is generally beneficial if you avoid overtraining",
+        };
+        let caveat = match self {
+            Self::Bmi => "BMI does not account for muscle mass, age, sex, or ethnicity, so it is only a rough guide.",
+            Self::SleepHours => "How much sleep you need depends on your age and health, and sleep quality matters as much as quantity.",
+            Self::ExerciseDuration => "Recommendations assume moderate activity such as brisk walking, vigorous activity counts for roughly twice as much.",
+        };
+        format!(
+            "Your {} of {} {} {}. The typical range for adults is {} to {} {}. {}",
+            self.name(),
+            value,
+            self.unit(),
+            assessment,
+            range.low,
+            range.high,
+            self.unit(),
+            caveat
+        )
+    }
+}
+
+/// A request to explain what the value of a health metric means
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExplainMetric {
+    pub metric: HealthMetric,
+    pub value: f64,
+    /// The model used to write the explanation, defaults to the first model
+    pub ai_model_id: Option<i64>,
+}
+
+/// Where an explanation came from
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExplanationSource {
+    /// Written by the AI
+    Ai,
+    /// Filled in from the reference range because the AI was unavailable
+    Reference,
+}
+
+/// An explanation of what the value of a health metric means
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricExplanation {
+    pub metric: HealthMetric,
+    pub value: f64,
+    pub unit: &'static str,
+    pub reference_range: ReferenceRange,
+    pub explanation: String,
+    pub source: ExplanationSource,
+    pub disclaimer: &'static str,
+}
+
+/// Explain what the value of a health metric means in plain language.
+/// The explanation is written by the AI if it is available,
+/// otherwise it is filled in from the reference range for the metric
+pub async fn explain_metric(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    AppJson(request): AppJson<ExplainMetric>,
+) -> Result<Response, AppError> {
+    let metric = request.metric;
+    let value = request.value;
+    if !value.is_finite() || value <= 0.0 || value > metric.max_value() {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Value must be greater than 0 and at most {} {}",
+                metric.max_value(),
+                metric.unit()
+            )
+            .into(),
+        )));
+    }
+
+    let model = match request.ai_model_id {
+        Some(id) => {
+            let Some(model) = sqlx::query_as!(
+                AiModel,
+                "SELECT id, name, max_tokens, provider FROM ai_models WHERE id = ?",
+                id
+            )
+            .fetch_optional(&state.pool)
+            .await?
+            else {
+                return Err(AppError::UserError((
+                    StatusCode::NOT_FOUND,
+                    "AI model not found".into(),
+                )));
+            };
+            Some(model)
+        }
+        None => {
+            sqlx::query_as!(
+                AiModel,
+                "SELECT id, name, max_tokens, provider FROM ai_models ORDER BY id LIMIT 1"
+            )
+            .fetch_optional(&state.pool)
+            .await?
+        }
+    };
+
+    let range = metric.reference_range();
+    let generated = match model {
+        Some(model) => {
+            let messages = json!([
+                { "role": "system", "content": "You explain health measurements to people without medical training. In two to four sentences, say what the value means compared to the typical range for adults and mention the main limitation of the measurement. Do not diagnose, do not list possible conditions, and do not ask questions." },
+                { "role": "user", "content": format!(
+                    "My {} is {} {}. The typical range for adults is {} to {} {}.",
+                    metric.name(), value, metric.unit(), range.low, range.high, metric.unit()
+                ) },
+            ]);
+            complete(&state, &model, messages, 200, user.id, None)
+                .await
+                .inspect_err(|e| warn!("Could not explain {:?} with {}: {}", metric, model.name, e))
+                .ok()
+        }
+        None => None,
+    };
+    let (explanation, source) = match generated {
+        Some(explanation) => (explanation, ExplanationSource::Ai),
+        None => (
+            metric.reference_explanation(value),
+            ExplanationSource::Reference,
+        ),
+    };
+
+    Ok((
+        StatusCode::OK,
+        AppJson(MetricExplanation {
+            metric,
+            value,
+            unit: metric.unit(),
+            reference_range: range,
+            explanation,
+            source,
+            disclaimer: EXPLANATION_DISCLAIMER,
+        }),
+    )
+        .into_response())
+}
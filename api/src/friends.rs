@@ -0,0 +1,249 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::JwtAuth,
+    chat::{get_user_status, handle_friend_request, FriendRequestStatus},
+    error::{AppError, AppJson},
+    state::AppState,
+    users::{default_true, visible_status, PublicUser, UserToken},
+    utils::Requested,
+};
+
+/// A friend of the user, the same as the `FriendData` websocket response
+/// along with the friend's profile
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Friend {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub user: PublicUser,
+}
+
+/// A pending friend request sent or received by the user, the same as the `FriendRequest`
+/// websocket response along with the profile of the other user
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingFriendRequest {
+    pub sender_id: i64,
+    pub receiver_id: i64,
+    pub created_at: NaiveDateTime,
+    pub status: FriendRequestStatus,
+    pub user: PublicUser,
+}
+
+/// The columns of the user on the other side of a friendship or friend request
+struct FriendRow {
+    id: i64,
+    username: String,
+    first_name: String,
+    last_name: Option<String>,
+    image_path: Option<String>,
+    bio: Option<String>,
+    pronouns: Option<String>,
+    location: Option<String>,
+    created_at: NaiveDateTime,
+    sender_id: i64,
+    receiver_id: i64,
+}
+
+impl FriendRow {
+    async fn public_user(&self, state: &AppState) -> PublicUser {
+        PublicUser {
+            id: self.id,
+            username: self.username.clone(),
+            first_name: self.first_name.clone(),
+            last_name: self.last_name.clone(),
+            image_path: self.image_path.clone(),
+            bio: self.bio.clone(),
+            pronouns: self.pronouns.clone(),
+            location: self.location.clone(),
+            status: Requested::Loaded(get_user_status(state, self.id).await),
+        }
+    }
+}
+
+/// List the friends of the logged in user
+/// The REST equivalent of the `RequestFriends` websocket request
+pub async fn get_friends(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    let rows = sqlx::query_as!(
+        FriendRow,
+        r#"SELECT users.id, username, first_name, last_name, path as "image_path?",
+        bio, pronouns, location, friendships.created_at,
+        user1_id as sender_id, user2_id as receiver_id
+        FROM friendships
+        JOIN users ON users.id = IIF(user1_id = ?1, user2_id, user1_id)
+        LEFT JOIN files ON files.id = users.image_id
+        WHERE user1_id = ?1 OR user2_id = ?1
+        ORDER BY friendships.created_at"#,
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut friends = Vec::with_capacity(rows.len());
+    for row in rows {
+        friends.push(Friend {
+            id: row.id,
+            created_at: row.created_at,
+            user: row.public_user(&state).await,
+        });
+    }
+    Ok((StatusCode::OK, AppJson(friends)).into_response())
+}
+
+/// List the pending friend requests the logged in user has sent or received
+/// The REST equivalent of the `RequestFriendRequests` websocket request
+pub async fn get_friend_requests(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    let rows = sqlx::query_as!(
+        FriendRow,
+        r#"SELECT users.id, username, first_name, last_name, path as "image_path?",
+        bio, pronouns, location, friend_requests.created_at, sender_id, receiver_id
+        FROM friend_requests
+        JOIN users ON users.id = IIF(sender_id = ?1, receiver_id, sender_id)
+        LEFT JOIN files ON files.id = users.image_id
+        WHERE sender_id = ?1 OR receiver_id = ?1
+        ORDER BY friend_requests.created_at"#,
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut requests = Vec::with_capacity(rows.len());
+    for row in rows {
+        requests.push(PendingFriendRequest {
+            sender_id: row.sender_id,
+            receiver_id: row.receiver_id,
+            created_at: row.created_at,
+            status: FriendRequestStatus::Pending,
+            user: row.public_user(&state).await,
+        });
+    }
+    Ok((StatusCode::OK, AppJson(requests)).into_response())
+}
+
+/// A friend request to send or answer, the same as the `SendFriendRequest` websocket request
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FriendRequestAction {
+    /// The id of the user involved in the friend request
+    pub other_user_id: i64,
+    /// Send or accept a friend request if true, reject or revoke it if false
+    #[serde(default = "default_true")]
+    pub accept: bool,
+}
+
+/// Send a friend request to another user, or accept theirs if they already sent one
+/// The REST equivalent of the `SendFriendRequest` websocket request
+pub async fn send_friend_request(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    AppJson(action): AppJson<FriendRequestAction>,
+) -> Result<Response, AppError> {
+    let friend_request =
+        handle_friend_request(&state, action.other_user_id, action.accept, &user).await?;
+    let status = match friend_request.status {
+        FriendRequestStatus::Pending => StatusCode::CREATED,
+        FriendRequestStatus::Accepted | FriendRequestStatus::Rejected => StatusCode::OK,
+    };
+    Ok((status, AppJson(friend_request)).into_response())
+}
+
+/// Reject a friend request from another user, or revoke one sent to them
+pub async fn delete_friend_request(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(user_id): Path<i64>,
+) -> Result<Response, AppError> {
+    let friend_request = handle_friend_request(&state, user_id, false, &user).await?;
+    Ok((StatusCode::OK, AppJson(friend_request)).into_response())
+}
+
+/// Query parameters for limiting the mutual friends returned
+#[derive(Deserialize, Debug)]
+pub struct MutualFriendsQuery {
+    /// The number of friends to return, defaults to 25 and is capped by `--max-mutual-friends`
+    pub limit: Option<u32>,
+}
+
+/// List the friends the logged in user has in common with another user, ordered by username.
+/// Users blocked by or blocking the logged in user are left out,
+/// and nothing is returned if either user blocked the other
+pub async fn get_mutual_friends(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(id): Path<i64>,
+    Query(query): Query<MutualFriendsQuery>,
+) -> Result<Response, AppError> {
+    let limit = query.limit.unwrap_or(25).clamp(1, state.max_mutual_friends);
+    if sqlx::query_scalar!(
+        "SELECT id FROM users WHERE id = ? AND deleted_at IS NULL",
+        id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User not found".into(),
+        )));
+    }
+
+    // Friendships are stored once with the lower id as `user1_id`,
+    // so the friend is whichever side isn't the user
+    let rows = sqlx::query!(
+        r#"WITH user_friends AS (
+            SELECT IIF(user1_id = ?1, user2_id, user1_id) as id FROM friendships
+            WHERE user1_id = ?1 OR user2_id = ?1
+        ), other_friends AS (
+            SELECT IIF(user1_id = ?2, user2_id, user1_id) as id FROM friendships
+            WHERE user1_id = ?2 OR user2_id = ?2
+        )
+        SELECT users.id, username, first_name, last_name, path as "image_path?",
+        bio, pronouns, location, COALESCE(hide_last_seen, FALSE) as "hide_last_seen!: bool"
+        FROM users
+        JOIN user_friends ON user_friends.id = users.id
+        JOIN other_friends ON other_friends.id = users.id
+        LEFT JOIN files ON files.id = users.image_id
+        LEFT JOIN user_settings ON user_settings.user_id = users.id
+        WHERE deleted_at IS NULL
+        AND NOT EXISTS (SELECT 1 FROM blocked_users
+            WHERE (blocker_id = ?1 AND blocked_id IN (?2, users.id))
+            OR (blocked_id = ?1 AND blocker_id IN (?2, users.id)))
+        ORDER BY username
+        LIMIT ?3"#,
+        user.id,
+        id,
+        limit
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut friends = Vec::with_capacity(rows.len());
+    for row in rows {
+        friends.push(PublicUser {
+            id: row.id,
+            username: row.username,
+            first_name: row.first_name,
+            last_name: row.last_name,
+            image_path: row.image_path,
+            bio: row.bio,
+            pronouns: row.pronouns,
+            location: row.location,
+            status: Requested::Loaded(visible_status(&state, row.id, row.hide_last_seen).await),
+        });
+    }
+    Ok((StatusCode::OK, AppJson(friends)).into_response())
+}
@@ -1,4 +1,8 @@
+/// Contains the audit log of sensitive changes made to accounts.
+pub mod audit;
 pub mod auth;
+/// Contains the admin routes for banning users and the checks that keep banned users out.
+pub mod bans;
 /// Contains the logic for the chat side of the application. Including the routes for creating a
 /// conversation, getting a conversation, and connecting to a websocket for chatting.
 pub mod chat;
@@ -6,11 +10,23 @@ pub mod chat;
 pub mod cli;
 /// Contains the error type and error handling logic for the application.
 pub mod error;
+/// Contains the logic for exporting all of a user's data.
+pub mod export;
 /// Contains logic for processing user forms saving them to the database as statistics.
 pub mod forms;
+/// Contains the REST routes for a user's friends and friend requests.
+pub mod friends;
+/// Contains the mailers used to send emails to users, such as password reset tokens.
+pub mod mail;
+/// Contains the logic for logging in with OAuth providers such as Google and GitHub.
+pub mod oauth;
+/// Contains the single use recovery codes users can log in with when they lose their password.
+pub mod recovery;
 pub mod report;
 /// Contains the state of the application that is shared across all routes.
 pub mod state;
+/// Contains the storage uploaded files are kept in, either on disk or in an S3 compatible bucket.
+pub mod storage;
 /// Contains logic for uploading files to the server.
 pub mod upload;
 /// Contains the logic for the users side of the application. Including the routes for creating a
@@ -20,14 +36,26 @@ pub mod users;
 pub mod utils;
 
 use anyhow::Result;
+use audit::{get_account_audit_log, get_audit_log};
 use axum::{
     extract::DefaultBodyLimit,
     http::{HeaderName, HeaderValue},
     routing::{delete, get, post, put},
     Router,
 };
-use forms::{get_forms, get_health_form, save_health_form, update_health_form};
-use report::generate_pdf_report;
+use bans::{ban_user, unban_user};
+use export::export_account;
+use forms::{
+    delete_health_form, explain_metric, get_forms, get_health_form, get_health_trends,
+    save_health_form, update_health_form,
+};
+use friends::{
+    delete_friend_request, get_friend_requests, get_friends, get_mutual_friends,
+    send_friend_request,
+};
+use oauth::{oauth_callback, oauth_exchange, oauth_start};
+use recovery::{generate_recovery_codes, get_recovery_codes};
+use report::{generate_csv_report, generate_pdf_report, generate_report};
 use reqwest::header::{self, CONTENT_ENCODING, CONTENT_LENGTH};
 use state::AppState;
 use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
@@ -41,19 +69,27 @@ use tower_http::{
     LatencyUnit, ServiceBuilderExt,
 };
 
-use chat::{create_conversation_rest, get_ai_models, get_conversation, init_ws};
+use chat::{
+    create_conversation_rest, export_conversation, get_ai_models, get_ai_usage, get_conversation,
+    get_personas, get_pinned_messages, get_thread, import_conversation, init_ws,
+};
 use cli::Args;
 use sqlx::{
     sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
-    SqlitePool,
+    ConnectOptions, Connection, SqlitePool,
 };
 use tokio::net::TcpListener;
-use tracing::{info, Level};
-use upload::{upload_file, upload_profile_image};
+use tracing::{error, info, Level};
+use upload::{
+    download_file, download_profile_image, upload_file, upload_profile_image, UPLOAD_DIR,
+};
 use users::{
-    authenticate_user, check_email, check_username, create_user, delete_user, get_settings,
-    get_user_by_id, get_user_by_username, get_user_from_token, search_users, update_settings,
-    update_user,
+    authenticate_user, change_password, check_availability, check_email, check_username,
+    confirm_email_change, create_api_key, create_user, delete_user, forgot_password, get_api_keys,
+    get_sessions, get_settings, get_unread_summary, get_user_by_id, get_user_by_username,
+    get_user_from_token, get_user_presence, get_username_history, get_users_by_ids, logout,
+    purge_deleted_accounts, resend_verification, reset_password, revoke_api_key, revoke_session,
+    search_users, update_settings, update_user, verify_email,
 };
 
 /// The name of the package. This is defined in the `Cargo.toml` file.
@@ -68,6 +104,11 @@ pub const PROTOCOL: &str = "sqlite:///";
 pub const PROTOCOL: &str = "sqlite://";
 
 pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often the server pings websocket connections to check they are still alive
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a websocket connection can go without answering a ping before it is closed.
+/// Long enough that a single late pong doesn't drop the connection
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(75);
 pub const MAX_MESSAGE_LEN: usize = 5_000;
 
 /// Start the server and listen for incoming connections.
@@ -90,6 +131,9 @@ pub async fn start_server(pool: SqlitePool, args: &Args) -> Result<()> {
             CONTENT_ENCODING,
             CONTENT_LENGTH,
             HeaderName::from_static("accept"),
+            // Paging of user search results
+            HeaderName::from_static("x-total-count"),
+            HeaderName::from_static("x-next-cursor"),
         ]);
 
     let sensitive_headers: Arc<[_]> = [header::AUTHORIZATION, header::COOKIE].into();
@@ -145,32 +189,151 @@ pub async fn start_server(pool: SqlitePool, args: &Args) -> Result<()> {
             HeaderValue::from_static("application/octet-stream"),
         );
 
+    let state = AppState::new(pool.clone(), args)?;
+
+    // Fail fast if uploads can't be stored rather than on the first upload
+    state.storage.check().await.map_err(|e| {
+        anyhow::anyhow!("Uploads must be writable by the server, check the {UPLOAD_DIR} directory or bucket: {e}")
+    })?;
+
+    // Periodically forget failed logins that have left the window
+    // so the map doesn't grow forever
+    let login_attempts = state.login_attempts.clone();
+    let prune_period = Duration::from_secs(args.login_failure_window_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(prune_period);
+        loop {
+            interval.tick().await;
+            login_attempts.prune().await;
+        }
+    });
+    let session_activity = state.session_activity.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            session_activity.prune().await;
+        }
+    });
+
+    // Permanently delete accounts once they can no longer be reactivated
+    let purge_state = state.clone();
+    let grace_days = args.account_deletion_grace_days;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match purge_deleted_accounts(&purge_state, grace_days).await {
+                Ok(0) => (),
+                Ok(purged) => info!("Purged {} deleted accounts", purged),
+                Err(e) => error!("Failed to purge deleted accounts: {}", e),
+            }
+        }
+    });
+
     let api = Router::new()
         .route("/register", post(create_user))
         // Logins users in based on the JSON data in the response body
         .route("/login", post(authenticate_user))
         // Logins users in based on the authorization header
         .route("/login", get(get_user_from_token))
+        // Redirect to an OAuth provider to log in
+        .route("/oauth/:provider/start", get(oauth_start))
+        // Finish logging in with an OAuth provider
+        .route("/oauth/:provider/callback", get(oauth_callback))
+        // Exchange the code the client was sent back with after an OAuth login for a login token
+        .route("/oauth/exchange", post(oauth_exchange))
+        // Revokes every token issued to the user
+        .route("/logout", post(logout))
+        // Verify a user's email using the token they were sent
+        .route("/verify/:token", get(verify_email))
+        // Send a new email verification token
+        .route("/verify/resend", post(resend_verification))
         .route("/users/id/:id", get(get_user_by_id))
+        // Whether a user is online and when they were last seen
+        .route("/users/:id/status", get(get_user_presence))
+        // Friends the logged in user has in common with another user
+        .route("/users/:id/friends/mutual", get(get_mutual_friends))
+        // Get multiple users at once with `?ids=1,2,3`
+        .route("/users/ids", get(get_users_by_ids))
         .route("/users/username/:username", get(get_user_by_username))
         .route("/users/search/:username", get(search_users))
+        // Manage the user's friends and friend requests without a websocket
+        .route("/friends", get(get_friends))
+        .route(
+            "/friends/requests",
+            get(get_friend_requests).post(send_friend_request),
+        )
+        .route("/friends/requests/:user_id", delete(delete_friend_request))
         .route("/check/username/:username", get(check_username))
         .route("/check/email/:email", get(check_email))
+        // Check the username and email in one request
+        .route("/check", post(check_availability))
         // Update user account data (email, username, etc.)
         .route("/account", post(update_user))
         // Delete user account
         .route("/account", delete(delete_user))
+        // Finish changing the user's email with the token sent to the new address
+        .route("/account/email/confirm/:token", get(confirm_email_change))
+        // Download all of the user's data
+        .route("/account/export", get(export_account))
+        // Count unread messages and pending friend requests for a badge
+        .route("/account/unread", get(get_unread_summary))
+        // List the usernames the user has changed away from
+        .route("/account/username-history", get(get_username_history))
         // Get user settings
         .route("/account/settings", get(get_settings))
         // Update user settings
         .route("/account/settings", post(update_settings))
+        // The log of sensitive changes made to the user's account
+        .route("/account/audit", get(get_account_audit_log))
+        // Get how many tokens the AI has used responding to the user
+        .route("/account/ai-usage", get(get_ai_usage))
+        // Change the password of the current user
+        .route("/account/password", post(change_password))
+        // Request a password reset token for a forgotten password
+        .route("/account/password/forgot", post(forgot_password))
+        // Set a new password using a password reset token
+        .route("/account/password/reset", post(reset_password))
+        // List the sessions the user is logged in with
+        .route("/account/sessions", get(get_sessions))
+        // Log out of a single session
+        .route("/account/sessions/:id", delete(revoke_session))
+        // Create an API key for accessing the API from scripts
+        .route("/account/apikeys", post(create_api_key))
+        // List the user's API keys
+        .route("/account/apikeys", get(get_api_keys))
+        // Revoke an API key
+        .route("/account/apikeys/:id", delete(revoke_api_key))
+        // Generate a new set of recovery codes, replacing the old ones
+        .route("/account/recovery-codes", post(generate_recovery_codes))
+        // Get how many unused recovery codes the user has left
+        .route("/account/recovery-codes", get(get_recovery_codes))
         // Upload a profile image
         .route("/account/upload", post(upload_profile_image))
         .layer(DefaultBodyLimit::max(10_100_000))
         .route("/chat/:id/messages", get(get_conversation))
+        // Get the pinned messages in a conversation
+        .route("/chat/:id/pins", get(get_pinned_messages))
+        // Get every message in the thread started by a message
+        .route("/chat/messages/:id/thread", get(get_thread))
         .route("/chat/create", post(create_conversation_rest))
         .route("/chat/models", get(get_ai_models))
+        // The personas the AI can take on in a conversation
+        .route("/personas", get(get_personas))
+        // Export a conversation as a JSON bundle, admins only
+        .route("/admin/conversations/:id/export", get(export_conversation))
+        // Recreate a conversation from an exported bundle, admins only
+        .route("/admin/conversations/import", post(import_conversation))
+        // Ban a user with an optional reason and expiry, or lift their ban, admins only
+        .route("/admin/users/:id/ban", post(ban_user).delete(unban_user))
+        // The audit log across every account, admins only
+        .route("/admin/audit", get(get_audit_log))
+        // Generate a report as a PDF, CSV, or JSON depending on the `Accept` header
+        .route("/report", get(generate_report))
         .route("/report/pdf", get(generate_pdf_report))
+        // Export the user's health forms as CSV, optionally from a range of dates
+        .route("/report/csv", get(generate_csv_report))
         // Used to submit a new health form
         .route("/forms/health", post(save_health_form))
         // Used to quickly check if a user should submit another health form
@@ -178,15 +341,28 @@ pub async fn start_server(pool: SqlitePool, args: &Args) -> Result<()> {
         .route("/forms/health", get(get_health_form))
         // Userd to update a health form with the given id
         .route("/forms/health/:id", put(update_health_form))
+        // Used to delete a mistaken health form
+        .route("/forms/health/:id", delete(delete_health_form))
         // Used to show a user all the health forms they have submitted
         .route("/forms", get(get_forms))
+        // Averages of a health form metric over time, for charts
+        .route("/forms/trends", get(get_health_trends))
+        // Explain what the value of a health metric means
+        .route("/forms/explain", post(explain_metric))
         // Used to upload files to the server
         .route("/upload", post(upload_file))
         .layer(DefaultBodyLimit::max(10_100_000))
-        // Used to upload files to the server
-        .nest_service("/upload/", ServeDir::new("uploads"))
+        // Used to download files the user can see, such as attachments in their conversations
+        .route("/files/:id", get(download_file))
+        // Profile images are shown to everyone so they can be downloaded without a token
+        .route("/upload/:file_name", get(download_profile_image))
         // .route("/chat/query_model/*model_name", get(query_model))
         .route("/ws", get(init_ws))
+        // Give clients a new token when theirs is close to expiring
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::sliding_expiration,
+        ))
         // Add CORS headers to all responses
         .layer(cors);
 
@@ -198,7 +374,7 @@ pub async fn start_server(pool: SqlitePool, args: &Args) -> Result<()> {
         // Add the trace layer to log all incoming requests
         // This logs the request method, path, response status, and response time
         .layer(middleware)
-        .with_state(AppState::new(pool.clone()));
+        .with_state(state);
 
     let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
     info!("Server listening on port {}", args.port);
@@ -220,16 +396,19 @@ pub async fn start_server(pool: SqlitePool, args: &Args) -> Result<()> {
 /// Initialize the database by creating the database file and running the migrations.
 /// Returns a connection pool to the database.
 pub async fn init_db(db_url: &str) -> Result<SqlitePool> {
-    let pool: SqlitePool = SqlitePool::connect_lazy_with(
-        SqliteConnectOptions::from_str(db_url)?
-            .foreign_keys(true)
-            .create_if_missing(true)
-            .journal_mode(SqliteJournalMode::Wal)
-            // Only user NORMAL is WAL mode is enabled
-            // as it provides extra performance benefits
-            // at the cost of durability
-            .synchronous(SqliteSynchronous::Normal),
-    );
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    let options = SqliteConnectOptions::from_str(db_url)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        // Only user NORMAL is WAL mode is enabled
+        // as it provides extra performance benefits
+        // at the cost of durability
+        .synchronous(SqliteSynchronous::Normal);
+    // Migrations run with foreign keys off so a table can be rebuilt without the rows referencing it
+    // being deleted, see https://www.sqlite.org/lang_altertable.html#otheralter.
+    // Foreign keys can't be turned off inside the transaction each migration runs in
+    let mut conn = options.clone().foreign_keys(false).connect().await?;
+    sqlx::migrate!("./migrations").run(&mut conn).await?;
+    conn.close().await?;
+    let pool: SqlitePool = SqlitePool::connect_lazy_with(options.foreign_keys(true));
     Ok(pool)
 }
@@ -1,7 +1,14 @@
 use std::env;
 
-use ai_health_assistant_api::{cli::Args, init_db, start_server, PROTOCOL};
+use ai_health_assistant_api::{
+    chat::rebuild_search_index,
+    cli::{Args, Command},
+    init_db, start_server,
+    users::set_admin,
+    PROTOCOL,
+};
 use anyhow::Result;
+use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use clap::Parser;
@@ -9,6 +16,8 @@ use clap::Parser;
 // TODO: Add better, more integrated and descriptive logging
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Load the .env file first so arguments that can be set through the environment can be set there
+    let _ = dotenvy::dotenv();
     let mut args = Args::parse();
 
     tracing_subscriber::registry()
@@ -34,5 +43,33 @@ async fn main() -> Result<()> {
         args.db_url = format!("{}{}", PROTOCOL, args.db_url);
     }
     let pool = init_db(&args.db_url).await?;
-    start_server(pool, &args).await
+    match args.command {
+        Some(Command::RebuildSearchIndex) => {
+            rebuild_search_index(&pool).await?;
+            info!("Search index rebuilt");
+            pool.close().await;
+            Ok(())
+        }
+        Some(Command::GrantAdmin {
+            ref username,
+            revoke,
+        }) => {
+            if set_admin(&pool, username, !revoke).await? {
+                info!(
+                    "{} {} admin",
+                    username,
+                    if revoke {
+                        "is no longer an"
+                    } else {
+                        "is now an"
+                    }
+                );
+            } else {
+                info!("User {} not found", username);
+            }
+            pool.close().await;
+            Ok(())
+        }
+        None => start_server(pool, &args).await,
+    }
 }
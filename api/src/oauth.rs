@@ -0,0 +1,736 @@
+use std::{net::SocketAddr, str::FromStr, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        HeaderMap, HeaderValue,
+    },
+    response::{IntoResponse, Redirect, Response},
+};
+use dotenvy::var;
+use reqwest::{
+    header::{ACCEPT, USER_AGENT},
+    StatusCode, Url,
+};
+use serde::Deserialize;
+use sonic_rs::{JsonContainerTrait, JsonValueTrait, Value};
+use sqlx::SqlitePool;
+use tracing::error;
+
+use crate::{
+    error::{AppError, AppJson},
+    state::AppState,
+    users::{
+        generate_token, hash_token, init_new_user, reactivate_account, start_session,
+        username_taken, validate_username, SessionUser,
+    },
+    PKG_NAME,
+};
+
+/// How long a user has to finish logging in with the provider
+const OAUTH_STATE_TTL: chrono::Duration = chrono::Duration::minutes(10);
+
+/// The cookie that ties a login to the browser that started it, so a callback url
+/// for someone else's login can't be used to log a victim into the wrong account
+const OAUTH_STATE_COOKIE: &str = "oauth_state";
+
+/// How long the client has to exchange the code it was sent back with for a login token
+const OAUTH_LOGIN_CODE_TTL: chrono::Duration = chrono::Duration::minutes(1);
+
+/// An OAuth provider users can log in with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OAuthProvider {
+    Google,
+    Github,
+}
+
+impl OAuthProvider {
+    /// The name used in urls and stored in the database
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Google => "google",
+            Self::Github => "github",
+        }
+    }
+
+    fn authorize_url(&self) -> &'static str {
+        match self {
+            Self::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+            Self::Github => "https://github.com/login/oauth/authorize",
+        }
+    }
+
+    fn token_url(&self) -> &'static str {
+        match self {
+            Self::Google => "https://oauth2.googleapis.com/token",
+            Self::Github => "https://github.com/login/oauth/access_token",
+        }
+    }
+
+    fn scope(&self) -> &'static str {
+        match self {
+            Self::Google => "openid email profile",
+            Self::Github => "read:user user:email",
+        }
+    }
+}
+
+impl FromStr for OAuthProvider {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "google" => Ok(Self::Google),
+            "github" => Ok(Self::Github),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The credentials the server was registered with at a provider
+pub struct OAuthClient {
+    client_id: String,
+    client_secret: String,
+}
+
+impl std::fmt::Debug for OAuthClient {
+    // Don't print the secret
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OAuthClient")
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The OAuth providers that are configured
+#[derive(Clone, Debug, Default)]
+pub struct OAuthConfig {
+    google: Option<Arc<OAuthClient>>,
+    github: Option<Arc<OAuthClient>>,
+    /// The url the server is reachable at, used to build the callback url sent to providers
+    redirect_base: Option<Url>,
+    /// The page of the client users are sent back to after logging in with a provider
+    client_url: Option<Url>,
+}
+
+impl OAuthConfig {
+    /// Read the provider credentials from the environment.
+    /// A provider is enabled when both its client id and secret are set, setting only one is an error
+    pub fn from_env() -> anyhow::Result<Self> {
+        let google = Self::client_from_env("GOOGLE")?;
+        let github = Self::client_from_env("GITHUB")?;
+        let redirect_base = match var("OAUTH_REDIRECT_URL") {
+            Ok(url) => {
+                let url = Url::parse(&url)
+                    .map_err(|e| anyhow!("OAUTH_REDIRECT_URL is not a valid url: {}", e))?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    return Err(anyhow!("OAUTH_REDIRECT_URL must be an http or https url"));
+                }
+                Some(url)
+            }
+            Err(_) if google.is_some() || github.is_some() => {
+                return Err(anyhow!(
+                    "OAUTH_REDIRECT_URL must be set to the url the server is reachable at to use OAuth"
+                ));
+            }
+            Err(_) => None,
+        };
+        let client_url = match var("OAUTH_CLIENT_URL") {
+            Ok(url) => Some(
+                Url::parse(&url)
+                    .map_err(|e| anyhow!("OAUTH_CLIENT_URL is not a valid url: {}", e))?,
+            ),
+            // The client is served by the server unless it is hosted somewhere else
+            Err(_) => redirect_base
+                .as_ref()
+                .map(|base| base.join("/login"))
+                .transpose()?,
+        };
+        Ok(Self {
+            google,
+            github,
+            redirect_base,
+            client_url,
+        })
+    }
+
+    fn client_from_env(prefix: &str) -> anyhow::Result<Option<Arc<OAuthClient>>> {
+        match (
+            var(format!("{}_CLIENT_ID", prefix)),
+            var(format!("{}_CLIENT_SECRET", prefix)),
+        ) {
+            (Ok(client_id), Ok(client_secret)) => Ok(Some(Arc::new(OAuthClient {
+                client_id,
+                client_secret,
+            }))),
+            (Err(_), Err(_)) => Ok(None),
+            _ => Err(anyhow!(
+                "Both {0}_CLIENT_ID and {0}_CLIENT_SECRET must be set to log in with {0}",
+                prefix
+            )),
+        }
+    }
+
+    fn client(&self, provider: OAuthProvider) -> Option<&OAuthClient> {
+        match provider {
+            OAuthProvider::Google => self.google.as_deref(),
+            OAuthProvider::Github => self.github.as_deref(),
+        }
+    }
+
+    /// The url the provider redirects back to after the user logs in
+    fn redirect_uri(&self, provider: OAuthProvider) -> String {
+        let base = self
+            .redirect_base
+            .as_ref()
+            .expect("Redirect url is checked at startup when a provider is configured");
+        format!(
+            "{}/api/oauth/{}/callback",
+            base.as_str().trim_end_matches('/'),
+            provider.name()
+        )
+    }
+}
+
+/// Send the user back to the client with the given query parameters
+fn redirect_to_client(config: &OAuthConfig, params: &[(&str, &str)]) -> Response {
+    let mut url = config
+        .client_url
+        .clone()
+        .expect("Client url is set whenever the redirect url is");
+    url.query_pairs_mut().extend_pairs(params);
+    Redirect::to(url.as_str()).into_response()
+}
+
+/// A `Set-Cookie` header for the state cookie that lasts for `max_age` seconds
+fn state_cookie(nonce: &str, max_age: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}={}; Max-Age={}; Path=/api/oauth; HttpOnly; Secure; SameSite=Lax",
+        OAUTH_STATE_COOKIE, nonce, max_age
+    ))
+    .expect("Nonces are url safe base64")
+}
+
+/// The state cookie sent with the request, if any
+fn state_from_cookies(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            (name == OAUTH_STATE_COOKIE).then_some(value)
+        })
+}
+
+/// Look up a provider by name, returning 404 if it doesn't exist or isn't configured
+fn get_provider<'a>(
+    state: &'a AppState,
+    name: &str,
+) -> Result<(OAuthProvider, &'a OAuthClient), AppError> {
+    name.parse::<OAuthProvider>()
+        .ok()
+        .and_then(|provider| Some((provider, state.oauth.client(provider)?)))
+        .ok_or_else(|| {
+            AppError::UserError((StatusCode::NOT_FOUND, "Unknown OAuth provider".into()))
+        })
+}
+
+/// The query parameters a login with a provider is started with
+#[derive(Deserialize, Debug, Default)]
+pub struct OAuthStart {
+    /// Restore the account if it was deleted but hasn't been purged yet
+    #[serde(default)]
+    pub reactivate: bool,
+}
+
+/// Redirect the user to the provider to log in
+pub async fn oauth_start(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    Query(start): Query<OAuthStart>,
+) -> Result<Response, AppError> {
+    let (provider, client) = get_provider(&state, &provider)?;
+
+    // Logins that were never finished can be cleaned up whenever a new one starts
+    sqlx::query!("DELETE FROM oauth_states WHERE expires_at <= CURRENT_TIMESTAMP")
+        .execute(&state.pool)
+        .await?;
+    let (nonce, nonce_hash) = generate_token();
+    let provider_name = provider.name();
+    let expires_at = (chrono::Utc::now() + OAUTH_STATE_TTL).naive_utc();
+    sqlx::query!(
+        "INSERT INTO oauth_states (state_hash, provider, expires_at, reactivate) VALUES (?, ?, ?, ?)",
+        nonce_hash,
+        provider_name,
+        expires_at,
+        start.reactivate
+    )
+    .execute(&state.pool)
+    .await?;
+
+    let mut url = Url::parse(provider.authorize_url()).expect("Provider urls are valid");
+    url.query_pairs_mut()
+        .append_pair("client_id", &client.client_id)
+        .append_pair("redirect_uri", &state.oauth.redirect_uri(provider))
+        .append_pair("response_type", "code")
+        .append_pair("scope", provider.scope())
+        .append_pair("state", &nonce);
+    Ok((
+        [(
+            SET_COOKIE,
+            state_cookie(&nonce, OAUTH_STATE_TTL.num_seconds()),
+        )],
+        Redirect::to(url.as_str()),
+    )
+        .into_response())
+}
+
+/// The query parameters the provider redirects back with
+#[derive(Deserialize, Debug)]
+pub struct OAuthCallback {
+    code: Option<String>,
+    state: String,
+    /// Set if the user denied access or the provider failed
+    error: Option<String>,
+}
+
+/// Finish logging in with the provider, creating a user for the account if it doesn't have one.
+/// Redirects back to the client with a single use `code` to exchange for a login token
+/// at `/oauth/exchange`, or with an `error` if logging in failed
+pub async fn oauth_callback(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    headers: HeaderMap,
+    Query(callback): Query<OAuthCallback>,
+) -> Result<Response, AppError> {
+    let (provider, client) = get_provider(&state, &provider)?;
+    // The state is single use either way, so the cookie is cleared whatever the outcome
+    let clear_cookie = [(SET_COOKIE, state_cookie("", 0))];
+    let cookie_state = state_from_cookies(&headers);
+    let user_id = match provider_login(&state, provider, client, cookie_state, callback).await {
+        Ok(user_id) => user_id,
+        Err(AppError::UserError((_, message))) => {
+            return Ok((
+                clear_cookie,
+                redirect_to_client(&state.oauth, &[("error", &message)]),
+            )
+                .into_response());
+        }
+        Err(e) => return Err(e),
+    };
+
+    // The login token isn't put in the url where it would end up in the browser's history,
+    // the client exchanges this code for it instead
+    let (code, code_hash) = generate_token();
+    let expires_at = (chrono::Utc::now() + OAUTH_LOGIN_CODE_TTL).naive_utc();
+    sqlx::query!("DELETE FROM oauth_login_codes WHERE expires_at <= CURRENT_TIMESTAMP")
+        .execute(&state.pool)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO oauth_login_codes (code_hash, user_id, expires_at) VALUES (?, ?, ?)",
+        code_hash,
+        user_id,
+        expires_at
+    )
+    .execute(&state.pool)
+    .await?;
+    Ok((
+        clear_cookie,
+        redirect_to_client(&state.oauth, &[("code", &code)]),
+    )
+        .into_response())
+}
+
+/// Check the callback from the provider and find the user for the provider account,
+/// creating one if the account doesn't have one yet.
+/// `cookie_state` is the state stored in the browser when the login was started
+async fn provider_login(
+    state: &AppState,
+    provider: OAuthProvider,
+    client: &OAuthClient,
+    cookie_state: Option<&str>,
+    callback: OAuthCallback,
+) -> Result<i64, AppError> {
+    // A callback the browser didn't start the login for was sent to it by someone else
+    if cookie_state != Some(callback.state.as_str()) {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired OAuth state".into(),
+        )));
+    }
+
+    // Consume the state so the callback can't be replayed
+    let nonce_hash = hash_token(&callback.state);
+    let provider_name = provider.name();
+    let Some(reactivate) = sqlx::query_scalar!(
+        "DELETE FROM oauth_states
+        WHERE state_hash = ? AND provider = ? AND expires_at > CURRENT_TIMESTAMP
+        RETURNING reactivate",
+        nonce_hash,
+        provider_name
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired OAuth state".into(),
+        )));
+    };
+
+    let code = match (callback.code, callback.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            return Err(AppError::UserError((
+                StatusCode::UNAUTHORIZED,
+                format!(
+                    "Login with {} failed: {}",
+                    provider_name,
+                    error.as_deref().unwrap_or("no code provided")
+                )
+                .into(),
+            )));
+        }
+    };
+
+    let access_token = exchange_code(state, provider, client, &code).await?;
+    let account = fetch_account(state, provider, &access_token).await?;
+
+    match existing_oauth_user(&state.pool, provider, &account.id, reactivate).await? {
+        Some(user_id) => Ok(user_id),
+        None => create_oauth_user(state, provider, account).await,
+    }
+}
+
+/// Find the user the provider account logs in as, if it has one.
+/// Like a password login, an account that was deleted but hasn't been purged yet
+/// is only restored if `reactivate` is set, otherwise logging in is refused
+pub async fn existing_oauth_user(
+    pool: &SqlitePool,
+    provider: OAuthProvider,
+    provider_user_id: &str,
+    reactivate: bool,
+) -> Result<Option<i64>, AppError> {
+    let provider_name = provider.name();
+    let Some(existing) = sqlx::query!(
+        r#"SELECT user_id, deleted_at IS NOT NULL as "deleted!: bool" FROM oauth_accounts
+        JOIN users ON users.id = oauth_accounts.user_id
+        WHERE provider = ? AND provider_user_id = ?"#,
+        provider_name,
+        provider_user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    if existing.deleted {
+        if !reactivate {
+            return Err(AppError::UserError((
+                StatusCode::FORBIDDEN,
+                "This account has been deleted, log in with reactivate set to restore it".into(),
+            )));
+        }
+        reactivate_account(pool, existing.user_id).await?;
+    }
+    Ok(Some(existing.user_id))
+}
+
+/// The single use code the client was sent back with after logging in with a provider
+#[derive(Deserialize)]
+pub struct OAuthExchange {
+    pub code: String,
+}
+
+/// Exchange the code from `oauth_callback` for a login token.
+/// Responds the same way as a password login
+pub async fn oauth_exchange(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AppJson(exchange): AppJson<OAuthExchange>,
+) -> Result<Response, AppError> {
+    // Consume the code so it can't be used twice
+    let code_hash = hash_token(&exchange.code);
+    let Some(user_id) = sqlx::query_scalar!(
+        "DELETE FROM oauth_login_codes
+        WHERE code_hash = ? AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id",
+        code_hash
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::UNAUTHORIZED,
+            "Invalid or expired login code".into(),
+        )));
+    };
+    let user = sqlx::query!(
+        "SELECT users.id, username, email, first_name, last_name, token_version,
+        bio, pronouns, location, path as image_path FROM users
+        LEFT JOIN files ON users.image_id = files.id
+        WHERE users.id = ?",
+        user_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    let session_user = SessionUser {
+        id: user.id,
+        username: user.username,
+        email: user.email,
+        first_name: user.first_name,
+        last_name: user.last_name,
+        image_path: user.image_path,
+        bio: user.bio,
+        pronouns: user.pronouns,
+        location: user.location,
+    };
+    start_session(
+        &state,
+        session_user,
+        user.token_version,
+        &headers,
+        addr.ip(),
+        false,
+    )
+    .await
+}
+
+/// Exchange the code from the callback for an access token
+async fn exchange_code(
+    state: &AppState,
+    provider: OAuthProvider,
+    client: &OAuthClient,
+    code: &str,
+) -> Result<String, AppError> {
+    let redirect_uri = state.oauth.redirect_uri(provider);
+    let response: Value = state
+        .client
+        .post(provider.token_url())
+        // GitHub responds with a form encoded body by default
+        .header(ACCEPT, "application/json")
+        .form(&[
+            ("client_id", client.client_id.as_str()),
+            ("client_secret", client.client_secret.as_str()),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("grant_type", "authorization_code"),
+        ])
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| provider_error(provider, e))?
+        .json()
+        .await
+        .map_err(|e| provider_error(provider, e))?;
+
+    response["access_token"]
+        .as_str()
+        .map(str::to_owned)
+        // GitHub responds with 200 and an error in the body if the code is invalid
+        .ok_or_else(|| {
+            provider_error(
+                provider,
+                response["error_description"]
+                    .as_str()
+                    .unwrap_or("no access token in response"),
+            )
+        })
+}
+
+/// The details of a provider account needed to create a user for it
+#[derive(Debug)]
+struct ProviderAccount {
+    id: String,
+    /// Only set if the provider has verified the email
+    email: Option<String>,
+    /// Used to pick a username
+    username_hint: String,
+    first_name: Option<String>,
+    last_name: Option<String>,
+}
+
+async fn fetch_account(
+    state: &AppState,
+    provider: OAuthProvider,
+    access_token: &str,
+) -> Result<ProviderAccount, AppError> {
+    let get = |url: &'static str| async move {
+        state
+            .client
+            .get(url)
+            .bearer_auth(access_token)
+            // GitHub rejects requests without a user agent
+            .header(USER_AGENT, PKG_NAME)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| provider_error(provider, e))?
+            .json::<Value>()
+            .await
+            .map_err(|e| provider_error(provider, e))
+    };
+
+    match provider {
+        OAuthProvider::Google => {
+            let info = get("https://openidconnect.googleapis.com/v1/userinfo").await?;
+            let email = info["email"]
+                .as_str()
+                .filter(|_| info["email_verified"].as_bool().unwrap_or(false))
+                .map(str::to_owned);
+            Ok(ProviderAccount {
+                id: info["sub"]
+                    .as_str()
+                    .ok_or_else(|| provider_error(provider, "no account id in response"))?
+                    .to_owned(),
+                username_hint: email
+                    .as_deref()
+                    .and_then(|email| email.split('@').next())
+                    .unwrap_or_default()
+                    .to_owned(),
+                email,
+                first_name: info["given_name"].as_str().map(str::to_owned),
+                last_name: info["family_name"].as_str().map(str::to_owned),
+            })
+        }
+        OAuthProvider::Github => {
+            let info = get("https://api.github.com/user").await?;
+            // The public email on the profile isn't necessarily verified so look for the primary one
+            let emails = get("https://api.github.com/user/emails").await?;
+            let email = emails.as_array().and_then(|emails| {
+                emails.iter().find_map(|email| {
+                    (email["primary"].as_bool().unwrap_or(false)
+                        && email["verified"].as_bool().unwrap_or(false))
+                    .then(|| email["email"].as_str().map(str::to_owned))
+                    .flatten()
+                })
+            });
+            let (first_name, last_name) = match info["name"].as_str() {
+                Some(name) => match name.trim().split_once(' ') {
+                    Some((first, last)) => (Some(first.to_owned()), Some(last.trim().to_owned())),
+                    None => (Some(name.trim().to_owned()), None),
+                },
+                None => (None, None),
+            };
+            Ok(ProviderAccount {
+                id: info["id"]
+                    .as_i64()
+                    .ok_or_else(|| provider_error(provider, "no account id in response"))?
+                    .to_string(),
+                username_hint: info["login"].as_str().unwrap_or_default().to_owned(),
+                email,
+                first_name,
+                last_name,
+            })
+        }
+    }
+}
+
+/// Create a user without a password for the provider account
+async fn create_oauth_user(
+    state: &AppState,
+    provider: OAuthProvider,
+    account: ProviderAccount,
+) -> Result<i64, AppError> {
+    let Some(email) = account.email else {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Your {} account does not have a verified email address",
+                provider.name()
+            )
+            .into(),
+        )));
+    };
+    // Accounts aren't linked by email automatically so a provider account
+    // can't be used to take over an existing user
+    if sqlx::query!("SELECT id FROM users WHERE email = ?", email)
+        .fetch_optional(&state.pool)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "An account with this email already exists, log in with your password instead".into(),
+        )));
+    }
+
+    let username = available_username(state, &account.username_hint).await?;
+    // Fall back to the username since a first name is required
+    let first_name: String = account
+        .first_name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| username.clone())
+        .chars()
+        .take(30)
+        .collect();
+    let last_name = account.last_name.filter(|name| !name.trim().is_empty());
+    let provider_name = provider.name();
+
+    let mut tx = state.pool.begin().await?;
+    // The provider has already verified the email
+    let user_id = sqlx::query_scalar!(
+        "INSERT INTO users (username, email, first_name, last_name, email_verified)
+        VALUES (?, ?, ?, ?, TRUE) RETURNING id",
+        username,
+        email,
+        first_name,
+        last_name
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO oauth_accounts (provider, provider_user_id, user_id) VALUES (?, ?, ?)",
+        provider_name,
+        account.id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    init_new_user(state, &mut tx, user_id).await?;
+    tx.commit().await?;
+    Ok(user_id)
+}
+
+/// Turn the hint into a valid username that isn't taken,
+/// adding a random number to the end if it is
+async fn available_username(state: &AppState, hint: &str) -> Result<String, AppError> {
+    let mut base: String = hint
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '_')
+        .take(15)
+        .collect();
+    if base.chars().count() < 3 || validate_username(&base).is_err() {
+        base = "user".to_owned();
+    }
+
+    let mut username = base.clone();
+    for _ in 0..10 {
+        if !username_taken(
+            &state.pool,
+            &username,
+            state.username_changes.reservation_days,
+            None,
+        )
+        .await?
+        {
+            return Ok(username);
+        }
+        username = format!("{}{}", base, rand::random::<u16>() % 10_000);
+    }
+    Err(anyhow!("Could not find an available username for {}", base).into())
+}
+
+/// Log the details of a failed request to a provider and hide them from the user
+fn provider_error(provider: OAuthProvider, error: impl std::fmt::Display) -> AppError {
+    error!("OAuth request to {} failed: {}", provider.name(), error);
+    AppError::UserError((
+        StatusCode::BAD_GATEWAY,
+        format!("Could not log in with {}", provider.name()).into(),
+    ))
+}
@@ -0,0 +1,193 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::NaiveDateTime;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{
+    audit::{AuditAction, AuditEvent},
+    auth::JwtAuth,
+    error::{AppError, AppJson},
+    users::{confirm_identity, hash_token, verify_password, UserToken},
+};
+
+/// The number of codes in a set of recovery codes
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// The characters a recovery code is made of, leaving out ones that are easy to mix up
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// The number of characters in a recovery code, not counting the dash in the middle
+const RECOVERY_CODE_LENGTH: usize = 12;
+
+/// Generate a recovery code to show to the user, split in half with a dash so it is easier to read
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code = String::with_capacity(RECOVERY_CODE_LENGTH + 1);
+    for i in 0..RECOVERY_CODE_LENGTH {
+        if i == RECOVERY_CODE_LENGTH / 2 {
+            code.push('-');
+        }
+        code.push(RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char);
+    }
+    code
+}
+
+/// Hash a recovery code the way it is stored.
+/// Codes are hashed without the dash or case so they can be typed however the user likes
+fn hash_recovery_code(code: &str) -> String {
+    let code = code
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    hash_token(&code)
+}
+
+/// Check if the code is one of the user's unused recovery codes without using it up
+pub(crate) async fn recovery_code_valid(
+    pool: &SqlitePool,
+    user_id: i64,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let code_hash = hash_recovery_code(code);
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(
+            SELECT 1 FROM recovery_codes WHERE user_id = ? AND code_hash = ? AND used_at IS NULL
+        ) as "valid!: bool""#,
+        user_id,
+        code_hash
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Use up one of the user's recovery codes.
+/// Returns false if the code isn't one of their unused codes
+pub(crate) async fn use_recovery_code(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let code_hash = hash_recovery_code(code);
+    // Only one code is marked in case the same code was somehow generated twice
+    let used = sqlx::query!(
+        "UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP WHERE id = (
+            SELECT id FROM recovery_codes WHERE user_id = ? AND code_hash = ? AND used_at IS NULL
+            LIMIT 1
+        )",
+        user_id,
+        code_hash
+    )
+    .execute(conn)
+    .await?;
+    Ok(used.rows_affected() > 0)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateRecoveryCodes {
+    /// Not needed if the user doesn't have a password and logged in recently
+    pub current_password: Option<String>,
+}
+
+/// A new set of recovery codes, only shown when they are generated
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NewRecoveryCodes {
+    pub codes: Vec<String>,
+}
+
+/// Generate a new set of recovery codes for the current user, replacing any they already had
+pub async fn generate_recovery_codes(
+    State(pool): State<SqlitePool>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    AppJson(data): AppJson<GenerateRecoveryCodes>,
+) -> Result<Response, AppError> {
+    let Some(stored_user) = sqlx::query!("SELECT password_hash FROM users WHERE id = ?", user.id)
+        .fetch_optional(&pool)
+        .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User does not exist".into(),
+        )));
+    };
+    // The codes can be used in place of the password, so whoever generates them
+    // has to know it. Users who signed up with an OAuth provider don't have one to check,
+    // so they have to have logged in recently instead
+    let current_password_valid = match (&stored_user.password_hash, &data.current_password) {
+        (Some(hash), Some(current_password)) => {
+            verify_password(current_password, hash).await?.is_ok()
+        }
+        (Some(_), None) => false,
+        (None, _) => {
+            confirm_identity(&pool, &user, None, "").await?;
+            true
+        }
+    };
+    if !current_password_valid {
+        return Err(AppError::UserError((
+            StatusCode::UNAUTHORIZED,
+            "Invalid password".into(),
+        )));
+    }
+
+    let codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect::<Vec<_>>();
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?", user.id)
+        .execute(&mut *tx)
+        .await?;
+    for code in &codes {
+        let code_hash = hash_recovery_code(code);
+        sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)",
+            user.id,
+            code_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    AuditEvent::new(user.id, AuditAction::RecoveryCodesGenerated)
+        .record(&mut tx, addr.ip())
+        .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, AppJson(NewRecoveryCodes { codes })).into_response())
+}
+
+/// How many of the user's recovery codes are left
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodeStatus {
+    pub remaining: i64,
+    /// None if the user has never generated recovery codes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated_at: Option<NaiveDateTime>,
+}
+
+/// Get how many unused recovery codes the current user has
+pub async fn get_recovery_codes(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    let status = sqlx::query_as!(
+        RecoveryCodeStatus,
+        r#"SELECT COUNT(*) - COUNT(used_at) as "remaining!: i64",
+        MIN(created_at) as "generated_at: NaiveDateTime"
+        FROM recovery_codes WHERE user_id = ?"#,
+        user.id
+    )
+    .fetch_one(&pool)
+    .await?;
+    Ok((StatusCode::OK, AppJson(status)).into_response())
+}
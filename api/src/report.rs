@@ -1,70 +1,293 @@
 use crate::auth::JwtAuth;
-use crate::error::AppError;
+use crate::error::{AppError, AppJson};
 use crate::forms::HealthForm;
-use crate::users::UserToken;
+use crate::users::{unit_system, UnitSystem, UserToken};
 use crate::AppState;
 use axum::{
-    extract::State,
-    http::{header, StatusCode},
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
-use printpdf::{Mm, PdfDocument};
-use std::fs::File;
+use chrono::{NaiveDate, NaiveTime};
+use mime::Mime;
+use printpdf::{BuiltinFont, Mm, PdfDocument};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
 use std::io::BufWriter;
 
+/// The formats a report can be rendered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Pdf,
+    Csv,
+    Json,
+}
+
+impl ReportFormat {
+    /// Pick the report format from the `Accept` header of the request.
+    /// Defaults to PDF if the header is missing or accepts anything.
+    /// Returns `None` if none of the accepted types can be produced.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let Some(accept) = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .filter(|accept| !accept.trim().is_empty())
+        else {
+            return Some(Self::Pdf);
+        };
+
+        // Parse every media range in the header along with its quality value
+        // so the client's most preferred format is used
+        let mut ranges: Vec<(Mime, f32)> = accept
+            .split(',')
+            .filter_map(|range| range.trim().parse::<Mime>().ok())
+            .map(|mime| {
+                let quality = mime
+                    .get_param("q")
+                    .and_then(|q| q.as_str().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (mime, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        // Stable sort so ranges with the same quality keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .iter()
+            .find_map(|(mime, _)| match (mime.type_(), mime.subtype()) {
+                (mime::STAR, mime::STAR) | (mime::APPLICATION, mime::STAR) => Some(Self::Pdf),
+                (mime::APPLICATION, mime::PDF) => Some(Self::Pdf),
+                (mime::APPLICATION, mime::JSON) => Some(Self::Json),
+                (mime::TEXT, mime::CSV) | (mime::TEXT, mime::STAR) => Some(Self::Csv),
+                _ => None,
+            })
+    }
+}
+
+/// Aggregated statistics over a user's health forms
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportSummary {
+    pub user_id: i64,
+    /// The number of forms the report was generated from
+    pub entries: usize,
+    /// The first day covered, the start of the range or the day of the first form
+    pub from: Option<NaiveDate>,
+    /// The last day covered, the end of the range or the day of the last form
+    pub to: Option<NaiveDate>,
+    /// Averages are None if none of the forms have a value for the metric
+    pub height_avg: Option<f64>,
+    pub weight_avg: Option<f64>,
+    pub sleep_hours_avg: Option<f64>,
+    pub exercise_duration_avg: Option<f64>,
+    /// The difference between the last and first weight in the range,
+    /// None unless at least two forms have a weight
+    pub weight_change: Option<f64>,
+    /// The units of the height and weight averages
+    pub unit_system: UnitSystem,
+}
+
+impl ReportSummary {
+    /// Summarize the forms in the range, which must be ordered oldest first
+    pub fn new(
+        user_id: i64,
+        data: &[HealthForm],
+        range: &ReportRange,
+        unit_system: UnitSystem,
+    ) -> Self {
+        let day = |form: &HealthForm| form.created_at.map(|created_at| created_at.date());
+        let mut weights = data.iter().filter_map(|f| f.weight);
+        let first_weight = weights.next();
+        let weight_change = first_weight
+            .zip(weights.next_back())
+            .map(|(first, last)| unit_system.weight(last - first));
+        Self {
+            user_id,
+            entries: data.len(),
+            from: range.from.or_else(|| data.first().and_then(day)),
+            to: range.to.or_else(|| data.last().and_then(day)),
+            height_avg: average(data.iter().filter_map(|f| f.height))
+                .map(|height| unit_system.height(height)),
+            weight_avg: average(data.iter().filter_map(|f| f.weight))
+                .map(|weight| unit_system.weight(weight)),
+            sleep_hours_avg: average(data.iter().filter_map(|f| f.sleep_hours)),
+            exercise_duration_avg: average(data.iter().filter_map(|f| f.exercise_duration)),
+            weight_change,
+            unit_system,
+        }
+    }
+}
+
+/// Average the values of an iterator, returning None if it is empty
+/// so we never divide by zero
+fn average(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0usize), |(sum, count), v| (sum + v, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// Generate a report of the user's health statistics, optionally from a range of dates.
+/// The format of the report is determined by the `Accept` header
+pub async fn generate_report(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Query(range): Query<ReportRange>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let Some(format) = ReportFormat::from_headers(&headers) else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_ACCEPTABLE,
+            "Report can only be generated as application/pdf, text/csv, or application/json".into(),
+        )));
+    };
+    render_report(&state, &user, &range, format).await
+}
+
+/// Generate a PDF report of the user's health statistics, optionally from a range of dates
 pub async fn generate_pdf_report(
     State(state): State<AppState>,
     JwtAuth(user): JwtAuth<UserToken>,
+    Query(range): Query<ReportRange>,
+) -> Result<Response, AppError> {
+    render_report(&state, &user, &range, ReportFormat::Pdf).await
+}
+
+/// Query parameters for limiting a report to a period of time
+#[derive(Deserialize, Debug, Default)]
+pub struct ReportRange {
+    /// Only include forms created on or after this date
+    pub from: Option<NaiveDate>,
+    /// Only include forms created on or before this date
+    pub to: Option<NaiveDate>,
+}
+
+/// Export the user's health forms as CSV for spreadsheets, optionally from a range of dates
+pub async fn generate_csv_report(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Query(range): Query<ReportRange>,
 ) -> Result<Response, AppError> {
-    // Fetch all forms for the user
-    let data = sqlx::query_as!(
+    render_report(&state, &user, &range, ReportFormat::Csv).await
+}
+
+/// Fetch the user's forms in the range, oldest first.
+/// Every form is included if the range is empty
+async fn fetch_forms(
+    state: &AppState,
+    user: &UserToken,
+    range: &ReportRange,
+) -> Result<Vec<HealthForm>, AppError> {
+    if let (Some(from), Some(to)) = (range.from, range.to) {
+        if from > to {
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                "`from` must not be after `to`".into(),
+            )));
+        }
+    }
+    // The range includes the whole of the `to` day
+    let from = range.from.map(|from| from.and_time(NaiveTime::MIN));
+    let to = range
+        .to
+        .map(|to| (to + chrono::Duration::days(1)).and_time(NaiveTime::MIN));
+    Ok(sqlx::query_as!(
         HealthForm,
-        "SELECT * FROM user_statistics WHERE user_id = ?",
-        user.id
+        "SELECT * FROM user_statistics
+        WHERE user_id = ?1 AND (?2 IS NULL OR created_at >= ?2) AND (?3 IS NULL OR created_at < ?3)
+        ORDER BY created_at ASC",
+        user.id,
+        from,
+        to
     )
     .fetch_all(&state.pool)
-    .await?;
+    .await?)
+}
+
+async fn render_report(
+    state: &AppState,
+    user: &UserToken,
+    range: &ReportRange,
+    format: ReportFormat,
+) -> Result<Response, AppError> {
+    let data = fetch_forms(state, user, range).await?;
+
+    let unit_system = unit_system(&state.pool, user.id).await?;
+    let summary = ReportSummary::new(user.id, &data, range, unit_system);
 
-    // Calculate averages
-    let total_entries = data.len() as f64;
-    let sleep_hours_avg = data.iter().filter_map(|f| f.sleep_hours).sum::<f64>() / total_entries;
-    let exercise_duration_avg =
-        data.iter().filter_map(|f| f.exercise_duration).sum::<f64>() / total_entries;
+    match format {
+        ReportFormat::Pdf => pdf_report(&summary),
+        ReportFormat::Csv => Ok(csv_report(&data, unit_system)),
+        ReportFormat::Json => Ok((StatusCode::OK, AppJson(summary)).into_response()),
+    }
+}
 
+fn pdf_report(summary: &ReportSummary) -> Result<Response, AppError> {
     // Create a PDF document
     let (doc, page1, layer1) =
         PdfDocument::new("User Health Report", Mm(210.0), Mm(297.0), "Layer 1");
     let current_layer = doc.get_page(page1).get_layer(layer1);
 
-    // Load external font
-    let font = doc.add_external_font(File::open("path/to/Helvetica.ttf")?)?;
+    // Use one of the fonts every PDF reader has so there is no font file to ship
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica)?;
 
     // Add content to the PDF
     current_layer.use_text(
-        format!("Health Statistics Report for User ID: {}", user.id),
+        format!("Health Statistics Report for User ID: {}", summary.user_id),
         24.0,
         Mm(10.0),
         Mm(280.0),
         &font,
     );
+    let period = match (summary.from, summary.to) {
+        (Some(from), Some(to)) => format!(", from {from} to {to}"),
+        (Some(from), None) => format!(", from {from}"),
+        (None, Some(to)) => format!(", up to {to}"),
+        (None, None) => String::new(),
+    };
     current_layer.use_text(
-        format!("Average Sleep Hours: {:.2}", sleep_hours_avg),
-        16.0,
+        format!("{} entries{}", summary.entries, period),
+        12.0,
         Mm(10.0),
-        Mm(250.0),
-        &font,
-    );
-    current_layer.use_text(
-        format!(
-            "Average Exercise Duration: {:.2} minutes",
-            exercise_duration_avg
-        ),
-        16.0,
-        Mm(10.0),
-        Mm(230.0),
+        Mm(268.0),
         &font,
     );
 
+    // Only show the metrics that were filled in on at least one form
+    // so a missing metric isn't mistaken for a value of zero
+    let units = summary.unit_system;
+    let lines: Vec<String> = [
+        summary
+            .height_avg
+            .map(|avg| format!("Average Height: {:.1} {}", avg, units.height_unit())),
+        summary
+            .weight_avg
+            .map(|avg| format!("Average Weight: {:.1} {}", avg, units.weight_unit())),
+        summary
+            .weight_change
+            .map(|change| format!("Weight Change: {:+.1} {}", change, units.weight_unit())),
+        summary
+            .sleep_hours_avg
+            .map(|avg| format!("Average Sleep Hours: {:.2}", avg)),
+        summary
+            .exercise_duration_avg
+            .map(|avg| format!("Average Exercise Duration: {:.2} minutes", avg)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if lines.is_empty() {
+        current_layer.use_text(
+            "No data available, submit a health form to see your statistics here",
+            16.0,
+            Mm(10.0),
+            Mm(250.0),
+            &font,
+        );
+    }
+    for (i, line) in lines.into_iter().enumerate() {
+        current_layer.use_text(line, 16.0, Mm(10.0), Mm(250.0 - 20.0 * i as f32), &font);
+    }
+
     // Save to a buffer
     let mut buffer = Vec::new();
     doc.save(&mut BufWriter::new(&mut buffer))?;
@@ -83,3 +306,67 @@ pub async fn generate_pdf_report(
     )
         .into_response())
 }
+
+/// Heights and weights are in the given unit system, which the header names
+fn csv_report(data: &[HealthForm], units: UnitSystem) -> Response {
+    let mut csv = format!(
+        "id,created_at,modified_at,height_{},weight_{},sleep_hours,exercise_minutes,food_intake,notes\n",
+        units.height_unit(),
+        units.weight_unit()
+    );
+    for form in data {
+        // Writing to a string cannot fail
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{},{}",
+            form.id.map(|v| v.to_string()).unwrap_or_default(),
+            form.created_at
+                .map(|v| v.and_utc().to_rfc3339())
+                .unwrap_or_default(),
+            form.modified_at
+                .map(|v| v.and_utc().to_rfc3339())
+                .unwrap_or_default(),
+            form.height
+                .map(|v| units.height(v).to_string())
+                .unwrap_or_default(),
+            form.weight
+                .map(|v| units.weight(v).to_string())
+                .unwrap_or_default(),
+            form.sleep_hours.map(|v| v.to_string()).unwrap_or_default(),
+            form.exercise_duration
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            escape_csv(form.food_intake.as_deref().unwrap_or_default()),
+            escape_csv(form.notes.as_deref().unwrap_or_default()),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"health_report.csv\"",
+            ),
+        ],
+        csv,
+    )
+        .into_response()
+}
+
+/// Quote a CSV field if it contains characters that would break the row.
+/// Spreadsheets run fields starting with `=`, `+`, `-`, `@`, a tab, or a carriage return as formulas,
+/// so those are prefixed with a `'` to be shown as text instead
+fn escape_csv(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_owned()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
@@ -1,24 +1,40 @@
 use std::{
-    collections::HashSet,
+    collections::{HashSet, VecDeque},
     fmt::Debug,
     hash::{Hash, Hasher},
     ops::Deref,
     sync::{
         atomic::{AtomicI64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
 use ahash::RandomState;
 use atomicbox::AtomicOptionBox;
 use axum::extract::FromRef;
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
+use dotenvy::var;
+use mime::Mime;
 use reqwest::{header, Client};
 use scc::HashMap;
 use sqlx::SqlitePool;
-use tokio::{sync::mpsc, task::AbortHandle};
+use tokio::{
+    sync::{broadcast, mpsc, Notify},
+    task::AbortHandle,
+};
 
-use crate::{chat::SocketResponse, IDLE_TIMEOUT};
+use crate::{
+    auth::{AuthConfig, LoginAttempts, PasswordHasher, SessionActivity},
+    chat::{SearchLimits, SearchThrottle, SequencedEvent, SocketResponse, StreamCoalescing},
+    cli::Args,
+    error::{AppError, ErrorResponse},
+    mail::{mailer_from_args, Mailer},
+    oauth::OAuthConfig,
+    storage::{storage_from_args, Storage},
+    users::UsernameChanges,
+    IDLE_TIMEOUT,
+};
 
 /// The application state that is shared across all routes.
 #[derive(Clone, Debug)]
@@ -39,10 +55,61 @@ pub struct AppState {
     pub(crate) pool: SqlitePool,
     /// Stemmer for stemming all messages sent
     pub(crate) stemmer: Arc<Stemmer>,
+    /// Settings for signing and verifying JWTs
+    pub(crate) auth: AuthConfig,
+    /// Hashes new passwords with the configured cost
+    pub(crate) password_hasher: PasswordHasher,
+    /// Whether new users get a conversation with the assistant when they register
+    pub(crate) assistant_conversation: bool,
+    /// Whether users have to verify their email before they can log in
+    pub(crate) require_email_verification: bool,
+    /// Replay buffers of users who recently disconnected from every connection.
+    /// Kept for `REPLAY_WINDOW` so they can be picked back up if the user reconnects
+    pub(crate) parked_replays: Arc<HashMap<i64, Arc<ReplayBuffer>, RandomState>>,
+    /// When sessions were last written as used
+    pub(crate) session_activity: SessionActivity,
+    /// Limits on searching messages over the websocket
+    pub(crate) search_limits: SearchLimits,
+    /// How streamed AI responses are batched before being sent to clients
+    pub(crate) stream_coalescing: StreamCoalescing,
+    /// How many times a failed request to the AI is retried before giving up
+    pub(crate) ai_max_retries: u32,
+    /// How many tokens the AI can use responding to a user each month, None if unlimited
+    pub(crate) ai_token_quota: Option<i64>,
+    /// How many websocket connections a user can have open at once
+    pub(crate) max_connections_per_user: usize,
+    /// The most mutual friends that can be requested at once
+    pub(crate) max_mutual_friends: u32,
+    /// The zstd level uploads are compressed with, None if uploads are stored as they are
+    pub(crate) upload_compression: Option<i32>,
+    /// The types of files users can upload as attachments
+    pub(crate) allowed_upload_types: Arc<[Mime]>,
+    /// How many bytes of files each user can upload, None if unlimited
+    pub(crate) upload_quota: Option<i64>,
+    /// Where uploaded files are stored
+    pub(crate) storage: Arc<dyn Storage>,
+    /// Sends emails to users
+    pub(crate) mailer: Arc<dyn Mailer>,
+    /// How often users can change their username and how long old usernames stay reserved
+    pub(crate) username_changes: UsernameChanges,
+    /// The OAuth providers users can log in with
+    pub(crate) oauth: OAuthConfig,
+    /// Failed login attempts used to lock out brute force attacks
+    pub(crate) login_attempts: Arc<LoginAttempts>,
+    /// Notifies tasks holding onto a conversation's senders, such as AI streams,
+    /// when a user leaves the conversation
+    pub(crate) conversation_leaves: broadcast::Sender<ConversationLeave>,
     // Maybe add a `Arc<HashSet<i64>>` to keep track of the conversation ids
     // that the AI is currently generating messages for.
 }
 
+/// A user leaving a conversation
+#[derive(Clone, Copy, Debug)]
+pub struct ConversationLeave {
+    pub conversation_id: i64,
+    pub user_id: i64,
+}
+
 #[derive(Clone, Debug)]
 pub struct Sender<T> {
     pub(crate) channel: mpsc::Sender<T>,
@@ -119,7 +186,8 @@ impl Stemmer {
 /// All the websocket connections for a user.
 #[derive(Clone, Debug)]
 pub struct ConnectionState {
-    pub(crate) connections: [Option<InnerConnection>; 10],
+    /// One slot per connection the user is allowed to have, sized by `max_connections_per_user`
+    pub(crate) connections: Vec<Option<InnerConnection>>,
     /// A flag that contains the conversation id of the conversation that the AI is currently
     /// generating messages for.
     /// This uses 0 as a sentinel value to represent that the AI is not currently generating
@@ -136,6 +204,102 @@ pub struct ConnectionState {
     /// initiated the task
     pub(crate) idle_handle: Arc<AbortHandle>,
     pub(crate) ai_handle: Arc<AtomicOptionBox<AbortHandle>>,
+    /// Recent events broadcast to the user so a connection that briefly dropped
+    /// can catch up on what it missed
+    pub(crate) replay: Arc<ReplayBuffer>,
+}
+
+/// How long broadcast events are kept for replay
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(30);
+/// The maximum number of events kept for replay per user
+pub const REPLAY_CAPACITY: usize = 100;
+
+/// A ring buffer of the most recent events broadcast to a user, each with a sequence number
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    inner: Mutex<ReplayInner>,
+}
+
+#[derive(Debug)]
+struct ReplayInner {
+    next_seq: u64,
+    events: VecDeque<(Instant, SequencedEvent)>,
+}
+
+impl Default for ReplayBuffer {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(ReplayInner {
+                // Start from the current time instead of 0 so sequence numbers keep increasing
+                // even if the buffer is dropped and recreated. Otherwise a client could mistake
+                // new events for ones it already has
+                next_seq: Utc::now().timestamp_micros() as u64,
+                events: VecDeque::with_capacity(REPLAY_CAPACITY),
+            }),
+        }
+    }
+}
+
+impl ReplayBuffer {
+    /// Assign the event the next sequence number and keep it for replay
+    pub fn push(&self, event: SocketResponse) -> SequencedEvent {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.prune(now);
+        if inner.events.len() == REPLAY_CAPACITY {
+            inner.events.pop_front();
+        }
+        let event = SequencedEvent {
+            seq: inner.next_seq,
+            event: Box::new(event),
+        };
+        inner.next_seq += 1;
+        inner.events.push_back((now, event.clone()));
+        event
+    }
+
+    /// Get every event after the given sequence number.
+    /// Returns `None` if some of those events are no longer in the buffer
+    /// and the client has to fully catch up instead
+    pub fn since(&self, seq: u64) -> Option<Vec<SequencedEvent>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune(Instant::now());
+        let oldest = inner
+            .events
+            .front()
+            .map_or(inner.next_seq, |(_, event)| event.seq);
+        // The client either missed events that were dropped or has a sequence number
+        // from a buffer that no longer exists
+        if seq.saturating_add(1) < oldest || seq >= inner.next_seq {
+            return None;
+        }
+        Some(
+            inner
+                .events
+                .iter()
+                .filter(|(_, event)| event.seq > seq)
+                .map(|(_, event)| event.clone())
+                .collect(),
+        )
+    }
+
+    /// The sequence number of the most recent event
+    pub fn latest_seq(&self) -> u64 {
+        self.inner.lock().unwrap().next_seq - 1
+    }
+}
+
+impl ReplayInner {
+    /// Drop the events older than the replay window
+    fn prune(&mut self, now: Instant) {
+        while self
+            .events
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= REPLAY_WINDOW)
+        {
+            self.events.pop_front();
+        }
+    }
 }
 
 impl ConnectionState {
@@ -161,17 +325,35 @@ impl ConnectionState {
 pub struct InnerConnection {
     /// The sender channel for sending messages to the user.
     /// Each individual connection from the user has its own sender channel.
-    /// Cap the number of connections to 10 to prevent abuse and simplify the implementation.
     pub(crate) channel: Sender<SocketResponse>,
     /// The id of the last conversation a user Requested using `SocketRequest::RequestConversation`
     /// This is assumed to be the last conversation the user was focused on.
     pub(crate) focused_conversation: Arc<AtomicI64>,
     pub(crate) focused_handle: Arc<AtomicOptionBox<AbortHandle>>,
+    /// The id of the root message of the thread the connection is currently viewing
+    /// Uses 0 as a sentinel value when no thread is being viewed
+    pub(crate) focused_thread: Arc<AtomicI64>,
+    /// Notified to close the connection from outside of the connection's tasks
+    pub(crate) close: Arc<Notify>,
+    /// The login session the connection was authorized with
+    pub(crate) session_id: Option<i64>,
+    /// Limits how often the connection can search messages
+    pub(crate) search_throttle: Arc<SearchThrottle>,
 }
 
 impl AppState {
-    pub fn new(pool: SqlitePool) -> Self {
-        Self {
+    pub fn new(pool: SqlitePool, args: &Args) -> anyhow::Result<Self> {
+        // Read at runtime so the secret can be rotated without rebuilding
+        let jwt_key = args
+            .jwt_key
+            .clone()
+            .or_else(|| var("JWT_KEY").ok())
+            .ok_or_else(|| anyhow::anyhow!("JWT_KEY must be set or passed with --jwt-key"))?;
+        let jwt_previous_key = args
+            .jwt_previous_key
+            .clone()
+            .or_else(|| var("JWT_PREVIOUS_KEY").ok());
+        Ok(Self {
             client: reqwest::ClientBuilder::new()
                 .default_headers({
                     let mut headers = reqwest::header::HeaderMap::new();
@@ -191,10 +373,129 @@ impl AppState {
             stemmer: Arc::new(Stemmer(rust_stemmers::Stemmer::create(
                 rust_stemmers::Algorithm::English,
             ))),
+            auth: AuthConfig::new(
+                args.jwt_ttl_hours,
+                args.jwt_sliding_expiration,
+                &args.jwt_alg,
+                &jwt_key,
+                jwt_previous_key.as_deref(),
+                args.jwt_issuer.clone().or_else(|| var("JWT_ISSUER").ok()),
+                args.jwt_audience
+                    .clone()
+                    .or_else(|| var("JWT_AUDIENCE").ok()),
+            )
+            .map_err(anyhow::Error::msg)?,
+            password_hasher: PasswordHasher::new(
+                &args.password_algorithm,
+                args.password_memory_kib,
+                args.password_iterations,
+                args.password_parallelism,
+            )
+            .map_err(anyhow::Error::msg)?,
+            assistant_conversation: !args.no_assistant_conversation,
+            require_email_verification: args.require_email_verification,
+            parked_replays: Arc::new(HashMap::with_hasher(RandomState::new())),
+            session_activity: SessionActivity::default(),
+            search_limits: SearchLimits::from(args),
+            stream_coalescing: StreamCoalescing::from(args),
+            ai_max_retries: args.ai_max_retries,
+            ai_token_quota: (args.ai_monthly_token_quota > 0)
+                .then_some(args.ai_monthly_token_quota),
+            max_connections_per_user: args.max_connections_per_user as usize,
+            max_mutual_friends: args.max_mutual_friends,
+            upload_compression: args
+                .compress_uploads
+                .then_some(args.upload_compression_level),
+            allowed_upload_types: args.allowed_upload_types.as_slice().into(),
+            upload_quota: (args.upload_quota_mb > 0).then(|| {
+                // Quotas too large to count in bytes are as good as unlimited
+                args.upload_quota_mb
+                    .saturating_mul(1_000_000)
+                    .try_into()
+                    .unwrap_or(i64::MAX)
+            }),
+            storage: storage_from_args(args)?,
+            mailer: mailer_from_args(args)?,
+            username_changes: UsernameChanges::from(args),
+            oauth: OAuthConfig::from_env()?,
+            login_attempts: Arc::new(LoginAttempts::new(
+                args.login_max_failures as usize,
+                args.login_max_ip_failures as usize,
+                Duration::from_secs(args.login_failure_window_secs),
+            )),
+            conversation_leaves: broadcast::channel(64).0,
+        })
+    }
+
+    /// Store uploads in the given storage instead of the one picked from the arguments
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Send emails with the given mailer instead of the one picked from the arguments
+    pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>) -> Self {
+        self.mailer = mailer;
+        self
+    }
+}
+
+impl AppState {
+    /// Close every websocket connection the user has open
+    /// The client is sent an auth error with the reason before each connection is closed
+    pub async fn disconnect_user(&self, user_id: i64, reason: &str) {
+        self.disconnect_user_with(user_id, AppError::AuthError(anyhow::anyhow!("{reason}")))
+            .await
+    }
+
+    /// Close every websocket connection the user has open,
+    /// sending the client the error before each connection is closed
+    pub async fn disconnect_user_with(&self, user_id: i64, error: AppError) {
+        let error = ErrorResponse::from(error);
+        let Some(connections) = self
+            .user_sockets
+            .read_async(&user_id, |_, v| v.connections.clone())
+            .await
+        else {
+            return;
+        };
+        for connection in connections.iter().flatten() {
+            // Don't wait on connections with a full queue, they are closed either way
+            let _ = connection
+                .channel
+                .try_send(SocketResponse::Error(error.clone()));
+            // Stores a permit if the connection isn't currently waiting
+            // so the notification is never lost
+            connection.close.notify_one();
+        }
+    }
+
+    /// Close the websocket connections that were authorized with the given session
+    pub async fn disconnect_session(&self, user_id: i64, session_id: i64) {
+        let Some(connections) = self
+            .user_sockets
+            .read_async(&user_id, |_, v| v.connections.clone())
+            .await
+        else {
+            return;
+        };
+        for connection in connections
+            .iter()
+            .flatten()
+            .filter(|connection| connection.session_id == Some(session_id))
+        {
+            connection.close.notify_one();
         }
     }
 }
 
+// Support for automatically converting an `AppState` into an `SessionActivity`
+impl FromRef<AppState> for SessionActivity {
+    fn from_ref(app_state: &AppState) -> SessionActivity {
+        app_state.session_activity.clone()
+    }
+}
+
 // Support for automatically converting an `AppState` into an `SqlitePool`
 impl FromRef<AppState> for SqlitePool {
     fn from_ref(app_state: &AppState) -> SqlitePool {
@@ -202,6 +503,37 @@ impl FromRef<AppState> for SqlitePool {
     }
 }
 
+impl FromRef<AppState> for UsernameChanges {
+    fn from_ref(app_state: &AppState) -> UsernameChanges {
+        app_state.username_changes
+    }
+}
+
+// Support for automatically converting an `AppState` into an `AuthConfig`
+impl FromRef<AppState> for AuthConfig {
+    fn from_ref(app_state: &AppState) -> AuthConfig {
+        app_state.auth.clone()
+    }
+}
+
+impl FromRef<AppState> for PasswordHasher {
+    fn from_ref(app_state: &AppState) -> PasswordHasher {
+        app_state.password_hasher.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn Storage> {
+    fn from_ref(app_state: &AppState) -> Arc<dyn Storage> {
+        app_state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn Mailer> {
+    fn from_ref(app_state: &AppState) -> Arc<dyn Mailer> {
+        app_state.mailer.clone()
+    }
+}
+
 // Support for automatically converting an `AppState` into an `Client`
 impl FromRef<AppState> for Client {
     fn from_ref(app_state: &AppState) -> Client {
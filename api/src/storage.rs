@@ -0,0 +1,200 @@
+use std::{
+    fmt::Debug,
+    io::{self, ErrorKind},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use axum::async_trait;
+use bytes::Bytes;
+use dotenvy::var;
+#[cfg(feature = "s3")]
+use object_store::ObjectStore;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::{cli::Args, upload::UPLOAD_DIR};
+
+/// Where uploaded files are kept.
+/// Files are stored under keys made from the hash of their contents, so a key always refers to the same data
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Store a file under the given key, replacing anything already stored there
+    async fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Read a stored file. Fails with `ErrorKind::NotFound` if nothing is stored under the key
+    async fn get(&self, key: &str) -> io::Result<Bytes>;
+
+    /// Check if a file is stored under the given key
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// Remove a stored file. Removing a file that doesn't exist is not an error
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Check that files can be stored, so a misconfigured server fails at startup
+    /// instead of on the first upload
+    async fn check(&self) -> io::Result<()> {
+        let key = ".write-check";
+        self.put(key, &[]).await?;
+        self.delete(key).await
+    }
+
+    /// The path of a stored file on disk, if the storage keeps files on disk.
+    /// Files on disk are served directly so they get range requests and caching headers
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Pick where to store uploads from the arguments.
+/// Uploads go to a bucket if one is given, otherwise to the uploads directory
+pub(crate) fn storage_from_args(args: &Args) -> anyhow::Result<Arc<dyn Storage>> {
+    let Some(bucket) = args.s3_bucket.clone().or_else(|| var("S3_BUCKET").ok()) else {
+        return Ok(Arc::new(LocalStorage::new(UPLOAD_DIR)));
+    };
+    #[cfg(feature = "s3")]
+    {
+        let endpoint = args.s3_endpoint.clone().or_else(|| var("S3_ENDPOINT").ok());
+        Ok(Arc::new(S3Storage::new(
+            &bucket,
+            &args.s3_region,
+            endpoint.as_deref(),
+        )?))
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        anyhow::bail!("Cannot store uploads in the {bucket} bucket, the server was built without the s3 feature")
+    }
+}
+
+/// Stores files in a directory on the server's disk
+#[derive(Debug)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        // Write to a file nobody else is using and move it into place once it is complete,
+        // so a file being stored by two requests at once is never read half written
+        // and one of them failing can't remove the file the other stored
+        let temp = self.path(&format!(".{}.{:016x}.tmp", key, rand::random::<u64>()));
+        let write = async {
+            // The directory is checked at startup, but recreate it in case it was removed since
+            tokio::fs::create_dir_all(&self.dir).await?;
+            let mut file = File::create_new(&temp).await?;
+            file.write_all(data).await?;
+            file.flush().await?;
+            tokio::fs::rename(&temp, self.path(key)).await
+        };
+        if let Err(e) = write.await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        tokio::fs::read(self.path(key)).await.map(Bytes::from)
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        tokio::fs::try_exists(self.path(key)).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+/// Stores files in an S3 compatible bucket, such as AWS S3, Cloudflare R2, or MinIO.
+/// Lets several servers share their uploads and keeps them when a server's disk is thrown away
+#[cfg(feature = "s3")]
+#[derive(Debug)]
+pub struct S3Storage {
+    store: object_store::aws::AmazonS3,
+}
+
+#[cfg(feature = "s3")]
+impl S3Storage {
+    /// Connect to a bucket. Credentials are read from the usual `AWS_` environment variables,
+    /// such as `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    /// The endpoint only needs to be set for services other than AWS
+    pub fn new(bucket: &str, region: &str, endpoint: Option<&str>) -> anyhow::Result<Self> {
+        let mut builder = object_store::aws::AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .with_region(region);
+        if let Some(endpoint) = endpoint {
+            // Self hosted services are often reached over plain HTTP on a private network
+            // and don't support putting the bucket in the host name
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        Ok(Self {
+            store: builder.build()?,
+        })
+    }
+}
+
+#[cfg(feature = "s3")]
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.store
+            .put(&key.into(), Bytes::copy_from_slice(data).into())
+            .await
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        let object = self.store.get(&key.into()).await.map_err(io_error)?;
+        object.bytes().await.map_err(io_error)
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self.store.head(&key.into()).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.store.delete(&key.into()).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
+
+/// Convert an error from the bucket into the kind of error the disk would give,
+/// so bad credentials are reported the same way as a directory the server can't write to
+#[cfg(feature = "s3")]
+fn io_error(e: object_store::Error) -> io::Error {
+    match e {
+        object_store::Error::PermissionDenied { .. }
+        | object_store::Error::Unauthenticated { .. } => {
+            io::Error::new(ErrorKind::PermissionDenied, e)
+        }
+        e => e.into(),
+    }
+}
@@ -1,12 +1,12 @@
 use std::{
     cmp::Ordering,
-    fs::create_dir,
-    io::{BufWriter, ErrorKind},
-    path::PathBuf,
+    io::{self, Cursor, ErrorKind},
+    net::SocketAddr,
 };
 
 use axum::{
-    extract::State,
+    extract::{ConnectInfo, Path as UrlPath, Request, State},
+    http::header,
     response::{IntoResponse, Response},
 };
 use base64::{engine::general_purpose, Engine};
@@ -16,15 +16,24 @@ use mime::Mime;
 use mime_guess::get_mime_extensions;
 use reqwest::StatusCode;
 use serde::Deserialize;
-use sqlx::SqlitePool;
-use tokio::{fs::File, io::AsyncWriteExt};
+use sqlx::SqliteConnection;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::error;
 
 use crate::{
+    audit::{AuditAction, AuditEvent},
     auth::JwtAuth,
     error::{AppError, AppJson},
+    state::AppState,
+    storage::Storage,
     users::UserToken,
 };
 
+/// The directory uploaded files are stored in when they aren't kept in a bucket,
+/// relative to the working directory
+pub const UPLOAD_DIR: &str = "uploads";
+
 /// A file to be uploaded to the server.
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -78,7 +87,8 @@ impl AppFile {
 }
 
 pub async fn upload_file(
-    State(state): State<SqlitePool>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     JwtAuth(user): JwtAuth<UserToken>,
     AppJson(upload_data): AppJson<FileUpload>,
 ) -> Result<Response, AppError> {
@@ -101,6 +111,8 @@ pub async fn upload_file(
         )));
     }
 
+    check_file_type(upload_file.mime.as_ref(), &state.allowed_upload_types)?;
+
     // Calculate the hash of the file to use as the filename
     let hash = blake3::hash(&upload_file.data).to_hex();
 
@@ -118,37 +130,82 @@ pub async fn upload_file(
         },
     );
 
-    // Create the uploads directory if it does not
-    // already exist and ignore the error if it does
-    match create_dir("./uploads") {
-        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
-        _ => (),
-    }
+    let size = upload_file.data.len() as i64;
+    // Checked before doing the work of storing the file, and again when it's recorded
+    check_quota(
+        &mut *state.pool.acquire().await?,
+        state.upload_quota,
+        user.id,
+        &file_name,
+        size,
+    )
+    .await?;
 
+    // Compressing can take a while for large files so don't block the runtime
+    let (upload_file, compressed_data) = match state
+        .upload_compression
+        .filter(|_| upload_file.mime.as_ref().is_none_or(is_compressible))
+    {
+        Some(level) => {
+            tokio::task::spawn_blocking(move || {
+                let compressed_data = compress(&upload_file.data, level);
+                (upload_file, compressed_data)
+            })
+            .await?
+        }
+        None => (upload_file, None),
+    };
+    let compressed = compressed_data.is_some();
+    save_upload(
+        state.storage.as_ref(),
+        &file_name,
+        compressed_data.as_deref().unwrap_or(&upload_file.data),
+    )
+    .await?;
+
+    let text_preview = match upload_file.mime {
+        Some(ref mime) if *mime == mime::APPLICATION_PDF => {
+            // Parsing the PDF can take a while so don't block the runtime
+            tokio::task::spawn_blocking(move || pdf_preview(&upload_file.data))
+                .await
+                .ok()
+                .flatten()
+        }
+        _ => None,
+    };
     let mime = upload_file.mime.map(|mime| mime.to_string());
-    let path = PathBuf::from(format!("uploads/{}", file_name));
-
-    if !path.exists() {
-        let mut file = File::create(&path).await?;
-        file.write_all(&upload_file.data).await?;
-    }
 
+    // A file that was already uploaded keeps the row describing how it was stored,
+    // but gets a preview if it was uploaded before previews were extracted
+    let mut tx = state.pool.begin().await?;
     let file_id = sqlx::query!(
-            "INSERT INTO files (path, mime) VALUES (?, ?) ON CONFLICT DO UPDATE SET path = path RETURNING id",
+            "INSERT INTO files (path, mime, text_preview, compressed, size) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT DO UPDATE SET text_preview = COALESCE(text_preview, excluded.text_preview) RETURNING id",
             file_name,
-            mime
+            mime,
+            text_preview,
+            compressed,
+            size
         )
-        .fetch_one(&state)
+        .fetch_one(&mut *tx)
         .await?
         .id;
 
+    // Inserting the file took the database's write lock, so no other upload can be
+    // recorded between checking the quota again and recording this one
+    check_quota(&mut tx, state.upload_quota, user.id, &file_name, size).await?;
     let id = sqlx::query!(
             "INSERT INTO file_uploads (file_id, user_id) VALUES (?, ?) ON CONFLICT DO UPDATE SET file_id = file_id RETURNING file_id as id",
             file_id,
             user.id
         )
-        .fetch_one(&state)
+        .fetch_one(&mut *tx)
         .await?.id;
+    AuditEvent::new(user.id, AuditAction::FileUpload)
+        .values(None, Some(id.to_string()))
+        .record(&mut tx, addr.ip())
+        .await?;
+    tx.commit().await?;
 
     Ok((
         StatusCode::CREATED,
@@ -157,9 +214,204 @@ pub async fn upload_file(
         .into_response())
 }
 
+/// Reject a file with `415 Unsupported Media Type` unless its type matches one of the allowed types.
+/// Files whose type couldn't be determined are rejected since they could be anything
+fn check_file_type(mime: Option<&Mime>, allowed: &[Mime]) -> Result<(), AppError> {
+    let allowed = mime.is_some_and(|mime| {
+        allowed.iter().any(|allowed| {
+            (allowed.type_() == mime::STAR || allowed.type_() == mime.type_())
+                && (allowed.subtype() == mime::STAR || allowed.subtype() == mime.subtype())
+        })
+    });
+    if !allowed {
+        return Err(AppError::UserError((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            match mime {
+                Some(mime) => format!("Files of type {} can't be uploaded", mime.essence_str()),
+                None => "The type of the file could not be determined".to_owned(),
+            }
+            .into(),
+        )));
+    }
+    Ok(())
+}
+
+/// Whether compressing a file of this type is likely to make it smaller.
+/// Images, video, and audio are already compressed, as are archives
+fn is_compressible(mime: &Mime) -> bool {
+    if matches!(mime.type_(), mime::IMAGE | mime::VIDEO | mime::AUDIO) {
+        return false;
+    }
+    !matches!(
+        mime.essence_str(),
+        "application/zip"
+            | "application/gzip"
+            | "application/x-bzip2"
+            | "application/x-xz"
+            | "application/x-7z-compressed"
+            | "application/vnd.rar"
+            | "application/x-rar-compressed"
+            | "application/zstd"
+    )
+}
+
+/// Compress a file with zstd at the given level.
+/// Returns None if compressing it didn't make it any smaller, so it's stored as it is
+fn compress(data: &[u8], level: i32) -> Option<Vec<u8>> {
+    match zstd::bulk::compress(data, level) {
+        Ok(compressed) => (compressed.len() < data.len()).then_some(compressed),
+        Err(e) => {
+            error!("Failed to compress upload: {e}");
+            None
+        }
+    }
+}
+
+/// Download an uploaded file the user can see.
+/// A file can be seen by anyone who uploaded it, anyone in a conversation it was sent to,
+/// and anyone if it is a user's profile image.
+/// Files the user can't see are not found so ids can't be probed for files that exist
+pub async fn download_file(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    UrlPath(id): UrlPath<i64>,
+    request: Request,
+) -> Result<Response, AppError> {
+    let Some(file) = sqlx::query!(
+        "SELECT path, mime, compressed FROM files WHERE id = ?1 AND (
+            EXISTS(SELECT 1 FROM file_uploads WHERE file_id = ?1 AND user_id = ?2)
+            OR EXISTS(SELECT 1 FROM messages
+                JOIN user_conversations ON messages.conversation_id = user_conversations.conversation_id
+                WHERE messages.file_id = ?1 AND user_conversations.user_id = ?2)
+            OR EXISTS(SELECT 1 FROM users WHERE image_id = ?1)
+        )",
+        id,
+        user.id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "File not found".into(),
+        )));
+    };
+    serve_upload(
+        state.storage.as_ref(),
+        &file.path,
+        file.mime,
+        file.compressed,
+        request,
+    )
+    .await
+}
+
+/// Download a profile image by its file name so it can be shown without a token.
+/// Every other upload has to be downloaded by id with `download_file`
+pub async fn download_profile_image(
+    State(state): State<AppState>,
+    UrlPath(file_name): UrlPath<String>,
+    request: Request,
+) -> Result<Response, AppError> {
+    let Some(file) = sqlx::query!(
+        "SELECT mime, compressed FROM files
+        WHERE path = ? AND EXISTS(SELECT 1 FROM users WHERE image_id = files.id)",
+        file_name
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "File not found".into(),
+        )));
+    };
+    serve_upload(
+        state.storage.as_ref(),
+        &file_name,
+        file.mime,
+        file.compressed,
+        request,
+    )
+    .await
+}
+
+/// Send an uploaded file from storage.
+/// Files that were compressed when they were uploaded are decompressed before being sent
+async fn serve_upload(
+    storage: &dyn Storage,
+    file_name: &str,
+    mime: Option<String>,
+    compressed: bool,
+    request: Request,
+) -> Result<Response, AppError> {
+    if let Some(path) = storage.local_path(file_name).filter(|_| !compressed) {
+        // Files stored on disk as they are get range requests and caching headers from `ServeFile`
+        return Ok(ServeFile::new(path).oneshot(request).await?.into_response());
+    }
+
+    let data = match storage.get(file_name).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(AppError::UserError((
+                StatusCode::NOT_FOUND,
+                "File not found".into(),
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let data = if compressed {
+        tokio::task::spawn_blocking(move || zstd::decode_all(data.as_ref()))
+            .await??
+            .into()
+    } else {
+        data
+    };
+    let mime = mime.unwrap_or_else(|| {
+        mime_guess::from_path(file_name)
+            .first_or_octet_stream()
+            .to_string()
+    });
+    Ok(([(header::CONTENT_TYPE, mime)], data).into_response())
+}
+
+/// The maximum number of characters kept in a document's text preview
+#[cfg(feature = "pdf-preview")]
+const PREVIEW_LENGTH: usize = 500;
+
+/// Extract a preview of the text on the first page of a PDF.
+/// Returns None for encrypted PDFs and PDFs without a text layer, such as scanned documents
+#[cfg(feature = "pdf-preview")]
+fn pdf_preview(data: &[u8]) -> Option<String> {
+    let document = lopdf::Document::load_mem(data).ok()?;
+    if document.is_encrypted() {
+        return None;
+    }
+    let text = document.extract_text(&[1]).ok()?;
+    // Collapse the line breaks and spacing left over from the PDF's layout
+    let mut preview = String::with_capacity(PREVIEW_LENGTH);
+    for word in text.split_whitespace() {
+        if !preview.is_empty() {
+            preview.push(' ');
+        }
+        preview.push_str(word);
+        if preview.chars().count() >= PREVIEW_LENGTH {
+            break;
+        }
+    }
+    let preview: String = preview.chars().take(PREVIEW_LENGTH).collect();
+    (!preview.is_empty()).then_some(preview)
+}
+
+#[cfg(not(feature = "pdf-preview"))]
+fn pdf_preview(_data: &[u8]) -> Option<String> {
+    None
+}
+
 // Used to upload specifically profile images
 pub async fn upload_profile_image(
-    State(state): State<SqlitePool>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     JwtAuth(user): JwtAuth<UserToken>,
     AppJson(upload_data): AppJson<FileUpload>,
 ) -> Result<Response, AppError> {
@@ -182,16 +434,7 @@ pub async fn upload_profile_image(
         )));
     }
 
-    if !upload_file
-        .mime
-        .as_ref()
-        .is_some_and(|mime| mime.type_() == mime::IMAGE)
-    {
-        return Err(AppError::UserError((
-            StatusCode::BAD_REQUEST,
-            "Invalid file type".into(),
-        )));
-    }
+    check_file_type(upload_file.mime.as_ref(), &[mime::IMAGE_STAR])?;
 
     let original_image = image::load_from_memory(&upload_file.data)?;
 
@@ -203,38 +446,46 @@ pub async fn upload_profile_image(
 
     let file_name = format!("{}.png", hash);
 
-    // Create the uploads directory if it does not
-    // already exist and ignore the error if it does
-    match create_dir("./uploads") {
-        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
-        _ => (),
-    }
-
-    let path = PathBuf::from(format!("uploads/{}", file_name));
-
-    if !path.exists() {
-        let mut file = std::fs::File::create(&path)?;
-        let mut buf_writer = BufWriter::new(&mut file);
-        cropped_image.write_to(&mut buf_writer, ImageFormat::Png)?;
-    }
+    // Encode the image in memory so writing it to disk is handled the same as any other upload
+    let mut data = Vec::new();
+    cropped_image.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+    let size = data.len() as i64;
+    check_quota(
+        &mut *state.pool.acquire().await?,
+        state.upload_quota,
+        user.id,
+        &file_name,
+        size,
+    )
+    .await?;
+    save_upload(state.storage.as_ref(), &file_name, &data).await?;
 
+    let mut tx = state.pool.begin().await?;
     let file_id = sqlx::query!(
-            "INSERT INTO files (path, mime, profile_image) VALUES (?, ?, ?) ON CONFLICT DO UPDATE SET path = path RETURNING id",
+            "INSERT INTO files (path, mime, profile_image, size) VALUES (?, ?, ?, ?) ON CONFLICT DO UPDATE SET path = path RETURNING id",
             file_name,
             "image/png",
-            true
+            true,
+            size
         )
-        .fetch_one(&state)
+        .fetch_one(&mut *tx)
         .await?
         .id;
 
+    // Checked again now that the write lock is held, like other uploads
+    check_quota(&mut tx, state.upload_quota, user.id, &file_name, size).await?;
     let id = sqlx::query!(
             "INSERT INTO file_uploads (file_id, user_id) VALUES (?, ?) ON CONFLICT DO UPDATE SET file_id = file_id RETURNING file_id as id",
             file_id,
             user.id
         )
-        .fetch_one(&state)
+        .fetch_one(&mut *tx)
         .await?.id;
+    AuditEvent::new(user.id, AuditAction::ProfileImageUpload)
+        .values(None, Some(id.to_string()))
+        .record(&mut tx, addr.ip())
+        .await?;
+    tx.commit().await?;
 
     Ok((
         StatusCode::CREATED,
@@ -243,6 +494,131 @@ pub async fn upload_profile_image(
         .into_response())
 }
 
+/// Reject an upload with `413 Payload Too Large` if storing it would put the user over their quota.
+/// Each file counts once however many times the user uploads it, so uploading one they already have is free
+async fn check_quota(
+    conn: &mut SqliteConnection,
+    quota: Option<i64>,
+    user_id: i64,
+    file_name: &str,
+    size: i64,
+) -> Result<(), AppError> {
+    let Some(quota) = quota else {
+        return Ok(());
+    };
+    // Files uploaded before sizes were recorded don't count towards the quota
+    let used = sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(files.size), 0) as "used!: i64" FROM file_uploads
+        JOIN files ON file_uploads.file_id = files.id
+        WHERE file_uploads.user_id = ? AND files.path != ?"#,
+        user_id,
+        file_name
+    )
+    .fetch_one(conn)
+    .await?;
+    if used + size > quota {
+        return Err(AppError::UserError((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Uploading this file would go over your storage quota of {} MB, {} MB is used",
+                quota / 1_000_000,
+                used / 1_000_000
+            )
+            .into(),
+        )));
+    }
+    Ok(())
+}
+
+/// Write an uploaded file to storage.
+/// Files are named by their hash so a file that already exists doesn't need to be written again.
+/// Two requests can still both find the file missing and store it at once, which is fine
+/// since storing a file replaces it in one step with the same contents
+async fn save_upload(storage: &dyn Storage, file_name: &str, data: &[u8]) -> Result<(), AppError> {
+    let save = async {
+        if storage.exists(file_name).await? {
+            return Ok(());
+        }
+        storage.put(file_name, data).await
+    };
+    save.await.map_err(|e| storage_error(file_name, e))
+}
+
+/// Delete the upload records of a user and the files nobody else refers to anymore.
+/// Returns the paths of the deleted files so they can be removed from storage once the transaction commits
+pub(crate) async fn delete_user_uploads(
+    tx: &mut SqliteConnection,
+    user_id: i64,
+) -> Result<Vec<String>, AppError> {
+    let file_ids = sqlx::query_scalar!(
+        "DELETE FROM file_uploads WHERE user_id = ? RETURNING file_id",
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut paths = Vec::new();
+    for file_id in file_ids {
+        // Files can still be attached to messages, used as a profile image,
+        // or kept for another deleted account that might be reactivated
+        if let Some(path) = sqlx::query_scalar!(
+            "DELETE FROM files WHERE id = ?1
+            AND NOT EXISTS (SELECT 1 FROM file_uploads WHERE file_id = ?1)
+            AND NOT EXISTS (SELECT 1 FROM messages WHERE file_id = ?1)
+            AND NOT EXISTS (SELECT 1 FROM users WHERE image_id = ?1)
+            AND NOT EXISTS (SELECT 1 FROM deleted_user_profiles WHERE image_id = ?1 AND user_id != ?2)
+            RETURNING path",
+            file_id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Remove files that are no longer referenced from storage
+pub(crate) async fn remove_uploads(storage: &dyn Storage, paths: &[String]) {
+    for path in paths {
+        if let Err(e) = storage.delete(path).await {
+            error!("Could not remove upload {path}: {e}");
+        }
+    }
+}
+
+/// Log why an upload could not be stored and pick the response to send back.
+/// Running out of space is reported as `507 Insufficient Storage`, anything else is a server error
+fn storage_error(file_name: &str, e: io::Error) -> AppError {
+    match e.kind() {
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => {
+            error!("Could not store upload {file_name}: the disk is full or over quota ({e})");
+            AppError::UserError((
+                StatusCode::INSUFFICIENT_STORAGE,
+                "There is not enough space on the server to store the file".into(),
+            ))
+        }
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => {
+            error!(
+                "Could not store upload {file_name}: permission denied, make sure the server can write to the {UPLOAD_DIR} directory or bucket ({e})"
+            );
+            AppError::UserError((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "The server is unable to store files right now".into(),
+            ))
+        }
+        _ => {
+            error!("Could not store upload {file_name}: {e}");
+            AppError::UserError((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "The server is unable to store files right now".into(),
+            ))
+        }
+    }
+}
+
 // Crop an image into a square using the center as the anchor point
 fn crop_square(image: &DynamicImage) -> DynamicImage {
     let (iwidth, iheight) = image.dimensions();
@@ -1,29 +1,45 @@
-use std::ops::ControlFlow;
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::ControlFlow,
+    sync::Arc,
+};
 
 use anyhow::{anyhow, Result};
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::{
         header::{self, AUTHORIZATION},
-        HeaderMap, StatusCode,
+        HeaderMap, HeaderValue, StatusCode,
     },
     response::{IntoResponse, Response},
 };
-use dotenvy_macro::dotenv;
-use futures::{FutureExt, StreamExt, TryStreamExt};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use base64::{engine::general_purpose, Engine};
+use chrono::{NaiveDateTime, Utc};
+use futures::future::join_all;
 use macros::response;
 use password_auth::VerifyError;
 use serde::{Deserialize, Serialize};
 use sonic_rs::json;
-use sqlx::{prelude::Type, SqlitePool};
+use sqlx::{prelude::Type, Executor, QueryBuilder, Row, Sqlite, SqliteConnection, SqlitePool};
+use tracing::error;
+use unicode_normalization::UnicodeNormalization;
 use validator::{Validate, ValidationError, ValidationErrorsKind};
 
 use crate::{
-    auth::JwtAuth,
-    chat::{get_user_status, OnlineStatus},
+    audit::{AuditAction, AuditEvent},
+    auth::{token_is_current, ApiKeyScope, AuthConfig, JwtAuth, JwtError, PasswordHasher},
+    bans::check_ban,
+    chat::{
+        announce_leave, get_user_status, leave_conversation_in, send_to_user, OnlineStatus,
+        SocketResponse,
+    },
+    cli::Args,
     error::{AppError, AppJson, AppValidate},
+    mail::{send_email, Email, Mailer},
+    recovery::{recovery_code_valid, use_recovery_code},
     state::AppState,
+    upload::{delete_user_uploads, remove_uploads},
+    utils::{double_option, Requested},
 };
 
 /// The data required to create a new user
@@ -62,6 +78,66 @@ pub struct CreateUser {
     pub image_id: Option<i64>,
 }
 
+/// The changes to make to a user's account.
+/// Only the fields that are provided are changed
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateUser {
+    /// The user's current password, required to make any change.
+    /// Users who signed up with an OAuth provider don't have one,
+    /// so they have to have logged in recently instead
+    pub password: String,
+    #[validate(email(code = "Invalid email address"))]
+    pub email: Option<String>,
+    #[validate(length(
+        min = 1,
+        max = 30,
+        code = "First name must be between 1 and 30 characters"
+    ))]
+    pub first_name: Option<String>,
+    /// `null` removes the last name
+    #[serde(default, deserialize_with = "double_option")]
+    pub last_name: Option<Option<String>>,
+    #[validate(
+        length(
+            min = 3,
+            max = 20,
+            code = "Username must be between 3 and 20 characters"
+        ),
+        custom(function = "validate_username")
+    )]
+    pub username: Option<String>,
+    /// `null` removes the profile image
+    #[serde(default, deserialize_with = "double_option")]
+    pub image_id: Option<Option<i64>>,
+    /// `null` removes the bio
+    #[serde(default, deserialize_with = "double_option")]
+    #[validate(length(max = 500, code = "Bio must be at most 500 characters"))]
+    pub bio: Option<Option<String>>,
+    /// `null` removes the pronouns
+    #[serde(default, deserialize_with = "double_option")]
+    #[validate(length(max = 30, code = "Pronouns must be at most 30 characters"))]
+    pub pronouns: Option<Option<String>>,
+    /// `null` removes the location
+    #[serde(default, deserialize_with = "double_option")]
+    #[validate(length(max = 100, code = "Location must be at most 100 characters"))]
+    pub location: Option<Option<String>>,
+}
+
+impl UpdateUser {
+    /// Whether any of the user's details besides their email are being changed.
+    /// A new email is only set once it has been confirmed
+    fn has_changes(&self) -> bool {
+        self.first_name.is_some()
+            || self.last_name.is_some()
+            || self.username.is_some()
+            || self.image_id.is_some()
+            || self.bio.is_some()
+            || self.pronouns.is_some()
+            || self.location.is_some()
+    }
+}
+
 pub trait PrettyValidate {
     fn pretty_validate(&self) -> Result<(), String>;
 }
@@ -89,32 +165,36 @@ impl<T: Validate> PrettyValidate for T {
 }
 
 pub async fn create_user(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     AppJson(user_data): AppJson<CreateUser>,
 ) -> Result<Response, AppError> {
     user_data.app_validate()?;
+    let pool = &state.pool;
 
-    if let Some(existing_user) = sqlx::query!(
-        "SELECT username, email FROM users where username = ? or email = ?",
-        user_data.username,
-        user_data.email
+    if username_taken(
+        pool,
+        &user_data.username,
+        state.username_changes.reservation_days,
+        None,
     )
-    .fetch_optional(&pool)
     .await?
     {
-        if existing_user.username == user_data.username {
-            return Err(AppError::UserError((
-                StatusCode::CONFLICT,
-                "Username already exists".into(),
-            )));
-        } else {
-            return Err(AppError::UserError((
-                StatusCode::CONFLICT,
-                "Email already in use".into(),
-            )));
-        }
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "Username already exists".into(),
+        )));
     }
-    let hashed_password = password_auth::generate_hash(&user_data.password);
+    if email_taken(pool, &user_data.email).await? {
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "Email already in use".into(),
+        )));
+    }
+    let hashed_password = hash_password(&state.password_hasher, &user_data.password).await?;
+
+    // Use a transaction so a user is never created without their settings
+    // or assistant conversation
+    let mut tx = pool.begin().await?;
 
     // Insert the user into the database
     let user_id = sqlx::query!(
@@ -124,12 +204,18 @@ pub async fn create_user(
         hashed_password,
         user_data.first_name,
         user_data.last_name
-    ).fetch_one(&pool).await?.id;
+    ).fetch_one(&mut *tx).await?.id;
 
-    // Insert the default user settings
-    sqlx::query!("INSERT INTO user_settings (user_id) VALUES (?)", user_id)
-        .execute(&pool)
-        .await?;
+    init_new_user(&state, &mut tx, user_id).await?;
+    let verification_token = create_email_verification(&mut tx, user_id).await?;
+
+    tx.commit().await?;
+
+    send_email(
+        &*state.mailer,
+        verification_email(&user_data.email, &verification_token),
+    )
+    .await;
 
     Ok((
         StatusCode::CREATED,
@@ -138,72 +224,302 @@ pub async fn create_user(
         .into_response())
 }
 
+/// The first message in the assistant conversation of a new user
+const ASSISTANT_GREETING: &str = "Hi! I'm your health assistant. \
+    Turn on AI in your settings and ask me anything about your health, \
+    or share your daily health forms and I'll help you spot trends.";
+
+/// Create the settings and assistant conversation every new user starts with
+pub(crate) async fn init_new_user(
+    state: &AppState,
+    conn: &mut SqliteConnection,
+    user_id: i64,
+) -> Result<(), sqlx::Error> {
+    // Insert the default user settings
+    // AI stays off until the user turns it on, but pick the default model
+    // so it works as soon as they do
+    sqlx::query!(
+        "INSERT INTO user_settings (user_id, ai_model_id)
+        VALUES (?, (SELECT id FROM ai_models ORDER BY id LIMIT 1))",
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    if state.assistant_conversation {
+        let conversation_id = sqlx::query!(
+            "INSERT INTO conversations (title) VALUES ('Health Assistant') RETURNING id"
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .id;
+        sqlx::query!(
+            "INSERT INTO user_conversations (user_id, conversation_id, role) VALUES (?, ?, 'owner')",
+            user_id,
+            conversation_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        // Start the conversation with a greeting from the assistant so it isn't empty
+        // The greeting is left out of the search index, there is nothing in it worth finding
+        sqlx::query!(
+            "INSERT INTO messages (conversation_id, message, ai_model_id)
+            SELECT ?, ?, ai_model_id FROM user_settings WHERE user_id = ?",
+            conversation_id,
+            ASSISTANT_GREETING,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
 pub async fn check_username(
     State(pool): State<SqlitePool>,
+    State(username_changes): State<UsernameChanges>,
     user: Option<JwtAuth<UserToken>>,
     Path(username): Path<String>,
 ) -> Result<Response, AppError> {
-    if username.len() < 3 || username.len() > 20 || validate_username(&username).is_err() {
-        return Err(AppError::UserError((
+    let user = user.map(|JwtAuth(user)| user);
+    match username_availability(&pool, username_changes, user.as_ref(), &username).await? {
+        Availability::Available => Ok(StatusCode::OK.into_response()),
+        Availability::Invalid => Err(AppError::UserError((
             StatusCode::BAD_REQUEST,
             "Invalid username".into(),
-        )));
-    }
-    // If the user is authenticated, check if the username is the same
-    // as the one already in the database. If it is, then that is allowed
-    if user.is_some_and(|JwtAuth(user)| user.username == username) {
-        return Ok(StatusCode::OK.into_response());
-    }
-    match sqlx::query!("SELECT username FROM users WHERE username = ?", username)
-        .fetch_optional(&pool)
-        .await?
-    {
-        Some(_) => Ok((
+        ))),
+        Availability::Taken => Ok((
             StatusCode::CONFLICT,
             AppJson(response!("Username is already in use")),
         )
             .into_response()),
-        None => Ok(StatusCode::OK.into_response()),
     }
 }
 
-pub async fn check_email(
-    State(pool): State<SqlitePool>,
-    user: Option<JwtAuth<UserToken>>,
-    Path(email): Path<String>,
-) -> Result<Response, AppError> {
-    let email_regex = regex::Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").unwrap();
-    if !email_regex.is_match(&email) {
-        return Err(AppError::UserError((
-            StatusCode::BAD_REQUEST,
-            "Invalid email".into(),
-        )));
+/// Whether a username or email can be used
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Availability {
+    Available,
+    /// Doesn't meet the rules for a username or email
+    Invalid,
+    Taken,
+}
+
+/// Check if the username can be used by the user.
+/// Users can always keep their own username
+async fn username_availability(
+    pool: &SqlitePool,
+    username_changes: UsernameChanges,
+    user: Option<&UserToken>,
+    username: &str,
+) -> Result<Availability, AppError> {
+    if username.len() < 3 || username.len() > 20 || validate_username(username).is_err() {
+        return Ok(Availability::Invalid);
+    }
+    // If the user is authenticated, check if the username is the same
+    // as the one already in the database. If it is, then that is allowed
+    if user.is_some_and(|user| user.username == username) {
+        return Ok(Availability::Available);
+    }
+    let user_id = user.map(|user| user.id);
+    if username_taken(pool, username, username_changes.reservation_days, user_id).await? {
+        Ok(Availability::Taken)
+    } else {
+        Ok(Availability::Available)
+    }
+}
+
+/// Check if the email can be used by the user.
+/// Users can always keep their own email
+async fn email_availability(
+    pool: &SqlitePool,
+    user: Option<&UserToken>,
+    email: &str,
+) -> Result<Availability, AppError> {
+    if !is_valid_email(email) {
+        return Ok(Availability::Invalid);
     }
     // If the user is authenticated, check if the email is the same
     // as the one already in the database. If it is, then that is allowed
-    if let Some(JwtAuth(user)) = user {
+    if let Some(user) = user {
         if sqlx::query!("SELECT email FROM users WHERE id = ?", user.id)
-            .fetch_optional(&pool)
+            .fetch_optional(pool)
             .await?
             .is_some_and(|row| row.email == email)
         {
-            return Ok(StatusCode::OK.into_response());
+            return Ok(Availability::Available);
         }
     }
-    match sqlx::query!("SELECT email FROM users WHERE email = ?", email)
-        .fetch_optional(&pool)
-        .await?
-    {
-        Some(_) => Ok((
+    if email_taken(pool, email).await? {
+        Ok(Availability::Taken)
+    } else {
+        Ok(Availability::Available)
+    }
+}
+
+/// The fields to check the availability of, any left out aren't checked
+#[derive(Deserialize, Debug)]
+pub struct CheckAvailability {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+/// The availability of each field that was checked
+#[derive(Serialize, Debug)]
+pub struct AvailabilityResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<Availability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<Availability>,
+}
+
+/// Check whether a username and email can be used in a single request.
+/// Uses the same rules as the single field checks
+pub async fn check_availability(
+    State(pool): State<SqlitePool>,
+    State(username_changes): State<UsernameChanges>,
+    user: Option<JwtAuth<UserToken>>,
+    AppJson(fields): AppJson<CheckAvailability>,
+) -> Result<Response, AppError> {
+    let user = user.map(|JwtAuth(user)| user);
+    let username = match &fields.username {
+        Some(username) => {
+            Some(username_availability(&pool, username_changes, user.as_ref(), username).await?)
+        }
+        None => None,
+    };
+    let email = match &fields.email {
+        Some(email) => Some(email_availability(&pool, user.as_ref(), email).await?),
+        None => None,
+    };
+    Ok((
+        StatusCode::OK,
+        AppJson(AvailabilityResult { username, email }),
+    )
+        .into_response())
+}
+
+/// Limits on how users can change their username
+#[derive(Clone, Copy, Debug)]
+pub struct UsernameChanges {
+    /// How many days a user has to wait between changing their username
+    pub cooldown_days: u32,
+    /// How many days an old username is held so no one else can take it
+    pub reservation_days: u32,
+}
+
+impl From<&Args> for UsernameChanges {
+    fn from(args: &Args) -> Self {
+        Self {
+            cooldown_days: args.username_change_cooldown_days,
+            reservation_days: args.username_reservation_days,
+        }
+    }
+}
+
+/// Check if a username belongs to anyone, including deleted accounts that can still be reactivated
+/// and usernames that were recently changed away from.
+/// `user_id` is the user asking for the username, who can always take back their own old usernames
+pub(crate) async fn username_taken(
+    pool: &SqlitePool,
+    username: &str,
+    reservation_days: u32,
+    user_id: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM users WHERE username = ?1)
+        OR EXISTS(SELECT 1 FROM deleted_user_profiles WHERE username = ?1)
+        OR EXISTS(SELECT 1 FROM username_history WHERE old_username = ?1
+        AND user_id IS NOT ?3
+        AND changed_at > datetime('now', '-' || ?2 || ' days')) as "taken!: bool""#,
+        username,
+        reservation_days,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn check_email(
+    State(pool): State<SqlitePool>,
+    user: Option<JwtAuth<UserToken>>,
+    Path(email): Path<String>,
+) -> Result<Response, AppError> {
+    let user = user.map(|JwtAuth(user)| user);
+    match email_availability(&pool, user.as_ref(), &email).await? {
+        Availability::Available => Ok(StatusCode::OK.into_response()),
+        Availability::Invalid => Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid email".into(),
+        ))),
+        Availability::Taken => Ok((
             StatusCode::CONFLICT,
             AppJson(response!("Email is already in use")),
         )
             .into_response()),
-        None => Ok(StatusCode::OK.into_response()),
     }
 }
 
+/// Check if an email belongs to anyone, including emails users are waiting to confirm changing to
+pub(crate) async fn email_taken<'e, E>(executor: E, email: &str) -> Result<bool, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM users WHERE email = ?1)
+        OR EXISTS(
+            SELECT 1 FROM pending_email_changes
+            WHERE email = ?1 AND expires_at > CURRENT_TIMESTAMP
+        ) as "taken!: bool""#,
+        email
+    )
+    .fetch_one(executor)
+    .await
+}
+
+/// Loosely check that a string looks like an email address
+fn is_valid_email(email: &str) -> bool {
+    let email_regex = regex::Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").unwrap();
+    email_regex.is_match(email)
+}
+
+/// Logins take either a username or an email, usernames can't contain an `@` so it tells them apart
+fn validate_login_name(login_name: &str) -> Result<(), ValidationError> {
+    if login_name.contains('@') {
+        return if is_valid_email(login_name) {
+            Ok(())
+        } else {
+            Err(ValidationError::new("Invalid email address"))
+        };
+    }
+    if !(3..=20).contains(&login_name.chars().count()) {
+        return Err(ValidationError::new(
+            "Username must be between 3 and 20 characters",
+        ));
+    }
+    validate_username(login_name)
+}
+
+/// Deleted accounts are renamed to this followed by their id
+const DELETED_USERNAME_PREFIX: &str = "deleted_user_";
+
+/// Placeholders for users from imported conversations are named this followed by their id
+pub(crate) const IMPORTED_USERNAME_PREFIX: &str = "imported_user_";
+
 pub fn validate_username(username: &str) -> Result<(), ValidationError> {
+    // Deleted and placeholder accounts are named with these prefixes so they can't be used by anyone else
+    let lowercase = username.to_lowercase();
+    if lowercase.starts_with(DELETED_USERNAME_PREFIX) {
+        return Err(ValidationError::new(
+            r#"must not start with "deleted_user_""#,
+        ));
+    }
+    if lowercase.starts_with(IMPORTED_USERNAME_PREFIX) {
+        return Err(ValidationError::new(
+            r#"must not start with "imported_user_""#,
+        ));
+    }
     match username
         .chars()
         .try_fold((0, 0), |(alphanumeric, underscore), c| {
@@ -231,38 +547,84 @@ pub fn validate_username(username: &str) -> Result<(), ValidationError> {
     }
 }
 
-/// Verify that the password only contains ASCII characters
+/// The most bytes a password can take up, regardless of how many characters it has.
+/// Passwords can contain any Unicode, so this bounds the work of hashing a long one
+const MAX_PASSWORD_BYTES: usize = 512;
+
+/// Check a password being set. Any printable character is allowed
 fn validate_password(password: &str) -> Result<(), ValidationError> {
-    if !password.is_ascii() {
-        Err(ValidationError::new(
-            r#"must only contain alphanumeric characters and ASCII symbols"#,
-        ))
-    } else {
-        Ok(())
+    validate_password_bytes(password)?;
+    if password.chars().any(char::is_control) {
+        return Err(ValidationError::new(
+            "Password must not contain control characters",
+        ));
+    }
+    Ok(())
+}
+
+/// Check a password being logged in with.
+/// Control characters aren't rejected so passwords set before they were disallowed still work
+fn validate_password_bytes(password: &str) -> Result<(), ValidationError> {
+    if password.len() > MAX_PASSWORD_BYTES {
+        return Err(ValidationError::new("Password is too long"));
     }
+    Ok(())
+}
+
+/// Normalize a password with NFKC before it is hashed or verified so the same passphrase
+/// matches however the user's keyboard encodes it. ASCII passwords are unchanged
+pub(crate) fn normalize_password(password: &str) -> String {
+    password.nfkc().collect()
+}
+
+/// Normalize and hash a new password on a blocking thread.
+/// Argon2 is slow on purpose, so hashing on the runtime would stall every other request
+pub(crate) async fn hash_password(
+    hasher: &PasswordHasher,
+    password: &str,
+) -> Result<String, AppError> {
+    let hasher = hasher.clone();
+    let password = normalize_password(password);
+    Ok(tokio::task::spawn_blocking(move || hasher.hash(password)).await?)
+}
+
+/// Check a password against its stored hash on a blocking thread, for the same reason as `hash_password`
+pub(crate) async fn verify_password(
+    password: &str,
+    hash: &str,
+) -> Result<Result<(), VerifyError>, AppError> {
+    let password = normalize_password(password);
+    let hash = hash.to_owned();
+    Ok(
+        tokio::task::spawn_blocking(move || password_auth::verify_password(password, &hash))
+            .await?,
+    )
 }
 
 /// The data required to authenticate a user
 #[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
 pub struct LoginData {
-    #[validate(
-        length(
-            min = 3,
-            max = 20,
-            code = "Username must be between 3 and 20 characters"
-        ),
-        custom(function = "validate_username")
-    )]
+    /// The username or email of the account
+    #[validate(custom(function = "validate_login_name"))]
     pub username: String,
+    /// Required unless logging in with a recovery code
     #[validate(
         length(
             min = 8,
             max = 128,
             code = "Password must be between 8 and 128 characters"
         ),
-        custom(function = "validate_password")
+        custom(function = "validate_password_bytes")
     )]
-    pub password: String,
+    pub password: Option<String>,
+    /// A single use recovery code to log in with instead of the password
+    #[serde(default)]
+    #[validate(length(max = 64, code = "Recovery code is too long"))]
+    pub recovery_code: Option<String>,
+    /// Restore the account if it was deleted but hasn't been purged yet
+    #[serde(default)]
+    pub reactivate: bool,
 }
 
 /// The data stored in the JWT token
@@ -271,22 +633,100 @@ pub struct UserToken {
     pub id: i64,
     pub username: String,
     pub exp: i64,
+    /// The user's token version when the token was issued
+    /// Tokens with an outdated version have been revoked
+    #[serde(default)]
+    pub ver: i64,
+    /// The login session the token belongs to
+    /// Tokens issued before sessions were tracked don't have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<i64>,
+    /// The server that issued the token, set from the config when the token is signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// Who the token is meant for, set from the config when the token is signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
 }
 
 pub async fn authenticate_user(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     AppJson(user_data): AppJson<LoginData>,
 ) -> Result<Response, AppError> {
     user_data.app_validate()?;
+    if user_data.password.is_some() == user_data.recovery_code.is_some() {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Log in with either a password or a recovery code".into(),
+        )));
+    }
+    let pool = &state.pool;
+
+    // Emails are swapped for the account's username so logging in with either
+    // counts towards the same lockout. Unknown emails are left as they are
+    // and fail like an unknown username
+    let username = if user_data.username.contains('@') {
+        sqlx::query_scalar!(
+            r#"SELECT COALESCE(deleted_user_profiles.username, users.username) as "username!: String" FROM users
+            LEFT JOIN deleted_user_profiles ON users.id = deleted_user_profiles.user_id
+            WHERE email = ?"#,
+            user_data.username
+        )
+        .fetch_optional(pool)
+        .await?
+        .unwrap_or_else(|| user_data.username.clone())
+    } else {
+        user_data.username.clone()
+    };
+
+    state
+        .login_attempts
+        .attempt(&username, addr.ip())
+        .await
+        .map_err(AppError::RateLimited)?;
+
+    // Deleted accounts are renamed, so they can only be found by their original username
+    if let Some(deleted_user) = sqlx::query!(
+        "SELECT user_id, password_hash FROM deleted_user_profiles
+        JOIN users ON users.id = deleted_user_profiles.user_id
+        WHERE deleted_user_profiles.username = ?",
+        username
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        if !credentials_valid(
+            pool,
+            deleted_user.user_id,
+            deleted_user.password_hash.as_deref(),
+            &user_data,
+        )
+        .await?
+        {
+            return Err(AppError::UserError((
+                StatusCode::UNAUTHORIZED,
+                "Invalid username or password".into(),
+            )));
+        }
+        if !user_data.reactivate {
+            return Err(AppError::UserError((
+                StatusCode::FORBIDDEN,
+                "This account has been deleted, log in with reactivate set to restore it".into(),
+            )));
+        }
+        reactivate_account(pool, deleted_user.user_id).await?;
+    }
 
     let Some(existing_user) = sqlx::query!(
-        "SELECT users.id, username, email, first_name, last_name,
-            password_hash, path as image_path FROM users
+        "SELECT users.id, username, email, first_name, last_name, bio, pronouns, location,
+            password_hash, token_version, email_verified, path as image_path FROM users
             LEFT JOIN files ON users.image_id = files.id
             WHERE username = ?",
-        user_data.username
+        username
     )
-    .fetch_optional(&pool)
+    .fetch_optional(pool)
     .await?
     else {
         return Err(AppError::UserError((
@@ -295,24 +735,63 @@ pub async fn authenticate_user(
         )));
     };
 
-    match password_auth::verify_password(&user_data.password, &existing_user.password_hash) {
-        Ok(_) => (),
-        Err(VerifyError::PasswordInvalid) => {
+    if !credentials_valid(
+        pool,
+        existing_user.id,
+        existing_user.password_hash.as_deref(),
+        &user_data,
+    )
+    .await?
+    {
+        return Err(AppError::UserError((
+            StatusCode::UNAUTHORIZED,
+            "Invalid username or password".into(),
+        )));
+    }
+    // Upgrade hashes made with weaker settings now that we know the password.
+    // Only replaced if it hasn't changed since it was read so a concurrent
+    // password change isn't overwritten
+    if let (Some(password), Some(password_hash)) =
+        (&user_data.password, &existing_user.password_hash)
+    {
+        if state.password_hasher.needs_rehash(password_hash) {
+            let new_hash = hash_password(&state.password_hasher, password).await?;
+            sqlx::query!(
+                "UPDATE users SET password_hash = ? WHERE id = ? AND password_hash = ?",
+                new_hash,
+                existing_user.id,
+                password_hash
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    // Only checked after the password so this doesn't reveal which accounts are unverified
+    if state.require_email_verification && !existing_user.email_verified {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Email address has not been verified".into(),
+        )));
+    }
+
+    // The code is used up after the other checks so a refused login can try it again
+    if let Some(code) = &user_data.recovery_code {
+        let mut tx = pool.begin().await?;
+        if !use_recovery_code(&mut tx, existing_user.id, code).await? {
+            // Another login used the code since it was checked
             return Err(AppError::UserError((
                 StatusCode::UNAUTHORIZED,
                 "Invalid username or password".into(),
             )));
         }
-        Err(e) => {
-            return Err(e.into());
-        }
+        AuditEvent::new(existing_user.id, AuditAction::RecoveryCodeLogin)
+            .record(&mut tx, addr.ip())
+            .await?;
+        tx.commit().await?;
     }
 
-    let token_data = UserToken {
-        id: existing_user.id,
-        username: existing_user.username.clone(),
-        exp: (chrono::Utc::now() + chrono::Duration::days(1)).timestamp(),
-    };
+    state.login_attempts.succeeded(&username, addr.ip()).await;
 
     let user = SessionUser {
         id: existing_user.id,
@@ -323,17 +802,108 @@ pub async fn authenticate_user(
         // Have to check if the image path is empty since it is left join and
         // sqlx can't check if the join has a null column for some reason
         image_path: (!existing_user.image_path.is_empty()).then_some(existing_user.image_path),
+        bio: existing_user.bio,
+        pronouns: existing_user.pronouns,
+        location: existing_user.location,
+    };
+    start_session(
+        &state,
+        user,
+        existing_user.token_version,
+        &headers,
+        addr.ip(),
+        // The user has likely lost their password if they needed a recovery code
+        user_data.recovery_code.is_some(),
+    )
+    .await
+}
+
+/// Check the password or recovery code the user is logging in with.
+/// Recovery codes aren't used up here since the login can still be refused
+async fn credentials_valid(
+    pool: &SqlitePool,
+    user_id: i64,
+    password_hash: Option<&str>,
+    login: &LoginData,
+) -> Result<bool, AppError> {
+    if let Some(code) = &login.recovery_code {
+        return Ok(recovery_code_valid(pool, user_id, code).await?);
+    }
+    // Users who signed up with an OAuth provider don't have a password to log in with
+    let (Some(password), Some(password_hash)) = (&login.password, password_hash) else {
+        return Ok(false);
+    };
+    match verify_password(password, password_hash).await? {
+        Ok(_) => Ok(true),
+        Err(VerifyError::PasswordInvalid) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The body of a successful login
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginResponse {
+    message: &'static str,
+    user: SessionUser,
+    /// Set when the user logged in with a recovery code so the client asks them to set a new password
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    password_reset_required: bool,
+}
+
+/// Start a new login session for the user.
+/// Responds with the session's token in the authorization header and the user's data
+pub(crate) async fn start_session(
+    state: &AppState,
+    user: SessionUser,
+    token_version: i64,
+    headers: &HeaderMap,
+    ip: IpAddr,
+    password_reset_required: bool,
+) -> Result<Response, AppError> {
+    // Every way of logging in ends up here, after the user has proven who they are
+    // so a ban doesn't reveal that the account exists
+    check_ban(&state.pool, user.id).await?;
+    let exp = state.auth.expiry();
+    let device = headers
+        .get(header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok());
+    let ip = ip.to_string();
+    let session_id = sqlx::query_scalar!(
+        "INSERT INTO sessions (user_id, device, ip, expires_at, password_reset_required)
+        VALUES (?, ?, ?, datetime(?, 'unixepoch'), ?) RETURNING id",
+        user.id,
+        device,
+        ip,
+        exp,
+        password_reset_required
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    let token_data = UserToken {
+        id: user.id,
+        username: user.username.clone(),
+        exp,
+        ver: token_version,
+        sid: Some(session_id),
+        iss: None,
+        aud: None,
     };
 
     Ok((
         StatusCode::OK,
         [(
             header::AUTHORIZATION,
-            format!("Bearer {}", generate_jwt(&token_data)?),
+            format!("Bearer {}", generate_jwt(&state.auth, &token_data)?),
         )],
         // Don't need to set the content-type header since axum does
         // it for us when we wrap the body in a `Json` struct
-        AppJson(response!("Successfully authenticated", user)),
+        AppJson(LoginResponse {
+            message: "Successfully authenticated",
+            user,
+            password_reset_required,
+        }),
     )
         .into_response())
 }
@@ -351,8 +921,14 @@ pub struct SessionUser {
     pub email: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub image_path: Option<String>,
-}
-
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pronouns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
 /// Returns the user data of the currently authenticated user
 /// from their JWT
 pub async fn get_user_from_token(
@@ -361,7 +937,8 @@ pub async fn get_user_from_token(
 ) -> Result<Response, AppError> {
     let Some(user) = sqlx::query_as!(
         SessionUser,
-        "SELECT users.id, username, email, first_name, last_name, path as image_path
+        "SELECT users.id, username, email, first_name, last_name, path as image_path,
+        bio, pronouns, location
         FROM users LEFT JOIN files ON users.image_id = files.id
         WHERE users.id = ?",
         user.id
@@ -377,48 +954,181 @@ pub async fn get_user_from_token(
     Ok((StatusCode::OK, AppJson(user)).into_response())
 }
 
-pub fn authorize_user(headers: &HeaderMap) -> Result<UserToken, AppError> {
+pub async fn authorize_user(
+    pool: &SqlitePool,
+    auth: &AuthConfig,
+    headers: &HeaderMap,
+) -> Result<UserToken, AppError> {
     let Some(token) = headers.get(AUTHORIZATION) else {
-        return Err(AppError::AuthError(anyhow!("No token provided")));
+        return Err(AppError::Jwt(JwtError::MissingToken));
     };
-    let token_data = decode::<UserToken>(
-        token
-            .to_str()?
-            .strip_prefix("Bearer ")
-            .ok_or_else(|| anyhow!("Invalid token"))?,
-        &DecodingKey::from_secret(dotenv!("JWT_KEY").as_bytes()),
-        &Validation::default(),
-    )
-    .map_err(|e| AppError::AuthError(e.into()))?;
+    let token_data = auth
+        .verify(
+            token
+                .to_str()
+                .ok()
+                .and_then(|token| token.strip_prefix("Bearer "))
+                .ok_or(AppError::Jwt(JwtError::Malformed))?,
+        )
+        .map_err(AppError::Jwt)?;
+
+    // Decoding allows some leeway for clock skew, but the token is used for a long lived connection
+    if token_data.exp < chrono::Utc::now().timestamp() {
+        return Err(AppError::Jwt(JwtError::Expired {
+            expired_at: token_data.exp,
+        }));
+    }
 
-    if token_data.claims.exp < chrono::Utc::now().timestamp() {
-        return Err(AppError::AuthError(anyhow!("Token expired")));
+    if !token_is_current(pool, &token_data).await? {
+        return Err(AppError::Jwt(JwtError::RevokedToken));
     }
+    check_ban(pool, token_data.id).await?;
 
-    Ok(token_data.claims)
+    Ok(token_data)
 }
 
 /// Public user data that can be shared with other users
 /// Does not include sensitive information such as email or password
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PublicUser {
     pub id: i64,
     pub username: String,
     pub first_name: String,
+    /// Omitted if the user has no last name
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_name: Option<String>,
+    /// Omitted if the user has no profile image
     #[serde(skip_serializing_if = "Option::is_none")]
     pub image_path: Option<String>,
-    pub status: Option<OnlineStatus>,
+    /// The profile details below are omitted if the user hasn't set them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pronouns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// Users who hide their last seen time are shown as online instead of idle.
+    /// Omitted when the user is looked up without logging in
+    #[serde(default, skip_serializing_if = "Requested::is_not_requested")]
+    pub status: Requested<OnlineStatus>,
+}
+
+/// The online status of a user as shown to other users.
+/// Being idle gives away roughly when the user was last active, so it is hidden along with
+/// the last seen time
+pub(crate) async fn visible_status(
+    state: &AppState,
+    user_id: i64,
+    hide_last_seen: bool,
+) -> OnlineStatus {
+    match get_user_status(state, user_id).await {
+        OnlineStatus::Idle if hide_last_seen => OnlineStatus::Online,
+        status => status,
+    }
+}
+
+/// The online status shown in public user lookups.
+/// It is left out for anyone who isn't logged in, the same as `get_user_presence` requires a login
+async fn public_status(
+    state: &AppState,
+    viewer: &Option<JwtAuth<UserToken>>,
+    user_id: i64,
+    hide_last_seen: bool,
+) -> Requested<OnlineStatus> {
+    match viewer {
+        Some(_) => Requested::Loaded(visible_status(state, user_id, hide_last_seen).await),
+        None => Requested::NotRequested,
+    }
+}
+
+/// The maximum number of users that can be looked up at once with `get_users_by_ids`
+const MAX_BULK_USERS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct UserIds {
+    /// Comma separated ids, optionally wrapped in brackets like a JSON array
+    pub ids: String,
+}
+
+/// Get the public data of multiple users at once.
+/// Users that don't exist are left out of the response
+pub async fn get_users_by_ids(
+    State(state): State<AppState>,
+    viewer: Option<JwtAuth<UserToken>>,
+    Query(query): Query<UserIds>,
+) -> Result<Response, AppError> {
+    let mut ids = query
+        .ids
+        .trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::parse::<i64>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| {
+            AppError::UserError((StatusCode::BAD_REQUEST, "Ids must be integers".into()))
+        })?;
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.len() > MAX_BULK_USERS {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!("Cannot request more than {} users at once", MAX_BULK_USERS).into(),
+        )));
+    }
+    if ids.is_empty() {
+        return Ok((StatusCode::OK, AppJson(Vec::<PublicUser>::new())).into_response());
+    }
+
+    // Final query will look like this: SELECT ... WHERE users.id IN (?, ?, ?)
+    let mut query_builder: QueryBuilder<'_, Sqlite> = QueryBuilder::new(
+        "SELECT users.id, username, first_name, last_name, path as image_path,
+        bio, pronouns, location, COALESCE(hide_last_seen, FALSE) as hide_last_seen FROM users
+        LEFT JOIN files ON files.id = users.image_id
+        LEFT JOIN user_settings ON user_settings.user_id = users.id WHERE users.id IN (",
+    );
+    let mut separated = query_builder.separated(", ");
+    for id in &ids {
+        separated.push_bind(id);
+    }
+    let rows = query_builder
+        .push(")")
+        .build()
+        .fetch_all(&state.pool)
+        .await?;
+
+    let mut users = Vec::with_capacity(rows.len());
+    for row in rows {
+        let id: i64 = row.try_get("id")?;
+        users.push(PublicUser {
+            id,
+            username: row.try_get("username")?,
+            first_name: row.try_get("first_name")?,
+            last_name: row.try_get("last_name")?,
+            image_path: row.try_get("image_path")?,
+            bio: row.try_get("bio")?,
+            pronouns: row.try_get("pronouns")?,
+            location: row.try_get("location")?,
+            status: public_status(&state, &viewer, id, row.try_get("hide_last_seen")?).await,
+        });
+    }
+
+    Ok((StatusCode::OK, AppJson(users)).into_response())
 }
 
 pub async fn get_user_by_id(
     State(state): State<AppState>,
+    viewer: Option<JwtAuth<UserToken>>,
     Path(id): Path<i64>,
 ) -> Result<Response, AppError> {
     let Some(user) = sqlx::query!(
-        "SELECT users.id, username, first_name, last_name, path as image_path FROM users LEFT JOIN files ON files.id = users.image_id WHERE users.id = ?",
+        r#"SELECT users.id, username, first_name, last_name, path as image_path, bio, pronouns, location,
+        COALESCE(hide_last_seen, FALSE) as "hide_last_seen!: bool"
+        FROM users LEFT JOIN files ON files.id = users.image_id
+        LEFT JOIN user_settings ON user_settings.user_id = users.id WHERE users.id = ?"#,
         id
     )
     .fetch_optional(&state.pool)
@@ -438,7 +1148,57 @@ pub async fn get_user_by_id(
             first_name: user.first_name,
             last_name: user.last_name,
             image_path: user.image_path,
-            status: Some(get_user_status(&state, id).await),
+            bio: user.bio,
+            pronouns: user.pronouns,
+            location: user.location,
+            status: public_status(&state, &viewer, id, user.hide_last_seen).await,
+        }),
+    )
+        .into_response())
+}
+
+/// A user's online status and when they were last online
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserPresence {
+    pub user_id: i64,
+    pub status: OnlineStatus,
+    /// When the user's last connection closed
+    /// Omitted if the user hid it or has never connected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_at: Option<NaiveDateTime>,
+}
+
+/// Get whether a user is online and when they were last seen.
+/// Users who hide their last seen time are only ever shown as online or offline
+pub async fn get_user_presence(
+    State(state): State<AppState>,
+    JwtAuth(_): JwtAuth<UserToken>,
+    Path(id): Path<i64>,
+) -> Result<Response, AppError> {
+    // Users who never changed their settings don't have a row for them
+    let Some(user) = sqlx::query!(
+        r#"SELECT last_seen_at, COALESCE(hide_last_seen, FALSE) as "hide_last_seen!: bool" FROM users
+        LEFT JOIN user_settings ON user_settings.user_id = users.id
+        WHERE users.id = ? AND deleted_at IS NULL"#,
+        id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User not found".into(),
+        )));
+    };
+
+    let status = visible_status(&state, id, user.hide_last_seen).await;
+    Ok((
+        StatusCode::OK,
+        AppJson(UserPresence {
+            user_id: id,
+            status,
+            last_seen_at: user.last_seen_at.filter(|_| !user.hide_last_seen),
         }),
     )
         .into_response())
@@ -446,12 +1206,15 @@ pub async fn get_user_by_id(
 
 pub async fn get_user_by_username(
     State(state): State<AppState>,
+    viewer: Option<JwtAuth<UserToken>>,
     Path(username): Path<String>,
 ) -> Result<Response, AppError> {
     let Some(user) = sqlx::query!(
-        "SELECT users.id, username, first_name, last_name, path as image_path FROM users
+        r#"SELECT users.id, username, first_name, last_name, path as "image_path?",
+        bio, pronouns, location, COALESCE(hide_last_seen, FALSE) as "hide_last_seen!: bool" FROM users
         LEFT JOIN files ON files.id = users.image_id
-        WHERE username = ?",
+        LEFT JOIN user_settings ON user_settings.user_id = users.id
+        WHERE username = ?"#,
         username
     )
     .fetch_optional(&state.pool)
@@ -470,57 +1233,193 @@ pub async fn get_user_by_username(
             username: user.username,
             first_name: user.first_name,
             last_name: user.last_name,
-            image_path: Some(user.image_path),
-            status: Some(get_user_status(&state, user.id).await),
+            image_path: user.image_path,
+            bio: user.bio,
+            pronouns: user.pronouns,
+            location: user.location,
+            status: public_status(&state, &viewer, user.id, user.hide_last_seen).await,
         }),
     )
         .into_response())
 }
 
+/// The current details of a user that `update_user` can change
+struct StoredUser {
+    password_hash: Option<String>,
+    username: String,
+    email: String,
+    first_name: String,
+    last_name: Option<String>,
+    image_id: Option<i64>,
+    bio: Option<String>,
+    pronouns: Option<String>,
+    location: Option<String>,
+}
+
+/// Update the details of the logged in user.
+/// Only the fields that are provided are changed, but the current password is always required.
+/// Changing the username again before the cooldown is over is refused with a `Retry-After` header
 pub async fn update_user(
     State(pool): State<SqlitePool>,
-    JwtAuth(user): JwtAuth<UserToken>,
-    AppJson(user_data): AppJson<CreateUser>,
+    State(auth): State<AuthConfig>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(username_changes): State<UsernameChanges>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    JwtAuth(token): JwtAuth<UserToken>,
+    AppJson(user_data): AppJson<UpdateUser>,
 ) -> Result<Response, AppError> {
+    user_data.app_validate()?;
+    let user = &token;
     // Check the user's password
-    let Some(stored_user) = sqlx::query!("SELECT password_hash FROM users WHERE id = ?", user.id)
-        .fetch_optional(&pool)
-        .await?
+    let Some(stored_user) = sqlx::query_as!(
+        StoredUser,
+        "SELECT password_hash, username, email, first_name, last_name, image_id, bio, pronouns,
+        location FROM users WHERE id = ?",
+        user.id
+    )
+    .fetch_optional(&pool)
+    .await?
     else {
         return Err(AppError::UserError((
             StatusCode::NOT_FOUND,
             "User does not exist".into(),
         )));
     };
-    if password_auth::verify_password(user_data.password, &stored_user.password_hash).is_err() {
-        return Err(AppError::UserError((
-            StatusCode::UNAUTHORIZED,
-            "Invalid password".into(),
-        )));
+    confirm_identity(
+        &pool,
+        user,
+        stored_user.password_hash.as_deref(),
+        &user_data.password,
+    )
+    .await?;
+
+    // Usernames and emails are unique regardless of case, so changing only the case
+    // can't conflict with anyone else
+    let new_username = user_data
+        .username
+        .as_deref()
+        .filter(|username| !username.eq_ignore_ascii_case(&stored_user.username));
+    if let Some(username) = new_username {
+        if username_changes.cooldown_days > 0 {
+            let last_change = sqlx::query_scalar!(
+                r#"SELECT MAX(changed_at) as "changed_at: NaiveDateTime"
+                FROM username_history WHERE user_id = ?"#,
+                user.id
+            )
+            .fetch_one(&pool)
+            .await?;
+            let next_change = last_change
+                .map(|changed_at| {
+                    changed_at + chrono::Duration::days(username_changes.cooldown_days.into())
+                })
+                .filter(|next_change| *next_change > Utc::now().naive_utc());
+            if let Some(next_change) = next_change {
+                // Round up so clients never retry too early
+                let retry_after = (next_change - Utc::now().naive_utc()).num_seconds() + 1;
+                let error = AppError::UserError((
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!(
+                        "Username can only be changed once every {} days, it can be changed again after {}",
+                        username_changes.cooldown_days,
+                        next_change.and_utc().to_rfc3339()
+                    )
+                    .into(),
+                ));
+                return Ok((
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    error.into_response(),
+                )
+                    .into_response());
+            }
+        }
+        if username_taken(
+            &pool,
+            username,
+            username_changes.reservation_days,
+            Some(user.id),
+        )
+        .await?
+        {
+            return Err(AppError::UserError((
+                StatusCode::CONFLICT,
+                "Username already exists".into(),
+            )));
+        }
     }
+    // Changing only the case of the email is applied straight away,
+    // a different address has to be confirmed first
+    let (new_email, email_case) = match user_data.email.as_deref() {
+        Some(email) if email.eq_ignore_ascii_case(&stored_user.email) => (None, Some(email)),
+        email => (email, None),
+    };
 
-    if let Some(image) = user_data.image_id {
+    if let Some(Some(image)) = user_data.image_id {
         check_image(&pool, image, user.id).await?;
     }
 
-    // Update the user in the database
-    sqlx::query!(
-        "UPDATE users SET first_name = ?, last_name = ?, email = ?,
-        username = ?, image_id = ? WHERE id = ?",
-        user_data.first_name,
-        user_data.last_name,
-        user_data.email,
-        user_data.username,
-        user_data.image_id,
-        user.id
-    )
-    .execute(&pool)
-    .await?;
+    // Only set the columns that were provided
+    let mut query_builder = QueryBuilder::<Sqlite>::new("UPDATE users SET ");
+    let mut columns = query_builder.separated(", ");
+    if let Some(first_name) = &user_data.first_name {
+        columns
+            .push("first_name = ")
+            .push_bind_unseparated(first_name);
+    }
+    if let Some(last_name) = &user_data.last_name {
+        columns
+            .push("last_name = ")
+            .push_bind_unseparated(last_name);
+    }
+    if let Some(username) = &user_data.username {
+        columns.push("username = ").push_bind_unseparated(username);
+    }
+    if let Some(email) = email_case {
+        columns.push("email = ").push_bind_unseparated(email);
+    }
+    if let Some(image_id) = &user_data.image_id {
+        columns.push("image_id = ").push_bind_unseparated(image_id);
+    }
+    if let Some(bio) = &user_data.bio {
+        columns.push("bio = ").push_bind_unseparated(bio);
+    }
+    if let Some(pronouns) = &user_data.pronouns {
+        columns.push("pronouns = ").push_bind_unseparated(pronouns);
+    }
+    if let Some(location) = &user_data.location {
+        columns.push("location = ").push_bind_unseparated(location);
+    }
+    let mut tx = pool.begin().await?;
+    if let Some(username) = new_username {
+        sqlx::query!(
+            "INSERT INTO username_history (user_id, old_username, new_username) VALUES (?, ?, ?)",
+            user.id,
+            stored_user.username,
+            username
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    let email_change_token = match new_email {
+        Some(email) => Some(stage_email_change(&mut tx, user.id, email).await?),
+        None => None,
+    };
+    if user_data.has_changes() || email_case.is_some() {
+        query_builder.push(" WHERE id = ").push_bind(user.id);
+        query_builder.build().execute(&mut *tx).await?;
+    }
+    for event in user_update_events(user.id, &stored_user, &user_data, new_email, email_case) {
+        event.record(&mut tx, addr.ip()).await?;
+    }
+    tx.commit().await?;
+
+    if let (Some(email), Some(token)) = (new_email, email_change_token) {
+        send_email_change(&*mailer, &stored_user.email, email, &token).await;
+    }
 
     let user = sqlx::query_as!(
         SessionUser,
         "SELECT users.id as id, username, first_name, last_name, email,
-        path as image_path FROM users
+        path as image_path, bio, pronouns, location FROM users
         LEFT JOIN files ON files.id = users.image_id
         WHERE users.id = ?",
         user.id
@@ -531,21 +1430,248 @@ pub async fn update_user(
     let token_data = UserToken {
         id: user.id,
         username: user.username.clone(),
-        exp: (chrono::Utc::now() + chrono::Duration::days(1)).timestamp(),
+        exp: auth.expiry(),
+        // The token version is unchanged so the user's other sessions stay valid
+        ver: token.ver,
+        sid: token.sid,
+        iss: None,
+        aud: None,
     };
+    // The new token extends the session
+    if let Some(session_id) = token.sid {
+        sqlx::query!(
+            "UPDATE sessions SET expires_at = datetime(?, 'unixepoch') WHERE id = ?",
+            token_data.exp,
+            session_id
+        )
+        .execute(&pool)
+        .await?;
+    }
 
     Ok((
         StatusCode::OK,
         // Give the user a new JWT
         [(
             header::AUTHORIZATION,
-            format!("Bearer {}", generate_jwt(&token_data)?),
+            format!("Bearer {}", generate_jwt(&auth, &token_data)?),
         )],
-        AppJson(response!("User successfully updated", user)),
+        AppJson(response!(
+            if new_email.is_some() {
+                "User successfully updated, confirm the new email address to finish changing it"
+            } else {
+                "User successfully updated"
+            },
+            user
+        )),
     )
         .into_response())
 }
 
+/// The audit log events for the changes an update makes to the user.
+/// Fields set to the value they already had aren't recorded
+fn user_update_events(
+    user_id: i64,
+    stored_user: &StoredUser,
+    user_data: &UpdateUser,
+    new_email: Option<&str>,
+    email_case: Option<&str>,
+) -> Vec<AuditEvent> {
+    let mut events = Vec::new();
+    if let Some(username) = user_data
+        .username
+        .as_deref()
+        .filter(|username| *username != stored_user.username)
+    {
+        events.push(
+            AuditEvent::new(user_id, AuditAction::UsernameChange).values(
+                Some(stored_user.username.clone()),
+                Some(username.to_owned()),
+            ),
+        );
+    }
+    if let Some(email) = new_email {
+        events.push(
+            AuditEvent::new(user_id, AuditAction::EmailChangeRequest)
+                .values(Some(stored_user.email.clone()), Some(email.to_owned())),
+        );
+    }
+    if let Some(email) = email_case.filter(|email| *email != stored_user.email) {
+        events.push(
+            AuditEvent::new(user_id, AuditAction::EmailChange)
+                .values(Some(stored_user.email.clone()), Some(email.to_owned())),
+        );
+    }
+    if let Some(image_id) = user_data
+        .image_id
+        .filter(|image_id| *image_id != stored_user.image_id)
+    {
+        events.push(
+            AuditEvent::new(user_id, AuditAction::ProfileImageChange).values(
+                stored_user.image_id.map(|id| id.to_string()),
+                image_id.map(|id| id.to_string()),
+            ),
+        );
+    }
+
+    // The rest of the profile is recorded together as JSON objects of the changed fields
+    let mut old_profile = serde_json::Map::new();
+    let mut new_profile = serde_json::Map::new();
+    let fields = [
+        (
+            "firstName",
+            Some(stored_user.first_name.as_str()),
+            user_data.first_name.as_deref().map(Some),
+        ),
+        (
+            "lastName",
+            stored_user.last_name.as_deref(),
+            user_data.last_name.as_ref().map(Option::as_deref),
+        ),
+        (
+            "bio",
+            stored_user.bio.as_deref(),
+            user_data.bio.as_ref().map(Option::as_deref),
+        ),
+        (
+            "pronouns",
+            stored_user.pronouns.as_deref(),
+            user_data.pronouns.as_ref().map(Option::as_deref),
+        ),
+        (
+            "location",
+            stored_user.location.as_deref(),
+            user_data.location.as_ref().map(Option::as_deref),
+        ),
+    ];
+    for (field, old, new) in fields {
+        if let Some(new) = new.filter(|new| *new != old) {
+            old_profile.insert(field.to_owned(), old.into());
+            new_profile.insert(field.to_owned(), new.into());
+        }
+    }
+    if !new_profile.is_empty() {
+        events.push(AuditEvent::new(user_id, AuditAction::ProfileUpdate).values(
+            Some(serde_json::Value::from(old_profile).to_string()),
+            Some(serde_json::Value::from(new_profile).to_string()),
+        ));
+    }
+    events
+}
+
+/// Hold on to a new email for the user until they confirm it with the token sent to it.
+/// Replaces any email change the user was already waiting to confirm.
+/// Returns the confirmation token to send to the new email
+async fn stage_email_change(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    new_email: &str,
+) -> Result<String, AppError> {
+    // Deleting first takes the write lock, so no one else can claim the email
+    // between checking it and inserting it
+    sqlx::query!(
+        "DELETE FROM pending_email_changes WHERE user_id = ? OR expires_at <= CURRENT_TIMESTAMP",
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    if email_taken(&mut *conn, new_email).await? {
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "Email already in use".into(),
+        )));
+    }
+
+    let (token, token_hash) = generate_token();
+    let expires_at = (chrono::Utc::now() + EMAIL_VERIFICATION_TTL).naive_utc();
+    sqlx::query!(
+        "INSERT INTO pending_email_changes (user_id, email, token_hash, expires_at) VALUES (?, ?, ?, ?)",
+        user_id,
+        new_email,
+        token_hash,
+        expires_at
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(token)
+}
+
+/// Send the token to confirm an email change to the new email,
+/// and let the old email know in case someone else made the change
+async fn send_email_change(mailer: &dyn Mailer, old_email: &str, new_email: &str, token: &str) {
+    send_email(
+        mailer,
+        Email {
+            to: new_email.to_owned(),
+            subject: "Confirm your new email".to_owned(),
+            body: format!(
+                "Use this code to confirm your new email, it expires in {} hours:\n\n{token}",
+                EMAIL_VERIFICATION_TTL.num_hours()
+            ),
+        },
+    )
+    .await;
+    send_email(
+        mailer,
+        Email {
+            to: old_email.to_owned(),
+            subject: "Your email is being changed".to_owned(),
+            body: format!(
+                "A change of your account's email to {new_email} was requested. \
+                If this wasn't you, change your password right away."
+            ),
+        },
+    )
+    .await;
+}
+
+/// Change the user's email to the one they confirmed with the token from `stage_email_change`
+pub async fn confirm_email_change(
+    State(pool): State<SqlitePool>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    let token_hash = hash_token(&token);
+    let mut tx = pool.begin().await?;
+    let Some(change) = sqlx::query!(
+        "DELETE FROM pending_email_changes
+        WHERE token_hash = ? AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id, email",
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired confirmation token".into(),
+        )));
+    };
+    let old_email = sqlx::query_scalar!("SELECT email FROM users WHERE id = ?", change.user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    // Confirming the change proves the user owns the new address
+    sqlx::query!(
+        "UPDATE users SET email = ?, email_verified = TRUE WHERE id = ?",
+        change.email,
+        change.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    AuditEvent::new(change.user_id, AuditAction::EmailChange)
+        .values(Some(old_email), Some(change.email))
+        .record(&mut tx, addr.ip())
+        .await?;
+    sqlx::query!(
+        "DELETE FROM email_verifications WHERE user_id = ?",
+        change.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::OK, AppJson(response!("Email changed"))).into_response())
+}
+
 async fn check_image(pool: &SqlitePool, image_id: i64, user_id: i64) -> Result<(), AppError> {
     let query = sqlx::query!(
         "SELECT id, profile_image FROM files
@@ -571,17 +1697,68 @@ async fn check_image(pool: &SqlitePool, image_id: i64, user_id: i64) -> Result<(
     }
 }
 
+/// How recently a user without a password has to have logged in to change or delete their account
+const REAUTHENTICATION_WINDOW: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Check that the user proved who they are before changing or deleting their account.
+/// Users with a password have to give it. Users who signed up with an OAuth provider don't have one,
+/// so they have to have logged in with the provider within `REAUTHENTICATION_WINDOW`
+/// to show the request isn't from a stolen token
+pub(crate) async fn confirm_identity(
+    pool: &SqlitePool,
+    user: &UserToken,
+    password_hash: Option<&str>,
+    password: &str,
+) -> Result<(), AppError> {
+    if let Some(hash) = password_hash {
+        if verify_password(password, hash).await?.is_err() {
+            return Err(AppError::UserError((
+                StatusCode::UNAUTHORIZED,
+                "Invalid password".into(),
+            )));
+        }
+        return Ok(());
+    }
+    let cutoff = format!("-{} minutes", REAUTHENTICATION_WINDOW.num_minutes());
+    let recent_login = sqlx::query_scalar!(
+        "SELECT id FROM sessions WHERE id = ? AND user_id = ? AND created_at > datetime('now', ?)",
+        user.sid,
+        user.id,
+        cutoff
+    )
+    .fetch_optional(pool)
+    .await?;
+    if recent_login.is_none() {
+        return Err(AppError::UserError((
+            StatusCode::UNAUTHORIZED,
+            "Log in again to confirm this change".into(),
+        )));
+    }
+    Ok(())
+}
+
+/// The body of a request to delete the current user's account
+#[derive(Deserialize, Debug)]
+pub struct DeleteAccount {
+    /// Has to match the username of the token
+    pub username: String,
+    /// Not needed if the user doesn't have a password
+    pub password: Option<String>,
+}
+
 pub async fn delete_user(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     JwtAuth(user): JwtAuth<UserToken>,
-    AppJson(user_data): AppJson<LoginData>,
+    AppJson(user_data): AppJson<DeleteAccount>,
 ) -> Result<Response, AppError> {
     if user.username != user_data.username {
         return Err(AppError::AuthError(anyhow!("Token does not match user")));
     }
 
+    let pool = &state.pool;
     let Some(stored_user) = sqlx::query!("SELECT password_hash FROM users WHERE id = ?", user.id)
-        .fetch_optional(&pool)
+        .fetch_optional(pool)
         .await?
     else {
         return Err(AppError::UserError((
@@ -590,69 +1767,1265 @@ pub async fn delete_user(
         )));
     };
 
-    if password_auth::verify_password(&user_data.password, &stored_user.password_hash).is_err() {
-        return Err(AppError::UserError((
-            StatusCode::UNAUTHORIZED,
-            "Invalid password".into(),
-        )));
-    }
+    confirm_identity(
+        pool,
+        &user,
+        stored_user.password_hash.as_deref(),
+        user_data.password.as_deref().unwrap_or_default(),
+    )
+    .await?;
 
-    sqlx::query!("DELETE FROM users WHERE id = ?", user.id)
-        .execute(&pool)
+    // The account is only anonymized for now so its messages keep their context
+    // and it can be reactivated until it is purged
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO deleted_user_profiles
+        (user_id, username, first_name, last_name, image_id, bio, pronouns, location)
+        SELECT id, username, first_name, last_name, image_id, bio, pronouns, location
+        FROM users WHERE id = ?",
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    let deleted_username = format!("{}{}", DELETED_USERNAME_PREFIX, user.id);
+    sqlx::query!(
+        "UPDATE users SET username = ?, first_name = 'Deleted', last_name = NULL, image_id = NULL,
+        bio = NULL, pronouns = NULL, location = NULL, deleted_at = CURRENT_TIMESTAMP,
+        token_version = token_version + 1
+        WHERE id = ?",
+        deleted_username,
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    AuditEvent::new(user.id, AuditAction::AccountDeletion)
+        .record(&mut tx, addr.ip())
         .await?;
+    tx.commit().await?;
+
+    state
+        .disconnect_user(user.id, "Your account was deleted")
+        .await;
 
     Ok((StatusCode::OK, AppJson(response!("User deleted"))).into_response())
 }
 
-fn generate_jwt(token_data: &UserToken) -> Result<String, AppError> {
-    Ok(encode(
-        &Header::default(),
-        token_data,
-        &EncodingKey::from_secret(dotenv!("JWT_KEY").as_bytes()),
-    )?)
+/// Restore the profile of a deleted account that hasn't been purged yet.
+/// Does nothing if the account isn't deleted
+pub(crate) async fn reactivate_account(pool: &SqlitePool, user_id: i64) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE users SET (username, first_name, last_name, image_id, bio, pronouns, location) =
+        (SELECT username, first_name, last_name, image_id, bio, pronouns, location
+        FROM deleted_user_profiles WHERE user_id = users.id),
+        deleted_at = NULL
+        WHERE id = ? AND deleted_at IS NOT NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM deleted_user_profiles WHERE user_id = ?",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
 }
 
-pub async fn search_users(
-    State(pool): State<SqlitePool>,
-    Path(username): Path<String>,
-) -> Result<Response, AppError> {
-    let username_query = format!("%{}%", username);
-    let query: Vec<PublicUser> = sqlx::query!(
-        "SELECT users.id, username, first_name, last_name, path as image_path FROM users
-        LEFT JOIN files ON files.id = users.image_id
-        WHERE username LIKE ?",
-        username_query
-    )
-    .fetch(&pool)
-    .map(|row| {
-        row.map(|row| PublicUser {
-            id: row.id,
-            username: row.username,
-            first_name: row.first_name,
-            last_name: row.last_name,
-            image_path: row.image_path,
-            status: None,
-        })
-    })
-    .try_collect()
-    .boxed()
+/// Permanently delete accounts that were deleted more than `grace_days` days ago.
+/// Returns the number of accounts purged
+pub async fn purge_deleted_accounts(state: &AppState, grace_days: u32) -> Result<u64, AppError> {
+    let cutoff = format!("-{} days", grace_days);
+    let user_ids = sqlx::query_scalar!(
+        "SELECT id FROM users WHERE deleted_at IS NOT NULL AND deleted_at <= datetime('now', ?)",
+        cutoff
+    )
+    .fetch_all(&state.pool)
     .await?;
 
-    Ok((StatusCode::OK, AppJson(query)).into_response())
-}
+    for &user_id in &user_ids {
+        let mut tx = state.pool.begin().await?;
+        // Leave every conversation the same way the user would themselves
+        // so the other members don't keep talking to an account that is gone
+        let conversation_ids = sqlx::query_scalar!(
+            "SELECT conversation_id FROM user_conversations WHERE user_id = ?",
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let mut leaves = Vec::with_capacity(conversation_ids.len());
+        for conversation_id in conversation_ids {
+            let new_owner = leave_conversation_in(&mut tx, conversation_id, user_id).await?;
+            leaves.push((conversation_id, new_owner));
+        }
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Settings {
-    pub ai_enabled: bool,
-    pub ai_model_id: Option<i64>,
-    /// Could be a bool for now but making it an enum in case we want to add more
-    /// themes in the future
-    pub theme: Theme,
-}
+        sqlx::query!(
+            "DELETE FROM friendships WHERE user1_id = ?1 OR user2_id = ?1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM friend_requests WHERE sender_id = ?1 OR receiver_id = ?1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM deleted_user_profiles WHERE user_id = ?",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let removed_files = delete_user_uploads(&mut tx, user_id).await?;
+        sqlx::query!("DELETE FROM users WHERE id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
 
-#[derive(Serialize, Deserialize, Type)]
-#[sqlx(rename_all = "snake_case")]
+        remove_uploads(state.storage.as_ref(), &removed_files).await;
+        for (conversation_id, new_owner) in leaves {
+            // The account is already gone so a member missing the news shouldn't stop the purge
+            if let Err(e) = announce_leave(state, conversation_id, user_id, new_owner).await {
+                error!(
+                    "Failed to announce deleted user {} leaving conversation {}: {}",
+                    user_id, conversation_id, e
+                );
+            }
+        }
+    }
+    Ok(user_ids.len() as u64)
+}
+
+/// Log the user out of every session by revoking all of their issued tokens
+pub async fn logout(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    sqlx::query!(
+        "UPDATE users SET token_version = token_version + 1 WHERE id = ?",
+        user.id
+    )
+    .execute(&state.pool)
+    .await?;
+    sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user.id)
+        .execute(&state.pool)
+        .await?;
+    // Tokens are only checked when a websocket connects, so close any open ones
+    state
+        .disconnect_user(user.id, "You were logged out of every session")
+        .await;
+    Ok((
+        StatusCode::OK,
+        AppJson(response!("Successfully logged out")),
+    )
+        .into_response())
+}
+
+/// A device the user is logged in on
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: i64,
+    /// The User-Agent of the client that logged in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+/// List the sessions the user is currently logged in with
+pub async fn get_sessions(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    // Expired sessions can't be used anymore so clean them up while we're here
+    sqlx::query!(
+        "DELETE FROM sessions WHERE user_id = ? AND expires_at <= CURRENT_TIMESTAMP",
+        user.id
+    )
+    .execute(&pool)
+    .await?;
+    let sessions = sqlx::query!(
+        "SELECT id, device, ip, created_at, last_used_at, expires_at FROM sessions
+        WHERE user_id = ? ORDER BY last_used_at DESC",
+        user.id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|row| Session {
+        id: row.id,
+        device: row.device,
+        ip: row.ip,
+        created_at: row.created_at,
+        last_used_at: row.last_used_at,
+        expires_at: row.expires_at,
+        current: user.sid == Some(row.id),
+    })
+    .collect::<Box<[Session]>>();
+    Ok((StatusCode::OK, AppJson(sessions)).into_response())
+}
+
+/// Log out of a single session, revoking the tokens issued for it
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(session_id): Path<i64>,
+) -> Result<Response, AppError> {
+    let deleted = sqlx::query!(
+        "DELETE FROM sessions WHERE id = ? AND user_id = ?",
+        session_id,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Session not found".into(),
+        )));
+    }
+    state.disconnect_session(user.id, session_id).await;
+    Ok((StatusCode::OK, AppJson(response!("Session revoked"))).into_response())
+}
+
+/// The most API keys a user can have at once
+const MAX_API_KEYS: i64 = 25;
+
+/// How many days an API key is valid for if the user doesn't choose
+const DEFAULT_API_KEY_DAYS: u32 = 90;
+
+fn default_api_key_days() -> u32 {
+    DEFAULT_API_KEY_DAYS
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateApiKey {
+    #[validate(length(
+        min = 1,
+        max = 50,
+        code = "API key name must be between 1 and 50 characters"
+    ))]
+    pub name: String,
+    /// Keys can do anything the user can unless they are created as read only
+    #[serde(default)]
+    pub scope: ApiKeyScope,
+    /// How many days until the key expires
+    #[serde(default = "default_api_key_days")]
+    #[validate(range(
+        min = 1,
+        max = 365,
+        code = "API keys must expire in between 1 and 365 days"
+    ))]
+    pub expires_in_days: u32,
+}
+
+/// An API key the user has created. The key itself is only shown when it is created
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    pub created_at: NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A newly created API key along with the key to authenticate with
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NewApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    pub key: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Create an API key that can be used in the `Authorization` header as `ApiKey <key>`.
+/// Keys can't be managed with an API key, see `LOGIN_ONLY_ROUTES`
+pub async fn create_api_key(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    AppJson(data): AppJson<CreateApiKey>,
+) -> Result<Response, AppError> {
+    data.app_validate()?;
+
+    let count = sqlx::query_scalar!("SELECT COUNT(*) FROM api_keys WHERE user_id = ?", user.id)
+        .fetch_one(&pool)
+        .await?;
+    if count >= MAX_API_KEYS {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!("Cannot have more than {} API keys", MAX_API_KEYS).into(),
+        )));
+    }
+
+    let (key, key_hash) = generate_token();
+    let expires_in = format!("+{} days", data.expires_in_days);
+    let created = sqlx::query!(
+        r#"INSERT INTO api_keys (user_id, name, key_hash, scope, expires_at)
+        VALUES (?, ?, ?, ?, datetime('now', ?))
+        RETURNING id, expires_at AS "expires_at!: NaiveDateTime""#,
+        user.id,
+        data.name,
+        key_hash,
+        data.scope,
+        expires_in
+    )
+    .fetch_one(&pool)
+    .await?;
+    Ok((
+        StatusCode::CREATED,
+        AppJson(NewApiKey {
+            id: created.id,
+            name: data.name,
+            scope: data.scope,
+            key,
+            expires_at: created.expires_at,
+        }),
+    )
+        .into_response())
+}
+
+/// List the user's API keys
+pub async fn get_api_keys(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    let keys = sqlx::query_as!(
+        ApiKey,
+        r#"SELECT id, name, scope, created_at, last_used_at,
+        expires_at AS "expires_at!: NaiveDateTime" FROM api_keys
+        WHERE user_id = ? ORDER BY created_at DESC, id DESC"#,
+        user.id
+    )
+    .fetch_all(&pool)
+    .await?;
+    Ok((StatusCode::OK, AppJson(keys)).into_response())
+}
+
+/// Revoke an API key so it can't be used anymore
+pub async fn revoke_api_key(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    Path(key_id): Path<i64>,
+) -> Result<Response, AppError> {
+    let deleted = sqlx::query!(
+        "DELETE FROM api_keys WHERE id = ? AND user_id = ?",
+        key_id,
+        user.id
+    )
+    .execute(&pool)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "API key not found".into(),
+        )));
+    }
+    Ok((StatusCode::OK, AppJson(response!("API key revoked"))).into_response())
+}
+
+/// Check that the user is an admin before letting them use an admin endpoint
+pub async fn require_admin(pool: &SqlitePool, user_id: i64) -> Result<(), AppError> {
+    let is_admin = sqlx::query_scalar!("SELECT is_admin FROM users WHERE id = ?", user_id)
+        .fetch_optional(pool)
+        .await?
+        .unwrap_or(false);
+    if !is_admin {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Only admins can do this".into(),
+        )));
+    }
+    Ok(())
+}
+
+/// Grant or revoke admin for a user.
+/// Returns false if the user doesn't exist
+pub async fn set_admin(pool: &SqlitePool, username: &str, is_admin: bool) -> Result<bool> {
+    let updated = sqlx::query!(
+        "UPDATE users SET is_admin = ? WHERE username = ?",
+        is_admin,
+        username
+    )
+    .execute(pool)
+    .await?;
+    Ok(updated.rows_affected() > 0)
+}
+
+/// Generate a random token to give to the user along with the hash to store in the database
+pub(crate) fn generate_token() -> (String, String) {
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let token_hash = hash_token(&token);
+    (token, token_hash)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+/// How long an email verification token can be used for
+const EMAIL_VERIFICATION_TTL: chrono::Duration = chrono::Duration::days(1);
+
+/// Create a new email verification token for the user, replacing any previous ones.
+/// Returns the token to send to the user
+async fn create_email_verification(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+) -> Result<String, AppError> {
+    let (token, token_hash) = generate_token();
+    let expires_at = (chrono::Utc::now() + EMAIL_VERIFICATION_TTL).naive_utc();
+    sqlx::query!("DELETE FROM email_verifications WHERE user_id = ?", user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO email_verifications (user_id, token_hash, expires_at) VALUES (?, ?, ?)",
+        user_id,
+        token_hash,
+        expires_at
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(token)
+}
+
+/// The email that sends a token from `create_email_verification` to the user
+fn verification_email(to: &str, token: &str) -> Email {
+    Email {
+        to: to.to_owned(),
+        subject: "Verify your email".to_owned(),
+        body: format!(
+            "Use this code to verify your email, it expires in {} hours:\n\n{token}",
+            EMAIL_VERIFICATION_TTL.num_hours()
+        ),
+    }
+}
+
+/// Mark the user's email as verified using a token from `create_email_verification`
+pub async fn verify_email(
+    State(pool): State<SqlitePool>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    let token_hash = hash_token(&token);
+    let mut tx = pool.begin().await?;
+    let Some(verification) = sqlx::query!(
+        "DELETE FROM email_verifications
+        WHERE token_hash = ? AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id",
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired verification token".into(),
+        )));
+    };
+    sqlx::query!(
+        "UPDATE users SET email_verified = TRUE WHERE id = ?",
+        verification.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::OK,
+        AppJson(response!("Email successfully verified")),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+/// Send a new verification token to an unverified email
+pub async fn resend_verification(
+    State(pool): State<SqlitePool>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    AppJson(data): AppJson<ResendVerification>,
+) -> Result<Response, AppError> {
+    if !is_valid_email(&data.email) {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid email".into(),
+        )));
+    }
+
+    // Respond the same way whether or not there is an unverified account with the email
+    // so this can't be used to find out which emails are registered
+    if let Some(user) = sqlx::query!(
+        "SELECT id FROM users WHERE email = ? AND NOT email_verified",
+        data.email
+    )
+    .fetch_optional(&pool)
+    .await?
+    {
+        let mut tx = pool.begin().await?;
+        let token = create_email_verification(&mut tx, user.id).await?;
+        tx.commit().await?;
+        send_email(&*mailer, verification_email(&data.email, &token)).await;
+    }
+
+    Ok((
+        StatusCode::OK,
+        AppJson(response!(
+            "If an unverified account with that email exists, a verification link has been sent"
+        )),
+    )
+        .into_response())
+}
+
+/// How long a password reset token can be used for
+const PASSWORD_RESET_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+/// Create a password reset token for the account with the given email
+pub async fn forgot_password(
+    State(pool): State<SqlitePool>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    AppJson(data): AppJson<ForgotPassword>,
+) -> Result<Response, AppError> {
+    if !is_valid_email(&data.email) {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid email".into(),
+        )));
+    }
+
+    // Always respond the same way whether or not the account exists
+    // so this can't be used to find out which emails are registered
+    let response = (
+        StatusCode::OK,
+        AppJson(response!(
+            "If an account with that email exists, a password reset link has been sent"
+        )),
+    )
+        .into_response();
+
+    let Some(user) = sqlx::query!("SELECT id FROM users WHERE email = ?", data.email)
+        .fetch_optional(&pool)
+        .await?
+    else {
+        return Ok(response);
+    };
+
+    let (token, token_hash) = generate_token();
+    let expires_at = (chrono::Utc::now() + PASSWORD_RESET_TTL).naive_utc();
+
+    let mut tx = pool.begin().await?;
+    // Only the most recently requested token can be used
+    sqlx::query!(
+        "DELETE FROM password_resets WHERE user_id = ? AND used_at IS NULL",
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO password_resets (user_id, token_hash, expires_at) VALUES (?, ?, ?)",
+        user.id,
+        token_hash,
+        expires_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    send_email(
+        &*mailer,
+        Email {
+            to: data.email,
+            subject: "Reset your password".to_owned(),
+            body: format!(
+                "Use this code to reset your password, it expires in {} minutes:\n\n{token}\n\n\
+                If you didn't ask to reset your password, you can ignore this email.",
+                PASSWORD_RESET_TTL.num_minutes()
+            ),
+        },
+    )
+    .await;
+
+    Ok(response)
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ResetPassword {
+    pub token: String,
+    #[validate(
+        length(
+            min = 8,
+            max = 128,
+            code = "Password must be between 8 and 128 characters"
+        ),
+        custom(function = "validate_password")
+    )]
+    pub password: String,
+}
+
+/// Set a new password using a token from `forgot_password`
+pub async fn reset_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    AppJson(data): AppJson<ResetPassword>,
+) -> Result<Response, AppError> {
+    data.app_validate()?;
+
+    let token_hash = hash_token(&data.token);
+    let mut tx = state.pool.begin().await?;
+    // Mark the token as used in the same statement that checks it so it can't be used twice
+    let Some(reset) = sqlx::query!(
+        "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id",
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired password reset token".into(),
+        )));
+    };
+
+    let hashed_password = hash_password(&state.password_hasher, &data.password).await?;
+    // Revoke every existing token so anyone else using the account is logged out
+    sqlx::query!(
+        "UPDATE users SET password_hash = ?, token_version = token_version + 1 WHERE id = ?",
+        hashed_password,
+        reset.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM sessions WHERE user_id = ?", reset.user_id)
+        .execute(&mut *tx)
+        .await?;
+    AuditEvent::new(reset.user_id, AuditAction::PasswordReset)
+        .record(&mut tx, addr.ip())
+        .await?;
+    tx.commit().await?;
+
+    state
+        .disconnect_user(reset.user_id, "Your password was reset")
+        .await;
+
+    Ok((
+        StatusCode::OK,
+        AppJson(response!("Password successfully reset")),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePassword {
+    /// Not needed if the user just logged in with a recovery code, or doesn't have a password yet
+    /// and logged in recently
+    pub current_password: Option<String>,
+    #[validate(
+        length(
+            min = 8,
+            max = 128,
+            code = "Password must be between 8 and 128 characters"
+        ),
+        custom(function = "validate_password")
+    )]
+    pub new_password: String,
+}
+
+/// Change the password of the current user, or set one if they signed up with an OAuth provider.
+/// Every other session is logged out and the current one is given a new token
+pub async fn change_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    JwtAuth(user): JwtAuth<UserToken>,
+    AppJson(data): AppJson<ChangePassword>,
+) -> Result<Response, AppError> {
+    data.app_validate()?;
+
+    let Some(stored_user) = sqlx::query!("SELECT password_hash FROM users WHERE id = ?", user.id)
+        .fetch_optional(&state.pool)
+        .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User does not exist".into(),
+        )));
+    };
+    let current_password_valid = match (&stored_user.password_hash, &data.current_password) {
+        (Some(hash), Some(current_password)) => {
+            verify_password(current_password, hash).await?.is_ok()
+        }
+        (Some(_), None) => password_reset_allowed(&state.pool, &user).await?,
+        // Users who signed up with an OAuth provider don't have a current password to check,
+        // so they have to have logged in recently for a stolen token not to be enough
+        (None, _) => {
+            confirm_identity(&state.pool, &user, None, "").await?;
+            true
+        }
+    };
+    if !current_password_valid {
+        return Err(AppError::UserError((
+            StatusCode::UNAUTHORIZED,
+            "Invalid password".into(),
+        )));
+    }
+
+    let hashed_password = hash_password(&state.password_hasher, &data.new_password).await?;
+    let mut tx = state.pool.begin().await?;
+    // Revoke every existing token so the other sessions are logged out
+    let token_version = sqlx::query_scalar!(
+        "UPDATE users SET password_hash = ?, token_version = token_version + 1 WHERE id = ?
+        RETURNING token_version",
+        hashed_password,
+        user.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM sessions WHERE user_id = ? AND id IS NOT ?",
+        user.id,
+        user.sid
+    )
+    .execute(&mut *tx)
+    .await?;
+    // The new password is needed to change it again
+    sqlx::query!(
+        "UPDATE sessions SET password_reset_required = FALSE WHERE id IS ?",
+        user.sid
+    )
+    .execute(&mut *tx)
+    .await?;
+    AuditEvent::new(user.id, AuditAction::PasswordChange)
+        .record(&mut tx, addr.ip())
+        .await?;
+    tx.commit().await?;
+
+    // Open websockets were authorized with the old token version
+    state
+        .disconnect_user(user.id, "Your password was changed")
+        .await;
+
+    let token_data = UserToken {
+        exp: state.auth.expiry(),
+        ver: token_version,
+        ..user
+    };
+    if let Some(session_id) = token_data.sid {
+        sqlx::query!(
+            "UPDATE sessions SET expires_at = datetime(?, 'unixepoch') WHERE id = ?",
+            token_data.exp,
+            session_id
+        )
+        .execute(&state.pool)
+        .await?;
+    }
+
+    Ok((
+        StatusCode::OK,
+        [(
+            header::AUTHORIZATION,
+            format!("Bearer {}", generate_jwt(&state.auth, &token_data)?),
+        )],
+        AppJson(response!("Password successfully changed")),
+    )
+        .into_response())
+}
+
+/// Whether the token's session was started with a recovery code within `REAUTHENTICATION_WINDOW`
+/// and the password hasn't been changed since, so a new one can be set without the current one
+async fn password_reset_allowed(pool: &SqlitePool, user: &UserToken) -> Result<bool, AppError> {
+    let cutoff = format!("-{} minutes", REAUTHENTICATION_WINDOW.num_minutes());
+    Ok(sqlx::query_scalar!(
+        "SELECT id FROM sessions WHERE id = ? AND user_id = ? AND password_reset_required
+        AND created_at > datetime('now', ?)",
+        user.sid,
+        user.id,
+        cutoff
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some())
+}
+
+fn generate_jwt(auth: &AuthConfig, token_data: &UserToken) -> Result<String, AppError> {
+    Ok(auth.encode(token_data)?)
+}
+
+/// The maximum number of users returned by a single search
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// Query parameters for paginating user searches
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    /// The number of users to return, defaults to 25
+    pub limit: Option<i64>,
+    /// The number of users to skip
+    pub offset: Option<i64>,
+    /// The `X-Next-Cursor` header of the previous page, can't be combined with `offset`
+    pub cursor: Option<i64>,
+    #[serde(default)]
+    pub mode: SearchMode,
+}
+
+/// How users are matched against the query
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SearchMode {
+    /// Usernames, first names, or last names containing the query anywhere
+    #[default]
+    Contains,
+    /// Usernames starting with the query.
+    /// Much faster on large tables since it can use the username index
+    Prefix,
+}
+
+/// The field of a user that matched a search
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MatchedField {
+    Username,
+    FirstName,
+    LastName,
+}
+
+/// A user found by a search
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSearchResult {
+    #[serde(flatten)]
+    pub user: PublicUser,
+    /// The field that best matched the query, so it can be highlighted
+    pub matched_field: MatchedField,
+}
+
+/// Search for users matching the query, ignoring the case of ASCII letters like SQLite does.
+/// Results are ordered by how well they match, an exact username match comes first,
+/// then users with a field starting with the query, then the rest, and then by username.
+/// The total number of matches is returned in the `X-Total-Count` header, and the cursor for
+/// the next page in the `X-Next-Cursor` header unless this is the last page
+pub async fn search_users(
+    State(state): State<AppState>,
+    viewer: Option<JwtAuth<UserToken>>,
+    Path(username): Path<String>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Response, AppError> {
+    let limit = query.limit.unwrap_or(25).clamp(1, MAX_SEARCH_LIMIT);
+    let offset = match (query.offset, query.cursor) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                "Only one of offset and cursor can be given".into(),
+            )))
+        }
+        (offset, cursor) => offset.or(cursor).unwrap_or(0).max(0),
+    };
+    // Escape LIKE wildcards so they are matched literally
+    let escaped = username
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let contains = format!("%{}%", escaped);
+    let prefix = format!("{}%", escaped);
+
+    // LIKE ignores case unless `case_sensitive_like` is turned on, which it never is,
+    // and equality is compared with NOCASE since only the username column uses it
+    let (total, rows) = match query.mode {
+        SearchMode::Contains => {
+            let total = sqlx::query_scalar!(
+                r"SELECT COUNT(*) FROM users
+                WHERE deleted_at IS NULL
+                AND (username LIKE ? ESCAPE '\'
+                OR first_name LIKE ? ESCAPE '\'
+                OR last_name LIKE ? ESCAPE '\')",
+                contains,
+                contains,
+                contains
+            )
+            .fetch_one(&state.pool)
+            .await?;
+            let rows = sqlx::query_as!(
+                PublicUserRow,
+                r#"SELECT users.id, username, first_name, last_name, path as image_path,
+                bio, pronouns, location, COALESCE(hide_last_seen, FALSE) as "hide_last_seen!: bool"
+                FROM users
+                LEFT JOIN files ON files.id = users.image_id
+                LEFT JOIN user_settings ON user_settings.user_id = users.id
+                WHERE deleted_at IS NULL
+                AND (username LIKE ? ESCAPE '\'
+                OR first_name LIKE ? ESCAPE '\'
+                OR last_name LIKE ? ESCAPE '\')
+                ORDER BY CASE
+                    WHEN username = ? COLLATE NOCASE THEN 0
+                    WHEN username LIKE ? ESCAPE '\'
+                        OR first_name LIKE ? ESCAPE '\'
+                        OR last_name LIKE ? ESCAPE '\' THEN 1
+                    ELSE 2
+                END, username
+                LIMIT ? OFFSET ?"#,
+                contains,
+                contains,
+                contains,
+                username,
+                prefix,
+                prefix,
+                prefix,
+                limit,
+                offset
+            )
+            .fetch_all(&state.pool)
+            .await?;
+            (total, rows)
+        }
+        // A prefix LIKE on the NOCASE username column is answered from the username index
+        SearchMode::Prefix => {
+            let total = sqlx::query_scalar!(
+                r"SELECT COUNT(*) FROM users WHERE username LIKE ? ESCAPE '\' AND deleted_at IS NULL",
+                prefix
+            )
+            .fetch_one(&state.pool)
+            .await?;
+            let rows = sqlx::query_as!(
+                PublicUserRow,
+                r#"SELECT users.id, username, first_name, last_name, path as image_path,
+                bio, pronouns, location, COALESCE(hide_last_seen, FALSE) as "hide_last_seen!: bool"
+                FROM users
+                LEFT JOIN files ON files.id = users.image_id
+                LEFT JOIN user_settings ON user_settings.user_id = users.id
+                WHERE username LIKE ? ESCAPE '\' AND deleted_at IS NULL
+                ORDER BY username
+                LIMIT ? OFFSET ?"#,
+                prefix,
+                limit,
+                offset
+            )
+            .fetch_all(&state.pool)
+            .await?;
+            (total, rows)
+        }
+    };
+
+    // Look the statuses up concurrently instead of waiting on each user in turn
+    let statuses = join_all(
+        rows.iter()
+            .map(|row| public_status(&state, &viewer, row.id, row.hide_last_seen)),
+    )
+    .await;
+    // SQLite only folds the case of ASCII letters, so the matched field has to be found the same way
+    let query_text = username.to_ascii_lowercase();
+    let users: Vec<UserSearchResult> = rows
+        .into_iter()
+        .zip(statuses)
+        .map(|(row, status)| UserSearchResult {
+            matched_field: match query.mode {
+                SearchMode::Contains => best_match(&query_text, &row),
+                // Prefix searches only look at the username
+                SearchMode::Prefix => MatchedField::Username,
+            },
+            user: PublicUser {
+                id: row.id,
+                username: row.username,
+                first_name: row.first_name,
+                last_name: row.last_name,
+                image_path: row.image_path,
+                bio: row.bio,
+                pronouns: row.pronouns,
+                location: row.location,
+                status,
+            },
+        })
+        .collect();
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Total-Count", HeaderValue::from(total));
+    if offset + (users.len() as i64) < total {
+        headers.insert("X-Next-Cursor", HeaderValue::from(offset + limit));
+    }
+
+    Ok((StatusCode::OK, headers, AppJson(users)).into_response())
+}
+
+/// The columns of a user search result
+struct PublicUserRow {
+    id: i64,
+    username: String,
+    first_name: String,
+    last_name: Option<String>,
+    image_path: Option<String>,
+    bio: Option<String>,
+    pronouns: Option<String>,
+    location: Option<String>,
+    hide_last_seen: bool,
+}
+
+/// Find the field of the user that best matches the ASCII lowercased query, using the same ranking
+/// and case folding as the search. The username wins ties so it is highlighted whenever it
+/// matches as well as a name
+fn best_match(query: &str, row: &PublicUserRow) -> MatchedField {
+    let username = row.username.to_ascii_lowercase();
+    if username == query {
+        return MatchedField::Username;
+    }
+    let quality = |field: &str| {
+        let field = field.to_ascii_lowercase();
+        if field.starts_with(query) {
+            Some(0)
+        } else if field.contains(query) {
+            Some(1)
+        } else {
+            None
+        }
+    };
+    [
+        (MatchedField::Username, quality(&username)),
+        (MatchedField::FirstName, quality(&row.first_name)),
+        (
+            MatchedField::LastName,
+            row.last_name.as_deref().and_then(quality),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(field, quality)| Some((field, quality?)))
+    // `min_by_key` keeps the first of equal elements
+    .min_by_key(|(_, quality)| *quality)
+    .map_or(MatchedField::Username, |(field, _)| field)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    pub ai_enabled: bool,
+    pub ai_model_id: Option<i64>,
+    /// Could be a bool for now but making it an enum in case we want to add more
+    /// themes in the future
+    pub theme: Theme,
+    /// Hide when the user was last seen and whether they are idle from other users
+    #[serde(default)]
+    pub hide_last_seen: bool,
+    /// The IANA name of the user's time zone, such as `America/New_York`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// The BCP 47 language tag of the user's preferred language, such as `en-US`
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    /// Which new messages are pushed to connections that aren't viewing the conversation
+    #[serde(default)]
+    pub message_notifications: MessageNotifications,
+    /// Whether friend requests from other users are pushed to the user's connections
+    #[serde(default = "default_true")]
+    pub friend_request_notifications: bool,
+    /// Who can send the user friend requests
+    #[serde(default)]
+    pub friend_request_policy: FriendRequestPolicy,
+    /// The units health values are shown in
+    #[serde(default)]
+    pub unit_system: UnitSystem,
+    /// Increases every time the settings are changed so clients can tell when theirs are stale.
+    /// Set by the server, any value sent by the client is ignored
+    #[serde(skip_deserializing)]
+    pub settings_version: i64,
+}
+
+/// The settings to change, the ones that aren't provided are left as they are
+#[derive(Deserialize, Validate, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateSettings {
+    pub ai_enabled: Option<bool>,
+    /// `null` clears the model so the default one is used
+    #[serde(default, deserialize_with = "double_option")]
+    pub ai_model_id: Option<Option<i64>>,
+    pub theme: Option<Theme>,
+    pub hide_last_seen: Option<bool>,
+    #[validate(custom(function = "validate_timezone"))]
+    pub timezone: Option<String>,
+    #[validate(custom(function = "validate_locale"))]
+    pub locale: Option<String>,
+    pub message_notifications: Option<MessageNotifications>,
+    pub friend_request_notifications: Option<bool>,
+    pub friend_request_policy: Option<FriendRequestPolicy>,
+    pub unit_system: Option<UnitSystem>,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_owned()
+}
+
+fn default_locale() -> String {
+    "en-US".to_owned()
+}
+
+pub(crate) fn default_true() -> bool {
+    true
+}
+
+/// Check that a time zone is in the IANA time zone database, such as `Europe/Helsinki` or `UTC`
+fn validate_timezone(timezone: &str) -> Result<(), ValidationError> {
+    timezone
+        .parse::<chrono_tz::Tz>()
+        .map(|_| ())
+        .map_err(|_| ValidationError::new("Invalid time zone"))
+}
+
+/// Check that a locale is a BCP 47 language tag like `en` or `pt-BR`
+fn validate_locale(locale: &str) -> Result<(), ValidationError> {
+    let mut parts = locale.split('-');
+    let language = parts.next().unwrap_or_default();
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.all(|part| {
+            (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+        && locale.len() <= 35;
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("Invalid locale"))
+    }
+}
+
+/// Which new messages are pushed to a user's connections that aren't viewing the conversation.
+/// Connections viewing the conversation always receive its messages
+#[derive(Serialize, Deserialize, Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum MessageNotifications {
+    #[default]
+    All,
+    /// Only messages that mention the user with `@username`
+    Mentions,
+    None,
+}
+
+/// Need for sqlx to convert the preference from the database to the enum
+impl From<String> for MessageNotifications {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "mentions" => MessageNotifications::Mentions,
+            "none" => MessageNotifications::None,
+            _ => MessageNotifications::All,
+        }
+    }
+}
+
+/// The units health values are shown in.
+/// Values are always stored in metric and only converted when they are shown
+#[derive(Serialize, Deserialize, Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum UnitSystem {
+    /// Centimeters and kilograms
+    #[default]
+    Metric,
+    /// Inches and pounds
+    Imperial,
+}
+
+const CENTIMETERS_PER_INCH: f64 = 2.54;
+const KILOGRAMS_PER_POUND: f64 = 0.453_592_37;
+
+impl UnitSystem {
+    /// Convert a height in centimeters to the unit system
+    pub fn height(&self, centimeters: f64) -> f64 {
+        match self {
+            Self::Metric => centimeters,
+            Self::Imperial => centimeters / CENTIMETERS_PER_INCH,
+        }
+    }
+
+    /// Convert a weight in kilograms to the unit system
+    pub fn weight(&self, kilograms: f64) -> f64 {
+        match self {
+            Self::Metric => kilograms,
+            Self::Imperial => kilograms / KILOGRAMS_PER_POUND,
+        }
+    }
+
+    pub fn height_unit(&self) -> &'static str {
+        match self {
+            Self::Metric => "cm",
+            Self::Imperial => "in",
+        }
+    }
+
+    pub fn weight_unit(&self) -> &'static str {
+        match self {
+            Self::Metric => "kg",
+            Self::Imperial => "lb",
+        }
+    }
+}
+
+/// Need for sqlx to convert the unit system from the database to the enum
+impl From<String> for UnitSystem {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "imperial" => UnitSystem::Imperial,
+            _ => UnitSystem::Metric,
+        }
+    }
+}
+
+/// Get the unit system the user wants health values shown in
+pub(crate) async fn unit_system(pool: &SqlitePool, user_id: i64) -> Result<UnitSystem, AppError> {
+    Ok(sqlx::query_scalar!(
+        r#"SELECT COALESCE(
+            (SELECT unit_system FROM user_settings WHERE user_id = ?), 'metric'
+        ) as "unit_system!: UnitSystem""#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Who can send a user friend requests.
+/// Users can always accept or reject requests they already have
+#[derive(Serialize, Deserialize, Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum FriendRequestPolicy {
+    #[default]
+    Everyone,
+    /// Only users the user shares a conversation with
+    ConversationMembers,
+    Nobody,
+}
+
+/// Need for sqlx to convert the policy from the database to the enum
+impl From<String> for FriendRequestPolicy {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "conversation_members" => FriendRequestPolicy::ConversationMembers,
+            "nobody" => FriendRequestPolicy::Nobody,
+            _ => FriendRequestPolicy::Everyone,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Type, Clone, Copy, Debug)]
+#[sqlx(rename_all = "snake_case")]
 #[serde(rename_all = "camelCase")]
 pub enum Theme {
     Light,
@@ -671,35 +3044,192 @@ impl From<String> for Theme {
     }
 }
 
-/// Update the logged in user's settings
+/// Update the logged in user's settings.
+/// Only the settings that are provided are changed, the rest keep their current values,
+/// or their defaults if the user doesn't have any settings yet.
+/// The new settings are sent to all of the user's connections so their other devices stay in sync
 pub async fn update_settings(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     JwtAuth(user): JwtAuth<UserToken>,
-    AppJson(user_data): AppJson<Settings>,
+    AppJson(user_data): AppJson<UpdateSettings>,
 ) -> Result<Response, AppError> {
-    sqlx::query!(
-        "UPDATE user_settings SET ai_enabled = ?, ai_model_id = ?, theme = ? WHERE user_id = ?",
+    user_data.app_validate()?;
+    let mut tx = state.pool.begin().await?;
+    let old_settings = sqlx::query_as!(
+        Settings,
+        "SELECT ai_enabled, ai_model_id, theme, hide_last_seen, timezone, locale,
+        message_notifications, friend_request_notifications, friend_request_policy, unit_system,
+        settings_version FROM user_settings WHERE user_id = ?",
+        user.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    // Start new settings one version early so they are saved as the first version
+    if old_settings.is_none() {
+        sqlx::query!(
+            "INSERT INTO user_settings (user_id, settings_version) VALUES (?, 0)",
+            user.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    let change_model = user_data.ai_model_id.is_some();
+    let ai_model_id = user_data.ai_model_id.flatten();
+    let settings = sqlx::query_as!(
+        Settings,
+        "UPDATE user_settings SET ai_enabled = COALESCE(?, ai_enabled),
+        ai_model_id = IIF(?, ?, ai_model_id), theme = COALESCE(?, theme),
+        hide_last_seen = COALESCE(?, hide_last_seen), timezone = COALESCE(?, timezone),
+        locale = COALESCE(?, locale),
+        message_notifications = COALESCE(?, message_notifications),
+        friend_request_notifications = COALESCE(?, friend_request_notifications),
+        friend_request_policy = COALESCE(?, friend_request_policy),
+        unit_system = COALESCE(?, unit_system),
+        settings_version = settings_version + 1
+        WHERE user_id = ?
+        RETURNING ai_enabled, ai_model_id, theme, hide_last_seen, timezone, locale,
+        message_notifications, friend_request_notifications, friend_request_policy, unit_system, settings_version",
         user_data.ai_enabled,
-        user_data.ai_model_id,
+        change_model,
+        ai_model_id,
         user_data.theme,
+        user_data.hide_last_seen,
+        user_data.timezone,
+        user_data.locale,
+        user_data.message_notifications,
+        user_data.friend_request_notifications,
+        user_data.friend_request_policy,
+        user_data.unit_system,
         user.id
     )
-    .execute(&pool)
+    .fetch_one(&mut *tx)
+    .await?;
+    AuditEvent::new(user.id, AuditAction::SettingsChange)
+        .values(
+            old_settings.map(|settings| sonic_rs::to_string(&settings).unwrap()),
+            Some(sonic_rs::to_string(&settings).unwrap()),
+        )
+        .record(&mut tx, addr.ip())
+        .await?;
+    tx.commit().await?;
+    send_to_user(
+        &state,
+        user.id,
+        SocketResponse::SettingsChanged {
+            version: settings.settings_version,
+            settings: settings.clone(),
+        },
+    )
     .await?;
-    Ok(StatusCode::OK.into_response())
+    Ok((StatusCode::OK, AppJson(settings)).into_response())
 }
 
-/// Returns the logged in user's settings
+/// Returns the logged in user's settings.
+/// Users without any settings, such as accounts made before settings existed,
+/// are given the defaults
 pub async fn get_settings(
     State(pool): State<SqlitePool>,
     JwtAuth(user): JwtAuth<UserToken>,
 ) -> Result<Response, AppError> {
+    let mut tx = pool.begin().await?;
     let settings = sqlx::query_as!(
         Settings,
-        "SELECT ai_enabled, ai_model_id, theme FROM user_settings WHERE user_id = ?",
+        "SELECT ai_enabled, ai_model_id, theme, hide_last_seen, timezone, locale,
+        message_notifications, friend_request_notifications, friend_request_policy, unit_system, settings_version
+        FROM user_settings WHERE user_id = ?",
         user.id
     )
-    .fetch_one(&pool)
+    .fetch_optional(&mut *tx)
     .await?;
+    let settings = match settings {
+        Some(settings) => settings,
+        None => {
+            sqlx::query_as!(
+                Settings,
+                "INSERT INTO user_settings (user_id) VALUES (?)
+                RETURNING ai_enabled, ai_model_id, theme, hide_last_seen, timezone, locale,
+                message_notifications, friend_request_notifications, friend_request_policy, unit_system, settings_version",
+                user.id
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        }
+    };
+    tx.commit().await?;
     Ok((StatusCode::OK, AppJson(settings)).into_response())
 }
+
+/// Everything the user hasn't seen yet, for a badge when the app is opened
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UnreadSummary {
+    /// Messages sent by anyone else since the user last read their conversation
+    pub unread_messages: i64,
+    /// Conversations with at least one unread message
+    pub unread_conversations: i64,
+    /// Friend requests sent to the user that they haven't answered
+    pub pending_friend_requests: i64,
+}
+
+/// Count the logged in user's unread messages and pending friend requests
+pub async fn get_unread_summary(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    // Counted the same way as the unread count of each conversation in `RequestConversations`
+    let unread = sqlx::query!(
+        r#"SELECT COUNT(*) as "unread_messages!: i64",
+        COUNT(DISTINCT messages.conversation_id) as "unread_conversations!: i64"
+        FROM messages
+        JOIN user_conversations AS reader
+        ON reader.conversation_id = messages.conversation_id AND reader.user_id = ?
+        WHERE messages.user_id IS NOT reader.user_id
+        AND (reader.last_read_at IS NULL
+        OR julianday(messages.created_at) > julianday(reader.last_read_at))"#,
+        user.id
+    )
+    .fetch_one(&pool)
+    .await?;
+    let pending_friend_requests = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM friend_requests WHERE receiver_id = ?",
+        user.id
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    Ok((
+        StatusCode::OK,
+        AppJson(UnreadSummary {
+            unread_messages: unread.unread_messages,
+            unread_conversations: unread.unread_conversations,
+            pending_friend_requests,
+        }),
+    )
+        .into_response())
+}
+
+/// A username the user changed away from
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UsernameChange {
+    pub old_username: String,
+    pub new_username: String,
+    pub changed_at: NaiveDateTime,
+}
+
+/// List every username change the logged in user has made, the most recent first
+pub async fn get_username_history(
+    State(pool): State<SqlitePool>,
+    JwtAuth(user): JwtAuth<UserToken>,
+) -> Result<Response, AppError> {
+    let history = sqlx::query_as!(
+        UsernameChange,
+        "SELECT old_username, new_username, changed_at FROM username_history
+        WHERE user_id = ? ORDER BY changed_at DESC, id DESC",
+        user.id
+    )
+    .fetch_all(&pool)
+    .await?;
+    Ok((StatusCode::OK, AppJson(history)).into_response())
+}
@@ -1,5 +1,7 @@
 use std::{env::current_dir, path::PathBuf};
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
 use crate::PKG_NAME;
 
 /// Path to the data directory for the application.
@@ -33,3 +35,53 @@ pub fn config_dir() -> PathBuf {
     path.push(PKG_NAME);
     path
 }
+
+/// Deserialize a field of a partial update that can be cleared.
+///
+/// Use with `#[serde(default, deserialize_with = "double_option")]` so that
+/// - a missing field is `None` and left unchanged
+/// - `null` is `Some(None)` and clears the field
+/// - a value is `Some(Some(value))`
+pub fn double_option<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// A response field that is only filled in when the data was asked for.
+///
+/// Clients can tell the two cases apart:
+/// - `NotRequested` is omitted from the response entirely
+/// - `Loaded` is always present, so a loaded `Option` that is `None` is sent as `null`
+///
+/// Fields using this must be marked with
+/// `#[serde(default, skip_serializing_if = "Requested::is_not_requested")]`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Requested<T> {
+    #[default]
+    NotRequested,
+    Loaded(T),
+}
+
+impl<T> Requested<T> {
+    pub fn is_not_requested(&self) -> bool {
+        matches!(self, Self::NotRequested)
+    }
+}
+
+impl<T: Serialize> Serialize for Requested<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Loaded(value) => value.serialize(serializer),
+            // Only reached if the field is missing `skip_serializing_if`
+            Self::NotRequested => serializer.serialize_none(),
+        }
+    }
+}
+
+// A missing field is handled by `#[serde(default)]`, so anything present was loaded
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Requested<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::Loaded)
+    }
+}
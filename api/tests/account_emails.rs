@@ -0,0 +1,198 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use ai_health_assistant_api::{
+    auth::{AuthConfig, JwtAuth},
+    cli::Args,
+    error::AppJson,
+    mail::{Email, Mailer},
+    state::AppState,
+    users::{
+        confirm_email_change, forgot_password, resend_verification, reset_password, update_user,
+        verify_email, ForgotPassword, ResendVerification, ResetPassword, UsernameChanges,
+    },
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use clap::Parser;
+use common::{addr, create_user, create_user_with_password, test_db, test_state, PASSWORD};
+use sqlx::SqlitePool;
+
+/// Keeps every email instead of sending it
+#[derive(Debug, Default)]
+struct RecordingMailer(Mutex<Vec<Email>>);
+
+#[async_trait]
+impl Mailer for RecordingMailer {
+    async fn send(&self, email: Email) -> anyhow::Result<()> {
+        self.0.lock().unwrap().push(email);
+        Ok(())
+    }
+}
+
+impl RecordingMailer {
+    fn take(&self) -> Vec<Email> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+fn mail_state(pool: &SqlitePool) -> (AppState, Arc<RecordingMailer>) {
+    let mailer = Arc::new(RecordingMailer::default());
+    (test_state(pool, &[]).with_mailer(mailer.clone()), mailer)
+}
+
+/// The token in an email, which is on its own line after the first paragraph
+fn token(email: &Email) -> String {
+    email.body.split("\n\n").nth(1).unwrap().to_owned()
+}
+
+fn into_response(result: Result<Response, impl IntoResponse>) -> Response {
+    result.unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn password_reset_token_is_emailed() {
+    let (pool, _dir) = test_db().await;
+    let (state, mailer) = mail_state(&pool);
+    create_user_with_password(&pool, "alice").await;
+
+    // Nothing is sent for an email without an account, but the response is the same
+    let response = into_response(
+        forgot_password(
+            State(pool.clone()),
+            State(FromRef::from_ref(&state)),
+            AppJson(ForgotPassword {
+                email: "nobody@example.com".into(),
+            }),
+        )
+        .await,
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(mailer.take().is_empty());
+
+    let response = into_response(
+        forgot_password(
+            State(pool.clone()),
+            State(FromRef::from_ref(&state)),
+            AppJson(ForgotPassword {
+                email: "alice@example.com".into(),
+            }),
+        )
+        .await,
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    let emails = mailer.take();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].to, "alice@example.com");
+
+    let response = into_response(
+        reset_password(
+            State(state),
+            ConnectInfo(addr()),
+            AppJson(ResetPassword {
+                token: token(&emails[0]),
+                password: "a brand new password".into(),
+            }),
+        )
+        .await,
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn verification_token_is_emailed() {
+    let (pool, _dir) = test_db().await;
+    let (state, mailer) = mail_state(&pool);
+    create_user(&pool, "alice").await;
+
+    let response = into_response(
+        resend_verification(
+            State(pool.clone()),
+            State(FromRef::from_ref(&state)),
+            AppJson(ResendVerification {
+                email: "alice@example.com".into(),
+            }),
+        )
+        .await,
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    let emails = mailer.take();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].to, "alice@example.com");
+
+    let response = into_response(verify_email(State(pool.clone()), Path(token(&emails[0]))).await);
+    assert_eq!(response.status(), StatusCode::OK);
+    let verified: bool =
+        sqlx::query_scalar("SELECT email_verified FROM users WHERE username = 'alice'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert!(verified);
+}
+
+#[tokio::test]
+async fn email_change_is_confirmed_by_the_new_email() {
+    let (pool, _dir) = test_db().await;
+    let (state, mailer) = mail_state(&pool);
+    let alice = create_user_with_password(&pool, "alice").await;
+
+    let response = into_response(
+        update_user(
+            State(pool.clone()),
+            State(AuthConfig::from_ref(&state)),
+            State(FromRef::from_ref(&state)),
+            State(UsernameChanges::from_ref(&state)),
+            ConnectInfo(addr()),
+            JwtAuth(alice),
+            AppJson(
+                sonic_rs::from_str(&format!(
+                    r#"{{"password": "{PASSWORD}", "email": "alice@example.org"}}"#
+                ))
+                .unwrap(),
+            ),
+        )
+        .await,
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The new email gets the token and the old one is told about the change
+    let emails = mailer.take();
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[0].to, "alice@example.org");
+    assert_eq!(emails[1].to, "alice@example.com");
+    assert!(emails[1].body.contains("alice@example.org"));
+
+    let response = into_response(
+        confirm_email_change(
+            State(pool.clone()),
+            ConnectInfo(addr()),
+            Path(token(&emails[0])),
+        )
+        .await,
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    let email: String = sqlx::query_scalar("SELECT email FROM users WHERE username = 'alice'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(email, "alice@example.org");
+}
+
+#[tokio::test]
+async fn requiring_verification_needs_a_mail_server() {
+    let (pool, _dir) = test_db().await;
+    let args = Args::parse_from([
+        "api",
+        "--jwt-key",
+        "test",
+        "--no-assistant-conversation",
+        "--require-email-verification",
+    ]);
+    let error = AppState::new(pool, &args).err().unwrap();
+    assert!(error.to_string().contains("needs a mail server"));
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use ai_health_assistant_api::{auth::JwtAuth, export::export_account};
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use common::{body_json, create_conversation, create_user, test_db};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+#[tokio::test]
+async fn the_export_includes_account_history_but_not_secrets() {
+    let (pool, _dir) = test_db().await;
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+
+    let message_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO messages (user_id, conversation_id, message) VALUES (?, ?, 'hello') RETURNING id",
+    )
+    .bind(bob.id)
+    .bind(conversation_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    for query in [
+        "INSERT INTO account_audit_log (user_id, actor_id, action, ip) VALUES (?1, ?1, 'password_change', '127.0.0.1')",
+        "INSERT INTO bans (user_id, reason, expires_at) VALUES (?1, 'spam', '2000-01-01 00:00:00')",
+        "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?1, 'secret hash')",
+        "INSERT INTO recovery_codes (user_id, code_hash, used_at) VALUES (?1, 'used hash', CURRENT_TIMESTAMP)",
+        "INSERT INTO ai_usage (user_id, conversation_id, month, prompt_tokens, completion_tokens) VALUES (?1, ?2, '2024-01', 10, 20)",
+        "INSERT INTO conversation_summaries (conversation_id, summary, last_message_id) VALUES (?2, 'They said hello', ?3)",
+        "INSERT INTO pinned_messages (message_id, conversation_id, pinned_by) VALUES (?3, ?2, ?1)",
+    ] {
+        sqlx::query(query)
+            .bind(alice.id)
+            .bind(conversation_id)
+            .bind(message_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let response = export_account(State(pool.clone()), JwtAuth(alice.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let export = body_json(response).await;
+
+    assert_eq!(export["profile"]["username"].as_str(), Some("alice"));
+    // Users who never saved their settings still get an export
+    assert!(export["settings"].is_null());
+    assert_eq!(
+        export["auditLog"][0]["action"].as_str(),
+        Some("passwordChange")
+    );
+    assert_eq!(export["ban"]["reason"].as_str(), Some("spam"));
+    assert_eq!(export["recoveryCodes"]["remaining"].as_i64(), Some(1));
+    assert_eq!(export["pins"][0]["messageId"].as_i64(), Some(message_id));
+    assert_eq!(export["aiUsage"][0]["completionTokens"].as_i64(), Some(20));
+    assert_eq!(
+        export["conversationSummaries"][0]["summary"].as_str(),
+        Some("They said hello")
+    );
+    // Bob's message is only there through alice's pin
+    assert!(export["messages"].as_array().unwrap().is_empty());
+    let excluded = export["excluded"].as_array().unwrap();
+    assert!(excluded
+        .iter()
+        .any(|item| item.as_str() == Some("recoveryCodeHashes")));
+
+    let body = export.to_string();
+    assert!(!body.contains("secret hash"));
+}
@@ -0,0 +1,24 @@
+mod common;
+
+use ai_health_assistant_api::chat::{query_model, AiModel};
+use axum::{http::StatusCode, response::IntoResponse};
+use common::{create_conversation, create_user, test_db, test_state};
+
+#[tokio::test]
+async fn unknown_model_is_a_bad_request() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    let user = create_user(&pool, "asker").await;
+    let conversation_id = create_conversation(&pool, &[]).await;
+    let bogus_id = i64::MAX;
+
+    let error = AiModel::get(&pool, bogus_id).await.err().unwrap();
+    assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST);
+
+    let error = query_model(&state, conversation_id, bogus_id, &user, None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST);
+}
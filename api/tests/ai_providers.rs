@@ -0,0 +1,35 @@
+mod common;
+
+use ai_health_assistant_api::chat::complete;
+use common::{create_user, test_db, test_state, MockProvider};
+use serde_json::json;
+
+#[tokio::test]
+async fn completions_go_to_the_provider_of_the_model() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let first = MockProvider::start("From the first").await;
+    let second = MockProvider::start("From the second").await;
+    // Both providers serve a model with the same name
+    first.add_model(&pool, "first", "org/model name").await;
+    let model = second.add_model(&pool, "second", "org/model name").await;
+
+    let reply = complete(
+        &state,
+        &model,
+        json!([{ "role": "user", "content": "Hello" }]),
+        10,
+        alice.id,
+        None,
+    )
+    .await
+    .ok();
+    assert_eq!(reply.as_deref(), Some("From the second"));
+    assert!(first.requests.lock().unwrap().is_empty());
+    // The name keeps its slash but everything else that isn't safe in a url is escaped
+    assert_eq!(
+        *second.requests.lock().unwrap(),
+        ["/org/model%20name/v1/chat/completions"]
+    );
+}
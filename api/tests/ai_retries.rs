@@ -0,0 +1,53 @@
+mod common;
+
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use ai_health_assistant_api::chat::query_model;
+use common::{create_conversation, create_user, test_db, test_state};
+use tokio::{io::AsyncReadExt, net::TcpListener};
+
+#[tokio::test]
+async fn requests_the_provider_dropped_are_not_sent_again() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &["--ai-max-retries", "2"]);
+    let alice = create_user(&pool, "alice").await;
+    let conversation_id = create_conversation(&pool, &[&alice]).await;
+
+    // A provider that reads the request and hangs up without responding
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    tokio::spawn({
+        let connections = connections.clone();
+        async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                connections.fetch_add(1, Ordering::SeqCst);
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+            }
+        }
+    });
+    sqlx::query("INSERT INTO ai_providers (name, base_url) VALUES ('mock', ?)")
+        .bind(format!("http://127.0.0.1:{port}/v1"))
+        .execute(&pool)
+        .await
+        .unwrap();
+    let model_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO ai_models (name, provider) VALUES ('mock-model', 'mock') RETURNING id",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+
+    assert!(query_model(&state, conversation_id, model_id, &alice, None)
+        .await
+        .is_err());
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use ai_health_assistant_api::chat::{complete, summarize_conversation};
+use axum::{http::StatusCode, response::IntoResponse};
+use common::{create_conversation, create_user, test_db, test_state, MockProvider};
+use serde_json::json;
+use sqlx::SqlitePool;
+
+/// The tokens recorded for the user in each conversation, None for usage outside of one
+async fn usage(pool: &SqlitePool, user_id: i64) -> Vec<(Option<i64>, i64, i64)> {
+    sqlx::query_as(
+        "SELECT conversation_id, prompt_tokens, completion_tokens FROM ai_usage
+        WHERE user_id = ? ORDER BY conversation_id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn completions_count_towards_the_users_usage() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let provider = MockProvider::start("An explanation").await;
+    let model = provider.add_model(&pool, "mock", "mock-model").await;
+
+    complete(
+        &state,
+        &model,
+        json!([{ "role": "user", "content": "Explain" }]),
+        10,
+        alice.id,
+        None,
+    )
+    .await
+    .ok()
+    .unwrap();
+    assert_eq!(usage(&pool, alice.id).await, [(None, 10, 5)]);
+}
+
+#[tokio::test]
+async fn summaries_count_towards_the_usage_of_who_asked_for_them() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+    let provider = MockProvider::start("They talked").await;
+    let model = provider.add_model(&pool, "mock", "mock-model").await;
+    // Long enough that the oldest messages no longer fit in the context
+    for _ in 0..3 {
+        sqlx::query("INSERT INTO messages (user_id, conversation_id, message) VALUES (?, ?, ?)")
+            .bind(bob.id)
+            .bind(conversation_id)
+            .bind("a".repeat(3000))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let summary = summarize_conversation(&state, conversation_id, &model, alice.id)
+        .await
+        .ok()
+        .flatten()
+        .unwrap();
+    assert_eq!(summary.summary, "They talked");
+    assert_eq!(
+        usage(&pool, alice.id).await,
+        [(Some(conversation_id), 10, 5)]
+    );
+    assert!(usage(&pool, bob.id).await.is_empty());
+}
+
+#[tokio::test]
+async fn summaries_are_refused_once_the_quota_is_used_up() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &["--ai-monthly-token-quota", "15"]);
+    let alice = create_user(&pool, "alice").await;
+    let conversation_id = create_conversation(&pool, &[&alice]).await;
+    let provider = MockProvider::start("They talked").await;
+    let model = provider.add_model(&pool, "mock", "mock-model").await;
+    for _ in 0..4 {
+        sqlx::query("INSERT INTO messages (user_id, conversation_id, message) VALUES (?, ?, ?)")
+            .bind(alice.id)
+            .bind(conversation_id)
+            .bind("a".repeat(3000))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    // The first summary uses up the quota
+    assert!(
+        summarize_conversation(&state, conversation_id, &model, alice.id)
+            .await
+            .is_ok()
+    );
+    sqlx::query("INSERT INTO messages (user_id, conversation_id, message) VALUES (?, ?, ?)")
+        .bind(alice.id)
+        .bind(conversation_id)
+        .bind("a".repeat(3000))
+        .execute(&pool)
+        .await
+        .unwrap();
+    let status = match summarize_conversation(&state, conversation_id, &model, alice.id).await {
+        Ok(_) => StatusCode::OK,
+        Err(e) => e.into_response().status(),
+    };
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(provider.requests.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{ApiKeyScope, JwtAuth},
+    error::AppJson,
+    state::AppState,
+    users::{create_api_key, CreateApiKey, UserToken},
+};
+use axum::{
+    extract::{FromRef, FromRequestParts, State},
+    http::{header::AUTHORIZATION, Method, Request, StatusCode},
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+use sqlx::SqlitePool;
+
+/// Create an API key for the user, returning the key
+async fn new_key(state: &AppState, user: &UserToken, scope: ApiKeyScope) -> String {
+    let response = create_api_key(
+        State(SqlitePool::from_ref(state)),
+        JwtAuth(user.clone()),
+        AppJson(CreateApiKey {
+            name: "script".to_owned(),
+            scope,
+            expires_in_days: 30,
+        }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body = body_json(response).await;
+    assert!(body["expiresAt"].is_str());
+    body["key"].as_str().unwrap().to_owned()
+}
+
+/// Authenticate a request to a route with an API key.
+/// Returns the status the request is rejected with, if it is
+async fn authenticate(
+    state: &AppState,
+    key: &str,
+    method: Method,
+    path: &str,
+) -> Option<StatusCode> {
+    let (mut parts, _) = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(AUTHORIZATION, format!("ApiKey {key}"))
+        .body(())
+        .unwrap()
+        .into_parts();
+    JwtAuth::<UserToken>::from_request_parts(&mut parts, state)
+        .await
+        .err()
+        .map(|e| e.status())
+}
+
+#[test]
+fn scopes_are_checked_by_route() {
+    let read_only = ApiKeyScope::ReadOnly;
+    assert!(read_only.allows(&Method::GET, "/forms"));
+    assert!(read_only.allows(&Method::GET, "/chat/12/messages"));
+    assert!(read_only.allows(&Method::GET, "/chat/12/messages/"));
+    // Reading with a POST is allowed but changing things with a GET isn't
+    assert!(read_only.allows(&Method::POST, "/check"));
+    assert!(!read_only.allows(&Method::GET, "/account/email/confirm/token"));
+    assert!(!read_only.allows(&Method::GET, "/ws"));
+    assert!(!read_only.allows(&Method::POST, "/forms/health"));
+    assert!(!read_only.allows(&Method::GET, "/chat//messages"));
+    assert!(!read_only.allows(&Method::GET, "/chat/12/messages/extra"));
+
+    let full = ApiKeyScope::Full;
+    assert!(full.allows(&Method::POST, "/forms/health"));
+    assert!(full.allows(&Method::DELETE, "/account/sessions/3"));
+    assert!(!full.allows(&Method::DELETE, "/account"));
+    assert!(!full.allows(&Method::POST, "/account/apikeys"));
+    assert!(!full.allows(&Method::DELETE, "/account/apikeys/3"));
+}
+
+#[tokio::test]
+async fn api_keys_are_limited_to_their_routes() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    let read_only = new_key(&state, &alice, ApiKeyScope::ReadOnly).await;
+    assert_eq!(
+        authenticate(&state, &read_only, Method::GET, "/forms").await,
+        None
+    );
+    assert_eq!(
+        authenticate(&state, &read_only, Method::POST, "/forms/health").await,
+        Some(StatusCode::FORBIDDEN)
+    );
+
+    let full = new_key(&state, &alice, ApiKeyScope::Full).await;
+    assert_eq!(
+        authenticate(&state, &full, Method::POST, "/forms/health").await,
+        None
+    );
+    // Keys can't manage keys or delete the account, even with full access
+    for (method, path) in [
+        (Method::GET, "/account/apikeys"),
+        (Method::POST, "/account/apikeys"),
+        (Method::DELETE, "/account"),
+    ] {
+        assert_eq!(
+            authenticate(&state, &full, method, path).await,
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+
+    assert_eq!(
+        authenticate(&state, "not a key", Method::GET, "/forms").await,
+        Some(StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[tokio::test]
+async fn expired_api_keys_are_rejected() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let key = new_key(&state, &alice, ApiKeyScope::Full).await;
+
+    let (mut parts, _) = Request::builder()
+        .uri("/forms")
+        .header(AUTHORIZATION, format!("ApiKey {key}"))
+        .body(())
+        .unwrap()
+        .into_parts();
+    let JwtAuth(user) = JwtAuth::<UserToken>::from_request_parts(&mut parts, &state)
+        .await
+        .unwrap();
+    // The token lasts as long as the key does
+    let expires_at = sqlx::query_scalar::<_, i64>("SELECT unixepoch(expires_at) FROM api_keys")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(user.exp, expires_at);
+
+    sqlx::query("UPDATE api_keys SET expires_at = datetime('now', '-1 minute')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        authenticate(&state, &key, Method::GET, "/forms").await,
+        Some(StatusCode::UNAUTHORIZED)
+    );
+}
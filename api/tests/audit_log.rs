@@ -0,0 +1,189 @@
+mod common;
+
+use std::io::Cursor;
+
+use ai_health_assistant_api::{
+    audit::{get_account_audit_log, get_audit_log, AuditAction, AuditQuery},
+    auth::JwtAuth,
+    error::AppJson,
+    state::AppState,
+    upload::{upload_file, upload_profile_image, FileUpload},
+    users::UserToken,
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose, Engine};
+use common::{addr, body_json, create_user, test_db, test_state, test_storage};
+use image::{ImageFormat, RgbImage};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use sqlx::SqlitePool;
+
+fn file_upload(data_url: String) -> AppJson<FileUpload> {
+    AppJson(sonic_rs::from_str(&format!(r#"{{"fileData":"{data_url}"}}"#)).unwrap())
+}
+
+fn query(user_id: Option<i64>, action: Option<AuditAction>) -> Query<AuditQuery> {
+    Query(AuditQuery {
+        limit: None,
+        offset: None,
+        user_id,
+        action,
+    })
+}
+
+/// The accounts and actions of the entries in an audit log response
+async fn entries(response: Response) -> Vec<(i64, String)> {
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                entry["userId"].as_i64().unwrap(),
+                entry["action"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect()
+}
+
+async fn record(pool: &SqlitePool, user: &UserToken, action: &str) {
+    sqlx::query("INSERT INTO account_audit_log (user_id, actor_id, action) VALUES (?1, ?1, ?2)")
+        .bind(user.id)
+        .bind(action)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+async fn own_log(state: &AppState, user: &UserToken, action: Option<AuditAction>) -> Response {
+    get_account_audit_log(
+        State(SqlitePool::from_ref(state)),
+        JwtAuth(user.clone()),
+        query(None, action),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn uploads_are_audited() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &[]).with_storage(test_storage(&dir));
+    let alice = create_user(&pool, "alice").await;
+
+    let text = format!(
+        "data:text/plain;base64,{}",
+        general_purpose::STANDARD.encode("notes")
+    );
+    let response = upload_file(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(alice.clone()),
+        file_upload(text),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let mut png = Vec::new();
+    RgbImage::new(8, 8)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+    let image = format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(png)
+    );
+    let response = upload_profile_image(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(alice.clone()),
+        file_upload(image),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = own_log(&state, &alice, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let log = body_json(response).await;
+    let log = log.as_array().unwrap();
+    assert_eq!(log.len(), 2);
+    for entry in log.iter() {
+        assert_eq!(entry["ip"].as_str(), Some("127.0.0.1"));
+        assert!(entry["newValue"].is_str());
+    }
+    assert_eq!(
+        entries(own_log(&state, &alice, Some(AuditAction::FileUpload)).await).await,
+        [(alice.id, "fileUpload".to_owned())]
+    );
+}
+
+#[tokio::test]
+async fn only_admins_see_every_account_and_can_filter_by_one() {
+    let (pool, _dir) = test_db().await;
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let admin = create_user(&pool, "admin").await;
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = ?")
+        .bind(admin.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    record(&pool, &alice, "password_change").await;
+    record(&pool, &bob, "username_change").await;
+
+    // Users only see their own account, even if they ask for someone else's
+    let response = get_account_audit_log(
+        State(pool.clone()),
+        JwtAuth(alice.clone()),
+        query(Some(bob.id), None),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(
+        entries(response).await,
+        [(alice.id, "passwordChange".to_owned())]
+    );
+    let status = get_audit_log(
+        State(pool.clone()),
+        JwtAuth(alice.clone()),
+        query(None, None),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status();
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let all = get_audit_log(
+        State(pool.clone()),
+        JwtAuth(admin.clone()),
+        query(None, None),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(entries(all).await.len(), 2);
+    let bobs = get_audit_log(
+        State(pool.clone()),
+        JwtAuth(admin.clone()),
+        query(Some(bob.id), None),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(entries(bobs).await, [(bob.id, "usernameChange".to_owned())]);
+    let password_changes = get_audit_log(
+        State(pool.clone()),
+        JwtAuth(admin.clone()),
+        query(None, Some(AuditAction::PasswordChange)),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(
+        entries(password_changes).await,
+        [(alice.id, "passwordChange".to_owned())]
+    );
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    state::AppState,
+    users::{check_availability, CheckAvailability, UserToken, UsernameChanges},
+};
+use axum::{
+    extract::{FromRef, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+use sqlx::SqlitePool;
+
+/// The availability of the username and email, `None` for fields that weren't checked
+async fn availability(
+    state: &AppState,
+    user: Option<&UserToken>,
+    username: Option<&str>,
+    email: Option<&str>,
+) -> (Option<String>, Option<String>) {
+    let fields = CheckAvailability {
+        username: username.map(str::to_owned),
+        email: email.map(str::to_owned),
+    };
+    let response = check_availability(
+        State(SqlitePool::from_ref(state)),
+        State(UsernameChanges::from_ref(state)),
+        user.map(|user| JwtAuth(user.clone())),
+        AppJson(fields),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let result = body_json(response).await;
+    let field = |name: &str| result.get(name).map(|v| v.as_str().unwrap().to_owned());
+    (field("username"), field("email"))
+}
+
+#[tokio::test]
+async fn usernames_and_emails_are_checked_together() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let available = || Some("available".to_owned());
+    let taken = || Some("taken".to_owned());
+    let invalid = || Some("invalid".to_owned());
+
+    assert_eq!(
+        availability(&state, None, Some("newcomer"), Some("newcomer@example.com")).await,
+        (available(), available())
+    );
+    assert_eq!(
+        availability(&state, None, Some("alice"), Some("alice@example.com")).await,
+        (taken(), taken())
+    );
+    assert_eq!(
+        availability(&state, None, Some("no spaces"), Some("not an email")).await,
+        (invalid(), invalid())
+    );
+    // Users can keep what they already have
+    assert_eq!(
+        availability(
+            &state,
+            Some(&alice),
+            Some("alice"),
+            Some("alice@example.com")
+        )
+        .await,
+        (available(), available())
+    );
+    // Fields that are left out aren't checked
+    assert_eq!(
+        availability(&state, None, Some("alice"), None).await,
+        (taken(), None)
+    );
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    bans::{ban_user, unban_user, BanRequest},
+    error::AppJson,
+    state::AppState,
+    users::{authenticate_user, LoginData, UserToken},
+};
+use axum::{
+    body::to_bytes,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{addr, create_user_with_password, test_db, test_state, PASSWORD};
+use sonic_rs::JsonValueTrait;
+use sqlx::SqlitePool;
+
+async fn create_user(pool: &SqlitePool, username: &str, is_admin: bool) -> UserToken {
+    let user = create_user_with_password(pool, username).await;
+    sqlx::query("UPDATE users SET is_admin = ? WHERE id = ?")
+        .bind(is_admin)
+        .bind(user.id)
+        .execute(pool)
+        .await
+        .unwrap();
+    user
+}
+
+async fn log_in(state: &AppState, username: &str) -> Response {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: Some(PASSWORD.to_owned()),
+        recovery_code: None,
+        reactivate: false,
+    };
+    authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn banned_users_cannot_log_in_until_unbanned() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let admin = create_user(&pool, "admin", true).await;
+    let troll = create_user(&pool, "troll", false).await;
+
+    let request = BanRequest {
+        reason: Some("Spamming".to_owned()),
+        expires_at: None,
+    };
+    let response = ban_user(
+        State(state.clone()),
+        JwtAuth(admin.clone()),
+        Path(troll.id),
+        AppJson(request),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = log_in(&state, "troll").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: sonic_rs::Value = sonic_rs::from_slice(&body).unwrap();
+    assert_eq!(body["errorType"].as_str(), Some("Banned"));
+    assert_eq!(body["ban"]["reason"].as_str(), Some("Spamming"));
+
+    let response = unban_user(State(pool.clone()), JwtAuth(admin), Path(troll.id))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(log_in(&state, "troll").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn expired_bans_are_ignored() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "reformed", false).await;
+    sqlx::query("INSERT INTO bans (user_id, expires_at) VALUES (?, datetime('now', '-1 minute'))")
+        .bind(user.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(log_in(&state, "reformed").await.status(), StatusCode::OK);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    friends::{get_friend_requests, send_friend_request, FriendRequestAction},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use common::{body_json, create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+async fn send(state: &AppState, sender: &UserToken, receiver: &UserToken) -> StatusCode {
+    let action = FriendRequestAction {
+        other_user_id: receiver.id,
+        accept: true,
+    };
+    send_friend_request(
+        State(state.clone()),
+        JwtAuth(sender.clone()),
+        AppJson(action),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+async fn pending_requests(state: &AppState, user: &UserToken) -> usize {
+    let response = get_friend_requests(State(state.clone()), JwtAuth(user.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await.as_array().unwrap().len()
+}
+
+#[tokio::test]
+async fn blocking_rejects_friend_requests_and_hides_messages() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let carol = create_user(&pool, "carol").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob, &carol]).await;
+    send(&state, &bob, &alice).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+    let mut carol_client = WsClient::connect(&state, &carol).await;
+
+    alice_client
+        .send(&format!(r#"{{"type": "BlockUser", "userId": {}}}"#, bob.id))
+        .await;
+    let event = alice_client.event("BlockEvent").await;
+    assert_eq!(event["userId"].as_i64(), Some(bob.id));
+    assert_eq!(event["blocked"].as_bool(), Some(true));
+    // The pending request is rejected and neither user can send a new one
+    let event = bob_client.event("FriendRequest").await;
+    assert_eq!(event["status"].as_str(), Some("Rejected"));
+    assert_eq!(pending_requests(&state, &alice).await, 0);
+    for (sender, receiver) in [(&bob, &alice), (&alice, &bob)] {
+        assert_eq!(send(&state, sender, receiver).await, StatusCode::FORBIDDEN);
+    }
+
+    // Bob's message reaches everyone but alice, so the first message she gets is carol's
+    let send_message = |message: &str| {
+        format!(
+            r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "{message}"}}"#
+        )
+    };
+    bob_client.send(&send_message("From bob")).await;
+    let event = carol_client.event("Message").await;
+    assert_eq!(event["message"].as_str(), Some("From bob"));
+    carol_client.send(&send_message("From carol")).await;
+    let event = alice_client.event("Message").await;
+    assert_eq!(event["message"].as_str(), Some("From carol"));
+
+    // Once unblocked the friend request can be sent again
+    alice_client
+        .send(&format!(
+            r#"{{"type": "UnblockUser", "userId": {}}}"#,
+            bob.id
+        ))
+        .await;
+    let event = alice_client.event("BlockEvent").await;
+    assert_eq!(event["blocked"].as_bool(), Some(false));
+    assert_eq!(send(&state, &bob, &alice).await, StatusCode::CREATED);
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use ai_health_assistant_api::{
+    state::AppState,
+    users::{get_users_by_ids, UserIds},
+};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+/// The usernames of the users found for the ids, or the status if the request failed
+async fn users(state: &AppState, ids: &str) -> Result<Vec<String>, StatusCode> {
+    let query = UserIds {
+        ids: ids.to_owned(),
+    };
+    let response = get_users_by_ids(State(state.clone()), None, Query(query))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    if response.status() != StatusCode::OK {
+        return Err(response.status());
+    }
+    let mut usernames: Vec<_> = body_json(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["username"].as_str().unwrap().to_owned())
+        .collect();
+    usernames.sort();
+    Ok(usernames)
+}
+
+#[tokio::test]
+async fn users_are_looked_up_together() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+
+    for ids in [
+        format!("{},{}", alice.id, bob.id),
+        format!("[{}, {}]", bob.id, alice.id),
+        // Repeated and unknown ids are left out
+        format!("{0},{0},{1},9999", alice.id, bob.id),
+    ] {
+        assert_eq!(
+            users(&state, &ids).await,
+            Ok(vec!["alice".to_owned(), "bob".to_owned()]),
+            "{ids}"
+        );
+    }
+    assert_eq!(users(&state, "").await, Ok(vec![]));
+    assert_eq!(users(&state, "1,two").await, Err(StatusCode::BAD_REQUEST));
+    let too_many = (1..=101)
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    assert_eq!(users(&state, &too_many).await, Err(StatusCode::BAD_REQUEST));
+}
@@ -0,0 +1,289 @@
+//! Fixtures shared by the integration tests.
+//! Each test file only uses some of them
+#![allow(dead_code)]
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use ai_health_assistant_api::{
+    auth::AuthConfig,
+    chat::{get_user_status, init_ws, AiModel, OnlineStatus},
+    cli::Args,
+    init_db,
+    state::AppState,
+    storage::{LocalStorage, Storage},
+    users::UserToken,
+};
+use axum::{
+    body::to_bytes,
+    extract::FromRef,
+    http::{header, HeaderValue, Uri},
+    response::Response,
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose, Engine};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use sqlx::SqlitePool;
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+/// The password of users created with `create_user_with_password`
+pub const PASSWORD: &str = "correct horse battery staple";
+
+/// Create a fresh database in a temporary directory.
+/// The directory is removed when the returned `TempDir` is dropped, so keep it alive for the whole test
+pub async fn test_db() -> (SqlitePool, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let pool = init_db(&format!(
+        "sqlite://{}",
+        dir.path().join("test.db").display()
+    ))
+    .await
+    .unwrap();
+    (pool, dir)
+}
+
+/// Create an app state with the arguments every test needs followed by `args`
+pub fn test_state(pool: &SqlitePool, args: &[&str]) -> AppState {
+    let args = Args::parse_from(
+        ["api", "--jwt-key", "test", "--no-assistant-conversation"]
+            .iter()
+            .chain(args),
+    );
+    AppState::new(pool.clone(), &args).unwrap()
+}
+
+/// Storage that keeps uploads in the test's temporary directory instead of the real uploads directory
+pub fn test_storage(dir: &TempDir) -> Arc<dyn Storage> {
+    Arc::new(LocalStorage::new(dir.path().join("uploads")))
+}
+
+/// The token of a user, as if they had logged in
+pub fn user_token(id: i64, username: &str) -> UserToken {
+    UserToken {
+        id,
+        username: username.to_owned(),
+        exp: 0,
+        ver: 0,
+        sid: None,
+        iss: None,
+        aud: None,
+    }
+}
+
+/// Create a user without a password
+pub async fn create_user(pool: &SqlitePool, username: &str) -> UserToken {
+    insert_user(pool, username, "").await
+}
+
+/// Create a user whose password is `PASSWORD`
+pub async fn create_user_with_password(pool: &SqlitePool, username: &str) -> UserToken {
+    insert_user(pool, username, &password_auth::generate_hash(PASSWORD)).await
+}
+
+async fn insert_user(pool: &SqlitePool, username: &str, password_hash: &str) -> UserToken {
+    let id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO users (first_name, username, email, password_hash) VALUES ('Test', ?, ?, ?) RETURNING id",
+    )
+    .bind(username)
+    .bind(format!("{username}@example.com"))
+    .bind(password_hash)
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    user_token(id, username)
+}
+
+/// Create a conversation with the given users as members, the first one as its owner
+pub async fn create_conversation(pool: &SqlitePool, members: &[&UserToken]) -> i64 {
+    let conversation_id =
+        sqlx::query_scalar::<_, i64>("INSERT INTO conversations DEFAULT VALUES RETURNING id")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+    for (i, member) in members.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO user_conversations (conversation_id, user_id, role) VALUES (?, ?, ?)",
+        )
+        .bind(conversation_id)
+        .bind(member.id)
+        .bind(if i == 0 { "owner" } else { "member" })
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    conversation_id
+}
+
+/// The address requests are made from
+pub fn addr() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+}
+
+/// Read a response's body as JSON
+pub async fn body_json(response: Response) -> sonic_rs::Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    sonic_rs::from_slice(&body).unwrap()
+}
+
+/// A websocket connection to a server running in the test
+pub struct WsClient(WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+impl WsClient {
+    /// Serve the websocket route with the state and connect to it as the user
+    pub async fn connect(state: &AppState, user: &UserToken) -> Self {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new()
+            .route("/ws", get(init_ws))
+            .with_state(state.clone());
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+
+        let auth = AuthConfig::from_ref(state);
+        let token = auth
+            .encode(&UserToken {
+                exp: auth.expiry(),
+                ..user.clone()
+            })
+            .unwrap();
+        // The token is sent as the second protocol since browsers can't set headers on websockets
+        let protocol = format!(
+            "fakeProtocol, {}",
+            general_purpose::STANDARD_NO_PAD.encode(format!("Bearer {token}"))
+        );
+        let mut request = format!("ws://127.0.0.1:{port}/ws")
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_str(&protocol).unwrap(),
+        );
+        let (stream, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        // The connection is registered after the handshake so wait for it before going on
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while get_user_status(state, user.id).await == OnlineStatus::Offline {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Timed out waiting for the connection to be registered");
+        Self(stream)
+    }
+
+    /// Send a request to the server
+    pub async fn send(&mut self, request: &str) {
+        self.0.send(Message::Text(request.into())).await.unwrap();
+    }
+
+    /// Wait for the next event of a type, skipping any others.
+    /// Panics if it doesn't arrive within a few seconds
+    pub async fn event(&mut self, event_type: &str) -> sonic_rs::Value {
+        use sonic_rs::JsonValueTrait;
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                match self.0.next().await {
+                    Some(Ok(Message::Text(text))) => {
+                        let event: sonic_rs::Value = sonic_rs::from_str(&text).unwrap();
+                        if event["type"].as_str() == Some(event_type) {
+                            return event;
+                        }
+                    }
+                    Some(Ok(_)) => (),
+                    other => panic!("Connection closed waiting for {event_type}: {other:?}"),
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Timed out waiting for {event_type}"))
+    }
+
+    /// Wait for the server to close the connection, returning the text events sent before it did
+    pub async fn closed(&mut self) -> Vec<sonic_rs::Value> {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            let mut events = Vec::new();
+            loop {
+                match self.0.next().await {
+                    Some(Ok(Message::Text(text))) => {
+                        events.push(sonic_rs::from_str(&text).unwrap())
+                    }
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return events,
+                    Some(Ok(_)) => (),
+                }
+            }
+        })
+        .await
+        .expect("Timed out waiting for the connection to close")
+    }
+}
+
+/// An OpenAI compatible provider running in the test that answers every completion with the same
+/// reply. Requests are sent to `/{model}/v1/chat/completions` so tests can see the model's name
+pub struct MockProvider {
+    pub base_url: String,
+    /// The paths of the requests the provider received, in order
+    pub requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockProvider {
+    pub async fn start(reply: &str) -> Self {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let body = serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": reply } }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5 },
+        })
+        .to_string();
+        let app = Router::new().fallback({
+            let requests = requests.clone();
+            move |uri: Uri| async move {
+                requests.lock().unwrap().push(uri.path().to_owned());
+                ([(header::CONTENT_TYPE, "application/json")], body)
+            }
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self {
+            base_url: format!("http://127.0.0.1:{port}/{{model}}/v1"),
+            requests,
+        }
+    }
+
+    /// Add the provider under the given name along with a model it serves
+    pub async fn add_model(&self, pool: &SqlitePool, provider: &str, name: &str) -> AiModel {
+        sqlx::query("INSERT INTO ai_providers (name, base_url) VALUES (?, ?)")
+            .bind(provider)
+            .bind(&self.base_url)
+            .execute(pool)
+            .await
+            .unwrap();
+        let id = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO ai_models (name, provider) VALUES (?, ?) RETURNING id",
+        )
+        .bind(name)
+        .bind(provider)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        AiModel {
+            id,
+            name: name.to_owned(),
+            max_tokens: 4096,
+            provider: provider.to_owned(),
+        }
+    }
+}
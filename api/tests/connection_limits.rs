@@ -0,0 +1,21 @@
+mod common;
+
+use common::{create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+#[tokio::test]
+async fn connections_past_the_limit_are_told_why_they_are_closed() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &["--max-connections-per-user", "2"]);
+    let alice = create_user(&pool, "alice").await;
+
+    let _first = WsClient::connect(&state, &alice).await;
+    let _second = WsClient::connect(&state, &alice).await;
+    let mut third = WsClient::connect(&state, &alice).await;
+    let events = third.closed().await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0]["message"].as_str(),
+        Some("Too many connections, at most 2 can be open at once")
+    );
+}
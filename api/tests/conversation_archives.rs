@@ -0,0 +1,151 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    chat::{export_conversation, import_conversation},
+};
+use axum::{
+    body::{to_bytes, Bytes},
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{body_json, create_conversation, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+#[tokio::test]
+async fn conversations_are_exported_and_imported_with_their_history() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let admin = create_user(&pool, "admin").await;
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = ?")
+        .bind(admin.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let bob = create_user(&pool, "bob").await;
+    let conversation_id = create_conversation(&pool, &[&admin, &bob]).await;
+
+    let message_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO messages (user_id, conversation_id, message) VALUES (?, ?, 'edited') RETURNING id",
+    )
+    .bind(bob.id)
+    .bind(conversation_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO message_edits (message_id, message) VALUES (?, 'original')")
+        .bind(message_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO message_reactions (message_id, user_id, emoji) VALUES (?, ?, '👍')")
+        .bind(message_id)
+        .bind(bob.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO pinned_messages (message_id, conversation_id, pinned_by) VALUES (?, ?, ?)",
+    )
+    .bind(message_id)
+    .bind(conversation_id)
+    .bind(admin.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO conversation_settings (conversation_id, system_prompt, temperature) VALUES (?, 'Be brief', 0.5)",
+    )
+    .bind(conversation_id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let response = export_conversation(
+        State(pool.clone()),
+        JwtAuth(admin.clone()),
+        Path(conversation_id),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let bundle = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let bundle = String::from_utf8(bundle.to_vec()).unwrap();
+
+    // Import the bundle somewhere bob doesn't have an account
+    let bundle = bundle.replace("\"bob\"", "\"carol\"");
+    let response = import_conversation(
+        State(state.clone()),
+        JwtAuth(admin.clone()),
+        Bytes::from(bundle),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let summary = body_json(response).await;
+    assert_eq!(summary["placeholderUsers"].as_array().unwrap().len(), 1);
+    assert_eq!(summary["placeholderUsers"][0].as_str(), Some("carol"));
+    assert_eq!(summary["skippedMessages"].as_u64(), Some(0));
+    let imported_id = summary["conversationId"].as_i64().unwrap();
+
+    // Carol's message and reaction belong to a placeholder that keeps her name but isn't a member
+    let (author, username, first_name) = sqlx::query_as::<_, (i64, String, String)>(
+        "SELECT users.id, username, first_name FROM messages
+        JOIN users ON users.id = messages.user_id WHERE conversation_id = ?",
+    )
+    .bind(imported_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(username, format!("imported_user_{author}"));
+    assert_eq!(first_name, "carol");
+    let members = sqlx::query_scalar::<_, i64>(
+        "SELECT user_id FROM user_conversations WHERE conversation_id = ?",
+    )
+    .bind(imported_id)
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(members, [admin.id]);
+
+    let imported_message =
+        sqlx::query_scalar::<_, i64>("SELECT id FROM messages WHERE conversation_id = ?")
+            .bind(imported_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    let reactor = sqlx::query_scalar::<_, i64>(
+        "SELECT user_id FROM message_reactions WHERE message_id = ? AND emoji = '👍'",
+    )
+    .bind(imported_message)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(reactor, author);
+    let edit =
+        sqlx::query_scalar::<_, String>("SELECT message FROM message_edits WHERE message_id = ?")
+            .bind(imported_message)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(edit, "original");
+    let pinned_by = sqlx::query_scalar::<_, i64>(
+        "SELECT pinned_by FROM pinned_messages WHERE message_id = ? AND conversation_id = ?",
+    )
+    .bind(imported_message)
+    .bind(imported_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(pinned_by, admin.id);
+    let (system_prompt, temperature) = sqlx::query_as::<_, (String, f64)>(
+        "SELECT system_prompt, temperature FROM conversation_settings WHERE conversation_id = ?",
+    )
+    .bind(imported_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(system_prompt, "Be brief");
+    assert_eq!(temperature, 0.5);
+}
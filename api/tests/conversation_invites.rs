@@ -0,0 +1,43 @@
+mod common;
+
+use ai_health_assistant_api::chat::invite_user;
+use axum::{http::StatusCode, response::IntoResponse};
+use common::{create_conversation, create_user, test_db};
+
+#[tokio::test]
+async fn only_owners_and_admins_can_invite() {
+    let (pool, _dir) = test_db().await;
+    let owner = create_user(&pool, "owner").await;
+    let admin = create_user(&pool, "admin").await;
+    let member = create_user(&pool, "member").await;
+    let outsider = create_user(&pool, "outsider").await;
+    let invitee = create_user(&pool, "invitee").await;
+    let conversation_id = create_conversation(&pool, &[&owner, &admin, &member]).await;
+    sqlx::query("UPDATE user_conversations SET role = 'admin' WHERE user_id = ?")
+        .bind(admin.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    for user in [&member, &outsider] {
+        let status = match invite_user(&pool, Some(conversation_id), &[invitee.id], user).await {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.into_response().status(),
+        };
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    let invited = invite_user(&pool, Some(conversation_id), &[invitee.id], &admin)
+        .await
+        .ok();
+    assert_eq!(invited, Some(conversation_id));
+    let invited = invite_user(&pool, Some(conversation_id), &[outsider.id], &owner)
+        .await
+        .ok();
+    assert_eq!(invited, Some(conversation_id));
+
+    // Members can still start a new group conversation of their own
+    assert!(invite_user(&pool, None, &[invitee.id], &member)
+        .await
+        .is_ok());
+}
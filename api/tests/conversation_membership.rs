@@ -0,0 +1,89 @@
+mod common;
+
+use ai_health_assistant_api::chat::{save_message, SendMessage};
+use axum::{http::StatusCode, response::IntoResponse};
+use common::{create_conversation, create_user, test_db, test_state};
+
+const ROUNDS: usize = 200;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn removed_user_cannot_send_message() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    let user = create_user(&pool, "racer").await;
+    let user_id = user.id;
+    let conversation_id = create_conversation(&pool, &[]).await;
+
+    // Record every message that gets inserted while its sender is not in the conversation
+    sqlx::query("CREATE TABLE orphaned_messages (message TEXT)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TRIGGER record_orphaned_messages BEFORE INSERT ON messages
+        WHEN NOT EXISTS (SELECT 1 FROM user_conversations WHERE conversation_id = NEW.conversation_id AND user_id = NEW.user_id)
+        BEGIN INSERT INTO orphaned_messages VALUES (NEW.message); END",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let mut saved = 0;
+    for round in 0..ROUNDS {
+        sqlx::query("INSERT INTO user_conversations (conversation_id, user_id) VALUES (?, ?)")
+            .bind(conversation_id)
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let message = SendMessage {
+            conversation_id: Some(conversation_id),
+            message: Some(format!("message {round}")),
+            ai_model_id: None,
+            attachment: None,
+            reply_to: None,
+        };
+        let send = {
+            let state = state.clone();
+            let user = user.clone();
+            tokio::spawn(async move { save_message(&state, &message, &user).await })
+        };
+        let leave = {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                sqlx::query(
+                    "DELETE FROM user_conversations WHERE conversation_id = ? AND user_id = ?",
+                )
+                .bind(conversation_id)
+                .bind(user_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            })
+        };
+        let (sent, left) = tokio::join!(send, leave);
+        left.unwrap();
+
+        match sent.unwrap() {
+            Ok(_) => saved += 1,
+            // The user was removed before the message got in
+            Err(e) => assert_eq!(e.into_response().status(), StatusCode::FORBIDDEN),
+        }
+    }
+
+    let orphaned = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM orphaned_messages")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(orphaned, 0);
+
+    let stored =
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM messages WHERE conversation_id = ?")
+            .bind(conversation_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(stored, saved);
+}
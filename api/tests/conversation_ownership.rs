@@ -0,0 +1,133 @@
+mod common;
+
+use ai_health_assistant_api::chat::leave_conversation;
+use common::{create_user, test_db};
+use sqlx::SqlitePool;
+
+/// Create a group conversation with a member for each role in order of joining
+async fn create_group(pool: &SqlitePool, roles: &[&str]) -> (i64, Vec<i64>) {
+    let conversation_id =
+        sqlx::query_scalar::<_, i64>("INSERT INTO conversations DEFAULT VALUES RETURNING id")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+    let mut user_ids = Vec::new();
+    for (i, role) in roles.iter().enumerate() {
+        let username = format!("member{conversation_id}x{i}");
+        let user_id = create_user(pool, &username).await.id;
+        sqlx::query(
+            "INSERT INTO user_conversations (conversation_id, user_id, role, joined_at)
+            VALUES (?, ?, ?, datetime('now', ? || ' minutes'))",
+        )
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(role)
+        .bind(i as i64)
+        .execute(pool)
+        .await
+        .unwrap();
+        user_ids.push(user_id);
+    }
+    (conversation_id, user_ids)
+}
+
+async fn role(pool: &SqlitePool, conversation_id: i64, user_id: i64) -> String {
+    sqlx::query_scalar::<_, String>(
+        "SELECT role FROM user_conversations WHERE conversation_id = ? AND user_id = ?",
+    )
+    .bind(conversation_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+async fn owners(pool: &SqlitePool, conversation_id: i64) -> i64 {
+    sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM user_conversations WHERE conversation_id = ? AND role = 'owner'",
+    )
+    .bind(conversation_id)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn creator_leaving_transfers_to_oldest_member() {
+    let (pool, _dir) = test_db().await;
+    let (conversation_id, users) = create_group(&pool, &["owner", "member", "member"]).await;
+
+    let new_owner = leave_conversation(&pool, conversation_id, users[0])
+        .await
+        .unwrap_or_else(|_| panic!("the owner should be able to leave"));
+    assert_eq!(new_owner, Some(users[1]));
+    assert_eq!(role(&pool, conversation_id, users[1]).await, "owner");
+    assert_eq!(role(&pool, conversation_id, users[2]).await, "member");
+    assert_eq!(owners(&pool, conversation_id).await, 1);
+}
+
+#[tokio::test]
+async fn creator_leaving_prefers_admins() {
+    let (pool, _dir) = test_db().await;
+    let (conversation_id, users) =
+        create_group(&pool, &["owner", "member", "admin", "admin"]).await;
+
+    let new_owner = leave_conversation(&pool, conversation_id, users[0])
+        .await
+        .unwrap_or_else(|_| panic!("the owner should be able to leave"));
+    // The admin who joined first gets the conversation over the older member
+    assert_eq!(new_owner, Some(users[2]));
+    assert_eq!(role(&pool, conversation_id, users[1]).await, "member");
+    assert_eq!(role(&pool, conversation_id, users[3]).await, "admin");
+    assert_eq!(owners(&pool, conversation_id).await, 1);
+}
+
+#[tokio::test]
+async fn last_admin_leaving_transfers_to_member() {
+    let (pool, _dir) = test_db().await;
+    let (conversation_id, users) = create_group(&pool, &["owner", "admin", "member"]).await;
+
+    let new_owner = leave_conversation(&pool, conversation_id, users[0])
+        .await
+        .unwrap_or_else(|_| panic!("the owner should be able to leave"));
+    assert_eq!(new_owner, Some(users[1]));
+
+    // The admin is now the owner, and when they leave there are no admins left
+    let new_owner = leave_conversation(&pool, conversation_id, users[1])
+        .await
+        .unwrap_or_else(|_| panic!("the new owner should be able to leave"));
+    assert_eq!(new_owner, Some(users[2]));
+    assert_eq!(role(&pool, conversation_id, users[2]).await, "owner");
+}
+
+#[tokio::test]
+async fn members_leaving_keep_the_owner() {
+    let (pool, _dir) = test_db().await;
+    let (conversation_id, users) = create_group(&pool, &["owner", "admin", "member"]).await;
+
+    for user_id in [users[2], users[1]] {
+        let new_owner = leave_conversation(&pool, conversation_id, user_id)
+            .await
+            .unwrap_or_else(|_| panic!("members should be able to leave"));
+        assert_eq!(new_owner, None);
+    }
+    assert_eq!(role(&pool, conversation_id, users[0]).await, "owner");
+}
+
+#[tokio::test]
+async fn last_member_leaving_deletes_the_conversation() {
+    let (pool, _dir) = test_db().await;
+    let (conversation_id, users) = create_group(&pool, &["owner"]).await;
+
+    let new_owner = leave_conversation(&pool, conversation_id, users[0])
+        .await
+        .unwrap_or_else(|_| panic!("the owner should be able to leave"));
+    assert_eq!(new_owner, None);
+
+    let remaining = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM conversations WHERE id = ?")
+        .bind(conversation_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 0);
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use common::{create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+fn remove_user(conversation_id: i64, user_id: i64) -> String {
+    format!(r#"{{"type": "RemoveUser", "conversationId": {conversation_id}, "userId": {user_id}}}"#)
+}
+
+#[tokio::test]
+async fn only_owners_and_admins_remove_members() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let carol = create_user(&pool, "carol").await;
+    let dave = create_user(&pool, "dave").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob, &carol]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+    let mut carol_client = WsClient::connect(&state, &carol).await;
+
+    bob_client
+        .send(&remove_user(conversation_id, carol.id))
+        .await;
+    let error = bob_client.event("Error").await;
+    assert_eq!(
+        error["message"].as_str(),
+        Some("Only owners and admins can remove users")
+    );
+
+    alice_client
+        .send(&format!(
+            r#"{{"type": "SetRole", "conversationId": {conversation_id}, "userId": {}, "role": "admin"}}"#,
+            bob.id
+        ))
+        .await;
+    let event = bob_client.event("RoleEvent").await;
+    assert_eq!(event["role"].as_str(), Some("admin"));
+
+    // Admins can't remove the owner or anyone outside the conversation
+    bob_client
+        .send(&remove_user(conversation_id, alice.id))
+        .await;
+    let error = bob_client.event("Error").await;
+    assert_eq!(
+        error["message"].as_str(),
+        Some("Admins can only remove members")
+    );
+    bob_client
+        .send(&remove_user(conversation_id, dave.id))
+        .await;
+    let error = bob_client.event("Error").await;
+    assert_eq!(
+        error["message"].as_str(),
+        Some("User is not in the conversation")
+    );
+
+    // Both the removed user and the ones left are told who removed them
+    bob_client
+        .send(&remove_user(conversation_id, carol.id))
+        .await;
+    for client in [&mut carol_client, &mut alice_client] {
+        let event = client.event("RemoveEvent").await;
+        assert_eq!(event["userId"].as_i64(), Some(carol.id));
+        assert_eq!(event["removedBy"].as_i64(), Some(bob.id));
+    }
+    let members: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM user_conversations WHERE conversation_id = ?")
+            .bind(conversation_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(members, 2);
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use common::{create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+#[tokio::test]
+async fn conversation_stats_count_messages_and_members() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let eve = create_user(&pool, "eve").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut eve_client = WsClient::connect(&state, &eve).await;
+    let stats = format!(r#"{{"type": "ConversationStats", "conversationId": {conversation_id}}}"#);
+
+    alice_client.send(&stats).await;
+    let event = alice_client.event("ConversationStats").await;
+    assert_eq!(event["messageCount"].as_i64(), Some(0));
+    assert_eq!(event["memberCount"].as_i64(), Some(2));
+    assert!(event["lastMessageAt"].is_null());
+
+    for message in ["One", "Two", "Three"] {
+        alice_client
+            .send(&format!(
+                r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "{message}"}}"#
+            ))
+            .await;
+        alice_client.event("Message").await;
+    }
+    alice_client.send(&stats).await;
+    let event = alice_client.event("ConversationStats").await;
+    assert_eq!(event["messageCount"].as_i64(), Some(3));
+    assert!(event["lastMessageAt"].is_str());
+
+    // Users outside the conversation don't get its stats
+    eve_client.send(&stats).await;
+    eve_client.event("Error").await;
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    report::{generate_csv_report, ReportRange},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    body::to_bytes,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use chrono::NaiveDate;
+use common::{create_user, test_db, test_state};
+use sqlx::SqlitePool;
+
+async fn save_form(pool: &SqlitePool, user: &UserToken, created_at: &str, notes: &str) {
+    sqlx::query(
+        "INSERT INTO user_statistics (user_id, height, weight, notes, created_at)
+        VALUES (?, 180, 75, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(notes)
+    .bind(created_at)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+/// The lines of the user's CSV report
+async fn csv(state: &AppState, user: &UserToken, range: ReportRange) -> Vec<String> {
+    let response = generate_csv_report(State(state.clone()), JwtAuth(user.clone()), Query(range))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()[header::CONTENT_DISPOSITION]
+        .to_str()
+        .unwrap()
+        .contains("health_report.csv"));
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(body.to_vec())
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect()
+}
+
+#[tokio::test]
+async fn csv_report_only_has_forms_in_the_range() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "csv").await;
+    save_form(&pool, &user, "2026-01-31 23:59:59", "before").await;
+    save_form(&pool, &user, "2026-02-01 00:00:00", "first").await;
+    save_form(&pool, &user, "2026-02-28 23:59:59", "last").await;
+    save_form(&pool, &user, "2026-03-01 00:00:00", "after").await;
+
+    let range = ReportRange {
+        from: NaiveDate::from_ymd_opt(2026, 2, 1),
+        to: NaiveDate::from_ymd_opt(2026, 2, 28),
+    };
+    let lines = csv(&state, &user, range).await;
+    assert_eq!(
+        lines[0],
+        "id,created_at,modified_at,height_cm,weight_kg,sleep_hours,exercise_minutes,food_intake,notes"
+    );
+    let notes: Vec<_> = lines[1..]
+        .iter()
+        .map(|line| line.rsplit(',').next().unwrap())
+        .collect();
+    assert_eq!(notes, ["first", "last"]);
+    assert!(lines[1].contains(",180,75,"));
+
+    // Without a range every form is included
+    assert_eq!(csv(&state, &user, ReportRange::default()).await.len(), 5);
+}
+
+#[tokio::test]
+async fn csv_report_fields_are_not_run_as_formulas() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "csv").await;
+    for notes in [
+        "=HYPERLINK(\"http://example.com\")",
+        "+1",
+        "-1",
+        "@SUM(A1)",
+        "fine = fine",
+    ] {
+        save_form(&pool, &user, "2026-02-01 08:00:00", notes).await;
+    }
+
+    let lines = csv(&state, &user, ReportRange::default()).await;
+    let notes: Vec<_> = lines[1..]
+        .iter()
+        .map(|line| line.splitn(9, ',').last().unwrap())
+        .collect();
+    assert_eq!(
+        notes,
+        [
+            "\"'=HYPERLINK(\"\"http://example.com\"\")\"",
+            "'+1",
+            "'-1",
+            "'@SUM(A1)",
+            "fine = fine",
+        ]
+    );
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+async fn edit(client: &mut WsClient, message_id: i64, message: &str) {
+    client
+        .send(&format!(
+            r#"{{"type": "EditMessage", "id": {message_id}, "message": "{message}"}}"#
+        ))
+        .await;
+}
+
+#[tokio::test]
+async fn edits_keep_the_previous_versions() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+    alice_client
+        .send(&format!(
+            r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "First"}}"#
+        ))
+        .await;
+    let message_id = bob_client.event("Message").await["id"].as_i64().unwrap();
+
+    for message in ["Second", "Third"] {
+        edit(&mut alice_client, message_id, message).await;
+        let event = bob_client.event("Message").await;
+        assert_eq!(event["id"].as_i64(), Some(message_id));
+        assert_eq!(event["message"].as_str(), Some(message));
+    }
+
+    // Only the sender can edit a message
+    edit(&mut bob_client, message_id, "Bob's version").await;
+    bob_client.event("Error").await;
+
+    bob_client
+        .send(&format!(
+            r#"{{"type": "RequestEditHistory", "messageId": {message_id}}}"#
+        ))
+        .await;
+    for previous in ["First", "Second"] {
+        let edit = bob_client.event("MessageEdit").await;
+        assert_eq!(edit["messageId"].as_i64(), Some(message_id));
+        assert_eq!(edit["message"].as_str(), Some(previous));
+    }
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use std::time::Duration;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    chat::{get_user_status, OnlineStatus},
+    error::AppJson,
+    friends::{send_friend_request, FriendRequestAction},
+    state::AppState,
+    users::{update_settings, UserToken},
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{addr, create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+/// Wait until the user has no connections left
+async fn wait_until_offline(state: &AppState, user: &UserToken) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while get_user_status(state, user.id).await != OnlineStatus::Offline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Timed out waiting for the user to go offline");
+}
+
+fn send_message(conversation_id: i64, message: &str) -> String {
+    format!(
+        r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "{message}"}}"#
+    )
+}
+
+#[tokio::test]
+async fn events_missed_while_disconnected_are_replayed() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+
+    bob_client
+        .send(&send_message(conversation_id, "Seen"))
+        .await;
+    bob_client.event("Message").await;
+    let seen = alice_client.event("Message").await;
+    let seen_seq = seen["seq"].as_u64().unwrap();
+
+    drop(alice_client);
+    wait_until_offline(&state, &alice).await;
+    for message in ["Missed", "Also missed"] {
+        bob_client
+            .send(&send_message(conversation_id, message))
+            .await;
+        bob_client.event("Message").await;
+    }
+
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    alice_client
+        .send(&format!(
+            r#"{{"type": "ReplayEvents", "since": {seen_seq}}}"#
+        ))
+        .await;
+    for (message, seq) in [("Missed", seen_seq + 1), ("Also missed", seen_seq + 2)] {
+        let event = alice_client.event("Message").await;
+        assert_eq!(event["message"].as_str(), Some(message));
+        assert_eq!(event["seq"].as_u64(), Some(seq));
+    }
+
+    // Events from before the buffer can't be replayed, so the client has to catch up itself
+    alice_client
+        .send(r#"{"type": "ReplayEvents", "since": 0}"#)
+        .await;
+    let event = alice_client.event("ReplayUnavailable").await;
+    assert_eq!(event["latestSeq"].as_u64(), Some(seen_seq + 2));
+}
+
+#[tokio::test]
+async fn events_sent_to_a_single_user_are_sequenced() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+
+    let action = FriendRequestAction {
+        other_user_id: alice.id,
+        accept: true,
+    };
+    let response = send_friend_request(State(state.clone()), JwtAuth(bob.clone()), AppJson(action))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let request_seq = alice_client.event("FriendRequest").await["seq"]
+        .as_u64()
+        .unwrap();
+
+    let response = update_settings(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(alice.clone()),
+        AppJson(sonic_rs::from_str(r#"{"theme": "light"}"#).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let event = alice_client.event("SettingsChanged").await;
+    assert_eq!(event["seq"].as_u64(), Some(request_seq + 1));
+
+    // Friends are told about each other's status on every connection
+    sqlx::query("INSERT INTO friendships (user1_id, user2_id) VALUES (?, ?)")
+        .bind(alice.id)
+        .bind(bob.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let _bob_client = WsClient::connect(&state, &bob).await;
+    let event = alice_client.event("UserStatus").await;
+    assert_eq!(event["seq"].as_u64(), Some(request_seq + 2));
+
+    alice_client
+        .send(&format!(
+            r#"{{"type": "ReplayEvents", "since": {request_seq}}}"#
+        ))
+        .await;
+    let event = alice_client.event("SettingsChanged").await;
+    assert_eq!(event["seq"].as_u64(), Some(request_seq + 1));
+    let event = alice_client.event("UserStatus").await;
+    assert_eq!(event["userId"].as_i64(), Some(bob.id));
+}
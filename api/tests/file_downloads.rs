@@ -0,0 +1,107 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    state::AppState,
+    upload::{download_file, upload_file, FileUpload},
+    users::UserToken,
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Path, State},
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose, Engine};
+use common::{addr, create_user, test_db, test_state, test_storage};
+use sqlx::SqlitePool;
+
+/// Upload a text file and return its id
+async fn upload(state: &AppState, pool: &SqlitePool, user: &UserToken, contents: &str) -> i64 {
+    let upload: FileUpload = sonic_rs::from_str(&format!(
+        r#"{{"fileData":"data:text/plain;base64,{}"}}"#,
+        general_purpose::STANDARD.encode(contents)
+    ))
+    .unwrap();
+    let response = upload_file(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(upload),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    sqlx::query_scalar::<_, i64>(
+        "SELECT file_id FROM file_uploads WHERE user_id = ? ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(user.id)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+async fn download(state: &AppState, user: &UserToken, file_id: i64) -> Response {
+    let request = Request::get(format!("/api/files/{file_id}"))
+        .body(Body::empty())
+        .unwrap();
+    download_file(
+        State(state.clone()),
+        JwtAuth(user.clone()),
+        Path(file_id),
+        request,
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn attachments_can_only_be_downloaded_by_conversation_members() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &[]).with_storage(test_storage(&dir));
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let eve = create_user(&pool, "eve").await;
+
+    let contents = "private attachment";
+    let file_id = upload(&state, &pool, &alice, contents).await;
+
+    // Nobody else can download the file before it is sent anywhere
+    let response = download(&state, &bob, file_id).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let conversation_id =
+        sqlx::query_scalar::<_, i64>("INSERT INTO conversations DEFAULT VALUES RETURNING id")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    sqlx::query(
+        "INSERT INTO user_conversations (conversation_id, user_id, role) VALUES (?1, ?2, 'owner'), (?1, ?3, 'member')",
+    )
+    .bind(conversation_id)
+    .bind(alice.id)
+    .bind(bob.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO messages (user_id, conversation_id, message, file_id, file_name) VALUES (?, ?, 'here', ?, 'notes.txt')",
+    )
+    .bind(alice.id)
+    .bind(conversation_id)
+    .bind(file_id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    for user in [&alice, &bob] {
+        let response = download(&state, user, file_id).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, contents.as_bytes());
+    }
+    let response = download(&state, &eve, file_id).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    friends::{send_friend_request, FriendRequestAction},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use common::{test_db, test_state};
+use sqlx::SqlitePool;
+
+async fn create_user(pool: &SqlitePool, username: &str, policy: &str) -> UserToken {
+    let user = common::create_user(pool, username).await;
+    sqlx::query("INSERT INTO user_settings (user_id, friend_request_policy) VALUES (?, ?)")
+        .bind(user.id)
+        .bind(policy)
+        .execute(pool)
+        .await
+        .unwrap();
+    user
+}
+
+async fn send(state: &AppState, sender: &UserToken, receiver: &UserToken) -> StatusCode {
+    let action: FriendRequestAction =
+        sonic_rs::from_str(&format!(r#"{{"otherUserId":{}}}"#, receiver.id)).unwrap();
+    send_friend_request(
+        State(state.clone()),
+        JwtAuth(sender.clone()),
+        AppJson(action),
+    )
+    .await
+    .map(IntoResponse::into_response)
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+#[tokio::test]
+async fn conversation_members_policy_requires_a_shared_conversation() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice", "everyone").await;
+    let bob = create_user(&pool, "bob", "conversation_members").await;
+
+    assert_eq!(send(&state, &alice, &bob).await, StatusCode::FORBIDDEN);
+
+    // Sharing a conversation that has other members too is enough
+    let carol = create_user(&pool, "carol", "everyone").await;
+    let conversation_id =
+        sqlx::query_scalar::<_, i64>("INSERT INTO conversations DEFAULT VALUES RETURNING id")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    sqlx::query(
+        "INSERT INTO user_conversations (conversation_id, user_id, role)
+        VALUES (?1, ?2, 'owner'), (?1, ?3, 'member'), (?1, ?4, 'member')",
+    )
+    .bind(conversation_id)
+    .bind(alice.id)
+    .bind(bob.id)
+    .bind(carol.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    assert_eq!(send(&state, &alice, &bob).await, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn nobody_policy_rejects_requests_but_allows_answering_them() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice", "nobody").await;
+    let bob = create_user(&pool, "bob", "everyone").await;
+
+    assert_eq!(send(&state, &bob, &alice).await, StatusCode::FORBIDDEN);
+
+    // Alice can still send requests and bob can accept them
+    assert_eq!(send(&state, &alice, &bob).await, StatusCode::CREATED);
+    assert_eq!(send(&state, &bob, &alice).await, StatusCode::OK);
+}
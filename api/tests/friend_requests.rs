@@ -0,0 +1,76 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    friends::{
+        delete_friend_request, get_friend_requests, send_friend_request, FriendRequestAction,
+    },
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+async fn send(state: &AppState, sender: &UserToken, receiver: &UserToken) -> StatusCode {
+    let action = FriendRequestAction {
+        other_user_id: receiver.id,
+        accept: true,
+    };
+    send_friend_request(
+        State(state.clone()),
+        JwtAuth(sender.clone()),
+        AppJson(action),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+async fn delete(state: &AppState, user: &UserToken, other: &UserToken) -> Response {
+    delete_friend_request(State(state.clone()), JwtAuth(user.clone()), Path(other.id))
+        .await
+        .unwrap_or_else(IntoResponse::into_response)
+}
+
+async fn pending_requests(state: &AppState, user: &UserToken) -> usize {
+    let response = get_friend_requests(State(state.clone()), JwtAuth(user.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await.as_array().unwrap().len()
+}
+
+#[tokio::test]
+async fn friend_requests_can_be_revoked_or_rejected() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+
+    // The sender revokes it
+    assert_eq!(send(&state, &alice, &bob).await, StatusCode::CREATED);
+    let response = delete(&state, &alice, &bob).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(response).await["status"].as_str(),
+        Some("Rejected")
+    );
+    assert_eq!(pending_requests(&state, &bob).await, 0);
+
+    // The receiver rejects it
+    assert_eq!(send(&state, &alice, &bob).await, StatusCode::CREATED);
+    let response = delete(&state, &bob, &alice).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(pending_requests(&state, &alice).await, 0);
+
+    // There is nothing left to delete, and no one can befriend themselves
+    let response = delete(&state, &bob, &alice).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(send(&state, &alice, &alice).await, StatusCode::FORBIDDEN);
+}
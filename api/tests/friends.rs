@@ -0,0 +1,92 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    friends::{get_friend_requests, get_friends, send_friend_request, FriendRequestAction},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+async fn send(state: &AppState, sender: &UserToken, receiver: &UserToken) -> Response {
+    let action = FriendRequestAction {
+        other_user_id: receiver.id,
+        accept: true,
+    };
+    send_friend_request(
+        State(state.clone()),
+        JwtAuth(sender.clone()),
+        AppJson(action),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+/// The usernames of the user's friends
+async fn friends(state: &AppState, user: &UserToken) -> Vec<String> {
+    let response = get_friends(State(state.clone()), JwtAuth(user.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|friend| friend["user"]["username"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+/// The sender and receiver ids of the user's pending friend requests
+async fn requests(state: &AppState, user: &UserToken) -> Vec<(i64, i64)> {
+    let response = get_friend_requests(State(state.clone()), JwtAuth(user.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|request| {
+            assert_eq!(request["status"].as_str(), Some("Pending"));
+            (
+                request["senderId"].as_i64().unwrap(),
+                request["receiverId"].as_i64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn friends_and_pending_requests_are_listed() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+
+    let response = send(&state, &alice, &bob).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    // Both users see the request, whichever side of it they are on
+    assert_eq!(requests(&state, &alice).await, [(alice.id, bob.id)]);
+    assert_eq!(requests(&state, &bob).await, [(alice.id, bob.id)]);
+    assert!(friends(&state, &bob).await.is_empty());
+
+    // Sending a request back accepts it
+    let response = send(&state, &bob, &alice).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(response).await["status"].as_str(),
+        Some("Accepted")
+    );
+    assert!(requests(&state, &bob).await.is_empty());
+    assert_eq!(friends(&state, &alice).await, ["bob"]);
+    assert_eq!(friends(&state, &bob).await, ["alice"]);
+}
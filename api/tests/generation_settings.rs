@@ -0,0 +1,90 @@
+mod common;
+
+use ai_health_assistant_api::{
+    chat::{set_generation_settings, GenerationSettings},
+    users::UserToken,
+};
+use axum::{http::StatusCode, response::IntoResponse};
+use common::{create_conversation, create_user, test_db};
+use sqlx::SqlitePool;
+
+async fn set_max_tokens(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    max_tokens: i64,
+    user: &UserToken,
+) -> StatusCode {
+    let settings = GenerationSettings {
+        conversation_id,
+        system_prompt: Some("Be brief".to_owned()),
+        temperature: None,
+        max_tokens: Some(max_tokens),
+        top_p: None,
+    };
+    match set_generation_settings(pool, &settings, user).await {
+        Ok(()) => StatusCode::OK,
+        Err(e) => e.into_response().status(),
+    }
+}
+
+#[tokio::test]
+async fn only_owners_and_admins_change_generation_settings() {
+    let (pool, _dir) = test_db().await;
+    let owner = create_user(&pool, "owner").await;
+    let admin = create_user(&pool, "admin").await;
+    let member = create_user(&pool, "member").await;
+    let outsider = create_user(&pool, "outsider").await;
+    let conversation_id = create_conversation(&pool, &[&owner, &admin, &member]).await;
+    sqlx::query("UPDATE user_conversations SET role = 'admin' WHERE user_id = ?")
+        .bind(admin.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    for user in [&member, &outsider] {
+        assert_eq!(
+            set_max_tokens(&pool, conversation_id, 100, user).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+    for user in [&owner, &admin] {
+        assert_eq!(
+            set_max_tokens(&pool, conversation_id, 100, user).await,
+            StatusCode::OK
+        );
+    }
+    let settings = GenerationSettings::get(&pool, conversation_id)
+        .await
+        .unwrap();
+    assert_eq!(settings.max_tokens, Some(100));
+}
+
+#[tokio::test]
+async fn max_tokens_is_limited_by_the_largest_model() {
+    let (pool, _dir) = test_db().await;
+    let owner = create_user(&pool, "owner").await;
+    let conversation_id = create_conversation(&pool, &[&owner]).await;
+    sqlx::query("UPDATE ai_models SET max_tokens = 1000")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        set_max_tokens(&pool, conversation_id, 1000, &owner).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        set_max_tokens(&pool, conversation_id, 1001, &owner).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // Without any models there is no limit to check against
+    sqlx::query("DELETE FROM ai_models")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        set_max_tokens(&pool, conversation_id, 1001, &owner).await,
+        StatusCode::OK
+    );
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth, error::AppJson, forms::save_health_form, state::AppState, users::UserToken,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+
+async fn save(state: &AppState, user: &UserToken, form: &str) -> Response {
+    save_health_form(
+        State(state.clone()),
+        JwtAuth(user.clone()),
+        AppJson(sonic_rs::from_str(form).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn forms_come_with_their_bmi() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    let response = save(&state, &alice, r#"{"height": 180, "weight": 81}"#).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let form = &body_json(response).await["data"];
+    assert_eq!(form["bmi"].as_f64(), Some(25.0));
+    assert_eq!(form["bmiCategory"].as_str(), Some("overweight"));
+
+    // Without a height there is nothing to calculate it from
+    let response = save(&state, &alice, r#"{"weight": 81}"#).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let form = &body_json(response).await["data"];
+    assert!(form.get("bmi").is_none());
+    assert!(form.get("bmiCategory").is_none());
+}
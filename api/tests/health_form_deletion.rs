@@ -0,0 +1,76 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    forms::{delete_health_form, get_health_form},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+use sqlx::SqlitePool;
+
+async fn save_form(pool: &SqlitePool, user: &UserToken, sleep_hours: f64) -> i64 {
+    // Forms saved in the same second share a `created_at`
+    sqlx::query_scalar(
+        "INSERT INTO user_statistics (user_id, sleep_hours, created_at)
+        VALUES (?, ?, '2026-03-01 08:00:00') RETURNING id",
+    )
+    .bind(user.id)
+    .bind(sleep_hours)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+async fn delete(state: &AppState, user: &UserToken, id: i64) -> StatusCode {
+    delete_health_form(State(state.clone()), JwtAuth(user.clone()), Path(id))
+        .await
+        .unwrap_or_else(IntoResponse::into_response)
+        .status()
+}
+
+#[tokio::test]
+async fn only_the_owner_can_delete_a_form() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let form = save_form(&pool, &alice, 8.0).await;
+
+    assert_eq!(delete(&state, &bob, form).await, StatusCode::FORBIDDEN);
+    assert_eq!(delete(&state, &alice, form).await, StatusCode::NO_CONTENT);
+    assert_eq!(delete(&state, &alice, form).await, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn the_latest_form_falls_back_to_the_one_before_the_deleted_one() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let first = save_form(&pool, &alice, 6.0).await;
+    let latest = save_form(&pool, &alice, 8.0).await;
+
+    let latest_sleep = || async {
+        let response = get_health_form(State(state.clone()), JwtAuth(alice.clone()))
+            .await
+            .unwrap_or_else(IntoResponse::into_response);
+        match response.status() {
+            StatusCode::OK => body_json(response).await["sleepHours"].as_f64(),
+            status => {
+                assert_eq!(status, StatusCode::NOT_FOUND);
+                None
+            }
+        }
+    };
+    assert_eq!(latest_sleep().await, Some(8.0));
+    assert_eq!(delete(&state, &alice, latest).await, StatusCode::NO_CONTENT);
+    assert_eq!(latest_sleep().await, Some(6.0));
+    assert_eq!(delete(&state, &alice, first).await, StatusCode::NO_CONTENT);
+    assert_eq!(latest_sleep().await, None);
+}
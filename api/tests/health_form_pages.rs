@@ -0,0 +1,97 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    forms::{get_forms, FormsQuery},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+/// The ids of the forms returned for the query, most recent first
+async fn forms(
+    state: &AppState,
+    user: &UserToken,
+    query: FormsQuery,
+) -> Result<Vec<i64>, StatusCode> {
+    let response = get_forms(State(state.clone()), JwtAuth(user.clone()), Query(query))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    if response.status() != StatusCode::OK {
+        return Err(response.status());
+    }
+    Ok(body_json(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|form| form["id"].as_i64().unwrap())
+        .collect())
+}
+
+fn between(from: &str, to: &str) -> FormsQuery {
+    FormsQuery {
+        limit: None,
+        offset: None,
+        from: Some(from.parse().unwrap()),
+        to: Some(to.parse().unwrap()),
+    }
+}
+
+#[tokio::test]
+async fn forms_are_paged_and_filtered_by_date() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let mut ids = Vec::new();
+    for created_at in [
+        "2026-01-01 08:00:00",
+        "2026-01-02 08:00:00",
+        "2026-01-03 23:59:00",
+        "2026-01-04 08:00:00",
+    ] {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO user_statistics (user_id, sleep_hours, created_at) VALUES (?, 8, ?) RETURNING id",
+        )
+        .bind(alice.id)
+        .bind(created_at)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        ids.push(id);
+    }
+
+    assert_eq!(
+        forms(&state, &alice, between("2026-01-01", "2026-01-04")).await,
+        Ok(vec![ids[3], ids[2], ids[1], ids[0]])
+    );
+    let page = FormsQuery {
+        limit: Some(2),
+        offset: Some(1),
+        ..between("2026-01-01", "2026-01-04")
+    };
+    assert_eq!(forms(&state, &alice, page).await, Ok(vec![ids[2], ids[1]]));
+    // The range includes the whole of the last day
+    assert_eq!(
+        forms(&state, &alice, between("2026-01-02", "2026-01-03")).await,
+        Ok(vec![ids[2], ids[1]])
+    );
+    // Without a range only the last 90 days are returned
+    let no_range = FormsQuery {
+        limit: None,
+        offset: None,
+        from: None,
+        to: None,
+    };
+    assert_eq!(forms(&state, &alice, no_range).await, Ok(vec![]));
+    assert_eq!(
+        forms(&state, &alice, between("2026-01-04", "2026-01-01")).await,
+        Err(StatusCode::BAD_REQUEST)
+    );
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    forms::{save_health_form, update_health_form},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+
+async fn save(state: &AppState, user: &UserToken, form: &str) -> Response {
+    save_health_form(
+        State(state.clone()),
+        JwtAuth(user.clone()),
+        AppJson(sonic_rs::from_str(form).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn impossible_values_are_rejected() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    for form in [
+        r#"{"height": 0}"#,
+        r#"{"height": 301}"#,
+        r#"{"weight": -70}"#,
+        r#"{"weight": 701}"#,
+        r#"{"sleepHours": 25}"#,
+        r#"{"exerciseDuration": -1}"#,
+        r#"{"exerciseDuration": 1441}"#,
+    ] {
+        let response = save(&state, &alice, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{form}");
+    }
+    let response = save(
+        &state,
+        &alice,
+        r#"{"height": 300, "weight": 700, "sleepHours": 0, "exerciseDuration": 1440}"#,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let id = body_json(response).await["data"]["id"].as_i64().unwrap();
+
+    // Updates are held to the same limits
+    let response = update_health_form(
+        State(state.clone()),
+        JwtAuth(alice.clone()),
+        Path(id),
+        AppJson(sonic_rs::from_str(r#"{"sleepHours": 24.5}"#).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
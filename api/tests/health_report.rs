@@ -0,0 +1,122 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    report::{generate_pdf_report, generate_report, ReportRange},
+};
+use axum::{
+    body::to_bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use chrono::NaiveDate;
+use common::{create_user, test_db, test_state};
+use sonic_rs::{JsonNumberTrait, JsonValueTrait};
+
+#[tokio::test]
+async fn pdf_report_is_generated_from_forms() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "reporter").await;
+    sqlx::query(
+        "INSERT INTO user_statistics (user_id, height, weight, exercise_duration, sleep_hours)
+        VALUES (?, 180, 75, 30, 8)",
+    )
+    .bind(user.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let response = generate_pdf_report(State(state), JwtAuth(user), Query(ReportRange::default()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/pdf");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.starts_with(b"%PDF-"));
+    assert!(body.trim_ascii_end().ends_with(b"%%EOF"));
+}
+
+#[tokio::test]
+async fn pdf_report_without_forms_is_still_generated() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "newcomer").await;
+
+    let response = generate_pdf_report(State(state), JwtAuth(user), Query(ReportRange::default()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.starts_with(b"%PDF-"));
+    // Text is written to the document as hex strings
+    let contains_text = |text: &str| {
+        let hex: String = text.bytes().map(|byte| format!("{byte:02X}")).collect();
+        body.windows(hex.len())
+            .any(|window| window == hex.as_bytes())
+    };
+    assert!(contains_text("No data available"));
+    assert!(!contains_text("NaN"));
+}
+
+#[tokio::test]
+async fn report_only_covers_forms_in_the_range() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "ranged").await;
+    for (created_at, weight) in [
+        ("2026-01-01 08:00:00", 90.0),
+        ("2026-02-01 08:00:00", 80.0),
+        ("2026-02-28 20:00:00", 77.5),
+        ("2026-03-01 08:00:00", 70.0),
+    ] {
+        sqlx::query("INSERT INTO user_statistics (user_id, weight, created_at) VALUES (?, ?, ?)")
+            .bind(user.id)
+            .bind(weight)
+            .bind(created_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    let range = ReportRange {
+        from: NaiveDate::from_ymd_opt(2026, 2, 1),
+        to: NaiveDate::from_ymd_opt(2026, 2, 28),
+    };
+    let response = generate_report(State(state), JwtAuth(user), Query(range), headers)
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let summary: sonic_rs::Value = sonic_rs::from_slice(&body).unwrap();
+    assert_eq!(summary["entries"].as_u64(), Some(2));
+    assert_eq!(summary["from"].as_str(), Some("2026-02-01"));
+    assert_eq!(summary["to"].as_str(), Some("2026-02-28"));
+    assert_eq!(
+        summary["weightAvg"].as_number().and_then(|n| n.as_f64()),
+        Some(78.75)
+    );
+    assert_eq!(
+        summary["weightChange"].as_number().and_then(|n| n.as_f64()),
+        Some(-2.5)
+    );
+}
+
+#[tokio::test]
+async fn report_range_must_not_be_backwards() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "backwards").await;
+
+    let range = ReportRange {
+        from: NaiveDate::from_ymd_opt(2026, 3, 1),
+        to: NaiveDate::from_ymd_opt(2026, 2, 1),
+    };
+    let response = generate_pdf_report(State(state), JwtAuth(user), Query(range))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
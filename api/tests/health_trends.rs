@@ -0,0 +1,175 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    forms::{get_health_trends, TrendBucket, TrendMetric, TrendsQuery},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::NaiveDate;
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use sqlx::SqlitePool;
+
+fn date(date: &str) -> NaiveDate {
+    date.parse().unwrap()
+}
+
+/// Save a form with only a weight, as if it was filled in at the given time
+async fn weigh_in(pool: &SqlitePool, user: &UserToken, created_at: &str, weight: f64) {
+    sqlx::query("INSERT INTO user_statistics (user_id, weight, created_at) VALUES (?, ?, ?)")
+        .bind(user.id)
+        .bind(weight)
+        .bind(created_at)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+async fn trend(
+    state: &AppState,
+    user: &UserToken,
+    bucket: TrendBucket,
+    from: &str,
+    to: &str,
+) -> Response {
+    get_health_trends(
+        State(state.clone()),
+        JwtAuth(user.clone()),
+        Query(TrendsQuery {
+            metric: TrendMetric::Weight,
+            from: Some(date(from)),
+            to: Some(date(to)),
+            bucket,
+        }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+/// The first day, average, and number of forms of each bucket in the trend
+async fn points(response: Response) -> Vec<(String, f64, i64)> {
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await["points"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|point| {
+            (
+                point["date"].as_str().unwrap().to_owned(),
+                point["average"].as_f64().unwrap(),
+                point["count"].as_i64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn weeks_start_on_monday() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    // 2024-06-03 and 2024-06-10 are Mondays
+    weigh_in(&pool, &alice, "2024-06-03 00:00:00", 70.0).await;
+    weigh_in(&pool, &alice, "2024-06-09 23:59:59", 72.0).await;
+    weigh_in(&pool, &alice, "2024-06-10 00:00:00", 80.0).await;
+
+    let response = trend(
+        &state,
+        &alice,
+        TrendBucket::Week,
+        "2024-06-01",
+        "2024-06-30",
+    )
+    .await;
+    assert_eq!(
+        points(response).await,
+        [
+            ("2024-06-03".to_owned(), 71.0, 2),
+            ("2024-06-10".to_owned(), 80.0, 1)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn months_start_on_the_first() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    weigh_in(&pool, &alice, "2024-01-31 23:59:59", 70.0).await;
+    weigh_in(&pool, &alice, "2024-02-01 00:00:00", 80.0).await;
+    weigh_in(&pool, &alice, "2024-02-29 12:00:00", 90.0).await;
+
+    let response = trend(
+        &state,
+        &alice,
+        TrendBucket::Month,
+        "2024-01-01",
+        "2024-03-31",
+    )
+    .await;
+    assert_eq!(
+        points(response).await,
+        [
+            ("2024-01-01".to_owned(), 70.0, 1),
+            ("2024-02-01".to_owned(), 85.0, 2)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn the_range_includes_the_whole_last_day() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    weigh_in(&pool, &alice, "2024-05-31 23:59:59", 60.0).await;
+    weigh_in(&pool, &alice, "2024-06-01 00:00:00", 70.0).await;
+    weigh_in(&pool, &alice, "2024-06-02 23:59:59", 80.0).await;
+    weigh_in(&pool, &alice, "2024-06-03 00:00:00", 90.0).await;
+
+    let response = trend(&state, &alice, TrendBucket::Day, "2024-06-01", "2024-06-02").await;
+    assert_eq!(
+        points(response).await,
+        [
+            ("2024-06-01".to_owned(), 70.0, 1),
+            ("2024-06-02".to_owned(), 80.0, 1)
+        ]
+    );
+
+    // A single day is a valid range but an inverted one isn't
+    let response = trend(&state, &alice, TrendBucket::Day, "2024-06-02", "2024-06-02").await;
+    assert_eq!(points(response).await.len(), 1);
+    let response = trend(&state, &alice, TrendBucket::Day, "2024-06-03", "2024-06-02").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn weights_are_in_the_users_units() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    sqlx::query("INSERT INTO user_settings (user_id, unit_system) VALUES (?, 'imperial')")
+        .bind(alice.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    weigh_in(&pool, &alice, "2024-06-01 12:00:00", 45.359237).await;
+    weigh_in(&pool, &alice, "2024-06-01 13:00:00", 90.718474).await;
+
+    let response = trend(&state, &alice, TrendBucket::Day, "2024-06-01", "2024-06-01").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let trend = body_json(response).await;
+    assert_eq!(trend["unitSystem"].as_str(), Some("imperial"));
+    let point = &trend["points"][0];
+    for (field, pounds) in [("min", 100.0), ("max", 200.0), ("average", 150.0)] {
+        assert!(
+            (point[field].as_f64().unwrap() - pounds).abs() < 1e-9,
+            "{field}"
+        );
+    }
+}
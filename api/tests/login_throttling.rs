@@ -0,0 +1,144 @@
+mod common;
+
+use ai_health_assistant_api::{
+    error::AppJson,
+    state::AppState,
+    users::{authenticate_user, LoginData},
+};
+use axum::{
+    body::to_bytes,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{addr, create_user_with_password, test_db, test_state, PASSWORD};
+use sonic_rs::JsonValueTrait;
+use sqlx::SqlitePool;
+
+/// Create an app state that locks a username out after two failed logins
+fn throttled_state(pool: &SqlitePool) -> AppState {
+    test_state(pool, &["--login-max-failures", "2"])
+}
+
+async fn log_in(state: &AppState, username: &str, password: &str) -> Response {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: Some(password.to_owned()),
+        recovery_code: None,
+        reactivate: false,
+    };
+    authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+async fn error_type(response: Response) -> String {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: sonic_rs::Value = sonic_rs::from_slice(&body).unwrap();
+    body["errorType"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn bad_credentials_are_unauthorized() {
+    let (pool, _dir) = test_db().await;
+    create_user_with_password(&pool, "guesser").await;
+    let state = throttled_state(&pool);
+
+    let response = log_in(&state, "guesser", "not the password").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(!response.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(error_type(response).await, "User");
+}
+
+#[tokio::test]
+async fn locked_out_logins_are_rate_limited() {
+    let (pool, _dir) = test_db().await;
+    create_user_with_password(&pool, "locked").await;
+    let state = throttled_state(&pool);
+
+    for _ in 0..2 {
+        let response = log_in(&state, "locked", "not the password").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // Even the right password is throttled until the failures leave the window
+    let response = log_in(&state, "locked", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 900);
+    assert_eq!(error_type(response).await, "RateLimited");
+}
+
+#[tokio::test]
+async fn users_can_log_in_with_their_email() {
+    let (pool, _dir) = test_db().await;
+    create_user_with_password(&pool, "emailed").await;
+    let state = throttled_state(&pool);
+
+    let response = log_in(&state, "Emailed@Example.com", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Unknown emails fail the same way as a wrong password
+    let response = log_in(&state, "nobody@example.com", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error_type(response).await, "User");
+}
+
+#[tokio::test]
+async fn email_logins_share_the_username_lockout() {
+    let (pool, _dir) = test_db().await;
+    create_user_with_password(&pool, "shared").await;
+    let state = throttled_state(&pool);
+
+    let response = log_in(&state, "shared", "not the password").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = log_in(&state, "shared@example.com", "not the password").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = log_in(&state, "shared@example.com", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn successful_logins_dont_count_against_the_ip() {
+    let (pool, _dir) = test_db().await;
+    create_user_with_password(&pool, "regular").await;
+    let state = test_state(&pool, &["--login-max-ip-failures", "2"]);
+
+    for _ in 0..3 {
+        let response = log_in(&state, "regular", PASSWORD).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Failures still do
+    for _ in 0..2 {
+        let response = log_in(&state, "regular", "not the password").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = log_in(&state, "regular", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn every_casing_of_a_username_shares_the_lockout() {
+    let (pool, _dir) = test_db().await;
+    create_user_with_password(&pool, "cased").await;
+    let state = throttled_state(&pool);
+
+    for username in ["Cased", "CASED"] {
+        let response = log_in(&state, username, "not the password").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let response = log_in(&state, "cased", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{token_is_current, AuthConfig, JwtAuth},
+    error::AppJson,
+    state::AppState,
+    users::{authenticate_user, logout, LoginData, UserToken},
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use common::{addr, create_user_with_password, test_db, test_state, PASSWORD};
+use sqlx::SqlitePool;
+
+/// Log in with `PASSWORD` and return the session's token
+async fn log_in(state: &AppState, username: &str) -> UserToken {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: Some(PASSWORD.to_owned()),
+        recovery_code: None,
+        reactivate: false,
+    };
+    let response = authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let authorization = response.headers()[AUTHORIZATION].to_str().unwrap();
+    AuthConfig::from_ref(state)
+        .decode(authorization.strip_prefix("Bearer ").unwrap())
+        .unwrap()
+}
+
+async fn is_current(pool: &SqlitePool, token: &UserToken) -> bool {
+    token_is_current(pool, token).await.unwrap()
+}
+
+#[tokio::test]
+async fn logging_out_revokes_every_session() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    create_user_with_password(&pool, "alice").await;
+    let laptop = log_in(&state, "alice").await;
+    let phone = log_in(&state, "alice").await;
+
+    let response = logout(State(state.clone()), JwtAuth(laptop.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!is_current(&pool, &laptop).await);
+    assert!(!is_current(&pool, &phone).await);
+
+    // Logging in again starts a new session
+    let laptop = log_in(&state, "alice").await;
+    assert!(is_current(&pool, &laptop).await);
+}
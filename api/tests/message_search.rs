@@ -0,0 +1,158 @@
+mod common;
+
+use ai_health_assistant_api::{
+    chat::{
+        save_message, search_message, SearchMessage, SearchResult, SendMessage, SocketResponse,
+    },
+    state::AppState,
+    users::UserToken,
+};
+use common::{create_conversation, test_db, test_state};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc;
+
+/// Create a user in a conversation of their own
+async fn create_user(pool: &SqlitePool, username: &str) -> (UserToken, i64) {
+    let user = common::create_user(pool, username).await;
+    let conversation_id = create_conversation(pool, &[&user]).await;
+    (user, conversation_id)
+}
+
+async fn send(state: &AppState, user: &UserToken, conversation_id: i64, message: &str) -> i64 {
+    let message = SendMessage {
+        conversation_id: Some(conversation_id),
+        message: Some(message.to_owned()),
+        ai_model_id: None,
+        attachment: None,
+        reply_to: None,
+    };
+    save_message(state, &message, user)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to send message: {e}"))
+        .id
+}
+
+/// Run a search as the user and collect the results and the cursor of the next page
+async fn run_search(
+    state: &AppState,
+    user: &UserToken,
+    request: &str,
+) -> (Vec<SearchResult>, Option<String>) {
+    let request: SearchMessage = sonic_rs::from_str(request).unwrap();
+    let (sender, mut receiver) = mpsc::channel(16);
+    search_message(state, &request, user, &sender)
+        .await
+        .unwrap_or_else(|e| panic!("Search failed: {e}"));
+    drop(sender);
+
+    let mut found = Vec::new();
+    while let Some(response) = receiver.recv().await {
+        match response {
+            SocketResponse::SearchMessage(result) => found.push(result),
+            SocketResponse::SearchEnd { next_cursor } => return (found, next_cursor),
+            _ => panic!("Unexpected search response"),
+        }
+    }
+    panic!("The search didn't end")
+}
+
+/// Search as the user and collect the ids of the messages found
+async fn search(state: &AppState, user: &UserToken, conversations: &[i64]) -> Vec<i64> {
+    let request = format!(
+        r#"{{"conversations":{:?},"query":"appointment"}}"#,
+        conversations
+    );
+    run_search(state, user, &request)
+        .await
+        .0
+        .into_iter()
+        .map(|result| result.message.id)
+        .collect()
+}
+
+#[tokio::test]
+async fn search_only_finds_messages_in_the_users_conversations() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let (alice, alice_conversation) = create_user(&pool, "alice").await;
+    let (bob, bob_conversation) = create_user(&pool, "bob").await;
+
+    let own = send(
+        &state,
+        &alice,
+        alice_conversation,
+        "My appointment is on monday",
+    )
+    .await;
+    send(&state, &bob, bob_conversation, "Private appointment notes").await;
+
+    // Not listing any conversations searches all of the user's, and only theirs
+    assert_eq!(search(&state, &alice, &[]).await, [own]);
+    // Listing someone else's conversation doesn't let the user into it
+    assert!(search(&state, &alice, &[bob_conversation]).await.is_empty());
+    assert_eq!(
+        search(&state, &alice, &[alice_conversation, bob_conversation]).await,
+        [own]
+    );
+}
+
+#[tokio::test]
+async fn snippets_escape_the_message_and_mark_the_matches() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let (alice, conversation_id) = create_user(&pool, "alice").await;
+    send(
+        &state,
+        &alice,
+        conversation_id,
+        "<script>appointment</script> & \"more\"",
+    )
+    .await;
+
+    let (results, _) = run_search(
+        &state,
+        &alice,
+        r#"{"conversations":[],"query":"appointment"}"#,
+    )
+    .await;
+    assert_eq!(
+        results[0].snippet.as_deref(),
+        Some("&lt;script&gt;<mark>appointment</mark>&lt;/script&gt; &amp; &quot;more&quot;")
+    );
+}
+
+#[tokio::test]
+async fn relevance_pages_start_from_the_best_match() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let (alice, conversation_id) = create_user(&pool, "alice").await;
+    let worst = send(
+        &state,
+        &alice,
+        conversation_id,
+        "The appointment is at the clinic on the far side of town sometime next week",
+    )
+    .await;
+    let best = send(&state, &alice, conversation_id, "Appointment appointment").await;
+    let middle = send(&state, &alice, conversation_id, "Appointment tomorrow").await;
+
+    // One result per page so every page continues from the cursor of the last one
+    let mut found = Vec::new();
+    let mut cursor = None;
+    loop {
+        let request = match &cursor {
+            Some(cursor) => format!(
+                r#"{{"conversations":[],"query":"appointment","order":"Relevance","limit":1,"cursor":"{cursor}"}}"#
+            ),
+            None => r#"{"conversations":[],"query":"appointment","order":"Relevance","limit":1}"#
+                .to_owned(),
+        };
+        let (results, next_cursor) = run_search(&state, &alice, &request).await;
+        found.extend(results.into_iter().map(|result| result.message.id));
+        cursor = next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(found, [best, middle, worst]);
+}
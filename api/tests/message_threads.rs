@@ -0,0 +1,51 @@
+mod common;
+
+use ai_health_assistant_api::{chat::get_thread_root, error::AppError, users::UserToken};
+use axum::http::StatusCode;
+use common::{create_conversation, create_user, test_db};
+use sqlx::SqlitePool;
+
+async fn send_message(
+    pool: &SqlitePool,
+    user: &UserToken,
+    conversation_id: i64,
+    reply_to: Option<i64>,
+    thread_root_id: Option<i64>,
+) -> i64 {
+    sqlx::query_scalar::<_, i64>(
+        "INSERT INTO messages (user_id, conversation_id, message, reply_to, thread_root_id)
+        VALUES (?, ?, 'hello', ?, ?) RETURNING id",
+    )
+    .bind(user.id)
+    .bind(conversation_id)
+    .bind(reply_to)
+    .bind(thread_root_id)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn replies_resolve_to_the_root_of_their_thread() {
+    let (pool, _dir) = test_db().await;
+    let alice = create_user(&pool, "alice").await;
+    let eve = create_user(&pool, "eve").await;
+    let conversation_id = create_conversation(&pool, &[&alice]).await;
+
+    let root = send_message(&pool, &alice, conversation_id, None, None).await;
+    let reply = send_message(&pool, &alice, conversation_id, Some(root), Some(root)).await;
+    let nested = send_message(&pool, &alice, conversation_id, Some(reply), Some(root)).await;
+
+    for message_id in [root, reply, nested] {
+        assert_eq!(
+            get_thread_root(&pool, alice.id, message_id).await.ok(),
+            Some(root)
+        );
+    }
+
+    // Users outside the conversation can't find out the message exists
+    match get_thread_root(&pool, eve.id, reply).await {
+        Err(AppError::UserError((status, _))) => assert_eq!(status, StatusCode::NOT_FOUND),
+        _ => panic!("Expected the message to not be found"),
+    }
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+#[tokio::test]
+async fn muted_conversations_only_reach_connections_viewing_them() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let eve = create_user(&pool, "eve").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut laptop = WsClient::connect(&state, &alice).await;
+    let mut phone = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+    let mut eve_client = WsClient::connect(&state, &eve).await;
+    let mute = |muted: bool| {
+        format!(
+            r#"{{"type": "MuteConversation", "conversationId": {conversation_id}, "muted": {muted}}}"#
+        )
+    };
+    let send_message = |message: &str| {
+        format!(
+            r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "{message}"}}"#
+        )
+    };
+
+    // Every device of the user is told, not only the one that muted it
+    laptop.send(&mute(true)).await;
+    let event = phone.event("MuteEvent").await;
+    assert_eq!(event["muted"].as_bool(), Some(true));
+    bob_client.send(&send_message("Quiet")).await;
+    bob_client.event("Message").await;
+
+    laptop.send(&mute(false)).await;
+    let event = phone.event("MuteEvent").await;
+    assert_eq!(event["muted"].as_bool(), Some(false));
+    bob_client.send(&send_message("Loud")).await;
+    let event = phone.event("Message").await;
+    assert_eq!(event["message"].as_str(), Some("Loud"));
+
+    // Only members can mute a conversation
+    eve_client.send(&mute(true)).await;
+    eve_client.event("Error").await;
+}
+
+#[tokio::test]
+async fn muted_messages_are_left_out_of_the_replay() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+    let mute = |muted: bool| {
+        format!(
+            r#"{{"type": "MuteConversation", "conversationId": {conversation_id}, "muted": {muted}}}"#
+        )
+    };
+    let send_message = |message: &str| {
+        format!(
+            r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "{message}"}}"#
+        )
+    };
+
+    bob_client.send(&send_message("Before")).await;
+    bob_client.event("Message").await;
+    let before_seq = alice_client.event("Message").await["seq"].as_u64().unwrap();
+
+    // The quiet message never reaches a connection, so it doesn't get a sequence number
+    // that would look like a missed event
+    alice_client.send(&mute(true)).await;
+    alice_client.event("MuteEvent").await;
+    bob_client.send(&send_message("Quiet")).await;
+    bob_client.event("Message").await;
+    alice_client.send(&mute(false)).await;
+    alice_client.event("MuteEvent").await;
+    bob_client.send(&send_message("Loud")).await;
+    let event = alice_client.event("Message").await;
+    assert_eq!(event["message"].as_str(), Some("Loud"));
+
+    alice_client
+        .send(&format!(
+            r#"{{"type": "ReplayEvents", "since": {before_seq}}}"#
+        ))
+        .await;
+    let event = alice_client.event("Message").await;
+    assert_eq!(event["message"].as_str(), Some("Loud"));
+}
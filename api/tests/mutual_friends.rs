@@ -0,0 +1,84 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    friends::{get_mutual_friends, MutualFriendsQuery},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use sqlx::SqlitePool;
+
+async fn befriend(pool: &SqlitePool, a: &UserToken, b: &UserToken) {
+    sqlx::query("INSERT INTO friendships (user1_id, user2_id) VALUES (?, ?)")
+        .bind(a.id.min(b.id))
+        .bind(a.id.max(b.id))
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+/// The usernames of the mutual friends the user has with another user
+async fn mutual_friends(
+    state: &AppState,
+    user: &UserToken,
+    other: &UserToken,
+    limit: Option<u32>,
+) -> Vec<String> {
+    let response = get_mutual_friends(
+        State(state.clone()),
+        JwtAuth(user.clone()),
+        Path(other.id),
+        Query(MutualFriendsQuery { limit }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|friend| friend["username"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn mutual_friends_are_capped_by_the_configured_limit() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &["--max-mutual-friends", "2"]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    for username in ["carol", "dave", "erin"] {
+        let friend = create_user(&pool, username).await;
+        befriend(&pool, &alice, &friend).await;
+        befriend(&pool, &bob, &friend).await;
+    }
+    // Only a friend of one of them isn't mutual
+    let frank = create_user(&pool, "frank").await;
+    befriend(&pool, &alice, &frank).await;
+
+    assert_eq!(
+        mutual_friends(&state, &alice, &bob, Some(1)).await,
+        ["carol"]
+    );
+    assert_eq!(
+        mutual_friends(&state, &alice, &bob, None).await,
+        ["carol", "dave"]
+    );
+    assert_eq!(
+        mutual_friends(&state, &alice, &bob, Some(100)).await,
+        ["carol", "dave"]
+    );
+    let state = test_state(&pool, &[]);
+    assert_eq!(
+        mutual_friends(&state, &alice, &bob, None).await,
+        ["carol", "dave", "erin"]
+    );
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use ai_health_assistant_api::{
+    cli::Args,
+    error::AppJson,
+    state::AppState,
+    users::{create_user, CreateUser},
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use clap::Parser;
+use common::{test_db, PASSWORD};
+
+#[tokio::test]
+async fn new_users_are_greeted_by_the_assistant() {
+    let (pool, _dir) = test_db().await;
+    sqlx::query("INSERT INTO ai_models (name) VALUES ('llama3.2')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let state = AppState::new(
+        pool.clone(),
+        &Args::parse_from(["api", "--jwt-key", "test"]),
+    )
+    .unwrap();
+
+    let response = create_user(
+        State(state),
+        AppJson(CreateUser {
+            email: "alice@example.com".into(),
+            first_name: "Alice".into(),
+            last_name: None,
+            password: PASSWORD.into(),
+            username: "alice".into(),
+            image_id: None,
+        }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // AI is left off until the user turns it on, with the default model ready to use
+    let (ai_enabled, ai_model_id) = sqlx::query_as::<_, (bool, Option<i64>)>(
+        "SELECT ai_enabled, ai_model_id FROM user_settings
+        JOIN users ON users.id = user_settings.user_id WHERE username = 'alice'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(!ai_enabled);
+    assert_eq!(ai_model_id, Some(1));
+
+    // The assistant conversation starts with a greeting instead of being empty
+    let messages = sqlx::query_as::<_, (Option<i64>, Option<i64>)>(
+        "SELECT messages.user_id, messages.ai_model_id FROM messages
+        JOIN user_conversations ON user_conversations.conversation_id = messages.conversation_id
+        JOIN users ON users.id = user_conversations.user_id WHERE username = 'alice'",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(messages, [(None, Some(1))]);
+}
@@ -0,0 +1,322 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{AuthConfig, JwtAuth},
+    error::AppError,
+    error::AppJson,
+    oauth::{
+        existing_oauth_user, oauth_callback, oauth_exchange, oauth_start, OAuthExchange,
+        OAuthProvider, OAuthStart,
+    },
+    state::AppState,
+    users::{change_password, update_user, ChangePassword, UserToken, UsernameChanges},
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use common::{addr, create_user, test_db, test_state};
+use reqwest::Url;
+use sqlx::SqlitePool;
+
+/// Store a login code for the user as if they just came back from the provider
+async fn login_code(pool: &SqlitePool, user: &UserToken, code: &str, expires_at: &str) {
+    sqlx::query(
+        "INSERT INTO oauth_login_codes (code_hash, user_id, expires_at) VALUES (?, ?, datetime('now', ?))",
+    )
+    .bind(blake3::hash(code.as_bytes()).to_hex().to_string())
+    .bind(user.id)
+    .bind(expires_at)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn exchange(state: &AppState, code: &str) -> Response {
+    oauth_exchange(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(OAuthExchange { code: code.into() }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn login_codes_are_exchanged_once_for_a_token() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    login_code(&pool, &alice, "fresh", "+1 minute").await;
+    let response = exchange(&state, "fresh").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key(header::AUTHORIZATION));
+
+    // The code can't be replayed
+    let response = exchange(&state, "fresh").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    login_code(&pool, &alice, "stale", "-1 minute").await;
+    let response = exchange(&state, "stale").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = exchange(&state, "made up").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+/// Start a session for the user that began the given time ago
+async fn session(pool: &SqlitePool, user: &UserToken, started: &str) -> UserToken {
+    let sid = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO sessions (user_id, created_at, expires_at)
+        VALUES (?, datetime('now', ?), datetime('now', '+1 day')) RETURNING id",
+    )
+    .bind(user.id)
+    .bind(started)
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    UserToken {
+        sid: Some(sid),
+        ..user.clone()
+    }
+}
+
+async fn rename(state: &AppState, user: UserToken) -> StatusCode {
+    update_user(
+        State(SqlitePool::from_ref(state)),
+        State(AuthConfig::from_ref(state)),
+        State(FromRef::from_ref(state)),
+        State(UsernameChanges::from_ref(state)),
+        ConnectInfo(addr()),
+        JwtAuth(user),
+        AppJson(sonic_rs::from_str(r#"{"password": "", "firstName": "Renamed"}"#).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+#[tokio::test]
+async fn users_without_a_password_have_to_log_in_again_to_change_their_account() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    sqlx::query("UPDATE users SET password_hash = NULL WHERE id = ?")
+        .bind(alice.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // A token on its own isn't enough
+    assert_eq!(
+        rename(&state, alice.clone()).await,
+        StatusCode::UNAUTHORIZED
+    );
+    let old_session = session(&pool, &alice, "-1 hour").await;
+    assert_eq!(rename(&state, old_session).await, StatusCode::UNAUTHORIZED);
+
+    let new_session = session(&pool, &alice, "-1 minute").await;
+    assert_eq!(rename(&state, new_session).await, StatusCode::OK);
+}
+
+async fn set_password(state: &AppState, user: UserToken) -> StatusCode {
+    change_password(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user),
+        AppJson(ChangePassword {
+            current_password: None,
+            new_password: "a password of my own".into(),
+        }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+#[tokio::test]
+async fn users_without_a_password_have_to_log_in_again_to_set_one() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    sqlx::query("UPDATE users SET password_hash = NULL WHERE id = ?")
+        .bind(alice.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let old_session = session(&pool, &alice, "-1 hour").await;
+    assert_eq!(
+        set_password(&state, old_session).await,
+        StatusCode::UNAUTHORIZED
+    );
+    let new_session = session(&pool, &alice, "-1 minute").await;
+    assert_eq!(set_password(&state, new_session).await, StatusCode::OK);
+}
+
+/// A state with GitHub logins configured
+fn github_state(pool: &SqlitePool) -> AppState {
+    // Every test in this file sets the same values so it doesn't matter which runs first
+    std::env::set_var("GITHUB_CLIENT_ID", "client");
+    std::env::set_var("GITHUB_CLIENT_SECRET", "secret");
+    std::env::set_var("OAUTH_REDIRECT_URL", "http://localhost:3000");
+    test_state(pool, &[])
+}
+
+fn location(response: &Response) -> &str {
+    response.headers()[header::LOCATION].to_str().unwrap()
+}
+
+async fn callback(state: &AppState, query: &str, cookie: Option<&str>) -> Response {
+    let uri: Uri = format!("/api/oauth/github/callback?{query}")
+        .parse()
+        .unwrap();
+    let mut headers = HeaderMap::new();
+    if let Some(cookie) = cookie {
+        headers.insert(header::COOKIE, HeaderValue::from_str(cookie).unwrap());
+    }
+    oauth_callback(
+        State(state.clone()),
+        Path("github".into()),
+        headers,
+        Query::try_from_uri(&uri).unwrap(),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+async fn pending_logins(pool: &SqlitePool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM oauth_states")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn failed_logins_are_sent_back_to_the_client() {
+    let (pool, _dir) = test_db().await;
+    let state = github_state(&pool);
+
+    let response = oauth_start(
+        State(state.clone()),
+        Path("github".into()),
+        Query(OAuthStart { reactivate: true }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert!(response.status().is_redirection());
+    assert!(location(&response).starts_with("https://github.com/login/oauth/authorize?"));
+    let reactivate: bool = sqlx::query_scalar("SELECT reactivate FROM oauth_states")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(reactivate);
+
+    // A callback for a login this server didn't start never reaches the provider
+    let response = callback(&state, "code=abc&state=forged", Some("oauth_state=forged")).await;
+    assert!(response.status().is_redirection());
+    assert!(location(&response).starts_with("http://localhost:3000/login?error="));
+}
+
+#[tokio::test]
+async fn logins_only_finish_in_the_browser_that_started_them() {
+    let (pool, _dir) = test_db().await;
+    let state = github_state(&pool);
+
+    let response = oauth_start(
+        State(state.clone()),
+        Path("github".into()),
+        Query(OAuthStart::default()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    let nonce = Url::parse(location(&response))
+        .unwrap()
+        .query_pairs()
+        .find_map(|(name, value)| (name == "state").then(|| value.into_owned()))
+        .unwrap();
+    let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    assert!(cookie.starts_with(&format!("oauth_state={nonce};")));
+    for attribute in ["HttpOnly", "Secure", "SameSite=Lax"] {
+        assert!(cookie.contains(attribute), "{cookie}");
+    }
+
+    // Someone else's callback url is refused without using up their login
+    let query = format!("code=abc&state={nonce}");
+    for cookie in [None, Some("oauth_state=someone_else")] {
+        let response = callback(&state, &query, cookie).await;
+        assert!(location(&response).starts_with("http://localhost:3000/login?error="));
+        assert_eq!(pending_logins(&pool).await, 1);
+    }
+    // The provider reporting an error still clears the cookie
+    let response = callback(
+        &state,
+        &format!("error=access_denied&state={nonce}"),
+        Some(&format!("theme=dark; oauth_state={nonce}")),
+    )
+    .await;
+    assert!(location(&response).contains("access_denied"));
+    assert_eq!(pending_logins(&pool).await, 0);
+    let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    assert!(cookie.starts_with("oauth_state=; Max-Age=0;"));
+}
+
+#[tokio::test]
+async fn deleted_accounts_are_only_restored_when_asked() {
+    let (pool, _dir) = test_db().await;
+    let alice = create_user(&pool, "alice").await;
+    sqlx::query("INSERT INTO oauth_accounts (provider, provider_user_id, user_id) VALUES ('github', '42', ?)")
+        .bind(alice.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO deleted_user_profiles (user_id, username, first_name) VALUES (?, 'alice', 'Alice')",
+    )
+    .bind(alice.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "UPDATE users SET username = 'deleted_user_1', deleted_at = CURRENT_TIMESTAMP WHERE id = ?",
+    )
+    .bind(alice.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    match existing_oauth_user(&pool, OAuthProvider::Github, "42", false).await {
+        Err(AppError::UserError((status, _))) => assert_eq!(status, StatusCode::FORBIDDEN),
+        _ => panic!("Expected logging in to be refused"),
+    }
+    let username: String = sqlx::query_scalar("SELECT username FROM users WHERE id = ?")
+        .bind(alice.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(username, "deleted_user_1");
+
+    assert_eq!(
+        existing_oauth_user(&pool, OAuthProvider::Github, "42", true)
+            .await
+            .ok(),
+        Some(Some(alice.id))
+    );
+    let username: String = sqlx::query_scalar("SELECT username FROM users WHERE id = ?")
+        .bind(alice.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(username, "alice");
+
+    // Accounts the provider account isn't linked to are left to be created
+    assert_eq!(
+        existing_oauth_user(&pool, OAuthProvider::Github, "7", false)
+            .await
+            .ok(),
+        Some(None)
+    );
+}
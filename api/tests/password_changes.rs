@@ -0,0 +1,87 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{token_is_current, AuthConfig, JwtAuth},
+    error::AppJson,
+    state::AppState,
+    users::{authenticate_user, change_password, ChangePassword, LoginData, UserToken},
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{addr, create_user_with_password, test_db, test_state, PASSWORD};
+use sqlx::SqlitePool;
+
+async fn log_in(state: &AppState, username: &str, password: &str) -> Response {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: Some(password.to_owned()),
+        recovery_code: None,
+        reactivate: false,
+    };
+    authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+/// The token a response authorized the user with
+fn token(state: &AppState, response: &Response) -> UserToken {
+    assert_eq!(response.status(), StatusCode::OK);
+    let authorization = response.headers()[AUTHORIZATION].to_str().unwrap();
+    AuthConfig::from_ref(state)
+        .decode(authorization.strip_prefix("Bearer ").unwrap())
+        .unwrap()
+}
+
+async fn is_current(pool: &SqlitePool, token: &UserToken) -> bool {
+    token_is_current(pool, token).await.unwrap()
+}
+
+#[tokio::test]
+async fn changing_the_password_logs_out_the_other_sessions() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    create_user_with_password(&pool, "alice").await;
+    let laptop = token(&state, &log_in(&state, "alice", PASSWORD).await);
+    let phone = token(&state, &log_in(&state, "alice", PASSWORD).await);
+    let change = |current_password: &str| ChangePassword {
+        current_password: Some(current_password.to_owned()),
+        new_password: "a brand new password".to_owned(),
+    };
+
+    let response = change_password(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(laptop.clone()),
+        AppJson(change("not the password")),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(is_current(&pool, &phone).await);
+
+    let response = change_password(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(laptop.clone()),
+        AppJson(change(PASSWORD)),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    // The session that changed the password carries on with a new token
+    let laptop = token(&state, &response);
+    assert!(is_current(&pool, &laptop).await);
+    assert!(!is_current(&pool, &phone).await);
+
+    let response = log_in(&state, "alice", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = log_in(&state, "alice", "a brand new password").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
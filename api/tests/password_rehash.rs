@@ -0,0 +1,123 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::PasswordHasher,
+    error::AppJson,
+    state::AppState,
+    users::{authenticate_user, LoginData},
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use common::{addr, create_user_with_password, test_db, test_state, PASSWORD};
+use sqlx::SqlitePool;
+
+/// Create a user whose password was hashed with the old defaults of `password_auth`
+async fn create_user(pool: &SqlitePool, username: &str) -> String {
+    create_user_with_password(pool, username).await;
+    stored_hash(pool, username).await
+}
+
+async fn stored_hash(pool: &SqlitePool, username: &str) -> String {
+    sqlx::query_scalar::<_, String>("SELECT password_hash FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+async fn log_in(state: &AppState, username: &str, password: &str) -> StatusCode {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: Some(password.to_owned()),
+        recovery_code: None,
+        reactivate: false,
+    };
+    match authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(login),
+    )
+    .await
+    {
+        Ok(response) => response.status(),
+        Err(e) => e.into_response().status(),
+    }
+}
+
+#[test]
+fn default_settings_match_old_hashes() {
+    let old_hash = password_auth::generate_hash(PASSWORD);
+    assert!(!PasswordHasher::default().needs_rehash(&old_hash));
+
+    let new_hash = PasswordHasher::default().hash(PASSWORD);
+    assert!(password_auth::verify_password(PASSWORD, &new_hash).is_ok());
+}
+
+#[test]
+fn weaker_hashes_need_rehashing() {
+    let old_hash = password_auth::generate_hash(PASSWORD);
+    let stronger = PasswordHasher::new("argon2id", 32 * 1024, 3, 1).unwrap();
+    assert!(stronger.needs_rehash(&old_hash));
+    assert!(!stronger.needs_rehash(&stronger.hash(PASSWORD)));
+
+    // Hashes with stronger parameters than configured are left alone
+    let weaker = PasswordHasher::new("argon2id", 8 * 1024, 1, 1).unwrap();
+    assert!(!weaker.needs_rehash(&old_hash));
+
+    // Changing the algorithm rehashes everything
+    let argon2i = PasswordHasher::new("argon2i", 32 * 1024, 3, 1).unwrap();
+    assert!(argon2i.needs_rehash(&stronger.hash(PASSWORD)));
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    assert!(PasswordHasher::new("bcrypt", 19 * 1024, 2, 1).is_err());
+    // Argon2 needs at least 8 KiB of memory per lane
+    assert!(PasswordHasher::new("argon2id", 8, 1, 2).is_err());
+}
+
+#[tokio::test]
+async fn login_upgrades_old_default_hashes() {
+    let (pool, _dir) = test_db().await;
+    let old_hash = create_user(&pool, "upgraded").await;
+    let state = test_state(
+        &pool,
+        &[
+            "--password-memory-kib",
+            "32768",
+            "--password-iterations",
+            "3",
+        ],
+    );
+
+    // A wrong password leaves the hash alone
+    assert_eq!(
+        log_in(&state, "upgraded", "not the password").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(stored_hash(&pool, "upgraded").await, old_hash);
+
+    assert!(log_in(&state, "upgraded", PASSWORD).await.is_success());
+    let new_hash = stored_hash(&pool, "upgraded").await;
+    assert_ne!(new_hash, old_hash);
+    assert!(new_hash.contains("m=32768,t=3,p=1"));
+    assert!(password_auth::verify_password(PASSWORD, &new_hash).is_ok());
+
+    // Logging in again with the same settings keeps the upgraded hash
+    assert!(log_in(&state, "upgraded", PASSWORD).await.is_success());
+    assert_eq!(stored_hash(&pool, "upgraded").await, new_hash);
+}
+
+#[tokio::test]
+async fn login_keeps_hashes_with_default_settings() {
+    let (pool, _dir) = test_db().await;
+    let old_hash = create_user(&pool, "unchanged").await;
+    let state = test_state(&pool, &[]);
+
+    assert!(log_in(&state, "unchanged", PASSWORD).await.is_success());
+    assert_eq!(stored_hash(&pool, "unchanged").await, old_hash);
+}
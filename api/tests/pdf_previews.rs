@@ -0,0 +1,97 @@
+#![cfg(feature = "pdf-preview")]
+
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    state::AppState,
+    upload::{upload_file, FileUpload},
+    users::UserToken,
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose, Engine};
+use common::{addr, body_json, create_user, test_db, test_state, test_storage};
+use printpdf::{BuiltinFont, Mm, PdfDocument};
+use sonic_rs::JsonValueTrait;
+use sqlx::SqlitePool;
+
+/// A one page PDF with the given text on it
+fn pdf(text: &str) -> Vec<u8> {
+    let (doc, page, layer) = PdfDocument::new("Test", Mm(210.0), Mm(297.0), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+    doc.get_page(page)
+        .get_layer(layer)
+        .use_text(text, 12.0, Mm(10.0), Mm(280.0), &font);
+    doc.save_to_bytes().unwrap()
+}
+
+async fn upload(state: &AppState, user: &UserToken, data: &[u8]) -> i64 {
+    let upload: FileUpload = sonic_rs::from_str(&format!(
+        r#"{{"fileData":"data:application/pdf;base64,{}"}}"#,
+        general_purpose::STANDARD.encode(data)
+    ))
+    .unwrap();
+    let response = upload_file(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(upload),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["id"].as_i64().unwrap()
+}
+
+async fn text_preview(pool: &SqlitePool, file_id: i64) -> Option<String> {
+    sqlx::query_scalar("SELECT text_preview FROM files WHERE id = ?")
+        .bind(file_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn text_is_extracted_from_uploaded_pdfs() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &[]).with_storage(test_storage(&dir));
+    let user = create_user(&pool, "reader").await;
+
+    let file_id = upload(&state, &user, &pdf("Blood   pressure\n  log")).await;
+    // Whitespace left over from the layout is collapsed
+    assert_eq!(
+        text_preview(&pool, file_id).await.as_deref(),
+        Some("Blood pressure log")
+    );
+
+    // Files that only look like PDFs don't get a preview
+    let file_id = upload(&state, &user, b"%PDF-1.7 not really a pdf").await;
+    assert_eq!(text_preview(&pool, file_id).await, None);
+}
+
+#[tokio::test]
+async fn previews_are_added_to_files_uploaded_before_them() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &[]).with_storage(test_storage(&dir));
+    let user = create_user(&pool, "reader").await;
+
+    let data = pdf("Cholesterol results");
+    let file_id = upload(&state, &user, &data).await;
+    sqlx::query("UPDATE files SET text_preview = NULL WHERE id = ?")
+        .bind(file_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // Uploading the same file again fills in the missing preview
+    assert_eq!(upload(&state, &user, &data).await, file_id);
+    assert_eq!(
+        text_preview(&pool, file_id).await.as_deref(),
+        Some("Cholesterol results")
+    );
+}
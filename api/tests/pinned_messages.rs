@@ -0,0 +1,205 @@
+mod common;
+
+use ai_health_assistant_api::{auth::JwtAuth, chat::get_pinned_messages, users::UserToken};
+use axum::{
+    body::to_bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use sqlx::SqlitePool;
+
+async fn send_message(pool: &SqlitePool, user: &UserToken, conversation_id: i64) -> i64 {
+    sqlx::query_scalar::<_, i64>(
+        "INSERT INTO messages (user_id, conversation_id, message) VALUES (?, ?, 'hello') RETURNING id",
+    )
+    .bind(user.id)
+    .bind(conversation_id)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+async fn pin(pool: &SqlitePool, user: &UserToken, conversation_id: i64, message_id: i64, at: &str) {
+    sqlx::query(
+        "INSERT INTO pinned_messages (message_id, conversation_id, pinned_by, pinned_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(message_id)
+    .bind(conversation_id)
+    .bind(user.id)
+    .bind(at)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn pins(pool: &SqlitePool, user: &UserToken, conversation_id: i64) -> Response {
+    get_pinned_messages(
+        State(pool.clone()),
+        JwtAuth(user.clone()),
+        Path(conversation_id),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+/// The ids of the pinned messages in the response, in order
+async fn pinned_ids(response: Response) -> Vec<i64> {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: sonic_rs::Value = sonic_rs::from_slice(&body).unwrap();
+    body.as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["id"].as_i64().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn pinned_messages_are_listed_for_members() {
+    let (pool, _dir) = test_db().await;
+    let alice = create_user(&pool, "alice").await;
+    let eve = create_user(&pool, "eve").await;
+    let conversation_id =
+        sqlx::query_scalar::<_, i64>("INSERT INTO conversations DEFAULT VALUES RETURNING id")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    sqlx::query(
+        "INSERT INTO user_conversations (conversation_id, user_id, role) VALUES (?, ?, 'owner')",
+    )
+    .bind(conversation_id)
+    .bind(alice.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let first = send_message(&pool, &alice, conversation_id).await;
+    let second = send_message(&pool, &alice, conversation_id).await;
+    send_message(&pool, &alice, conversation_id).await;
+    pin(&pool, &alice, conversation_id, first, "2024-01-01 00:00:00").await;
+    pin(
+        &pool,
+        &alice,
+        conversation_id,
+        second,
+        "2024-01-02 00:00:00",
+    )
+    .await;
+
+    // Most recently pinned first, without the messages that aren't pinned
+    let response = pins(&pool, &alice, conversation_id).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(pinned_ids(response).await, [second, first]);
+
+    let response = pins(&pool, &eve, conversation_id).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Deleting a message unpins it
+    sqlx::query("DELETE FROM messages WHERE id = ?")
+        .bind(second)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let response = pins(&pool, &alice, conversation_id).await;
+    assert_eq!(pinned_ids(response).await, [first]);
+}
+
+/// Ask the server to pin or unpin a message
+async fn request_pin(client: &mut WsClient, message_id: i64, pinned: bool) {
+    client
+        .send(&format!(
+            r#"{{"type": "PinMessage", "messageId": {message_id}, "pinned": {pinned}}}"#
+        ))
+        .await;
+}
+
+/// The message and pinned state of the next pin event
+async fn next_pin(client: &mut WsClient) -> (i64, bool) {
+    let event = client.event("PinEvent").await;
+    (
+        event["messageId"].as_i64().unwrap(),
+        event["pinned"].as_bool().unwrap(),
+    )
+}
+
+async fn next_error(client: &mut WsClient) -> String {
+    client.event("Error").await["message"]
+        .as_str()
+        .unwrap()
+        .to_owned()
+}
+
+#[tokio::test]
+async fn pins_are_only_announced_when_they_change() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let conversation_id = create_conversation(&pool, &[&alice]).await;
+    let first = send_message(&pool, &alice, conversation_id).await;
+    let second = send_message(&pool, &alice, conversation_id).await;
+    let mut client = WsClient::connect(&state, &alice).await;
+
+    request_pin(&mut client, first, true).await;
+    assert_eq!(next_pin(&mut client).await, (first, true));
+    // Pinning it again changes nothing, so the next event is the unpin
+    request_pin(&mut client, first, true).await;
+    request_pin(&mut client, first, false).await;
+    assert_eq!(next_pin(&mut client).await, (first, false));
+    request_pin(&mut client, first, false).await;
+    request_pin(&mut client, second, true).await;
+    assert_eq!(next_pin(&mut client).await, (second, true));
+
+    let response = pins(&pool, &alice, conversation_id).await;
+    assert_eq!(pinned_ids(response).await, [second]);
+}
+
+#[tokio::test]
+async fn messages_past_the_limit_or_deleted_cannot_be_pinned() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let conversation_id = create_conversation(&pool, &[&alice]).await;
+    let mut messages = Vec::new();
+    for _ in 0..51 {
+        messages.push(send_message(&pool, &alice, conversation_id).await);
+    }
+    for &message_id in &messages[..50] {
+        pin(
+            &pool,
+            &alice,
+            conversation_id,
+            message_id,
+            "2024-01-01 00:00:00",
+        )
+        .await;
+    }
+    let deleted = send_message(&pool, &alice, conversation_id).await;
+    sqlx::query("DELETE FROM messages WHERE id = ?")
+        .bind(deleted)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let mut client = WsClient::connect(&state, &alice).await;
+
+    request_pin(&mut client, messages[50], true).await;
+    assert_eq!(
+        next_error(&mut client).await,
+        "Cannot pin more than 50 messages in a conversation"
+    );
+
+    // Pinning a message that is already pinned isn't an error even at the limit,
+    // so the next error is the one for the deleted message
+    request_pin(&mut client, messages[0], true).await;
+    request_pin(&mut client, deleted, true).await;
+    assert_eq!(next_error(&mut client).await, "Message not found");
+
+    // A deleted message is already unpinned
+    request_pin(&mut client, deleted, false).await;
+    request_pin(&mut client, messages[50], true).await;
+    assert_eq!(
+        next_error(&mut client).await,
+        "Cannot pin more than 50 messages in a conversation"
+    );
+}
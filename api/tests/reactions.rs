@@ -0,0 +1,67 @@
+mod common;
+
+use common::{create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+async fn react(client: &mut WsClient, message_id: i64, emoji: &str, add: bool) {
+    client
+        .send(&format!(
+            r#"{{"type": "React", "messageId": {message_id}, "emoji": "{emoji}", "add": {add}}}"#
+        ))
+        .await;
+}
+
+/// The emoji and whether it was added for the next reaction event
+async fn next_reaction(client: &mut WsClient) -> (String, bool) {
+    let event = client.event("ReactionEvent").await;
+    (
+        event["emoji"].as_str().unwrap().to_owned(),
+        event["added"].as_bool().unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn reactions_are_announced_once_per_change() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let conversation_id = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+    bob_client
+        .send(&format!(
+            r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "Hello"}}"#
+        ))
+        .await;
+    let message_id = bob_client.event("Message").await["id"].as_i64().unwrap();
+
+    react(&mut alice_client, message_id, "👍", true).await;
+    assert_eq!(
+        next_reaction(&mut bob_client).await,
+        ("👍".to_owned(), true)
+    );
+    // Adding it again changes nothing, so the next event is the removal
+    react(&mut alice_client, message_id, "👍", true).await;
+    react(&mut alice_client, message_id, "👍", false).await;
+    assert_eq!(
+        next_reaction(&mut bob_client).await,
+        ("👍".to_owned(), false)
+    );
+    // Emoji made of several code points still count as one
+    react(&mut alice_client, message_id, "👍🏽", true).await;
+    assert_eq!(
+        next_reaction(&mut bob_client).await,
+        ("👍🏽".to_owned(), true)
+    );
+
+    for emoji in ["ok", "👍👍", " "] {
+        react(&mut alice_client, message_id, emoji, true).await;
+        let error = alice_client.event("Error").await;
+        assert_eq!(
+            error["message"].as_str(),
+            Some("Reaction must be a single emoji"),
+            "{emoji}"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{AuthConfig, JwtAuth},
+    error::AppJson,
+    recovery::{generate_recovery_codes, get_recovery_codes, GenerateRecoveryCodes},
+    state::AppState,
+    users::{authenticate_user, change_password, ChangePassword, LoginData, UserToken},
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{addr, body_json, create_user_with_password, test_db, test_state, PASSWORD};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use sqlx::SqlitePool;
+
+async fn generate(pool: &SqlitePool, user: &UserToken, password: Option<&str>) -> Response {
+    let data = GenerateRecoveryCodes {
+        current_password: password.map(str::to_owned),
+    };
+    generate_recovery_codes(
+        State(pool.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(data),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+/// Generate a set of recovery codes and return them
+async fn generate_codes(pool: &SqlitePool, user: &UserToken) -> Vec<String> {
+    let response = generate(pool, user, Some(PASSWORD)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["codes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|code| code.as_str().unwrap().to_owned())
+        .collect()
+}
+
+async fn log_in(
+    state: &AppState,
+    username: &str,
+    password: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Response {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: password.map(str::to_owned),
+        recovery_code: recovery_code.map(str::to_owned),
+        reactivate: false,
+    };
+    authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+/// The token a successful login responded with
+fn session_token(state: &AppState, response: &Response) -> UserToken {
+    assert_eq!(response.status(), StatusCode::OK);
+    let authorization = response.headers()[AUTHORIZATION].to_str().unwrap();
+    AuthConfig::from_ref(state)
+        .decode(authorization.strip_prefix("Bearer ").unwrap())
+        .unwrap()
+}
+
+/// Set a new password without giving the current one
+async fn reset_password(state: &AppState, user: &UserToken, new_password: &str) -> StatusCode {
+    let data = ChangePassword {
+        current_password: None,
+        new_password: new_password.to_owned(),
+    };
+    change_password(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(data),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+async fn remaining(pool: &SqlitePool, user: &UserToken) -> i64 {
+    let response = get_recovery_codes(State(pool.clone()), JwtAuth(user.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await["remaining"].as_i64().unwrap()
+}
+
+#[tokio::test]
+async fn recovery_codes_can_only_be_used_once() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user_with_password(&pool, "forgetful").await;
+    assert_eq!(remaining(&pool, &user).await, 0);
+
+    let codes = generate_codes(&pool, &user).await;
+    assert_eq!(codes.len(), 10);
+    assert_eq!(remaining(&pool, &user).await, 10);
+
+    // Codes can be typed without the dash and in any case
+    let typed = codes[0].replace('-', "").to_uppercase();
+    let response = log_in(&state, "forgetful", None, Some(&typed)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(response).await["passwordResetRequired"].as_bool(),
+        Some(true)
+    );
+    assert_eq!(remaining(&pool, &user).await, 9);
+
+    let response = log_in(&state, "forgetful", None, Some(&codes[0])).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Logging in with the password doesn't ask for a new one
+    let response = log_in(&state, "forgetful", Some(PASSWORD), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_json(response)
+        .await
+        .get("passwordResetRequired")
+        .is_none());
+}
+
+#[tokio::test]
+async fn regenerating_codes_invalidates_the_old_ones() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user_with_password(&pool, "regenerator").await;
+
+    // Generating codes needs the password since they can be used in its place
+    let response = generate(&pool, &user, None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = generate(&pool, &user, Some("not the password")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let old_codes = generate_codes(&pool, &user).await;
+    let new_codes = generate_codes(&pool, &user).await;
+    assert_eq!(remaining(&pool, &user).await, 10);
+
+    let response = log_in(&state, "regenerator", None, Some(&old_codes[0])).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = log_in(&state, "regenerator", None, Some(&new_codes[0])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logins_need_exactly_one_credential() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user_with_password(&pool, "ambiguous").await;
+    let codes = generate_codes(&pool, &user).await;
+
+    let response = log_in(&state, "ambiguous", Some(PASSWORD), Some(&codes[0])).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = log_in(&state, "ambiguous", None, None).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(remaining(&pool, &user).await, 10);
+}
+
+#[tokio::test]
+async fn recovery_code_sessions_set_a_new_password_without_the_old_one() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user_with_password(&pool, "locked_out").await;
+    let codes = generate_codes(&pool, &user).await;
+
+    // Sessions started with the password still need it
+    let response = log_in(&state, "locked_out", Some(PASSWORD), None).await;
+    let password_session = session_token(&state, &response);
+    assert_eq!(
+        reset_password(&state, &password_session, "a brand new password").await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let response = log_in(&state, "locked_out", None, Some(&codes[0])).await;
+    let recovery_session = session_token(&state, &response);
+    assert_eq!(
+        reset_password(&state, &recovery_session, "a brand new password").await,
+        StatusCode::OK
+    );
+    let response = log_in(&state, "locked_out", Some("a brand new password"), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Once the password is reset the session needs the new one to change it again
+    let status = reset_password(&state, &recovery_session, "yet another password").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // The reset has to happen soon after logging in
+    let response = log_in(&state, "locked_out", None, Some(&codes[1])).await;
+    let late_session = session_token(&state, &response);
+    sqlx::query("UPDATE sessions SET created_at = datetime('now', '-1 hour') WHERE id = ?")
+        .bind(late_session.sid)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        reset_password(&state, &late_session, "yet another password").await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn users_without_a_password_have_to_log_in_again_to_generate_codes() {
+    let (pool, _dir) = test_db().await;
+    let user = create_user_with_password(&pool, "oauth_only").await;
+    sqlx::query("UPDATE users SET password_hash = NULL WHERE id = ?")
+        .bind(user.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    for (started, status) in [
+        ("-1 hour", StatusCode::UNAUTHORIZED),
+        ("-1 minute", StatusCode::CREATED),
+    ] {
+        let sid = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO sessions (user_id, created_at, expires_at)
+            VALUES (?, datetime('now', ?), datetime('now', '+1 day')) RETURNING id",
+        )
+        .bind(user.id)
+        .bind(started)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let session = UserToken {
+            sid: Some(sid),
+            ..user.clone()
+        };
+        assert_eq!(generate(&pool, &session, None).await.status(), status);
+    }
+}
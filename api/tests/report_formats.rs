@@ -0,0 +1,84 @@
+mod common;
+
+use ai_health_assistant_api::auth::JwtAuth;
+use ai_health_assistant_api::report::{generate_report, ReportFormat, ReportRange};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{create_user, test_db, test_state};
+
+fn accept(value: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+    headers
+}
+
+#[test]
+fn format_is_picked_from_the_accept_header() {
+    assert_eq!(
+        ReportFormat::from_headers(&HeaderMap::new()),
+        Some(ReportFormat::Pdf)
+    );
+    assert_eq!(
+        ReportFormat::from_headers(&accept("*/*")),
+        Some(ReportFormat::Pdf)
+    );
+    assert_eq!(
+        ReportFormat::from_headers(&accept("application/json")),
+        Some(ReportFormat::Json)
+    );
+    assert_eq!(
+        ReportFormat::from_headers(&accept("text/csv")),
+        Some(ReportFormat::Csv)
+    );
+    // The range with the highest quality wins, whatever order they are sent in
+    assert_eq!(
+        ReportFormat::from_headers(&accept("application/pdf;q=0.5, text/csv;q=0.9")),
+        Some(ReportFormat::Csv)
+    );
+    // Types the client refuses are skipped
+    assert_eq!(
+        ReportFormat::from_headers(&accept("application/json;q=0, application/pdf")),
+        Some(ReportFormat::Pdf)
+    );
+    assert_eq!(ReportFormat::from_headers(&accept("image/png")), None);
+}
+
+async fn report(headers: HeaderMap) -> Response {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "negotiator").await;
+    generate_report(
+        State(state),
+        JwtAuth(user),
+        Query(ReportRange::default()),
+        headers,
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn report_is_sent_in_the_accepted_format() {
+    for (accepted, content_type) in [
+        ("application/json", "application/json"),
+        ("text/csv", "text/csv"),
+        ("application/pdf", "application/pdf"),
+    ] {
+        let response = report(accept(accepted)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let sent = response.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        assert!(
+            sent.starts_with(content_type),
+            "{accepted} was sent as {sent}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn unsupported_formats_are_not_acceptable() {
+    let response = report(accept("image/png, text/html")).await;
+    assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use ai_health_assistant_api::{
+    chat::{invite_user, request_conversations, Conversation, RequestConversation, SocketResponse},
+    users::UserToken,
+};
+use common::{create_user, test_db};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc;
+
+/// Collect every conversation sent in response to the request
+async fn conversations(
+    pool: &SqlitePool,
+    request: &RequestConversation,
+    user: &UserToken,
+) -> Vec<Conversation> {
+    let (tx, mut rx) = mpsc::channel(64);
+    if request_conversations(pool, request, &tx, user)
+        .await
+        .is_err()
+    {
+        panic!("requesting conversations should succeed");
+    }
+    drop(tx);
+    let mut conversations = Vec::new();
+    while let Some(response) = rx.recv().await {
+        match response {
+            SocketResponse::Conversation(conversation) => conversations.push(conversation),
+            _ => panic!("only conversations should be sent"),
+        }
+    }
+    conversations
+}
+
+#[tokio::test]
+async fn invited_conversation_without_messages_is_listed() {
+    let (pool, _dir) = test_db().await;
+    let inviter = create_user(&pool, "inviter").await;
+    let invitee = create_user(&pool, "invitee").await;
+
+    // A conversation with a message in it from before the invite
+    let active_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO conversations (created_at, last_message_at)
+        VALUES (datetime('now', '-2 days'), datetime('now', '-1 day')) RETURNING id",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO user_conversations (conversation_id, user_id) VALUES (?, ?)")
+        .bind(active_id)
+        .bind(invitee.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let Ok(invited_id) = invite_user(&pool, None, &[invitee.id], &inviter).await else {
+        panic!("inviting a user should succeed");
+    };
+
+    let listed = conversations(&pool, &RequestConversation::default(), &invitee).await;
+    let ids = listed.iter().map(|c| c.id).collect::<Vec<_>>();
+    // The new group was created after the last message of the other conversation
+    assert_eq!(ids, [invited_id, active_id]);
+    assert_eq!(listed[0].last_message_at, None);
+
+    // The inviter sees it too
+    let listed = conversations(&pool, &RequestConversation::default(), &inviter).await;
+    assert_eq!(
+        listed.iter().map(|c| c.id).collect::<Vec<_>>(),
+        [invited_id]
+    );
+}
+
+#[tokio::test]
+async fn conversations_are_paginated_by_activity() {
+    let (pool, _dir) = test_db().await;
+    let inviter = create_user(&pool, "inviter").await;
+    let invitee = create_user(&pool, "invitee").await;
+
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+        let Ok(id) = invite_user(&pool, None, &[invitee.id], &inviter).await else {
+            panic!("inviting a user should succeed");
+        };
+        ids.push(id);
+    }
+    // Give each conversation a distinct creation time, the oldest first
+    for (i, id) in ids.iter().enumerate() {
+        sqlx::query(
+            "UPDATE conversations SET created_at = datetime('now', ? || ' hours') WHERE id = ?",
+        )
+        .bind(i as i64 - 3)
+        .bind(id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
+    let first_page = conversations(
+        &pool,
+        &RequestConversation {
+            last_message_at: None,
+            message_num: Some(2),
+        },
+        &invitee,
+    )
+    .await;
+    assert_eq!(
+        first_page.iter().map(|c| c.id).collect::<Vec<_>>(),
+        [ids[2], ids[1]]
+    );
+
+    let second_page = conversations(
+        &pool,
+        &RequestConversation {
+            last_message_at: Some(first_page[1].created_at),
+            message_num: Some(2),
+        },
+        &invitee,
+    )
+    .await;
+    assert_eq!(
+        second_page.iter().map(|c| c.id).collect::<Vec<_>>(),
+        [ids[0]]
+    );
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    chat::{create_conversation_rest, SendMessage},
+    error::AppJson,
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{body_json, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+async fn create(state: &AppState, user: &UserToken, message: &str) -> Response {
+    let message: SendMessage = sonic_rs::from_str(message).unwrap();
+    create_conversation_rest(
+        State(state.clone()),
+        JwtAuth(user.clone()),
+        AppJson(message),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn first_message_is_broadcast_to_the_users_connections() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let mut socket = WsClient::connect(&state, &alice).await;
+
+    let response = create(&state, &alice, r#"{"message": "Hello there"}"#).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let conversation_id = body_json(response).await["id"].as_i64().unwrap();
+
+    let event = socket.event("Message").await;
+    assert_eq!(event["conversationId"].as_i64(), Some(conversation_id));
+    assert_eq!(event["message"].as_str(), Some("Hello there"));
+}
+
+#[tokio::test]
+async fn unknown_model_is_rejected_before_the_conversation_is_created() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    let response = create(&state, &alice, r#"{"message": "Hello", "aiModelId": 9999}"#).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let conversations = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM conversations")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(conversations, 0);
+}
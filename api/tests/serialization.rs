@@ -0,0 +1,109 @@
+use ai_health_assistant_api::{
+    chat::{Conversation, ConversationUser, OnlineStatus},
+    users::PublicUser,
+    utils::Requested,
+};
+use chrono::NaiveDateTime;
+use serde_json::Value;
+
+fn timestamp() -> NaiveDateTime {
+    NaiveDateTime::parse_from_str("2024-01-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+}
+
+fn conversation(users: Requested<Box<[ConversationUser]>>) -> Conversation {
+    Conversation {
+        id: 1,
+        title: None,
+        created_at: timestamp(),
+        last_message_at: None,
+        persona_id: None,
+        muted: Requested::NotRequested,
+        admins_only_rename: false,
+        users,
+        unread_count: Requested::NotRequested,
+    }
+}
+
+#[test]
+fn users_not_requested_are_omitted() {
+    let json = serde_json::to_value(conversation(Requested::NotRequested)).unwrap();
+    assert!(json.get("users").is_none());
+
+    let conversation: Conversation = serde_json::from_value(json).unwrap();
+    assert!(conversation.users.is_not_requested());
+}
+
+#[test]
+fn empty_users_are_present() {
+    let json = serde_json::to_value(conversation(Requested::Loaded([].into()))).unwrap();
+    assert_eq!(json["users"], Value::Array(vec![]));
+
+    let conversation: Conversation = serde_json::from_value(json).unwrap();
+    match conversation.users {
+        Requested::Loaded(users) => assert!(users.is_empty()),
+        Requested::NotRequested => panic!("Empty users should not become not requested"),
+    }
+}
+
+#[test]
+fn conversation_user_null_is_not_omitted() {
+    let user = ConversationUser {
+        id: 1,
+        last_message_at: Requested::Loaded(Some(timestamp())),
+        last_read_at: Requested::Loaded(None),
+        online_status: Requested::Loaded(OnlineStatus::Offline),
+        role: Requested::NotRequested,
+    };
+    let json = serde_json::to_value(&user).unwrap();
+    assert_eq!(json["lastReadAt"], Value::Null);
+    assert!(json.get("lastMessageAt").is_some_and(|v| !v.is_null()));
+    assert_eq!(json["onlineStatus"], "Offline");
+
+    let user: ConversationUser = serde_json::from_value(json).unwrap();
+    assert_eq!(user.last_message_at, Requested::Loaded(Some(timestamp())));
+    assert_eq!(user.last_read_at, Requested::Loaded(None));
+    assert_eq!(user.online_status, Requested::Loaded(OnlineStatus::Offline));
+}
+
+#[test]
+fn conversation_user_not_requested_is_omitted() {
+    let user = ConversationUser {
+        id: 1,
+        ..Default::default()
+    };
+    let json = serde_json::to_value(&user).unwrap();
+    assert_eq!(json, serde_json::json!({ "id": 1 }));
+
+    let user: ConversationUser = serde_json::from_value(json).unwrap();
+    assert!(user.last_message_at.is_not_requested());
+    assert!(user.last_read_at.is_not_requested());
+    assert!(user.online_status.is_not_requested());
+}
+
+#[test]
+fn public_user_status_round_trip() {
+    let user = PublicUser {
+        id: 1,
+        username: "user".to_owned(),
+        first_name: "First".to_owned(),
+        last_name: None,
+        image_path: None,
+        bio: None,
+        pronouns: None,
+        location: None,
+        status: Requested::NotRequested,
+    };
+    let json = serde_json::to_value(&user).unwrap();
+    assert!(json.get("status").is_none());
+    let user: PublicUser = serde_json::from_value(json).unwrap();
+    assert!(user.status.is_not_requested());
+
+    let user = PublicUser {
+        status: Requested::Loaded(OnlineStatus::Online),
+        ..user
+    };
+    let json = serde_json::to_value(&user).unwrap();
+    assert_eq!(json["status"], "Online");
+    let user: PublicUser = serde_json::from_value(json).unwrap();
+    assert_eq!(user.status, Requested::Loaded(OnlineStatus::Online));
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{token_is_current, AuthConfig, JwtAuth},
+    error::AppJson,
+    state::AppState,
+    users::{authenticate_user, get_sessions, revoke_session, LoginData, UserToken},
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, Path, State},
+    http::{
+        header::{AUTHORIZATION, USER_AGENT},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::IntoResponse,
+};
+use common::{addr, body_json, create_user_with_password, test_db, test_state, PASSWORD};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use sqlx::SqlitePool;
+
+/// Log in with `PASSWORD` on a device and return the session's token
+async fn session(state: &AppState, username: &str, device: &str) -> UserToken {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: Some(PASSWORD.to_owned()),
+        recovery_code: None,
+        reactivate: false,
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_str(device).unwrap());
+    let response = authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        headers,
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let authorization = response.headers()[AUTHORIZATION].to_str().unwrap();
+    AuthConfig::from_ref(state)
+        .decode(authorization.strip_prefix("Bearer ").unwrap())
+        .unwrap()
+}
+
+async fn is_current(pool: &SqlitePool, token: &UserToken) -> bool {
+    token_is_current(pool, token).await.unwrap()
+}
+
+async fn revoke(state: &AppState, user: &UserToken, session_id: i64) -> StatusCode {
+    revoke_session(
+        State(state.clone()),
+        JwtAuth(user.clone()),
+        Path(session_id),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+#[tokio::test]
+async fn sessions_are_listed_and_revoked_one_at_a_time() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    create_user_with_password(&pool, "alice").await;
+    let bob = create_user_with_password(&pool, "bob").await;
+    let laptop = session(&state, "alice", "Laptop").await;
+    let phone = session(&state, "alice", "Phone").await;
+
+    let response = get_sessions(State(pool.clone()), JwtAuth(laptop.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let sessions = body_json(response).await;
+    let mut sessions: Vec<_> = sessions
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|session| {
+            (
+                session["device"].as_str().unwrap().to_owned(),
+                session["current"].as_bool().unwrap(),
+            )
+        })
+        .collect();
+    sessions.sort();
+    assert_eq!(
+        sessions,
+        [("Laptop".to_owned(), true), ("Phone".to_owned(), false)]
+    );
+
+    // Other users' sessions can't be revoked
+    assert_eq!(
+        revoke(&state, &bob, phone.sid.unwrap()).await,
+        StatusCode::NOT_FOUND
+    );
+    assert!(is_current(&pool, &phone).await);
+
+    assert_eq!(
+        revoke(&state, &laptop, phone.sid.unwrap()).await,
+        StatusCode::OK
+    );
+    assert!(!is_current(&pool, &phone).await);
+    assert!(is_current(&pool, &laptop).await);
+    assert_eq!(
+        revoke(&state, &laptop, phone.sid.unwrap()).await,
+        StatusCode::NOT_FOUND
+    );
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    users::{get_settings, update_settings},
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{addr, body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+
+#[tokio::test]
+async fn missing_settings_are_created_with_the_defaults() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    for _ in 0..2 {
+        let response = get_settings(State(pool.clone()), JwtAuth(alice.clone()))
+            .await
+            .unwrap_or_else(IntoResponse::into_response);
+        assert_eq!(response.status(), StatusCode::OK);
+        let settings = body_json(response).await;
+        assert_eq!(settings["locale"].as_str(), Some("en-US"));
+        assert_eq!(settings["settingsVersion"].as_i64(), Some(1));
+    }
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_settings WHERE user_id = ?")
+        .bind(alice.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(rows, 1);
+
+    // Updating the created settings starts the next version
+    let response = update_settings(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(alice.clone()),
+        AppJson(sonic_rs::from_str(r#"{"theme": "light"}"#).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(response).await["settingsVersion"].as_i64(),
+        Some(2)
+    );
+}
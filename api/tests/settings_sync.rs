@@ -0,0 +1,34 @@
+mod common;
+
+use ai_health_assistant_api::{auth::JwtAuth, error::AppJson, users::update_settings};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{addr, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+#[tokio::test]
+async fn changes_are_sent_to_every_connection() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let mut laptop = WsClient::connect(&state, &alice).await;
+    let mut phone = WsClient::connect(&state, &alice).await;
+
+    let response = update_settings(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(alice.clone()),
+        AppJson(sonic_rs::from_str(r#"{"theme": "light"}"#).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    for client in [&mut laptop, &mut phone] {
+        let event = client.event("SettingsChanged").await;
+        assert_eq!(event["version"].as_i64(), Some(1));
+        assert_eq!(event["settings"]["theme"].as_str(), Some("light"));
+    }
+}
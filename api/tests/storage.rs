@@ -0,0 +1,46 @@
+use std::io::ErrorKind;
+
+use ai_health_assistant_api::storage::{LocalStorage, Storage};
+
+#[tokio::test]
+async fn local_storage_keeps_files_in_its_directory() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().join("uploads");
+    let storage = LocalStorage::new(&dir);
+
+    // The directory is created when it is checked at startup
+    storage.check().await.unwrap();
+    assert!(dir.is_dir());
+
+    assert!(!storage.exists("file.txt").await.unwrap());
+    let e = storage.get("file.txt").await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+
+    storage.put("file.txt", b"contents").await.unwrap();
+    assert!(storage.exists("file.txt").await.unwrap());
+    assert_eq!(storage.get("file.txt").await.unwrap(), &b"contents"[..]);
+    assert_eq!(storage.local_path("file.txt"), Some(dir.join("file.txt")));
+
+    storage.delete("file.txt").await.unwrap();
+    assert!(!storage.exists("file.txt").await.unwrap());
+    // Deleting a file that is already gone is not an error
+    storage.delete("file.txt").await.unwrap();
+}
+
+#[tokio::test]
+async fn storing_the_same_file_at_once_leaves_one_complete_copy() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().join("uploads");
+    let storage = LocalStorage::new(&dir);
+    let data = vec![7u8; 1 << 20];
+
+    let puts = (0..8).map(|_| storage.put("file.bin", &data));
+    for result in futures::future::join_all(puts).await {
+        result.unwrap();
+    }
+
+    assert_eq!(storage.get("file.bin").await.unwrap(), &data[..]);
+    // The temporary files were all moved into place
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(files.len(), 1);
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::sync::Arc;
+
+use ai_health_assistant_api::storage::Storage;
+use axum::extract::FromRef;
+use common::{test_db, test_state};
+
+#[tokio::test]
+async fn uploads_go_to_disk_without_a_bucket() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    let storage = Arc::<dyn Storage>::from_ref(&state);
+    assert!(storage.local_path("some-file").is_some());
+}
+
+#[cfg(not(feature = "s3"))]
+#[tokio::test]
+async fn buckets_need_the_s3_feature() {
+    use ai_health_assistant_api::{cli::Args, state::AppState};
+    use clap::Parser;
+
+    let (pool, _dir) = test_db().await;
+    let args = Args::parse_from([
+        "api",
+        "--jwt-key",
+        "test",
+        "--no-assistant-conversation",
+        "--s3-bucket",
+        "uploads",
+    ]);
+
+    let error = AppState::new(pool, &args).err().unwrap();
+    assert!(error.to_string().contains("s3 feature"), "{error}");
+}
+
+/// A bucket at an S3 compatible service running in the test
+#[cfg(feature = "s3")]
+async fn test_bucket(pool: &sqlx::SqlitePool) -> Arc<dyn Storage> {
+    use axum::{http::Method, http::StatusCode, Router};
+    use tokio::net::TcpListener;
+
+    // Every object is missing and the credentials are only good enough to look
+    let app = Router::new().fallback(|method: Method| async move {
+        match method {
+            Method::GET | Method::HEAD => StatusCode::NOT_FOUND,
+            Method::PUT => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    std::env::set_var("AWS_ACCESS_KEY_ID", "test");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "test");
+    let state = test_state(
+        pool,
+        &["--s3-bucket", "uploads", "--s3-endpoint", &endpoint],
+    );
+    Arc::<dyn Storage>::from_ref(&state)
+}
+
+#[cfg(feature = "s3")]
+#[tokio::test]
+async fn bucket_errors_are_reported_like_disk_errors() {
+    use std::io::ErrorKind;
+
+    let (pool, _dir) = test_db().await;
+    let storage = test_bucket(&pool).await;
+
+    // Files in a bucket are never served from disk
+    assert!(storage.local_path("some-file").is_none());
+    assert_eq!(
+        storage.get("some-file").await.unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert!(!storage.exists("some-file").await.unwrap());
+    // Both kinds of bad credentials look like a directory the server can't write to
+    assert_eq!(
+        storage.put("some-file", b"data").await.unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        storage.delete("some-file").await.unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::{create_conversation, create_user, test_db};
+use sqlx::SqlitePool;
+
+/// Whether the message has a different `modified_at` than `created_at`, which clients show as edited
+async fn edited(pool: &SqlitePool, message_id: i64) -> bool {
+    sqlx::query_scalar::<_, bool>("SELECT modified_at != created_at FROM messages WHERE id = ?")
+        .bind(message_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn streamed_responses_are_not_marked_edited() {
+    let (pool, _dir) = test_db().await;
+    let alice = create_user(&pool, "alice").await;
+    let conversation_id = create_conversation(&pool, &[&alice]).await;
+    let model_id = sqlx::query_scalar::<_, i64>("SELECT id FROM ai_models LIMIT 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    // Saved the same way a response is while it streams in
+    let message_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO messages (conversation_id, message, ai_model_id, incomplete, created_at, modified_at)
+        VALUES (?, 'Hel', ?, TRUE, datetime('now', '-1 minute'), datetime('now', '-1 minute'))
+        RETURNING id",
+    )
+    .bind(conversation_id)
+    .bind(model_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    for (message, incomplete) in [("Hello", true), ("Hello there", false)] {
+        sqlx::query("UPDATE messages SET message = ?, incomplete = ? WHERE id = ?")
+            .bind(message)
+            .bind(incomplete)
+            .bind(message_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    assert!(!edited(&pool, message_id).await);
+
+    // Changing the finished message is still an edit
+    sqlx::query("UPDATE messages SET message = 'Hi' WHERE id = ?")
+        .bind(message_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(edited(&pool, message_id).await);
+}
@@ -0,0 +1,70 @@
+use ai_health_assistant_api::{
+    auth::{AuthConfig, JwtError},
+    users::UserToken,
+};
+
+fn config(issuer: Option<&str>, audience: Option<&str>) -> AuthConfig {
+    AuthConfig::new(
+        24,
+        false,
+        "HS256",
+        "test",
+        None,
+        issuer.map(str::to_owned),
+        audience.map(str::to_owned),
+    )
+    .unwrap()
+}
+
+fn token(config: &AuthConfig) -> String {
+    let claims = UserToken {
+        id: 1,
+        username: "claims".to_owned(),
+        exp: config.expiry(),
+        ver: 0,
+        sid: None,
+        iss: None,
+        aud: None,
+    };
+    config.encode(&claims).unwrap()
+}
+
+#[test]
+fn tokens_carry_the_configured_claims() {
+    let config = config(Some("health-api"), Some("health-app"));
+    let user = config.verify(&token(&config)).unwrap();
+    assert_eq!(user.iss.as_deref(), Some("health-api"));
+    assert_eq!(user.aud.as_deref(), Some("health-app"));
+}
+
+#[test]
+fn tokens_without_claims_are_accepted_when_none_are_configured() {
+    let config = config(None, None);
+    assert!(config.verify(&token(&config)).is_ok());
+}
+
+#[test]
+fn tokens_without_claims_are_rejected_when_they_are_configured() {
+    let old = token(&config(None, None));
+    assert!(matches!(
+        config(Some("health-api"), None).verify(&old),
+        Err(JwtError::InvalidToken)
+    ));
+    assert!(matches!(
+        config(None, Some("health-app")).verify(&old),
+        Err(JwtError::InvalidToken)
+    ));
+}
+
+#[test]
+fn tokens_for_another_issuer_or_audience_are_rejected() {
+    let verifier = config(Some("health-api"), Some("health-app"));
+    assert!(matches!(
+        verifier.verify(&token(&config(Some("other-api"), Some("health-app")))),
+        Err(JwtError::InvalidToken)
+    ));
+    assert!(matches!(
+        verifier.verify(&token(&config(Some("health-api"), Some("other-app")))),
+        Err(JwtError::InvalidToken)
+    ));
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{AuthConfig, JwtAuth},
+    state::AppState,
+    users::UserToken,
+};
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use jsonwebtoken::{EncodingKey, Header};
+use sonic_rs::JsonValueTrait;
+
+/// Authenticate a request with the authorization header,
+/// returning the status and body it is rejected with
+async fn reject(state: &AppState, authorization: &str) -> (StatusCode, sonic_rs::Value) {
+    let (mut parts, _) = Request::builder()
+        .uri("/api/login")
+        .header(AUTHORIZATION, authorization)
+        .body(())
+        .unwrap()
+        .into_parts();
+    let response = JwtAuth::<UserToken>::from_request_parts(&mut parts, state)
+        .await
+        .err()
+        .expect("The token was accepted")
+        .into_response();
+    (response.status(), body_json(response).await)
+}
+
+#[tokio::test]
+async fn expired_tokens_say_when_they_expired() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let expired_at = chrono::Utc::now().timestamp() - 60 * 60;
+    let token = AuthConfig::from_ref(&state)
+        .encode(&UserToken {
+            exp: expired_at,
+            ..alice
+        })
+        .unwrap();
+
+    let (status, error) = reject(&state, &format!("Bearer {token}")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["errorType"].as_str(), Some("Expired"));
+    assert_eq!(error["expiredAt"].as_i64(), Some(expired_at));
+}
+
+#[tokio::test]
+async fn malformed_tokens_are_told_apart() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    for authorization in ["Bearer not-a-jwt", "Token abc", "Bearer a.b.c"] {
+        let (status, error) = reject(&state, authorization).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{authorization}");
+        assert_eq!(
+            error["errorType"].as_str(),
+            Some("Malformed"),
+            "{authorization}"
+        );
+        assert!(error.get("expiredAt").is_none());
+    }
+}
+
+#[tokio::test]
+async fn tokens_signed_by_someone_else_have_the_wrong_signature() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    // Expired as well, which shouldn't be revealed for a token the server didn't sign
+    let token = jsonwebtoken::encode(
+        &Header::default(),
+        &UserToken {
+            exp: chrono::Utc::now().timestamp() - 60 * 60,
+            ..alice
+        },
+        &EncodingKey::from_secret(b"someone else's key"),
+    )
+    .unwrap();
+
+    let (status, error) = reject(&state, &format!("Bearer {token}")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["errorType"].as_str(), Some("WrongSignature"));
+    assert!(error.get("expiredAt").is_none());
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use ai_health_assistant_api::{
+    error::AppJson,
+    state::AppState,
+    users::{authenticate_user, create_user, CreateUser, LoginData},
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use common::{addr, test_db, test_state};
+
+async fn sign_up(state: &AppState, username: &str, password: &str) -> StatusCode {
+    let user = CreateUser {
+        email: format!("{username}@example.com"),
+        first_name: "Test".to_owned(),
+        last_name: None,
+        password: password.to_owned(),
+        username: username.to_owned(),
+        image_id: None,
+    };
+    match create_user(State(state.clone()), AppJson(user)).await {
+        Ok(response) => response.status(),
+        Err(e) => e.into_response().status(),
+    }
+}
+
+async fn log_in(state: &AppState, username: &str, password: &str) -> StatusCode {
+    let login = LoginData {
+        username: username.to_owned(),
+        password: Some(password.to_owned()),
+        recovery_code: None,
+        reactivate: false,
+    };
+    match authenticate_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        HeaderMap::new(),
+        AppJson(login),
+    )
+    .await
+    {
+        Ok(response) => response.status(),
+        Err(e) => e.into_response().status(),
+    }
+}
+
+#[tokio::test]
+async fn non_ascii_passwords_round_trip() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    let passwords = [
+        ("ascii", "correct horse battery staple"),
+        ("emoji", "🔒🐴🔋📎 staple"),
+        ("cjk", "正しい馬の電池ホチキス"),
+        ("combining", "cafe\u{301} au lait"),
+    ];
+    for (username, password) in passwords {
+        assert!(sign_up(&state, username, password).await.is_success());
+        assert!(log_in(&state, username, password).await.is_success());
+        assert_eq!(
+            log_in(&state, username, "not the password").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
+
+#[tokio::test]
+async fn equivalent_passwords_verify() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    // The decomposed and precomposed forms of "é" are the same passphrase
+    assert!(sign_up(&state, "decomposed", "cafe\u{301} au lait")
+        .await
+        .is_success());
+    assert!(log_in(&state, "decomposed", "caf\u{e9} au lait")
+        .await
+        .is_success());
+
+    assert!(sign_up(&state, "precomposed", "caf\u{e9} au lait")
+        .await
+        .is_success());
+    assert!(log_in(&state, "precomposed", "cafe\u{301} au lait")
+        .await
+        .is_success());
+}
+
+#[tokio::test]
+async fn control_characters_are_rejected() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    assert_eq!(
+        sign_up(&state, "control", "pass\u{7}word").await,
+        StatusCode::BAD_REQUEST
+    );
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    forms::{get_forms, get_health_form, FormsQuery},
+    report::{generate_report, ReportRange},
+    users::UserToken,
+};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+use sqlx::SqlitePool;
+
+/// Create a user who shows health values in inches and pounds,
+/// with a 70 in tall form weighing 150 lb followed by one weighing 160 lb
+async fn imperial_user(pool: &SqlitePool) -> UserToken {
+    let user = create_user(pool, "imperial").await;
+    sqlx::query("INSERT INTO user_settings (user_id, unit_system) VALUES (?, 'imperial')")
+        .bind(user.id)
+        .execute(pool)
+        .await
+        .unwrap();
+    for (created_at, weight) in [
+        ("2026-02-01 08:00:00", 68.038_855_5),
+        ("2026-02-02 08:00:00", 72.574_779_2),
+    ] {
+        sqlx::query(
+            "INSERT INTO user_statistics (user_id, height, weight, created_at) VALUES (?, 177.8, ?, ?)",
+        )
+        .bind(user.id)
+        .bind(weight)
+        .bind(created_at)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    user
+}
+
+fn assert_close(value: &sonic_rs::Value, expected: f64) {
+    let value = value.as_f64().unwrap();
+    assert!((value - expected).abs() < 1e-6, "{value} != {expected}");
+}
+
+#[tokio::test]
+async fn forms_are_shown_in_the_users_units() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = imperial_user(&pool).await;
+
+    let response = get_health_form(State(state.clone()), JwtAuth(user.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let form = body_json(response).await;
+    assert_eq!(form["unitSystem"].as_str(), Some("imperial"));
+    assert_close(&form["height"], 70.0);
+    assert_close(&form["weight"], 160.0);
+    // BMI is calculated from the metric values and isn't converted
+    assert_eq!(form["bmi"].as_f64(), Some(23.0));
+
+    let query = FormsQuery {
+        limit: None,
+        offset: None,
+        from: "2026-01-01".parse().ok(),
+        to: None,
+    };
+    let response = get_forms(State(state), JwtAuth(user), Query(query))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let forms = body_json(response).await;
+    let weights: Vec<_> = forms
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|form| (form["weight"].as_f64().unwrap() * 1000.0).round() / 1000.0)
+        .collect();
+    assert_eq!(weights, [160.0, 150.0]);
+}
+
+#[tokio::test]
+async fn reports_are_in_the_users_units() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = imperial_user(&pool).await;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    let response = generate_report(
+        State(state),
+        JwtAuth(user),
+        Query(ReportRange::default()),
+        headers,
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let summary = body_json(response).await;
+    assert_eq!(summary["unitSystem"].as_str(), Some("imperial"));
+    assert_close(&summary["heightAvg"], 70.0);
+    assert_close(&summary["weightAvg"], 155.0);
+    assert_close(&summary["weightChange"], 10.0);
+}
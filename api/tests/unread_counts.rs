@@ -0,0 +1,63 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::{create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+/// Send a message over the websocket and wait until it is broadcast back
+async fn send_message(client: &mut WsClient, conversation_id: i64, message: &str) {
+    client
+        .send(&format!(
+            r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "{message}"}}"#
+        ))
+        .await;
+    client.event("Message").await;
+}
+
+/// The unread count of each of the user's conversations
+async fn unread_counts(client: &mut WsClient, conversations: usize) -> HashMap<i64, i64> {
+    client.send(r#"{"type": "RequestConversations"}"#).await;
+    let mut unread = HashMap::new();
+    for _ in 0..conversations {
+        let conversation = client.event("Conversation").await;
+        unread.insert(
+            conversation["id"].as_i64().unwrap(),
+            conversation["unreadCount"].as_i64().unwrap(),
+        );
+    }
+    unread
+}
+
+#[tokio::test]
+async fn messages_from_others_are_unread_until_the_conversation_is_read() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let first = create_conversation(&pool, &[&alice, &bob]).await;
+    let second = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+
+    for (conversation_id, message) in [(first, "One"), (first, "Two"), (second, "Three")] {
+        send_message(&mut bob_client, conversation_id, message).await;
+    }
+    // The user's own messages are never unread
+    send_message(&mut alice_client, first, "Mine").await;
+    assert_eq!(
+        unread_counts(&mut alice_client, 2).await,
+        HashMap::from([(first, 2), (second, 1)])
+    );
+
+    alice_client
+        .send(&format!(
+            r#"{{"type": "ReadMessage", "conversationId": {first}}}"#
+        ))
+        .await;
+    alice_client.event("ReadEvent").await;
+    assert_eq!(
+        unread_counts(&mut alice_client, 2).await,
+        HashMap::from([(first, 0), (second, 1)])
+    );
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    friends::{send_friend_request, FriendRequestAction},
+    users::{get_unread_summary, UserToken},
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use common::{body_json, create_conversation, create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+use sqlx::SqlitePool;
+
+/// Send a message over the websocket and wait until it is broadcast back
+async fn send_message(client: &mut WsClient, conversation_id: i64, message: &str) {
+    client
+        .send(&format!(
+            r#"{{"type": "SendMessage", "conversationId": {conversation_id}, "message": "{message}"}}"#
+        ))
+        .await;
+    client.event("Message").await;
+}
+
+/// Unread messages, unread conversations and pending friend requests
+async fn summary(pool: &SqlitePool, user: &UserToken) -> (i64, i64, i64) {
+    let response = get_unread_summary(State(pool.clone()), JwtAuth(user.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let summary = body_json(response).await;
+    (
+        summary["unreadMessages"].as_i64().unwrap(),
+        summary["unreadConversations"].as_i64().unwrap(),
+        summary["pendingFriendRequests"].as_i64().unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn the_summary_counts_everything_unread() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let carol = create_user(&pool, "carol").await;
+    let first = create_conversation(&pool, &[&alice, &bob]).await;
+    let second = create_conversation(&pool, &[&alice, &bob]).await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+    let mut bob_client = WsClient::connect(&state, &bob).await;
+
+    for (conversation_id, message) in [(first, "One"), (first, "Two"), (second, "Three")] {
+        send_message(&mut bob_client, conversation_id, message).await;
+    }
+    // The user's own messages are never unread
+    send_message(&mut alice_client, first, "Mine").await;
+    let request = FriendRequestAction {
+        other_user_id: alice.id,
+        accept: true,
+    };
+    let response = send_friend_request(
+        State(state.clone()),
+        JwtAuth(carol.clone()),
+        AppJson(request),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(summary(&pool, &alice).await, (3, 2, 1));
+
+    alice_client
+        .send(&format!(
+            r#"{{"type": "ReadMessage", "conversationId": {first}}}"#
+        ))
+        .await;
+    alice_client.event("ReadEvent").await;
+    assert_eq!(summary(&pool, &alice).await, (1, 1, 1));
+    // Requests the user sent aren't waiting on them
+    assert_eq!(summary(&pool, &carol).await, (0, 0, 0));
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    state::AppState,
+    upload::{upload_file, FileUpload},
+    users::UserToken,
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose, Engine};
+use common::{addr, create_user, test_db, test_state, test_storage};
+
+/// A text file of about 600 KB
+fn text_file(name: &str) -> String {
+    let line = format!("{name}\n");
+    line.repeat(600_000 / line.len())
+}
+
+async fn upload(state: &AppState, user: &UserToken, contents: &str) -> StatusCode {
+    let upload: FileUpload = sonic_rs::from_str(&format!(
+        r#"{{"fileData":"data:text/plain;base64,{}"}}"#,
+        general_purpose::STANDARD.encode(contents)
+    ))
+    .unwrap();
+    upload_file(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(upload),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+#[tokio::test]
+async fn uploads_over_the_quota_are_rejected() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &["--upload-quota-mb", "1"]).with_storage(test_storage(&dir));
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+
+    let first = text_file("first");
+    assert_eq!(upload(&state, &alice, &first).await, StatusCode::CREATED);
+
+    let second = text_file("second");
+    assert_eq!(
+        upload(&state, &alice, &second).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    // A file the user already has doesn't count twice
+    assert_eq!(upload(&state, &alice, &first).await, StatusCode::CREATED);
+
+    // Quotas are per user
+    assert_eq!(upload(&state, &bob, &second).await, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn concurrent_uploads_cant_both_fit_under_the_quota() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &["--upload-quota-mb", "1"]).with_storage(test_storage(&dir));
+    let alice = create_user(&pool, "alice").await;
+
+    let (first, second) = (text_file("first"), text_file("second"));
+    let statuses = tokio::join!(
+        upload(&state, &alice, &first),
+        upload(&state, &alice, &second)
+    );
+    let mut statuses = [statuses.0, statuses.1];
+    statuses.sort();
+    assert_eq!(
+        statuses,
+        [StatusCode::CREATED, StatusCode::PAYLOAD_TOO_LARGE]
+    );
+}
+
+#[tokio::test]
+async fn huge_quotas_dont_overflow() {
+    let (pool, dir) = test_db().await;
+    let quota = u64::MAX.to_string();
+    let state = test_state(&pool, &["--upload-quota-mb", &quota]).with_storage(test_storage(&dir));
+    let alice = create_user(&pool, "alice").await;
+
+    assert_eq!(
+        upload(&state, &alice, &text_file("first")).await,
+        StatusCode::CREATED
+    );
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    state::AppState,
+    upload::{upload_file, FileUpload},
+    users::UserToken,
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose, Engine};
+use common::{addr, create_user, test_db, test_state, test_storage};
+
+/// Upload a file with the given data URL header and return the response status
+async fn upload(state: &AppState, user: &UserToken, head: Option<&str>, data: &[u8]) -> StatusCode {
+    let encoded = general_purpose::STANDARD.encode(data);
+    let file_data = match head {
+        Some(head) => format!("{head},{encoded}"),
+        None => encoded,
+    };
+    let upload: FileUpload =
+        sonic_rs::from_str(&format!(r#"{{"fileData":"{file_data}"}}"#)).unwrap();
+    upload_file(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(upload),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+    .status()
+}
+
+#[tokio::test]
+async fn only_allowed_file_types_can_be_uploaded() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &[]).with_storage(test_storage(&dir));
+    let user = create_user(&pool, "uploader").await;
+
+    // Executables are detected from their contents whatever they claim to be
+    let mut elf = b"\x7fELF\x02\x01\x01\x00".to_vec();
+    elf.resize(64, 0);
+    let status = upload(&state, &user, Some("data:text/plain;base64"), &elf).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let html = b"<script>alert(1)</script>";
+    let status = upload(&state, &user, Some("data:text/html;base64"), html).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    // Files that don't say what they are and can't be sniffed are rejected
+    let status = upload(&state, &user, None, b"just some bytes").await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn allowed_file_types_can_be_configured() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(
+        &pool,
+        &["--allowed-upload-types", "image/*,application/pdf"],
+    )
+    .with_storage(test_storage(&dir));
+    let user = create_user(&pool, "configured").await;
+
+    let status = upload(&state, &user, Some("data:text/plain;base64"), b"notes").await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    // Any image matches the wildcard
+    let status = upload(&state, &user, None, b"\x89PNG\r\n\x1a\nimage").await;
+    assert_eq!(status, StatusCode::CREATED);
+}
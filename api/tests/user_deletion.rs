@@ -0,0 +1,129 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    error::AppJson,
+    state::AppState,
+    upload::{upload_file, FileUpload},
+    users::{delete_user, purge_deleted_accounts, DeleteAccount, UserToken},
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose, Engine};
+use common::{addr, create_user_with_password, test_db, test_state, test_storage, PASSWORD};
+use sqlx::SqlitePool;
+
+/// Upload a text file and return where it was stored
+async fn upload(state: &AppState, pool: &SqlitePool, user: &UserToken, contents: &str) -> String {
+    let upload: FileUpload = sonic_rs::from_str(&format!(
+        r#"{{"fileData":"data:text/plain;base64,{}"}}"#,
+        general_purpose::STANDARD.encode(contents)
+    ))
+    .unwrap();
+    let response = upload_file(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(upload),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    sqlx::query_scalar::<_, String>(
+        "SELECT path FROM files JOIN file_uploads ON files.id = file_uploads.file_id
+        WHERE user_id = ? ORDER BY file_uploads.created_at DESC, files.id DESC LIMIT 1",
+    )
+    .bind(user.id)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+async fn members(pool: &SqlitePool, conversation_id: i64) -> Vec<(i64, String)> {
+    sqlx::query_as("SELECT user_id, role FROM user_conversations WHERE conversation_id = ?")
+        .bind(conversation_id)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+async fn friendships(pool: &SqlitePool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM friendships")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn purging_a_deleted_user_removes_files_only_they_uploaded() {
+    let (pool, dir) = test_db().await;
+    let state = test_state(&pool, &[]).with_storage(test_storage(&dir));
+    let uploads = dir.path().join("uploads");
+    let alice = create_user_with_password(&pool, "alice").await;
+    let bob = create_user_with_password(&pool, "bob").await;
+
+    let private = upload(&state, &pool, &alice, "only alice").await;
+    let shared = upload(&state, &pool, &alice, "shared").await;
+    assert_eq!(upload(&state, &pool, &bob, "shared").await, shared);
+
+    let conversation_id =
+        sqlx::query_scalar::<_, i64>("INSERT INTO conversations DEFAULT VALUES RETURNING id")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    sqlx::query(
+        "INSERT INTO user_conversations (conversation_id, user_id, role) VALUES (?1, ?2, 'owner'), (?1, ?3, 'member')",
+    )
+    .bind(conversation_id)
+    .bind(alice.id)
+    .bind(bob.id)
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO friendships (user1_id, user2_id) VALUES (?, ?)")
+        .bind(alice.id)
+        .bind(bob.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let login = DeleteAccount {
+        username: alice.username.clone(),
+        password: Some(PASSWORD.to_owned()),
+    };
+    let response = delete_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(alice.clone()),
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Everything is kept while the account can still be reactivated
+    assert!(uploads.join(&private).exists());
+    assert_eq!(members(&pool, conversation_id).await.len(), 2);
+    assert_eq!(friendships(&pool).await, 1);
+    assert_eq!(purge_deleted_accounts(&state, 30).await.ok(), Some(0));
+
+    sqlx::query("UPDATE users SET deleted_at = datetime('now', '-31 days') WHERE id = ?")
+        .bind(alice.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(purge_deleted_accounts(&state, 30).await.ok(), Some(1));
+
+    assert!(!uploads.join(&private).exists());
+    assert!(uploads.join(&shared).exists());
+    // Bob keeps the conversation and loses the friendship with a deleted account
+    assert_eq!(
+        members(&pool, conversation_id).await,
+        [(bob.id, "owner".to_owned())]
+    );
+    assert_eq!(friendships(&pool).await, 0);
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    state::AppState,
+    users::{get_user_by_id, get_user_presence, UserToken},
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+
+async fn presence(state: &AppState, viewer: &UserToken, id: i64) -> Response {
+    get_user_presence(State(state.clone()), JwtAuth(viewer.clone()), Path(id))
+        .await
+        .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn last_seen_is_only_shown_when_not_hidden() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let viewer = create_user(&pool, "viewer").await;
+    let shown = create_user(&pool, "shown").await;
+    let hidden = create_user(&pool, "hidden").await;
+    sqlx::query("UPDATE users SET last_seen_at = '2024-01-01 00:00:00'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO user_settings (user_id, hide_last_seen) VALUES (?, TRUE)")
+        .bind(hidden.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // Users who never saved their settings are shown with the defaults
+    let response = presence(&state, &viewer, shown.id).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["status"].as_str(), Some("Offline"));
+    assert_eq!(body["lastSeenAt"].as_str(), Some("2024-01-01T00:00:00"));
+
+    let response = presence(&state, &viewer, hidden.id).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_json(response).await["lastSeenAt"].is_null());
+
+    sqlx::query("UPDATE users SET deleted_at = CURRENT_TIMESTAMP WHERE id = ?")
+        .bind(shown.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let response = presence(&state, &viewer, shown.id).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn public_lookups_only_show_the_status_when_logged_in() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let viewer = create_user(&pool, "viewer").await;
+    let shown = create_user(&pool, "shown").await;
+
+    let lookup = |viewer: Option<UserToken>| {
+        get_user_by_id(State(state.clone()), viewer.map(JwtAuth), Path(shown.id))
+    };
+    let response = lookup(Some(viewer.clone()))
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(response).await["status"].as_str(),
+        Some("Offline")
+    );
+
+    let response = lookup(None)
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["username"].as_str(), Some("shown"));
+    assert!(body["status"].is_null());
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use ai_health_assistant_api::{
+    state::AppState,
+    users::{search_users, SearchQuery},
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{body_json, create_user, test_db, test_state};
+use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+
+async fn search(state: &AppState, query: &str, params: &str) -> Response {
+    let Query(params): Query<SearchQuery> =
+        Query::try_from_uri(&format!("/?{params}").parse().unwrap()).unwrap();
+    search_users(
+        State(state.clone()),
+        None,
+        Path(query.to_owned()),
+        Query(params),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+fn header(response: &Response, name: &str) -> Option<i64> {
+    response
+        .headers()
+        .get(name)
+        .map(|value| value.to_str().unwrap().parse().unwrap())
+}
+
+#[tokio::test]
+async fn search_results_are_paged_with_headers() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    for username in ["sam_a", "sam_b", "sam_c"] {
+        create_user(&pool, username).await;
+    }
+
+    let response = search(&state, "sam", "limit=2").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, "X-Total-Count"), Some(3));
+    let cursor = header(&response, "X-Next-Cursor").unwrap();
+    // The body is still a plain array of users
+    let body = body_json(response).await;
+    let usernames: Vec<_> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["username"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(usernames, ["sam_a", "sam_b"]);
+
+    let response = search(&state, "sam", &format!("limit=2&cursor={cursor}")).await;
+    assert_eq!(header(&response, "X-Total-Count"), Some(3));
+    assert_eq!(header(&response, "X-Next-Cursor"), None);
+    let body = body_json(response).await;
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["username"].as_str(), Some("sam_c"));
+}
+
+#[tokio::test]
+async fn offset_and_cursor_cannot_be_combined() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+
+    let response = search(&state, "sam", "offset=1&cursor=2").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn matched_field_folds_case_like_the_database() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let user = create_user(&pool, "Éva_x").await;
+    sqlx::query("UPDATE users SET first_name = 'Béa' WHERE id = ?")
+        .bind(user.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // The database doesn't match "é" with the "É" in the username, only with the first name
+    let response = search(&state, "é", "").await;
+    let body = body_json(response).await;
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["matchedField"].as_str(), Some("firstName"));
+
+    let response = search(&state, "EVA", "").await;
+    assert_eq!(body_json(response).await.as_array().unwrap().len(), 0);
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth, error::AppJson, state::AppState, users::update_settings, users::UserToken,
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::{addr, body_json, create_user, test_db, test_state};
+use sonic_rs::JsonValueTrait;
+
+async fn update(state: &AppState, user: &UserToken, settings: &str) -> Response {
+    update_settings(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(sonic_rs::from_str(settings).unwrap()),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn settings_that_are_left_out_keep_their_values() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    // Users without settings start from the defaults
+    let response = update(&state, &alice, r#"{"timezone": "Europe/Helsinki"}"#).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let settings = body_json(response).await;
+    assert_eq!(settings["timezone"].as_str(), Some("Europe/Helsinki"));
+    assert_eq!(settings["locale"].as_str(), Some("en-US"));
+
+    let response = update(&state, &alice, r#"{"theme": "light", "locale": "fi-FI"}"#).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let settings = body_json(response).await;
+    assert_eq!(settings["theme"].as_str(), Some("light"));
+    assert_eq!(settings["locale"].as_str(), Some("fi-FI"));
+    assert_eq!(settings["timezone"].as_str(), Some("Europe/Helsinki"));
+    assert_eq!(settings["settingsVersion"].as_i64(), Some(2));
+}
+
+#[tokio::test]
+async fn time_zones_have_to_exist() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+
+    for timezone in ["UTC", "America/Argentina/Buenos_Aires", "Etc/GMT+5"] {
+        let response = update(&state, &alice, &format!(r#"{{"timezone": "{timezone}"}}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{timezone}");
+    }
+    for timezone in ["Europe/Atlantis", "Mars/Olympus_Mons", "europe", ""] {
+        let response = update(&state, &alice, &format!(r#"{{"timezone": "{timezone}"}}"#)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{timezone}");
+    }
+}
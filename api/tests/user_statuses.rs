@@ -0,0 +1,37 @@
+mod common;
+
+use common::{create_user, test_db, test_state, WsClient};
+use sonic_rs::JsonValueTrait;
+
+#[tokio::test]
+async fn statuses_are_sent_for_each_requested_user() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user(&pool, "alice").await;
+    let bob = create_user(&pool, "bob").await;
+    let mut alice_client = WsClient::connect(&state, &alice).await;
+
+    alice_client
+        .send(&format!(
+            r#"{{"type": "RequestUserStatus", "userIds": [{}, {}]}}"#,
+            alice.id, bob.id
+        ))
+        .await;
+    for (user_id, status) in [(alice.id, "Online"), (bob.id, "Offline")] {
+        let event = alice_client.event("UserStatus").await;
+        assert_eq!(event["userId"].as_i64(), Some(user_id));
+        assert_eq!(event["status"].as_str(), Some(status));
+    }
+
+    let user_ids = vec![bob.id.to_string(); 101].join(", ");
+    alice_client
+        .send(&format!(
+            r#"{{"type": "RequestUserStatus", "userIds": [{user_ids}]}}"#
+        ))
+        .await;
+    let error = alice_client.event("Error").await;
+    assert_eq!(
+        error["message"].as_str(),
+        Some("Cannot request the status of more than 100 users at once")
+    );
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::{AuthConfig, JwtAuth},
+    error::AppJson,
+    state::AppState,
+    users::{update_user, UserToken, UsernameChanges},
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{addr, create_user_with_password, test_db, test_state, PASSWORD};
+use sqlx::SqlitePool;
+
+async fn change_username(state: &AppState, user: &UserToken, username: &str) -> Response {
+    update_user(
+        State(SqlitePool::from_ref(state)),
+        State(AuthConfig::from_ref(state)),
+        State(FromRef::from_ref(state)),
+        State(UsernameChanges::from_ref(state)),
+        ConnectInfo(addr()),
+        JwtAuth(user.clone()),
+        AppJson(
+            sonic_rs::from_str(&format!(
+                r#"{{"password": "{PASSWORD}", "username": "{username}"}}"#
+            ))
+            .unwrap(),
+        ),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response)
+}
+
+#[tokio::test]
+async fn changing_the_username_again_too_soon_says_when_it_can_be_changed() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &["--username-change-cooldown-days", "2"]);
+    let alice = create_user_with_password(&pool, "alice").await;
+
+    let response = change_username(&state, &alice, "alice2").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = change_username(&state, &alice, "alice3").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: i64 = response.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let two_days = 2 * 24 * 60 * 60;
+    assert!((two_days - 60..=two_days + 1).contains(&retry_after));
+
+    // Once the cooldown is over the username can be changed again
+    sqlx::query("UPDATE username_history SET changed_at = datetime('now', '-3 days')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let response = change_username(&state, &alice, "alice3").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use ai_health_assistant_api::{
+    auth::JwtAuth,
+    bans::{ban_user, BanRequest},
+    error::AppJson,
+    users::{delete_user, DeleteAccount},
+};
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use common::{
+    addr, create_user, create_user_with_password, test_db, test_state, WsClient, PASSWORD,
+};
+use sonic_rs::JsonValueTrait;
+
+/// The error the connection was sent last before it was closed
+async fn closed_with(socket: &mut WsClient) -> sonic_rs::Value {
+    let mut events = socket.closed().await;
+    let last = events
+        .pop()
+        .expect("The connection closed without a reason");
+    assert_eq!(last["type"].as_str(), Some("Error"));
+    last
+}
+
+#[tokio::test]
+async fn deleting_the_account_closes_its_connections_with_the_reason() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let alice = create_user_with_password(&pool, "alice").await;
+    let mut first = WsClient::connect(&state, &alice).await;
+    let mut second = WsClient::connect(&state, &alice).await;
+
+    let login = DeleteAccount {
+        username: alice.username.clone(),
+        password: Some(PASSWORD.to_owned()),
+    };
+    let response = delete_user(
+        State(state.clone()),
+        ConnectInfo(addr()),
+        JwtAuth(alice.clone()),
+        AppJson(login),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for socket in [&mut first, &mut second] {
+        let error = closed_with(socket).await;
+        assert_eq!(error["errorType"].as_str(), Some("AuthError"));
+        assert_eq!(error["message"].as_str(), Some("Your account was deleted"));
+    }
+}
+
+#[tokio::test]
+async fn banning_a_user_closes_their_connections_with_the_ban() {
+    let (pool, _dir) = test_db().await;
+    let state = test_state(&pool, &[]);
+    let admin = create_user(&pool, "admin").await;
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = ?")
+        .bind(admin.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let bob = create_user(&pool, "bob").await;
+    let mut socket = WsClient::connect(&state, &bob).await;
+
+    let response = ban_user(
+        State(state.clone()),
+        JwtAuth(admin.clone()),
+        Path(bob.id),
+        AppJson(BanRequest {
+            reason: Some("Spamming".to_owned()),
+            expires_at: None,
+        }),
+    )
+    .await
+    .unwrap_or_else(IntoResponse::into_response);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let error = closed_with(&mut socket).await;
+    assert_eq!(error["errorType"].as_str(), Some("Banned"));
+    assert_eq!(error["ban"]["reason"].as_str(), Some("Spamming"));
+}
@@ -143,7 +143,7 @@ export default function ChatMessagePage() {
               isFromUser={message.userId === user.id}
               key={`${message.userId}-${i}`}
             >
-              {message.filePath && <MessageAttachment fileName={message.fileName} filePath={`http://localhost:3000/api/upload/${message.filePath}`} />}
+              {message.fileId && <MessageAttachment fileName={message.fileName} fileId={message.fileId} />}
             </SpeechBubble>
           );
         })}
@@ -88,7 +88,7 @@ export default function Login() {
                     username: e.target.value,
                   });
                 }}
-                placeholder="Username"
+                placeholder="Username or email"
                 autoComplete="off"
                 required
                 className="w-full mt-8 pr-4 py-2 border-b-[1px] placeholder:text-surface75 focus:outline-none transition-colors duration-200 border-b-offwhite focus:border-b-lilac bg-main-black text-offwhite"
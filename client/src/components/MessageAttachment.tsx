@@ -1,35 +1,39 @@
 import { useEffect, useState } from 'react';
+import { BASE_URL, getJwt } from '../utils/utils';
 
 interface Props {
   fileName?: string;
-  filePath?: string;
+  fileId?: number;
 }
 
-export default function MessageAttachment({ fileName, filePath }: Props) {
+export default function MessageAttachment({ fileName, fileId }: Props) {
   const [fileType, setFileType] = useState<string | null>(null);
   const [fileSize, setFileSize] = useState<number | null>(null);
+  // Attachments need the JWT to download so they are shown from a local copy
+  const [filePath, setFilePath] = useState<string | null>(null);
 
   useEffect(() => {
-    if (filePath) {
-      // Can't use HEAD request because of a bug in hyper, 
-      // the lower level library used by the rust backend
-      // reference: https://github.com/hyperium/hyper/issues/2427
-      fetch(filePath, { method: 'GET' })
-        .then(response => {
-          const contentType = response.headers.get('Content-Type');
-          const contentLength = response.headers.get('Content-Length');
-          if (contentType) {
-            setFileType(contentType);
-          }
-          if (contentLength) {
-            setFileSize(parseInt(contentLength, 10));
-          }
-        })
-        .catch(error => {
-          console.error('Error fetching file metadata:', error);
-        });
-    }
-  }, [filePath]);
+    if (!fileId) return;
+    let objectUrl: string | null = null;
+    fetch(`${BASE_URL}/api/files/${fileId}`, {
+      method: 'GET',
+      headers: { authorization: `Bearer ${getJwt()}` },
+    })
+      .then(async response => {
+        if (!response.ok) throw new Error(`Status ${response.status}`);
+        const blob = await response.blob();
+        objectUrl = URL.createObjectURL(blob);
+        setFileType(response.headers.get('Content-Type') ?? blob.type);
+        setFileSize(blob.size);
+        setFilePath(objectUrl);
+      })
+      .catch(error => {
+        console.error('Error fetching file:', error);
+      });
+    return () => {
+      if (objectUrl) URL.revokeObjectURL(objectUrl);
+    };
+  }, [fileId]);
 
   const renderAttachment = () => {
     if (!filePath || !fileType) return null;
@@ -50,7 +54,7 @@ export default function MessageAttachment({ fileName, filePath }: Props) {
       return (
         <div className="flex space-x-4 items-center bg-opacity-40 bg-black rounded-lg px-6 py-4">
           <div className="flex-shrink-0 w-6 h-6">
-            <a href={filePath} download>
+            <a href={filePath} download={fileName}>
               <img
                 src="/download.svg"
                 alt="Download"
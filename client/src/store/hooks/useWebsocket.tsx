@@ -80,7 +80,7 @@ export default function useWebsocketSetup() {
                   userId: data.userId,
                   content: data.message,
                   fromAi: !data.userId,
-                  filePath: data.filePath,
+                  fileId: data.fileId,
                   fileName: data.fileName,
                   streaming: false,
                 },
@@ -97,7 +97,7 @@ export default function useWebsocketSetup() {
                     userId: data.userId,
                     content: data.message,
                     fromAi: !data.userId,
-                    filePath: data.filePath,
+                    fileId: data.fileId,
                     fileName: data.fileName,
                     streaming: false,
                   },
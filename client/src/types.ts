@@ -52,7 +52,7 @@ export type Message = {
     fromAi?: boolean,
     streaming?: boolean,
     querierId?: number,
-    filePath?: string,
+    fileId?: number,
     fileName?: string
 }
 